@@ -145,6 +145,31 @@ pub enum AmmError {
     UnknownAmmError,
     #[error("Not allowed due to same mint.")]
     NotAllowed,
+
+    #[error("Mint extension is not supported.")]
+    UnsupportedMintExtension,
+    #[error("Invalid observation account.")]
+    InvalidObservationAccount,
+    #[error("Invalid referrer token account.")]
+    InvalidReferrerAccount,
+    #[error("Invalid dynamic fee account.")]
+    InvalidDynamicFeeAccount,
+    #[error("A flash loan of the pool is open.")]
+    FlashLoanLocked,
+    #[error("The flash loan and its fee were not repaid.")]
+    FlashLoanNotRepaid,
+    #[error("Invalid range order account.")]
+    InvalidRangeOrderAccount,
+    #[error("The range order slot is in use.")]
+    RangeOrderSlotInUse,
+    #[error("Invalid limit order account.")]
+    InvalidLimitOrderAccount,
+    #[error("No queued params are effective yet.")]
+    PendingParamsNotEffective,
+    #[error("The program is paused by the guardian.")]
+    GlobalPaused,
+    #[error("The range order is below the minimum size.")]
+    RangeOrderTooSmall,
 }
 
 impl From<AmmError> for ProgramError {
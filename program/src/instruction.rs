@@ -3,7 +3,10 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(deprecated)]
 
-use crate::state::{AmmParams, Fees};
+use crate::{
+    processor::{AMM_CONFIG_SEED, EVENT_AUTHORITY_SEED},
+    state::{AmmParams, DynamicFeeParams, Fees, SimulateParams},
+};
 use arrayref::array_ref;
 use solana_program::{
     instruction::{AccountMeta, Instruction},
@@ -35,6 +38,8 @@ pub struct InitializeInstruction2 {
     pub init_pc_amount: u64,
     /// init token coin amount
     pub init_coin_amount: u64,
+    /// (coin weight, pc weight) of a weighted pool, summing to WEIGHT_DENOMINATOR
+    pub weights: Option<(u64, u64)>,
 }
 
 #[repr(C)]
@@ -82,6 +87,99 @@ pub struct SetParamsInstruction {
     pub fees: Option<Fees>,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DepositSingleSideInstruction {
+    /// Amount of the source token to deposit, part of it is swapped into the other token
+    pub amount_in: u64,
+    /// Minimum amount of lp token to mint, prevents excessive slippage
+    pub minimum_lp_out: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WithdrawSingleSideInstruction {
+    /// Pool token amount to burn
+    pub amount: u64,
+    /// Minimum amount of the destination token received, prevents excessive slippage
+    pub min_amount_out: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlashLoanInstruction {
+    /// Amount of coin lent from the coin vault
+    pub coin_amount: u64,
+    /// Amount of pc lent from the pc vault
+    pub pc_amount: u64,
+    /// Instruction data of the borrower program
+    pub data: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlashSwapInstruction {
+    /// Maximum amount of SOURCE token owed to the pool, prevents excessive slippage
+    pub max_amount_in: u64,
+    /// Amount of DESTINATION token sent before the callback
+    pub amount_out: u64,
+    /// Instruction data of the callback program, after the owed amount
+    pub data: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SetCurveInstruction {
+    /// CurveType of the swaps
+    pub curve_type: u8,
+    /// Amplification coefficient of a stable curve at ramp_stop_timestamp,
+    /// 0 stops the ramp at the current amplification coefficient
+    pub amp: u64,
+    /// End of the amplification coefficient ramp
+    pub ramp_stop_timestamp: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PauseInstruction {
+    /// AmmStatus::WithdrawOnly or AmmStatus::Disabled
+    pub status: u8,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlaceRangeOrderInstruction {
+    /// OrderSide, 0 to buy coin with pc and 1 to sell coin for pc
+    pub side: u8,
+    /// Slot of the order in the buy or sell orders of the TargetOrders
+    pub slot: u8,
+    /// Pc per coin, scaled like the pool price
+    pub price: u64,
+    /// Amount deposited, pc for a buy order and coin for a sell order
+    pub amount: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PlaceLimitOrderInstruction {
+    /// OrderSide, 0 to buy coin with pc and 1 to sell coin for pc
+    pub side: u8,
+    /// Id of the order among the orders of the user
+    pub order_id: u64,
+    /// Limit price, pc per coin scaled like the pool price
+    pub price: u64,
+    /// Amount escrowed, pc for a buy order and coin for a sell order
+    pub amount: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DynamicFeeInstruction {
+    /// 1 to charge the dynamic swap fee, 0 to charge the fixed swap fee
+    pub enabled: u8,
+    pub params: DynamicFeeParams,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct WithdrawSrmInstruction {
@@ -95,6 +193,8 @@ pub struct SwapInstructionBaseIn {
     pub amount_in: u64,
     /// Minimum amount of DESTINATION token to output, prevents excessive slippage
     pub minimum_amount_out: u64,
+    /// The `referrer_accounts` are appended, packed as an optional trailing byte
+    pub referrer: bool,
 }
 
 #[repr(C)]
@@ -104,6 +204,8 @@ pub struct SwapInstructionBaseOut {
     pub max_amount_in: u64,
     /// Minimum amount of DESTINATION token to output, prevents excessive slippage
     pub amount_out: u64,
+    /// The `referrer_accounts` are appended, packed as an optional trailing byte
+    pub referrer: bool,
 }
 
 #[repr(C)]
@@ -127,6 +229,10 @@ pub struct ConfigArgs {
     pub param: u8,
     pub owner: Option<Pubkey>,
     pub create_pool_fee: Option<u64>,
+    pub referrer_fee_rate: Option<u64>,
+    pub flash_fee_rate: Option<u64>,
+    pub params_delay: Option<u64>,
+    pub global_status: Option<u64>,
 }
 
 /// Instructions supported by the AmmInfo program.
@@ -162,6 +268,10 @@ pub enum AmmInstruction {
     ///   18. `[]` User token coin Account
     ///   19. '[]` User token pc Account
     ///   20. `[writable]` User destination lp token ATA Account
+    ///   21. `[]` (optional)Token-2022 program id, required if coin or pc mint is a Token-2022 mint.
+    ///
+    ///   With weights the pool swaps on the weighted curve, and the initial liquidity is the
+    ///   weighted geometric mean of the amounts instead of their square root.
     Initialize2(InitializeInstruction2),
 
     ///   MonitorStep. To monitor place Amm order state machine turn around step by step.
@@ -185,6 +295,14 @@ pub enum AmmInstruction {
     ///   11. `[writable]` User lp token. To deposit the generated tokens, user is the owner.
     ///   12. '[signer]` User wallet Account
     ///   13. `[]` Market event queue Account.
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     Deposit(DepositInstruction),
 
     ///   Withdraw the vault tokens from the pool at the current ratio.
@@ -209,6 +327,14 @@ pub enum AmmInstruction {
     ///   17. `[writable]` Market event queue Account
     ///   18. `[writable]` Market bids Account
     ///   19. `[writable]` Market asks Account
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     Withdraw(WithdrawInstruction),
 
     ///   Migrate the associated market from Serum to OpenBook.
@@ -216,9 +342,21 @@ pub enum AmmInstruction {
     MigrateToOpenBook,
 
     ///   Set AMM params
+    ///   A new status or new fees are queued in the target orders Account, and are applied by
+    ///   ApplyParams once the params_delay of the AMM config has passed. Moving the status to
+    ///   Disabled or WithdrawOnly takes effect at once and drops the queued status.
     ///
     ///   0. `[writable]` AMM Account.
     ///   1. `[signer]` Admin Account
+    ///   2. `[]` AMM config Account, holding the admin
+    ///
+    ///   Setting the status or the fees needs the target orders appended:
+    ///   `[writable]` AMM target orders Account.
+    ///   Setting the fees of a pool in dynamic fee mode needs its bounds appended after
+    ///   them: `[]` AMM dynamic fee Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     SetParams(SetParamsInstruction),
 
     ///   Withdraw Pnl from pool by protocol
@@ -233,6 +371,9 @@ pub enum AmmInstruction {
     ///   8. `[writable]` User pc token Account to withdraw to
     ///   9. `[signer]` User wallet account
     ///   10. `[writable]` AMM target orders Account
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
     WithdrawPnl,
 
     ///   Withdraw (M)SRM from the (M)SRM Account used for fee discounts by admin
@@ -259,6 +400,28 @@ pub enum AmmInstruction {
     ///   15. `[writable]` User source token Account.
     ///   16. `[writable]` User destination token Account.
     ///   17. `[signer]` User wallet Account
+    ///
+    ///   The range orders priced at or better than the pool price are filled first when the
+    ///   target orders Account is passed and the `range_order_vaults` are appended:
+    ///   `[writable]` Range order coin vault Account, `[writable]` Range order pc vault Account.
+    ///   The limit orders reached by the pool price are executed after the swap when the
+    ///   `limit_order_accounts` are appended before any other optional account:
+    ///   `[writable]` Limit order coin vault Account, `[writable]` Limit order pc vault Account,
+    ///   and up to MAX_ORDER_LIMIT `[writable]` Limit order Accounts.
+    ///   A referrer earns a share of the swap fee in the source mint when `referrer` is set and
+    ///   the `referrer_accounts` are appended first: `[]` AMM config Account, `[writable]` Referrer
+    ///   token Account of the source mint, not owned by the user.
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     SwapBaseIn(SwapInstructionBaseIn),
 
     ///   Continue Initializes a new Amm pool because of compute units limit.
@@ -286,18 +449,54 @@ pub enum AmmInstruction {
     ///   15. `[writable]` User source token Account.
     ///   16. `[writable]` User destination token Account.
     ///   17. `[signer]` User wallet Account
+    ///
+    ///   The limit orders reached by the pool price are executed after the swap when the
+    ///   `limit_order_accounts` are appended before any other optional account:
+    ///   `[writable]` Limit order coin vault Account, `[writable]` Limit order pc vault Account,
+    ///   and up to MAX_ORDER_LIMIT `[writable]` Limit order Accounts.
+    ///   A referrer earns a share of the swap fee in the source mint when `referrer` is set and
+    ///   the `referrer_accounts` are appended first: `[]` AMM config Account, `[writable]` Referrer
+    ///   token Account of the source mint, not owned by the user.
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     SwapBaseOut(SwapInstructionBaseOut),
 
-    #[deprecated(note = "Not supported yet")]
+    ///   Simulate a swap or read the pool info without changing any account.
+    ///   The result is returned by `set_return_data` as a `SimulatePoolInfo` or `SimulateSwapInfo`.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` AMM Account
+    ///   2. `[]` AMM coin vault Account
+    ///   3. `[]` AMM pc vault Account
+    ///   4. `[]` (swap only)Source token mint, the destination is the other mint of the pool.
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended last:
+    ///   `[]` AMM dynamic fee Account.
     SimulateInfo(SimulateInstruction),
 
     #[deprecated(note = "Not supported yet")]
     AdminCancelOrders(AdminCancelOrdersInstruction),
 
-    /// Create amm config account by admin
+    /// Create amm config account by the compile-time amm owner, which becomes the admin.
+    /// A config account created before it held the admin only gets the admin set.
+    /// The event authority and AMM program Accounts can be appended to emit the
+    /// event by an EmitEvent self invocation instead of a ray_log.
     CreateConfigAccount,
 
     /// Update amm config account by admin
+    /// The event authority and AMM program Accounts can be appended to emit the
+    /// event by an EmitEvent self invocation instead of a ray_log.
     UpdateConfigAccount(ConfigArgs),
 
     /// Swap coin or pc from pool with orderbook disable, base amount_in with a slippage of minimum_amount_out
@@ -310,6 +509,25 @@ pub enum AmmInstruction {
     ///   5. `[writable]` User source token Account.
     ///   6. `[writable]` User destination token Account.
     ///   7. `[signer]` User wallet Account
+    ///
+    ///   The limit orders reached by the pool price are executed after the swap when the
+    ///   `limit_order_accounts` are appended before any other optional account:
+    ///   `[writable]` Limit order coin vault Account, `[writable]` Limit order pc vault Account,
+    ///   and up to MAX_ORDER_LIMIT `[writable]` Limit order Accounts.
+    ///   A referrer earns a share of the swap fee in the source mint when `referrer` is set and
+    ///   the `referrer_accounts` are appended first: `[]` AMM config Account, `[writable]` Referrer
+    ///   token Account of the source mint, not owned by the user.
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     SwapBaseInV2(SwapInstructionBaseIn),
 
     /// Swap coin or pc from pool with orderbook disable, base amount_out with a slippage of max_amount_in
@@ -322,9 +540,371 @@ pub enum AmmInstruction {
     ///   5. `[writable]` User source token Account.
     ///   6. `[writable]` User destination token Account.
     ///   7. `[signer]` User wallet Account
+    ///
+    ///   The limit orders reached by the pool price are executed after the swap when the
+    ///   `limit_order_accounts` are appended before any other optional account:
+    ///   `[writable]` Limit order coin vault Account, `[writable]` Limit order pc vault Account,
+    ///   and up to MAX_ORDER_LIMIT `[writable]` Limit order Accounts.
+    ///   A referrer earns a share of the swap fee in the source mint when `referrer` is set and
+    ///   the `referrer_accounts` are appended first: `[]` AMM config Account, `[writable]` Referrer
+    ///   token Account of the source mint, not owned by the user.
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
     SwapBaseOutV2(SwapInstructionBaseOut),
+
+    ///   Create the observation account of a pool by admin. From then on every instruction
+    ///   moving the pool price must pass it, and records the pool price into it.
+    ///
+    ///   0. `[]` Sys program id
+    ///   1. `[]` Rent program id
+    ///   2. `[writable]` AMM Account
+    ///   3. `[writable]` AMM observation Account, derived from `find_program_address(&[amm_program, amm, OBSERVATION_ASSOCIATED_SEED])`.
+    ///   4. `[writable, signer]` Payer Account
+    ///   5. `[signer]` Admin Account
+    ///   6. `[]` AMM config Account
+    CreateObservation,
+
+    ///   Swap through several pools in order with orderbook disable, base amount_in
+    ///   with a single slippage of minimum_amount_out on the output of the last pool.
+    ///   The user destination token Account of a pool is the user source token Account of the next one.
+    ///
+    ///   For each pool, at most `MAX_SWAP_ROUTE_HOPS`:
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM coin vault Account to swap FROM or To.
+    ///   4. `[writable]` AMM pc vault Account to swap FROM or To.
+    ///   5. `[writable]` User source token Account.
+    ///   6. `[writable]` User destination token Account.
+    ///   7. `[signer]` User wallet Account
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended to their accounts:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended after them:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it after them, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   No referrer is paid on a route swap, setting `referrer` is refused.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    SwapRouteBaseIn(SwapInstructionBaseIn),
+
+    ///   Swap through several pools in order with orderbook disable, base amount_out
+    ///   of the last pool with a single slippage of max_amount_in on the input of the first pool.
+    ///   The accounts are the same as `SwapRouteBaseIn`.
+    SwapRouteBaseOut(SwapInstructionBaseOut),
+
+    ///   Switch a pool between the fixed and the dynamic swap fee, and set the dynamic fee bounds.
+    ///   The dynamic swap fee is the fixed one plus a part growing with the recent volatility
+    ///   of the pool price, within min_fee_numerator and max_fee_numerator.
+    ///
+    ///   0. `[]` Sys program id
+    ///   1. `[]` Rent program id
+    ///   2. `[writable]` AMM Account
+    ///   3. `[writable]` AMM dynamic fee Account, derived from `find_program_address(&[amm_program, amm, DYNAMIC_FEE_ASSOCIATED_SEED])`.
+    ///   4. `[writable, signer]` Admin Account, pays for the dynamic fee Account the first time.
+    ///   5. `[]` AMM config Account
+    SetDynamicFee(DynamicFeeInstruction),
+
+    ///   Deposit only the coin or only the pc token into the pool. The part of amount_in
+    ///   swapped at the pool price and swap fee is chosen so that the rest and the swap
+    ///   output are in the pool ratio, then lp is minted against both.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM target orders Account
+    ///   4. `[writable]` AMM lp mint Account
+    ///   5. `[writable]` AMM coin vault Account
+    ///   6. `[writable]` AMM pc vault Account
+    ///   7. `[writable]` User source coin or pc token Account
+    ///   8. `[writable]` User lp token Account
+    ///   9. `[signer]` User wallet Account
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended next:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   No referrer is paid on the swapped part, the `referrer_accounts` are refused.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    DepositSingleSide(DepositSingleSideInstruction),
+
+    ///   Withdraw only the coin or only the pc token from the pool. The lp share of the
+    ///   other token is swapped at the pool reserves after the withdrawal and swap fee.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM target orders Account
+    ///   4. `[writable]` AMM lp mint Account
+    ///   5. `[writable]` AMM coin vault Account
+    ///   6. `[writable]` AMM pc vault Account
+    ///   7. `[writable]` User lp token Account
+    ///   8. `[writable]` User destination coin or pc token Account
+    ///   9. `[signer]` User wallet Account
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` appended:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account appended next:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must append it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   No referrer is paid on the swapped part, the `referrer_accounts` are refused.
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    WithdrawSingleSide(WithdrawSingleSideInstruction),
+
+    ///   Lend coin and pc from the pool vaults within the instruction. The borrower program is
+    ///   invoked with `data` and the remaining accounts after the loan is sent, and must have
+    ///   repaid the vaults plus the flash fee of the config account when it returns.
+    ///   Deposit, withdraw and swap of the pool are refused while the loan is open.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[]` AMM config Account, derived from `find_program_address(&[&&AMM_CONFIG_SEED])`.
+    ///   4. `[writable]` AMM coin vault Account
+    ///   5. `[writable]` AMM pc vault Account
+    ///   6. `[writable]` Borrower coin token Account
+    ///   7. `[writable]` Borrower pc token Account
+    ///   8. `[]` Borrower program id
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` next:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   All the following accounts are passed to the borrower program.
+    FlashLoan(FlashLoanInstruction),
+
+    ///   Swap with orderbook disable, base amount_out sent before the input is paid.
+    ///   The callback program is invoked with the owed amount_in as a little endian u64
+    ///   followed by `data`, and the remaining accounts. When it returns, the source vault
+    ///   must have received the amount_in less the source mint transfer fee.
+    ///   Deposit, withdraw and swap of the pool are refused during the callback.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[writable]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[]` AMM config Account, derived from `find_program_address(&[&&AMM_CONFIG_SEED])`.
+    ///   4. `[writable]` AMM coin vault Account
+    ///   5. `[writable]` AMM pc vault Account
+    ///   6. `[writable]` User destination token Account, its mint is the DESTINATION token.
+    ///   7. `[]` Callback program id
+    ///
+    ///   Pools with a Token-2022 vault need the `token_2022_accounts` next:
+    ///   `[]` Token-2022 program id, `[]` AMM coin mint Account, `[]` AMM pc mint Account.
+    ///   Pools in dynamic fee mode need their dynamic fee account next:
+    ///   `[writable]` AMM dynamic fee Account.
+    ///   Pools with an observation account must pass it next, it records the pool price:
+    ///   `[writable]` AMM observation Account.
+    ///   All the following accounts are passed to the callback program.
+    ///   No referrer is paid on a flash swap, the pool and the protocol share the swap fee.
+    ///   The event authority and AMM program Accounts can be appended after all the others,
+    ///   they are not passed to the callback program, to emit the swap event by an EmitEvent
+    ///   self invocation instead of a ray_log: `[]` event authority Account, `[]` AMM program.
+    FlashSwap(FlashSwapInstruction),
+
+    ///   Set the curve of a pool, or ramp the amplification coefficient of a stable pool.
+    ///   The curve type can only be changed before the pool opens, and is then set with amp
+    ///   at once. Weighted pools get their weights at Initialize2 and keep their curve. The amplification coefficient of a stable pool moves linearly from the
+    ///   current one to amp until ramp_stop_timestamp, at least MIN_RAMP_DURATION away, and
+    ///   by a factor of at most MAX_AMP_CHANGE.
+    ///
+    ///   0. `[writable]` AMM Account
+    ///   1. `[signer]` Admin Account
+    ///   2. `[]` AMM config Account
+    SetCurve(SetCurveInstruction),
+
+    ///   Turn the unused TargetOrders slots of a pool into range orders, and create the
+    ///   vaults holding their deposits. Pools with a Token-2022 vault have no range orders.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` System program id
+    ///   2. `[]` Rent program id
+    ///   3. `[]` AMM Account
+    ///   4. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   5. `[writable]` AMM target orders Account
+    ///   6. `[]` AMM coin mint Account
+    ///   7. `[]` AMM pc mint Account
+    ///   8. `[writable]` Range order coin vault Account, derived from `RANGE_ORDER_COIN_VAULT_SEED`
+    ///   9. `[writable]` Range order pc vault Account, derived from `RANGE_ORDER_PC_VAULT_SEED`
+    ///   10. `[writable, signer]` Payer Account
+    InitRangeOrders,
+
+    ///   Deposit pc to buy coin, or coin to sell for pc, at a price. The order is filled by
+    ///   SwapBaseIn when the pool price crosses it, its proceeds are claimable. The deposit
+    ///   is at least the pool vault of its token over `RANGE_ORDER_MIN_SIZE_DIVISOR`.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` System program id
+    ///   2. `[]` Rent program id
+    ///   3. `[]` AMM Account
+    ///   4. `[writable]` AMM target orders Account
+    ///   5. `[writable]` Range order vault Account of the deposited token
+    ///   6. `[writable]` Range order Account, derived from `RANGE_ORDER_SEED` with the side and slot
+    ///   7. `[writable]` User source token Account
+    ///   8. `[writable, signer]` User wallet Account
+    ///   9. `[]` AMM vault Account of the deposited token, pc vault for a buy order and coin vault for a sell order
+    ///
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    PlaceRangeOrder(PlaceRangeOrderInstruction),
+
+    ///   Cancel a range order, paying out its unclaimed proceeds and its unfilled deposit.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` AMM target orders Account
+    ///   4. `[writable]` Range order coin vault Account
+    ///   5. `[writable]` Range order pc vault Account
+    ///   6. `[writable]` Range order Account
+    ///   7. `[writable]` User coin token Account
+    ///   8. `[writable]` User pc token Account
+    ///   9. `[writable, signer]` User wallet Account, owner of the range order
+    ///
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    CancelRangeOrder,
+
+    ///   Pay out the unclaimed proceeds of a range order. A fully filled order is closed.
+    ///
+    ///   Same accounts as CancelRangeOrder.
+    ClaimRangeOrder,
+
+    ///   Escrow pc to buy coin, or coin to sell for pc, at a limit price. The whole amount is
+    ///   swapped through the pool by a swap that passes the order once the pool price reaches
+    ///   the limit price, if the pool pays at least the limit price for it.
+    ///   Pools with a Token-2022 vault have no limit orders.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` System program id
+    ///   2. `[]` Rent program id
+    ///   3. `[]` AMM Account
+    ///   4. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   5. `[]` AMM coin mint Account
+    ///   6. `[]` AMM pc mint Account
+    ///   7. `[writable]` Limit order coin vault Account, derived from `LIMIT_ORDER_COIN_VAULT_SEED`
+    ///   8. `[writable]` Limit order pc vault Account, derived from `LIMIT_ORDER_PC_VAULT_SEED`
+    ///   9. `[writable]` Limit order Account, derived from
+    ///      `find_program_address(&[LIMIT_ORDER_SEED, amm, owner, order_id])`
+    ///   10. `[writable]` User source token Account
+    ///   11. `[writable, signer]` User wallet Account
+    ///
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    PlaceLimitOrder(PlaceLimitOrderInstruction),
+
+    ///   Cancel a limit order, paying out its unclaimed proceeds and its escrowed amount.
+    ///
+    ///   0. `[]` Spl Token program id
+    ///   1. `[]` AMM Account
+    ///   2. `[]` $authority derived from `create_program_address(&[AUTHORITY_AMM, &[nonce]])`.
+    ///   3. `[writable]` Limit order coin vault Account
+    ///   4. `[writable]` Limit order pc vault Account
+    ///   5. `[writable]` Limit order Account
+    ///   6. `[writable]` User coin token Account
+    ///   7. `[writable]` User pc token Account
+    ///   8. `[writable, signer]` User wallet Account, owner of the limit order
+    ///
+    ///   The AMM config Account is appended after all the other accounts, its global status
+    ///   can stop the instruction: `[]` AMM config Account.
+    CancelLimitOrder,
+
+    ///   Pay out the proceeds of an executed limit order and close it.
+    ///
+    ///   Same accounts as CancelLimitOrder.
+    ClaimLimitOrder,
+
+    ///   Settle the pnl accrued by a pool without protocol fee into need_take_pnl_coin &
+    ///   need_take_pnl_pc, and move calc_pnl_x & calc_pnl_y to the reserves left. A pool
+    ///   with protocol fee only moves calc_pnl. Nothing is transferred, anyone can call it.
+    ///
+    ///   0. `[writable]` AMM Account
+    ///   1. `[writable]` AMM target orders Account
+    ///   2. `[]` AMM coin vault Account
+    ///   3. `[]` AMM pc vault Account
+    SettlePnl,
+
+    ///   Propose a new admin of the program, who becomes admin once it accepts.
+    ///   Proposing the default pubkey withdraws the pending proposal.
+    ///
+    ///   0. `[signer]` Admin Account
+    ///   1. `[writable]` AMM config Account
+    ///   2. `[]` New admin Account
+    ///
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    ProposeAdmin,
+
+    ///   Accept the admin of the program proposed by the admin.
+    ///
+    ///   0. `[signer]` Pending admin Account
+    ///   1. `[writable]` AMM config Account
+    ///
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    AcceptAdmin,
+
+    ///   Apply the status and fees queued by SetParams whose delay has passed.
+    ///   Anyone can call it.
+    ///
+    ///   0. `[writable]` AMM Account
+    ///   1. `[writable]` AMM target orders Account
+    ///
+    ///   Applying the fees of a pool in dynamic fee mode needs its bounds appended:
+    ///   `[]` AMM dynamic fee Account.
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    ApplyParams,
+
+    ///   Move a pool, or all the pools when no AMM Account is given, to WithdrawOnly or
+    ///   Disabled by the guardian or the admin. The status can only get more restrictive,
+    ///   the admin lifts it by SetParams or UpdateConfigAccount.
+    ///
+    ///   0. `[signer]` Guardian or admin Account
+    ///   1. `[writable]` AMM config Account
+    ///   2. `[writable]` (optional)AMM Account
+    ///   3. `[writable]` (optional)AMM target orders Account, its queued status is dropped
+    ///
+    ///   The event authority and AMM program Accounts can be appended after it to emit
+    ///   the events by an EmitEvent self invocation instead of ray_logs: `[]` event authority
+    ///   Account, `[]` AMM program.
+    Pause(PauseInstruction),
+
+    ///   Carries an event the program emits by invoking itself, kept in the inner
+    ///   instructions when the transaction logs are truncated. The data after the tag is
+    ///   the event as a ray_log encodes it, only the program can sign for its event authority.
+    ///
+    ///   0. `[signer]` Event authority Account, PDA of EVENT_AUTHORITY_SEED
+    EmitEvent(Vec<u8>),
 }
 
+/// Tag of the EmitEvent instruction.
+pub const EMIT_EVENT_TAG: u8 = 39;
+
 impl AmmInstruction {
     /// Unpacks a byte buffer into a [AmmInstruction](enum.AmmInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
@@ -336,12 +916,20 @@ impl AmmInstruction {
                 let (nonce, rest) = Self::unpack_u8(rest)?;
                 let (open_time, rest) = Self::unpack_u64(rest)?;
                 let (init_pc_amount, rest) = Self::unpack_u64(rest)?;
-                let (init_coin_amount, _reset) = Self::unpack_u64(rest)?;
+                let (init_coin_amount, rest) = Self::unpack_u64(rest)?;
+                let weights = if rest.len() >= 16 {
+                    let (coin_weight, rest) = Self::unpack_u64(rest)?;
+                    let (pc_weight, _rest) = Self::unpack_u64(rest)?;
+                    Some((coin_weight, pc_weight))
+                } else {
+                    None
+                };
                 Self::Initialize2(InitializeInstruction2 {
                     nonce,
                     open_time,
                     init_pc_amount,
                     init_coin_amount,
+                    weights,
                 })
             }
             3 => {
@@ -409,31 +997,75 @@ impl AmmInstruction {
             7 => Self::WithdrawPnl,
             9 => {
                 let (amount_in, rest) = Self::unpack_u64(rest)?;
-                let (minimum_amount_out, _rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
                 Self::SwapBaseIn(SwapInstructionBaseIn {
                     amount_in,
                     minimum_amount_out,
+                    referrer,
                 })
             }
             11 => {
                 let (max_amount_in, rest) = Self::unpack_u64(rest)?;
-                let (amount_out, _rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
                 Self::SwapBaseOut(SwapInstructionBaseOut {
                     max_amount_in,
                     amount_out,
+                    referrer,
                 })
             }
+            12 => {
+                let (param, rest) = Self::unpack_u8(rest)?;
+                match SimulateParams::from_u64(param as u64)? {
+                    SimulateParams::PoolInfo => Self::SimulateInfo(SimulateInstruction {
+                        param,
+                        swap_base_in_value: None,
+                        swap_base_out_value: None,
+                    }),
+                    SimulateParams::SwapBaseInInfo => {
+                        let (amount_in, rest) = Self::unpack_u64(rest)?;
+                        let (minimum_amount_out, _rest) = Self::unpack_u64(rest)?;
+                        Self::SimulateInfo(SimulateInstruction {
+                            param,
+                            swap_base_in_value: Some(SwapInstructionBaseIn {
+                                amount_in,
+                                minimum_amount_out,
+                                referrer: false,
+                            }),
+                            swap_base_out_value: None,
+                        })
+                    }
+                    SimulateParams::SwapBaseOutInfo => {
+                        let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+                        let (amount_out, _rest) = Self::unpack_u64(rest)?;
+                        Self::SimulateInfo(SimulateInstruction {
+                            param,
+                            swap_base_in_value: None,
+                            swap_base_out_value: Some(SwapInstructionBaseOut {
+                                max_amount_in,
+                                amount_out,
+                                referrer: false,
+                            }),
+                        })
+                    }
+                }
+            }
             14 => Self::CreateConfigAccount,
             15 => {
                 let (param, rest) = Self::unpack_u8(rest)?;
                 match param {
-                    0 | 1 => {
+                    0 | 1 | 7 => {
                         if rest.len() >= 32 {
                             let pubkey = array_ref![rest, 0, 32];
                             Self::UpdateConfigAccount(ConfigArgs {
                                 param,
                                 owner: Some(Pubkey::new_from_array(*pubkey)),
                                 create_pool_fee: None,
+                                referrer_fee_rate: None,
+                                flash_fee_rate: None,
+                                params_delay: None,
+                                global_status: None,
                             })
                         } else {
                             return Err(ProgramError::InvalidInstructionData.into());
@@ -445,6 +1077,58 @@ impl AmmInstruction {
                             param,
                             owner: None,
                             create_pool_fee: Some(create_pool_fee),
+                            referrer_fee_rate: None,
+                            flash_fee_rate: None,
+                            params_delay: None,
+                            global_status: None,
+                        })
+                    }
+                    3 => {
+                        let (referrer_fee_rate, _rest) = Self::unpack_u64(rest)?;
+                        Self::UpdateConfigAccount(ConfigArgs {
+                            param,
+                            owner: None,
+                            create_pool_fee: None,
+                            referrer_fee_rate: Some(referrer_fee_rate),
+                            flash_fee_rate: None,
+                            params_delay: None,
+                            global_status: None,
+                        })
+                    }
+                    4 => {
+                        let (flash_fee_rate, _rest) = Self::unpack_u64(rest)?;
+                        Self::UpdateConfigAccount(ConfigArgs {
+                            param,
+                            owner: None,
+                            create_pool_fee: None,
+                            referrer_fee_rate: None,
+                            flash_fee_rate: Some(flash_fee_rate),
+                            params_delay: None,
+                            global_status: None,
+                        })
+                    }
+                    5 => {
+                        let (params_delay, _rest) = Self::unpack_u64(rest)?;
+                        Self::UpdateConfigAccount(ConfigArgs {
+                            param,
+                            owner: None,
+                            create_pool_fee: None,
+                            referrer_fee_rate: None,
+                            flash_fee_rate: None,
+                            params_delay: Some(params_delay),
+                            global_status: None,
+                        })
+                    }
+                    6 => {
+                        let (global_status, _rest) = Self::unpack_u64(rest)?;
+                        Self::UpdateConfigAccount(ConfigArgs {
+                            param,
+                            owner: None,
+                            create_pool_fee: None,
+                            referrer_fee_rate: None,
+                            flash_fee_rate: None,
+                            params_delay: None,
+                            global_status: Some(global_status),
                         })
                     }
                     _ => {
@@ -454,22 +1138,149 @@ impl AmmInstruction {
             }
             16 => {
                 let (amount_in, rest) = Self::unpack_u64(rest)?;
-                let (minimum_amount_out, _rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
                 Self::SwapBaseInV2(SwapInstructionBaseIn {
                     amount_in,
                     minimum_amount_out,
+                    referrer,
                 })
             }
             17 => {
                 let (max_amount_in, rest) = Self::unpack_u64(rest)?;
-                let (amount_out, _rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
                 Self::SwapBaseOutV2(SwapInstructionBaseOut {
                     max_amount_in,
                     amount_out,
+                    referrer,
+                })
+            }
+            18 => Self::CreateObservation,
+            19 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
+                Self::SwapRouteBaseIn(SwapInstructionBaseIn {
+                    amount_in,
+                    minimum_amount_out,
+                    referrer,
+                })
+            }
+            20 => {
+                let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                let referrer = Self::unpack_referrer_flag(rest)?;
+                Self::SwapRouteBaseOut(SwapInstructionBaseOut {
+                    max_amount_in,
+                    amount_out,
+                    referrer,
+                })
+            }
+            21 => {
+                let (enabled, rest) = Self::unpack_u8(rest)?;
+                let (min_fee_numerator, rest) = Self::unpack_u64(rest)?;
+                let (max_fee_numerator, rest) = Self::unpack_u64(rest)?;
+                let (variable_fee_control, rest) = Self::unpack_u64(rest)?;
+                let (filter_period, rest) = Self::unpack_u64(rest)?;
+                let (decay_period, rest) = Self::unpack_u64(rest)?;
+                let (reduction_factor, _rest) = Self::unpack_u64(rest)?;
+                Self::SetDynamicFee(DynamicFeeInstruction {
+                    enabled,
+                    params: DynamicFeeParams {
+                        min_fee_numerator,
+                        max_fee_numerator,
+                        variable_fee_control,
+                        filter_period,
+                        decay_period,
+                        reduction_factor,
+                    },
+                })
+            }
+            22 => {
+                let (amount_in, rest) = Self::unpack_u64(rest)?;
+                let (minimum_lp_out, _rest) = Self::unpack_u64(rest)?;
+                Self::DepositSingleSide(DepositSingleSideInstruction {
+                    amount_in,
+                    minimum_lp_out,
+                })
+            }
+            23 => {
+                let (amount, rest) = Self::unpack_u64(rest)?;
+                let (min_amount_out, _rest) = Self::unpack_u64(rest)?;
+                Self::WithdrawSingleSide(WithdrawSingleSideInstruction {
+                    amount,
+                    min_amount_out,
+                })
+            }
+            24 => {
+                let (coin_amount, rest) = Self::unpack_u64(rest)?;
+                let (pc_amount, rest) = Self::unpack_u64(rest)?;
+                Self::FlashLoan(FlashLoanInstruction {
+                    coin_amount,
+                    pc_amount,
+                    data: rest.to_vec(),
+                })
+            }
+            25 => {
+                let (max_amount_in, rest) = Self::unpack_u64(rest)?;
+                let (amount_out, rest) = Self::unpack_u64(rest)?;
+                Self::FlashSwap(FlashSwapInstruction {
+                    max_amount_in,
+                    amount_out,
+                    data: rest.to_vec(),
+                })
+            }
+            26 => {
+                let (curve_type, rest) = Self::unpack_u8(rest)?;
+                let (amp, rest) = Self::unpack_u64(rest)?;
+                let (ramp_stop_timestamp, _rest) = Self::unpack_u64(rest)?;
+                Self::SetCurve(SetCurveInstruction {
+                    curve_type,
+                    amp,
+                    ramp_stop_timestamp,
+                })
+            }
+            27 => Self::InitRangeOrders,
+            28 => {
+                let (side, rest) = Self::unpack_u8(rest)?;
+                let (slot, rest) = Self::unpack_u8(rest)?;
+                let (price, rest) = Self::unpack_u64(rest)?;
+                let (amount, _rest) = Self::unpack_u64(rest)?;
+                Self::PlaceRangeOrder(PlaceRangeOrderInstruction {
+                    side,
+                    slot,
+                    price,
+                    amount,
                 })
             }
-            0 | 2 | 5 | 8 | 10 | 12 | 13 => {
-                // Not support instructions: 0, 2, 5, 8, 10, 12, 13.
+            29 => Self::CancelRangeOrder,
+            30 => Self::ClaimRangeOrder,
+            31 => {
+                let (side, rest) = Self::unpack_u8(rest)?;
+                let (order_id, rest) = Self::unpack_u64(rest)?;
+                let (price, rest) = Self::unpack_u64(rest)?;
+                let (amount, _rest) = Self::unpack_u64(rest)?;
+                Self::PlaceLimitOrder(PlaceLimitOrderInstruction {
+                    side,
+                    order_id,
+                    price,
+                    amount,
+                })
+            }
+            32 => Self::CancelLimitOrder,
+            33 => Self::ClaimLimitOrder,
+            34 => Self::SettlePnl,
+            35 => Self::ProposeAdmin,
+            36 => Self::AcceptAdmin,
+            37 => Self::ApplyParams,
+            38 => {
+                let (status, _rest) = Self::unpack_u8(rest)?;
+                Self::Pause(PauseInstruction { status })
+            }
+            EMIT_EVENT_TAG => Self::EmitEvent(rest.to_vec()),
+            0 | 2 | 5 | 8 | 10 | 13 => {
+                // Not support instructions: 0, 2, 5, 8, 10, 13.
                 unimplemented!("This instruction is not supported")
             }
             _ => {
@@ -478,6 +1289,16 @@ impl AmmInstruction {
         })
     }
 
+    /// Unpacks the optional trailing flag of a swap telling the `referrer_accounts` are
+    /// appended, absent in the swaps of the clients not paying a referrer.
+    fn unpack_referrer_flag(input: &[u8]) -> Result<bool, ProgramError> {
+        match input.first() {
+            None | Some(0) => Ok(false),
+            Some(1) => Ok(true),
+            Some(_) => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
     fn unpack_u8(input: &[u8]) -> Result<(u8, &[u8]), ProgramError> {
         if input.len() >= 1 {
             let (amount, rest) = input.split_at(1);
@@ -515,12 +1336,17 @@ impl AmmInstruction {
                 open_time,
                 init_pc_amount,
                 init_coin_amount,
+                weights,
             }) => {
                 buf.push(1);
                 buf.push(*nonce);
                 buf.extend_from_slice(&open_time.to_le_bytes());
                 buf.extend_from_slice(&init_pc_amount.to_le_bytes());
                 buf.extend_from_slice(&init_coin_amount.to_le_bytes());
+                if let Some((coin_weight, pc_weight)) = weights {
+                    buf.extend_from_slice(&coin_weight.to_le_bytes());
+                    buf.extend_from_slice(&pc_weight.to_le_bytes());
+                }
             }
             Self::Deposit(DepositInstruction {
                 max_coin_amount,
@@ -576,18 +1402,53 @@ impl AmmInstruction {
             Self::SwapBaseIn(SwapInstructionBaseIn {
                 amount_in,
                 minimum_amount_out,
+                referrer,
             }) => {
                 buf.push(9);
                 buf.extend_from_slice(&amount_in.to_le_bytes());
                 buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
             }
             Self::SwapBaseOut(SwapInstructionBaseOut {
                 max_amount_in,
                 amount_out,
+                referrer,
             }) => {
                 buf.push(11);
                 buf.extend_from_slice(&max_amount_in.to_le_bytes());
                 buf.extend_from_slice(&amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
+            }
+            Self::SimulateInfo(SimulateInstruction {
+                param,
+                swap_base_in_value,
+                swap_base_out_value,
+            }) => {
+                buf.push(12);
+                buf.push(*param);
+                match SimulateParams::from_u64(*param as u64)? {
+                    SimulateParams::PoolInfo => {}
+                    SimulateParams::SwapBaseInInfo => {
+                        let swap = match swap_base_in_value {
+                            Some(a) => a,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&swap.amount_in.to_le_bytes());
+                        buf.extend_from_slice(&swap.minimum_amount_out.to_le_bytes());
+                    }
+                    SimulateParams::SwapBaseOutInfo => {
+                        let swap = match swap_base_out_value {
+                            Some(a) => a,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&swap.max_amount_in.to_le_bytes());
+                        buf.extend_from_slice(&swap.amount_out.to_le_bytes());
+                    }
+                }
             }
             Self::CreateConfigAccount => {
                 buf.push(14);
@@ -596,11 +1457,15 @@ impl AmmInstruction {
                 param,
                 owner,
                 create_pool_fee,
+                referrer_fee_rate,
+                flash_fee_rate,
+                params_delay,
+                global_status,
             }) => {
                 buf.push(15);
                 buf.push(*param);
                 match param {
-                    0 | 1 => {
+                    0 | 1 | 7 => {
                         let owner = match owner {
                             Some(owner) => {
                                 if *owner == Pubkey::default() {
@@ -620,64 +1485,244 @@ impl AmmInstruction {
                         };
                         buf.extend_from_slice(&create_pool_fee.to_le_bytes());
                     }
+                    3 => {
+                        let referrer_fee_rate = match referrer_fee_rate {
+                            Some(referrer_fee_rate) => referrer_fee_rate,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&referrer_fee_rate.to_le_bytes());
+                    }
+                    4 => {
+                        let flash_fee_rate = match flash_fee_rate {
+                            Some(flash_fee_rate) => flash_fee_rate,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&flash_fee_rate.to_le_bytes());
+                    }
+                    5 => {
+                        let params_delay = match params_delay {
+                            Some(params_delay) => params_delay,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&params_delay.to_le_bytes());
+                    }
+                    6 => {
+                        let global_status = match global_status {
+                            Some(global_status) => global_status,
+                            None => return Err(ProgramError::InvalidInstructionData),
+                        };
+                        buf.extend_from_slice(&global_status.to_le_bytes());
+                    }
                     _ => return Err(ProgramError::InvalidInstructionData.into()),
                 }
             }
             Self::SwapBaseInV2(SwapInstructionBaseIn {
                 amount_in,
                 minimum_amount_out,
+                referrer,
             }) => {
                 buf.push(16);
                 buf.extend_from_slice(&amount_in.to_le_bytes());
                 buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
             }
             Self::SwapBaseOutV2(SwapInstructionBaseOut {
                 max_amount_in,
                 amount_out,
+                referrer,
             }) => {
                 buf.push(17);
                 buf.extend_from_slice(&max_amount_in.to_le_bytes());
                 buf.extend_from_slice(&amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
             }
-            _ => {
-                // Not support instructions: 0, 2, 5, 8, 10, 12, 13, etc.
-                return Err(ProgramError::InvalidInstructionData.into());
+            Self::CreateObservation => {
+                buf.push(18);
             }
-        }
-        Ok(buf)
-    }
-}
-
-/// Creates an 'initialize2' instruction.
-pub fn initialize2(
-    amm_program: &Pubkey,
-    amm_pool: &Pubkey,
-    amm_authority: &Pubkey,
-    amm_open_orders: &Pubkey,
-    amm_lp_mint: &Pubkey,
-    amm_coin_mint: &Pubkey,
-    amm_pc_mint: &Pubkey,
-    amm_coin_vault: &Pubkey,
-    amm_pc_vault: &Pubkey,
-    amm_target_orders: &Pubkey,
-    amm_config: &Pubkey,
-    create_fee_destination: &Pubkey,
-    market_program: &Pubkey,
-    market: &Pubkey,
-    user_wallet: &Pubkey,
-    user_token_coin: &Pubkey,
-    user_token_pc: &Pubkey,
-    user_token_lp: &Pubkey,
-    nonce: u8,
-    open_time: u64,
-    init_pc_amount: u64,
-    init_coin_amount: u64,
-) -> Result<Instruction, ProgramError> {
-    let init_data = AmmInstruction::Initialize2(InitializeInstruction2 {
+            Self::SwapRouteBaseIn(SwapInstructionBaseIn {
+                amount_in,
+                minimum_amount_out,
+                referrer,
+            }) => {
+                buf.push(19);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
+            }
+            Self::SwapRouteBaseOut(SwapInstructionBaseOut {
+                max_amount_in,
+                amount_out,
+                referrer,
+            }) => {
+                buf.push(20);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                buf.extend_from_slice(&amount_out.to_le_bytes());
+                if *referrer {
+                    buf.push(1);
+                }
+            }
+            Self::SetDynamicFee(DynamicFeeInstruction { enabled, params }) => {
+                buf.push(21);
+                buf.push(*enabled);
+                buf.extend_from_slice(&params.min_fee_numerator.to_le_bytes());
+                buf.extend_from_slice(&params.max_fee_numerator.to_le_bytes());
+                buf.extend_from_slice(&params.variable_fee_control.to_le_bytes());
+                buf.extend_from_slice(&params.filter_period.to_le_bytes());
+                buf.extend_from_slice(&params.decay_period.to_le_bytes());
+                buf.extend_from_slice(&params.reduction_factor.to_le_bytes());
+            }
+            Self::DepositSingleSide(DepositSingleSideInstruction {
+                amount_in,
+                minimum_lp_out,
+            }) => {
+                buf.push(22);
+                buf.extend_from_slice(&amount_in.to_le_bytes());
+                buf.extend_from_slice(&minimum_lp_out.to_le_bytes());
+            }
+            Self::WithdrawSingleSide(WithdrawSingleSideInstruction {
+                amount,
+                min_amount_out,
+            }) => {
+                buf.push(23);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&min_amount_out.to_le_bytes());
+            }
+            Self::FlashLoan(FlashLoanInstruction {
+                coin_amount,
+                pc_amount,
+                data,
+            }) => {
+                buf.push(24);
+                buf.extend_from_slice(&coin_amount.to_le_bytes());
+                buf.extend_from_slice(&pc_amount.to_le_bytes());
+                buf.extend_from_slice(data);
+            }
+            Self::FlashSwap(FlashSwapInstruction {
+                max_amount_in,
+                amount_out,
+                data,
+            }) => {
+                buf.push(25);
+                buf.extend_from_slice(&max_amount_in.to_le_bytes());
+                buf.extend_from_slice(&amount_out.to_le_bytes());
+                buf.extend_from_slice(data);
+            }
+            Self::SetCurve(SetCurveInstruction {
+                curve_type,
+                amp,
+                ramp_stop_timestamp,
+            }) => {
+                buf.push(26);
+                buf.push(*curve_type);
+                buf.extend_from_slice(&amp.to_le_bytes());
+                buf.extend_from_slice(&ramp_stop_timestamp.to_le_bytes());
+            }
+            Self::InitRangeOrders => {
+                buf.push(27);
+            }
+            Self::PlaceRangeOrder(PlaceRangeOrderInstruction {
+                side,
+                slot,
+                price,
+                amount,
+            }) => {
+                buf.push(28);
+                buf.push(*side);
+                buf.push(*slot);
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::CancelRangeOrder => {
+                buf.push(29);
+            }
+            Self::ClaimRangeOrder => {
+                buf.push(30);
+            }
+            Self::PlaceLimitOrder(PlaceLimitOrderInstruction {
+                side,
+                order_id,
+                price,
+                amount,
+            }) => {
+                buf.push(31);
+                buf.push(*side);
+                buf.extend_from_slice(&order_id.to_le_bytes());
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+            }
+            Self::CancelLimitOrder => {
+                buf.push(32);
+            }
+            Self::ClaimLimitOrder => {
+                buf.push(33);
+            }
+            Self::SettlePnl => {
+                buf.push(34);
+            }
+            Self::ProposeAdmin => {
+                buf.push(35);
+            }
+            Self::AcceptAdmin => {
+                buf.push(36);
+            }
+            Self::ApplyParams => {
+                buf.push(37);
+            }
+            Self::Pause(PauseInstruction { status }) => {
+                buf.push(38);
+                buf.push(*status);
+            }
+            Self::EmitEvent(event) => {
+                buf.push(EMIT_EVENT_TAG);
+                buf.extend_from_slice(event);
+            }
+            _ => {
+                // Not support instructions: 0, 2, 5, 8, 10, 13, etc.
+                return Err(ProgramError::InvalidInstructionData.into());
+            }
+        }
+        Ok(buf)
+    }
+}
+
+/// Creates an 'initialize2' instruction.
+pub fn initialize2(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_mint: &Pubkey,
+    amm_pc_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_config: &Pubkey,
+    create_fee_destination: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    user_wallet: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_token_lp: &Pubkey,
+    nonce: u8,
+    open_time: u64,
+    init_pc_amount: u64,
+    init_coin_amount: u64,
+    weights: Option<(u64, u64)>,
+) -> Result<Instruction, ProgramError> {
+    let init_data = AmmInstruction::Initialize2(InitializeInstruction2 {
         nonce,
         open_time,
         init_pc_amount,
         init_coin_amount,
+        weights,
     });
     let data = init_data.pack()?;
 
@@ -873,6 +1918,7 @@ pub fn swap_base_in(
     let data = AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
         amount_in,
         minimum_amount_out,
+        referrer: false,
     })
     .pack()?;
 
@@ -925,6 +1971,7 @@ pub fn swap_base_in_v2(
     let data = AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
         amount_in,
         minimum_amount_out,
+        referrer: false,
     })
     .pack()?;
 
@@ -975,6 +2022,7 @@ pub fn swap_base_out(
     let data = AmmInstruction::SwapBaseOut(SwapInstructionBaseOut {
         max_amount_in,
         amount_out,
+        referrer: false,
     })
     .pack()?;
 
@@ -1027,6 +2075,7 @@ pub fn swap_base_out_v2(
     let data = AmmInstruction::SwapBaseOutV2(SwapInstructionBaseOut {
         max_amount_in,
         amount_out,
+        referrer: false,
     })
     .pack()?;
 
@@ -1088,23 +2137,55 @@ pub fn withdrawpnl(
     })
 }
 
+/// Creates a 'settle_pnl' instruction.
+pub fn settle_pnl(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SettlePnl.pack()?;
+
+    let accounts = vec![
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+    ];
+
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
 /// Creates a 'SetParams' instruction.
 pub fn set_params(
     amm_program: &Pubkey,
     amm_pool: &Pubkey,
     admin: &Pubkey,
+    amm_config: &Pubkey,
+    amm_target_orders: Option<&Pubkey>,
     param: u8,
     value: Option<u64>,
     fees: Option<Fees>,
 ) -> Result<Instruction, ProgramError> {
     let data = AmmInstruction::SetParams(SetParamsInstruction { param, value, fees }).pack()?;
 
-    let accounts = vec![
+    let mut accounts = vec![
         // amm
         AccountMeta::new(*amm_pool, false),
         // admin
         AccountMeta::new_readonly(*admin, true),
+        // amm config
+        AccountMeta::new_readonly(*amm_config, false),
     ];
+    // target orders, needed by the status and the fees
+    if let Some(amm_target_orders) = amm_target_orders {
+        accounts.push(AccountMeta::new(*amm_target_orders, false));
+    }
     Ok(Instruction {
         program_id: *amm_program,
         accounts,
@@ -1152,3 +2233,757 @@ pub fn update_config_account(
         data,
     })
 }
+
+/// Creates an 'apply_params' instruction.
+pub fn apply_params(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_target_orders: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::ApplyParams.pack()?;
+    let accounts = vec![
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new(*amm_target_orders, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'propose_admin' instruction.
+pub fn propose_admin(
+    amm_program: &Pubkey,
+    admin: &Pubkey,
+    amm_config: &Pubkey,
+    new_admin: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::ProposeAdmin.pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(*amm_config, false),
+        AccountMeta::new_readonly(*new_admin, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates an 'accept_admin' instruction.
+pub fn accept_admin(
+    amm_program: &Pubkey,
+    pending_admin: &Pubkey,
+    amm_config: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::AcceptAdmin.pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(*pending_admin, true),
+        AccountMeta::new(*amm_config, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'pause' instruction, the pool and its target orders are None to pause all the pools.
+pub fn pause(
+    amm_program: &Pubkey,
+    guardian: &Pubkey,
+    amm_pool: Option<(&Pubkey, &Pubkey)>,
+    status: u8,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::Pause(PauseInstruction { status }).pack()?;
+    let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], amm_program);
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*guardian, true),
+        AccountMeta::new(amm_config, false),
+    ];
+    if let Some((amm_pool, amm_target_orders)) = amm_pool {
+        accounts.push(AccountMeta::new(*amm_pool, false));
+        accounts.push(AccountMeta::new(*amm_target_orders, false));
+    }
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Account to append after all the other accounts of deposit, withdraw, swap, swap route,
+/// single side, range order and limit order instructions, whose global status can stop them.
+pub fn amm_config_account(amm_program: &Pubkey) -> AccountMeta {
+    let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], amm_program);
+    AccountMeta::new_readonly(amm_config, false)
+}
+
+/// Accounts to append after the AMM config account of deposit, withdraw, swap, swap route
+/// and single side instructions, or after the callback accounts of a flash swap, to emit
+/// their events by a self invocation instead of ray_logs.
+pub fn event_accounts(amm_program: &Pubkey) -> Vec<AccountMeta> {
+    let (event_authority, _) = Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], amm_program);
+    vec![
+        AccountMeta::new_readonly(event_authority, false),
+        AccountMeta::new_readonly(*amm_program, false),
+    ]
+}
+
+/// Trailing accounts to append to deposit, withdraw, withdrawpnl and swap
+/// instructions of a pool holding a Token-2022 vault.
+pub fn token_2022_accounts(amm_coin_mint: &Pubkey, amm_pc_mint: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new_readonly(spl_token_2022::id(), false),
+        AccountMeta::new_readonly(*amm_coin_mint, false),
+        AccountMeta::new_readonly(*amm_pc_mint, false),
+    ]
+}
+
+/// Trailing accounts to append to swap instructions to pay the referrer
+/// its share of the swap fee, before any `token_2022_accounts`. The swap must
+/// set `referrer`, see `append_referrer_accounts`.
+pub fn referrer_accounts(
+    amm_program: &Pubkey,
+    referrer_token_account: &Pubkey,
+) -> Vec<AccountMeta> {
+    let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], amm_program);
+    vec![
+        AccountMeta::new_readonly(amm_config, false),
+        AccountMeta::new(*referrer_token_account, false),
+    ]
+}
+
+/// Appends the `referrer_accounts` to a swap instruction built without optional accounts
+/// and sets its `referrer`.
+pub fn append_referrer_accounts(
+    instruction: &mut Instruction,
+    referrer_token_account: &Pubkey,
+) -> Result<(), ProgramError> {
+    instruction.data = match AmmInstruction::unpack(&instruction.data)? {
+        AmmInstruction::SwapBaseIn(swap) => AmmInstruction::SwapBaseIn(SwapInstructionBaseIn {
+            referrer: true,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseInV2(swap) => AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+            referrer: true,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseOut(swap) => AmmInstruction::SwapBaseOut(SwapInstructionBaseOut {
+            referrer: true,
+            ..swap
+        }),
+        AmmInstruction::SwapBaseOutV2(swap) => {
+            AmmInstruction::SwapBaseOutV2(SwapInstructionBaseOut {
+                referrer: true,
+                ..swap
+            })
+        }
+        _ => return Err(ProgramError::InvalidInstructionData),
+    }
+    .pack()?;
+    instruction.accounts.extend(referrer_accounts(
+        &instruction.program_id,
+        referrer_token_account,
+    ));
+    Ok(())
+}
+
+/// Creates a 'simulate_info' instruction, `source_mint` is only needed to simulate a swap.
+pub fn simulate_info(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    source_mint: Option<&Pubkey>,
+    simulate: SimulateInstruction,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SimulateInfo(simulate).pack()?;
+    let mut accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_coin_vault, false),
+        AccountMeta::new_readonly(*amm_pc_vault, false),
+    ];
+    if let Some(source_mint) = source_mint {
+        accounts.push(AccountMeta::new_readonly(*source_mint, false));
+    }
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'create_observation' instruction.
+pub fn create_observation(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_observation: &Pubkey,
+    payer: &Pubkey,
+    admin: &Pubkey,
+    amm_config: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::CreateObservation.pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new(*amm_observation, false),
+        AccountMeta::new(*payer, true),
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new_readonly(*amm_config, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set_dynamic_fee' instruction.
+pub fn set_dynamic_fee(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_dynamic_fee: &Pubkey,
+    admin: &Pubkey,
+    amm_config: &Pubkey,
+    enabled: u8,
+    params: DynamicFeeParams,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SetDynamicFee(DynamicFeeInstruction { enabled, params }).pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new(*amm_dynamic_fee, false),
+        AccountMeta::new(*admin, true),
+        AccountMeta::new_readonly(*amm_config, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'set_curve' instruction.
+pub fn set_curve(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    admin: &Pubkey,
+    amm_config: &Pubkey,
+    curve_type: u8,
+    amp: u64,
+    ramp_stop_timestamp: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SetCurve(SetCurveInstruction {
+        curve_type,
+        amp,
+        ramp_stop_timestamp,
+    })
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new_readonly(*amm_config, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'deposit_single_side' instruction.
+pub fn deposit_single_side(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_owner: &Pubkey,
+    amount_in: u64,
+    minimum_lp_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::DepositSingleSide(DepositSingleSideInstruction {
+        amount_in,
+        minimum_lp_out,
+    })
+    .pack()?;
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*amm_lp_mint, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_lp, false),
+        AccountMeta::new_readonly(*user_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'withdraw_single_side' instruction.
+pub fn withdraw_single_side(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_lp_mint: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_lp: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_owner: &Pubkey,
+    amount: u64,
+    min_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::WithdrawSingleSide(WithdrawSingleSideInstruction {
+        amount,
+        min_amount_out,
+    })
+    .pack()?;
+    let accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*amm_lp_mint, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_lp, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'flash_loan' instruction, borrower_accounts are passed to the borrower program.
+pub fn flash_loan(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    borrower_token_coin: &Pubkey,
+    borrower_token_pc: &Pubkey,
+    borrower_program: &Pubkey,
+    borrower_accounts: Vec<AccountMeta>,
+    coin_amount: u64,
+    pc_amount: u64,
+    data: Vec<u8>,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::FlashLoan(FlashLoanInstruction {
+        coin_amount,
+        pc_amount,
+        data,
+    })
+    .pack()?;
+    let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], amm_program);
+    let mut accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(amm_config, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // borrower
+        AccountMeta::new(*borrower_token_coin, false),
+        AccountMeta::new(*borrower_token_pc, false),
+        AccountMeta::new_readonly(*borrower_program, false),
+    ];
+    accounts.extend(borrower_accounts);
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'flash_swap' instruction, callback_accounts are passed to the callback program.
+pub fn flash_swap(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_destination: &Pubkey,
+    callback_program: &Pubkey,
+    callback_accounts: Vec<AccountMeta>,
+    max_amount_in: u64,
+    amount_out: u64,
+    data: Vec<u8>,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::FlashSwap(FlashSwapInstruction {
+        max_amount_in,
+        amount_out,
+        data,
+    })
+    .pack()?;
+    let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], amm_program);
+    let mut accounts = vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(amm_config, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*callback_program, false),
+    ];
+    accounts.extend(callback_accounts);
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Accounts of one pool of a 'swap route' instruction, in the 'swap base in v2' layout.
+/// Pools with a Token-2022 vault need the `token_2022_accounts` appended, followed by
+/// the dynamic fee account and the observation account if the pool has them.
+pub fn swap_route_hop(
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_source_owner: &Pubkey,
+) -> Vec<AccountMeta> {
+    vec![
+        // spl token
+        AccountMeta::new_readonly(spl_token::id(), false),
+        // amm
+        AccountMeta::new(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_coin_vault, false),
+        AccountMeta::new(*amm_pc_vault, false),
+        // user
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_token_destination, false),
+        AccountMeta::new_readonly(*user_source_owner, true),
+    ]
+}
+
+/// Creates a 'swap route base in' instruction from the `swap_route_hop` accounts of each pool in order.
+pub fn swap_route_base_in(
+    amm_program: &Pubkey,
+    hops: Vec<Vec<AccountMeta>>,
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SwapRouteBaseIn(SwapInstructionBaseIn {
+        amount_in,
+        minimum_amount_out,
+        referrer: false,
+    })
+    .pack()?;
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts: hops.into_iter().flatten().collect(),
+        data,
+    })
+}
+
+/// Creates a 'swap route base out' instruction from the `swap_route_hop` accounts of each pool in order.
+pub fn swap_route_base_out(
+    amm_program: &Pubkey,
+    hops: Vec<Vec<AccountMeta>>,
+    max_amount_in: u64,
+    amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::SwapRouteBaseOut(SwapInstructionBaseOut {
+        max_amount_in,
+        amount_out,
+        referrer: false,
+    })
+    .pack()?;
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts: hops.into_iter().flatten().collect(),
+        data,
+    })
+}
+
+/// Creates a 'swap base in' instruction filling the range orders of the pool first.
+pub fn swap_base_in_with_range_orders(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_open_orders: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_vault: &Pubkey,
+    amm_pc_vault: &Pubkey,
+    market_program: &Pubkey,
+    market: &Pubkey,
+    market_bids: &Pubkey,
+    market_asks: &Pubkey,
+    market_event_queue: &Pubkey,
+    market_coin_vault: &Pubkey,
+    market_pc_vault: &Pubkey,
+    market_vault_signer: &Pubkey,
+    user_token_source: &Pubkey,
+    user_token_destination: &Pubkey,
+    user_source_owner: &Pubkey,
+    range_coin_vault: &Pubkey,
+    range_pc_vault: &Pubkey,
+
+    amount_in: u64,
+    minimum_amount_out: u64,
+) -> Result<Instruction, ProgramError> {
+    let mut instruction = swap_base_in(
+        amm_program,
+        amm_pool,
+        amm_authority,
+        amm_open_orders,
+        amm_coin_vault,
+        amm_pc_vault,
+        market_program,
+        market,
+        market_bids,
+        market_asks,
+        market_event_queue,
+        market_coin_vault,
+        market_pc_vault,
+        market_vault_signer,
+        user_token_source,
+        user_token_destination,
+        user_source_owner,
+        amount_in,
+        minimum_amount_out,
+    )?;
+    instruction
+        .accounts
+        .insert(4, AccountMeta::new(*amm_target_orders, false));
+    instruction
+        .accounts
+        .push(AccountMeta::new(*range_coin_vault, false));
+    instruction
+        .accounts
+        .push(AccountMeta::new(*range_pc_vault, false));
+    Ok(instruction)
+}
+
+/// Creates an 'init_range_orders' instruction.
+pub fn init_range_orders(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_target_orders: &Pubkey,
+    amm_coin_mint: &Pubkey,
+    amm_pc_mint: &Pubkey,
+    range_coin_vault: &Pubkey,
+    range_pc_vault: &Pubkey,
+    payer: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::InitRangeOrders.pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new_readonly(*amm_coin_mint, false),
+        AccountMeta::new_readonly(*amm_pc_mint, false),
+        AccountMeta::new(*range_coin_vault, false),
+        AccountMeta::new(*range_pc_vault, false),
+        AccountMeta::new(*payer, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'place_range_order' instruction.
+pub fn place_range_order(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_target_orders: &Pubkey,
+    range_vault: &Pubkey,
+    range_order: &Pubkey,
+    user_token_source: &Pubkey,
+    user_owner: &Pubkey,
+    amm_vault: &Pubkey,
+    side: u8,
+    slot: u8,
+    price: u64,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::PlaceRangeOrder(PlaceRangeOrderInstruction {
+        side,
+        slot,
+        price,
+        amount,
+    })
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*range_vault, false),
+        AccountMeta::new(*range_order, false),
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_owner, true),
+        AccountMeta::new_readonly(*amm_vault, false),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'cancel_range_order' instruction, or a 'claim_range_order' one if `cancel` is false.
+pub fn cancel_range_order(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_target_orders: &Pubkey,
+    range_coin_vault: &Pubkey,
+    range_pc_vault: &Pubkey,
+    range_order: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_owner: &Pubkey,
+    cancel: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = if cancel {
+        AmmInstruction::CancelRangeOrder.pack()?
+    } else {
+        AmmInstruction::ClaimRangeOrder.pack()?
+    };
+    let accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*amm_target_orders, false),
+        AccountMeta::new(*range_coin_vault, false),
+        AccountMeta::new(*range_pc_vault, false),
+        AccountMeta::new(*range_order, false),
+        AccountMeta::new(*user_token_coin, false),
+        AccountMeta::new(*user_token_pc, false),
+        AccountMeta::new(*user_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'place_limit_order' instruction.
+pub fn place_limit_order(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    amm_coin_mint: &Pubkey,
+    amm_pc_mint: &Pubkey,
+    limit_coin_vault: &Pubkey,
+    limit_pc_vault: &Pubkey,
+    limit_order: &Pubkey,
+    user_token_source: &Pubkey,
+    user_owner: &Pubkey,
+    side: u8,
+    order_id: u64,
+    price: u64,
+    amount: u64,
+) -> Result<Instruction, ProgramError> {
+    let data = AmmInstruction::PlaceLimitOrder(PlaceLimitOrderInstruction {
+        side,
+        order_id,
+        price,
+        amount,
+    })
+    .pack()?;
+    let accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(solana_program::system_program::id(), false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new_readonly(*amm_coin_mint, false),
+        AccountMeta::new_readonly(*amm_pc_mint, false),
+        AccountMeta::new(*limit_coin_vault, false),
+        AccountMeta::new(*limit_pc_vault, false),
+        AccountMeta::new(*limit_order, false),
+        AccountMeta::new(*user_token_source, false),
+        AccountMeta::new(*user_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
+
+/// Creates a 'cancel_limit_order' instruction, or a 'claim_limit_order' one if `cancel` is false.
+pub fn cancel_limit_order(
+    amm_program: &Pubkey,
+    amm_pool: &Pubkey,
+    amm_authority: &Pubkey,
+    limit_coin_vault: &Pubkey,
+    limit_pc_vault: &Pubkey,
+    limit_order: &Pubkey,
+    user_token_coin: &Pubkey,
+    user_token_pc: &Pubkey,
+    user_owner: &Pubkey,
+    cancel: bool,
+) -> Result<Instruction, ProgramError> {
+    let data = if cancel {
+        AmmInstruction::CancelLimitOrder.pack()?
+    } else {
+        AmmInstruction::ClaimLimitOrder.pack()?
+    };
+    let accounts = vec![
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(*amm_pool, false),
+        AccountMeta::new_readonly(*amm_authority, false),
+        AccountMeta::new(*limit_coin_vault, false),
+        AccountMeta::new(*limit_pc_vault, false),
+        AccountMeta::new(*limit_order, false),
+        AccountMeta::new(*user_token_coin, false),
+        AccountMeta::new(*user_token_pc, false),
+        AccountMeta::new(*user_owner, true),
+    ];
+    Ok(Instruction {
+        program_id: *amm_program,
+        accounts,
+        data,
+    })
+}
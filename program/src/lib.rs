@@ -10,6 +10,8 @@ pub mod instruction;
 pub mod invokers;
 pub mod math;
 pub mod processor;
+#[cfg(feature = "client")]
+pub mod quoter;
 pub mod state;
 
 // Export current solana-sdk types for downstream users who may also be building with a different solana-sdk version
@@ -1,3 +1,5 @@
+#[cfg(feature = "client")]
+use crate::instruction::EMIT_EVENT_TAG;
 use arrform::{arrform, ArrForm};
 use serde::{Deserialize, Serialize};
 use solana_program::{
@@ -45,20 +47,76 @@ pub enum LogType {
     Withdraw,
     SwapBaseIn,
     SwapBaseOut,
+    SwapRouteBaseIn,
+    SwapRouteBaseOut,
+    DepositSingleSide,
+    WithdrawSingleSide,
+    FlashLoan,
+    FlashSwap,
+    RangeOrderFill,
+    LimitOrderFill,
+    QueueParams,
+    ApplyParams,
+    SwapV2,
+    DepositV2,
+    WithdrawV2,
+    WithdrawPnl,
+    SetParams,
+    CreateConfig,
+    UpdateConfig,
+    SwapBaseInV2,
+    SwapBaseOutV2,
+    SettlePnl,
+    ProposeAdmin,
+    AcceptAdmin,
+    Pause,
 }
 
+/// Version of the v2 events, written right after their log_type. Later versions
+/// only append fields, so a decoder reads any event of at least its version.
+pub const EVENT_VERSION: u8 = 2;
+
 impl LogType {
-    /// Converts a u8 discriminant into a LogType. Panics if the discriminant is invalid.
-    pub fn from_u8(log_type: u8) -> Self {
-        match log_type {
+    /// Converts a u8 discriminant into a LogType, None if the discriminant is unknown.
+    pub fn try_from_u8(log_type: u8) -> Option<Self> {
+        Some(match log_type {
             0 => LogType::Init,
             1 => LogType::Deposit,
             2 => LogType::Withdraw,
             3 => LogType::SwapBaseIn,
             4 => LogType::SwapBaseOut,
-            // Changed unreachable!() to panic!() for safer handling of unexpected external data
-            _ => panic!("Invalid LogType discriminant: {}", log_type),
-        }
+            5 => LogType::SwapRouteBaseIn,
+            6 => LogType::SwapRouteBaseOut,
+            7 => LogType::DepositSingleSide,
+            8 => LogType::WithdrawSingleSide,
+            9 => LogType::FlashLoan,
+            10 => LogType::FlashSwap,
+            11 => LogType::RangeOrderFill,
+            12 => LogType::LimitOrderFill,
+            13 => LogType::QueueParams,
+            14 => LogType::ApplyParams,
+            15 => LogType::SwapV2,
+            16 => LogType::DepositV2,
+            17 => LogType::WithdrawV2,
+            18 => LogType::WithdrawPnl,
+            19 => LogType::SetParams,
+            20 => LogType::CreateConfig,
+            21 => LogType::UpdateConfig,
+            22 => LogType::SwapBaseInV2,
+            23 => LogType::SwapBaseOutV2,
+            24 => LogType::SettlePnl,
+            25 => LogType::ProposeAdmin,
+            26 => LogType::AcceptAdmin,
+            27 => LogType::Pause,
+            _ => return None,
+        })
+    }
+
+    /// Converts a u8 discriminant into a LogType. Panics if the discriminant is invalid.
+    pub fn from_u8(log_type: u8) -> Self {
+        // panic!() rather than unreachable!() for safer handling of unexpected external data
+        Self::try_from_u8(log_type)
+            .unwrap_or_else(|| panic!("Invalid LogType discriminant: {}", log_type))
     }
 
     /// Converts a LogType into its u8 discriminant for serialization.
@@ -69,6 +127,29 @@ impl LogType {
             LogType::Withdraw => 2u8,
             LogType::SwapBaseIn => 3u8,
             LogType::SwapBaseOut => 4u8,
+            LogType::SwapRouteBaseIn => 5u8,
+            LogType::SwapRouteBaseOut => 6u8,
+            LogType::DepositSingleSide => 7u8,
+            LogType::WithdrawSingleSide => 8u8,
+            LogType::FlashLoan => 9u8,
+            LogType::FlashSwap => 10u8,
+            LogType::RangeOrderFill => 11u8,
+            LogType::LimitOrderFill => 12u8,
+            LogType::QueueParams => 13u8,
+            LogType::ApplyParams => 14u8,
+            LogType::SwapV2 => 15u8,
+            LogType::DepositV2 => 16u8,
+            LogType::WithdrawV2 => 17u8,
+            LogType::WithdrawPnl => 18u8,
+            LogType::SetParams => 19u8,
+            LogType::CreateConfig => 20u8,
+            LogType::UpdateConfig => 21u8,
+            LogType::SwapBaseInV2 => 22u8,
+            LogType::SwapBaseOutV2 => 23u8,
+            LogType::SettlePnl => 24u8,
+            LogType::ProposeAdmin => 25u8,
+            LogType::AcceptAdmin => 26u8,
+            LogType::Pause => 27u8,
         }
     }
 }
@@ -157,6 +238,395 @@ pub struct SwapBaseOutLog {
     pub deduct_in: u64,
 }
 
+/// SwapBaseInLog with the transfer fee and swap fee results. Swaps now only emit the legacy
+/// log, whose layout is kept for the existing indexers, and the SwapEventV2, this one is
+/// kept to decode the logs of earlier swaps.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapBaseInLogV2 {
+    pub log_type: u8,
+    pub version: u8,
+    // input
+    pub amount_in: u64,
+    pub minimum_out: u64,
+    pub direction: u64,
+    // user info
+    pub user_source: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // calc result, sent by the vault
+    pub out_amount: u64,
+    // transfer fee result, received by the vault and by the user
+    pub amount_in_after_transfer_fee: u64,
+    pub out_amount_after_transfer_fee: u64,
+    // swap fee and the parts of it paid to the referrer and the protocol, in the source mint
+    pub swap_fee: u64,
+    pub referrer_fee: u64,
+    pub protocol_fee: u64,
+}
+
+impl SwapBaseInLogV2 {
+    /// The legacy log of the swap.
+    pub fn legacy(&self) -> SwapBaseInLog {
+        SwapBaseInLog {
+            log_type: LogType::SwapBaseIn.into_u8(),
+            amount_in: self.amount_in,
+            minimum_out: self.minimum_out,
+            direction: self.direction,
+            user_source: self.user_source,
+            pool_coin: self.pool_coin,
+            pool_pc: self.pool_pc,
+            out_amount: self.out_amount,
+        }
+    }
+}
+
+/// SwapBaseOutLog with the transfer fee and swap fee results, kept like SwapBaseInLogV2
+/// to decode the logs of earlier swaps.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapBaseOutLogV2 {
+    pub log_type: u8,
+    pub version: u8,
+    // input
+    pub max_in: u64,
+    pub amount_out: u64,
+    pub direction: u64,
+    // user info
+    pub user_source: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // calc result, sent by the user
+    pub deduct_in: u64,
+    // transfer fee result, received by the vault and sent by the vault
+    pub deduct_in_after_transfer_fee: u64,
+    pub amount_out_before_transfer_fee: u64,
+    // swap fee and the parts of it paid to the referrer and the protocol, in the source mint
+    pub swap_fee: u64,
+    pub referrer_fee: u64,
+    pub protocol_fee: u64,
+}
+
+impl SwapBaseOutLogV2 {
+    /// The legacy log of the swap.
+    pub fn legacy(&self) -> SwapBaseOutLog {
+        SwapBaseOutLog {
+            log_type: LogType::SwapBaseOut.into_u8(),
+            max_in: self.max_in,
+            amount_out: self.amount_out,
+            direction: self.direction,
+            user_source: self.user_source,
+            pool_coin: self.pool_coin,
+            pool_pc: self.pool_pc,
+            deduct_in: self.deduct_in,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapRouteBaseInLog {
+    pub log_type: u8,
+    // input
+    pub amount_in: u64,
+    pub minimum_out: u64,
+    pub hops: u64,
+    // user info
+    pub user_source: u64,
+    // calc result, received by the user from the last pool
+    pub out_amount: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapRouteBaseOutLog {
+    pub log_type: u8,
+    // input
+    pub max_in: u64,
+    pub amount_out: u64,
+    pub hops: u64,
+    // user info
+    pub user_source: u64,
+    // calc result, sent by the user to the first pool
+    pub deduct_in: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DepositSingleSideLog {
+    pub log_type: u8,
+    // input
+    pub amount_in: u64,
+    pub minimum_lp_out: u64,
+    pub direction: u64,
+    // user info
+    pub user_source: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    pub pool_lp: u64,
+    // implied swap of part of the amount received by the vault into the other mint
+    pub swap_in: u64,
+    pub swap_out: u64,
+    pub swap_fee: u64,
+    // calc result
+    pub amount_in_after_transfer_fee: u64,
+    pub mint_lp: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WithdrawSingleSideLog {
+    pub log_type: u8,
+    // input
+    pub withdraw_lp: u64,
+    pub min_amount_out: u64,
+    pub direction: u64,
+    // user info
+    pub user_lp: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    pub pool_lp: u64,
+    // pro-rata share of the lp
+    pub share_coin: u64,
+    pub share_pc: u64,
+    // implied swap of the unwanted share at the reserves after the withdrawal
+    pub swap_out: u64,
+    pub swap_fee: u64,
+    // calc result, sent by the vault and received by the user
+    pub amount_out: u64,
+    pub amount_out_after_transfer_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlashLoanLog {
+    pub log_type: u8,
+    // input
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    // vault balances before the loan
+    pub vault_coin: u64,
+    pub vault_pc: u64,
+    // calc result, repaid on top of the loan
+    pub coin_fee: u64,
+    pub pc_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FlashSwapLog {
+    pub log_type: u8,
+    // input
+    pub max_in: u64,
+    pub amount_out: u64,
+    pub direction: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // calc result, owed by the callback and received by the vault
+    pub deduct_in: u64,
+    pub deduct_in_after_transfer_fee: u64,
+    // sent by the vault before the callback
+    pub amount_out_before_transfer_fee: u64,
+    // swap fee and the part of it paid to the protocol, in the source mint
+    pub swap_fee: u64,
+    pub protocol_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RangeOrderFillLog {
+    pub log_type: u8,
+    pub direction: u64,
+    // paid by the user to the range orders, in the source mint
+    pub amount_in: u64,
+    // paid by the range orders to the user, in the destination mint
+    pub amount_out: u64,
+    // number of range order fills, a partial fill counts as one
+    pub fill_count: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LimitOrderFillLog {
+    pub log_type: u8,
+    // order info
+    pub owner: Pubkey,
+    pub order_id: u64,
+    pub side: u64,
+    pub price: u64,
+    // pool info
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // escrowed amount swapped into the pool and the amount received for it
+    pub amount_in: u64,
+    pub amount_out: u64,
+    // swap fee and the part of it paid to the protocol, in the source mint
+    pub swap_fee: u64,
+    pub protocol_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParamsLog {
+    // QueueParams or ApplyParams
+    pub log_type: u8,
+    // AmmParams::Status or AmmParams::Fees
+    pub param: u8,
+    // status of the pool, for AmmParams::Status
+    pub status: u64,
+    // fees of the pool, for AmmParams::Fees
+    pub min_separate_numerator: u64,
+    pub min_separate_denominator: u64,
+    pub trade_fee_numerator: u64,
+    pub trade_fee_denominator: u64,
+    pub pnl_numerator: u64,
+    pub pnl_denominator: u64,
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
+    // unix timestamp the change can be applied from
+    pub effective_time: u64,
+}
+
+/// Swap event of a swap or of each pool of a route swap, the one event of a swap besides
+/// the legacy swap log.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SwapEventV2 {
+    pub log_type: u8,
+    pub version: u8,
+    // pool and user info
+    pub amm: Pubkey,
+    pub signer: Pubkey,
+    pub mint_in: Pubkey,
+    pub mint_out: Pubkey,
+    // paid by the user, before the mint_in transfer fee
+    pub amount_in: u64,
+    // received by the user, after the mint_out transfer fee
+    pub amount_out: u64,
+    // swap fee, in mint_in
+    pub swap_fee: u64,
+    // vault balances after the swap
+    pub vault_coin: u64,
+    pub vault_pc: u64,
+}
+
+/// Deposit or withdraw event, balanced or single side, emitted after the legacy log.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LiquidityEventV2 {
+    // DepositV2 or WithdrawV2
+    pub log_type: u8,
+    pub version: u8,
+    // pool and user info
+    pub amm: Pubkey,
+    pub signer: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    // paid by the user on a deposit, received by the user on a withdraw
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    pub lp_amount: u64,
+    // swap fee of a single side deposit or withdraw, 0 otherwise
+    pub fee: u64,
+    // vault balances after the deposit or withdraw
+    pub vault_coin: u64,
+    pub vault_pc: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeesLog {
+    pub min_separate_numerator: u64,
+    pub min_separate_denominator: u64,
+    pub trade_fee_numerator: u64,
+    pub trade_fee_denominator: u64,
+    pub pnl_numerator: u64,
+    pub pnl_denominator: u64,
+    pub swap_fee_numerator: u64,
+    pub swap_fee_denominator: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WithdrawPnlLog {
+    pub log_type: u8,
+    // pool and signer info
+    pub amm: Pubkey,
+    pub pnl_owner: Pubkey,
+    // protocol fee received by the pnl owner, after the transfer fees
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SetParamsLog {
+    pub log_type: u8,
+    // AmmParams of the change
+    pub param: u8,
+    pub amm: Pubkey,
+    // status, state or open time before and after the change, the requested one for a
+    // queued status
+    pub old_value: u64,
+    pub new_value: u64,
+    // fees before and after the change, the requested ones as fees are queued
+    pub old_fees: FeesLog,
+    pub new_fees: FeesLog,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CreateConfigLog {
+    pub log_type: u8,
+    pub admin: Pubkey,
+    pub pnl_owner: Pubkey,
+    pub create_pool_fee: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UpdateConfigLog {
+    pub log_type: u8,
+    // UpdateConfig param
+    pub param: u8,
+    // pnl owner, cancel owner or guardian before and after the change, for params 0, 1 & 7
+    pub old_owner: Pubkey,
+    pub new_owner: Pubkey,
+    // fee, rate, delay or status before and after the change, for the other params
+    pub old_value: u64,
+    pub new_value: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SettlePnlLog {
+    pub log_type: u8,
+    pub amm: Pubkey,
+    // pool info, before the pnl is booked
+    pub pool_coin: u64,
+    pub pool_pc: u64,
+    // calc result, the pnl owed to the pnl owner and the new pnl reference
+    pub need_take_pnl_coin: u64,
+    pub need_take_pnl_pc: u64,
+    pub calc_pnl_x: u128,
+    pub calc_pnl_y: u128,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProposeAdminLog {
+    pub log_type: u8,
+    pub admin: Pubkey,
+    // pending admin before and after the proposal
+    pub old_pending_admin: Pubkey,
+    pub new_pending_admin: Pubkey,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AcceptAdminLog {
+    pub log_type: u8,
+    // admin before and after the pending admin accepted
+    pub old_admin: Pubkey,
+    pub new_admin: Pubkey,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PauseLog {
+    pub log_type: u8,
+    // guardian or admin
+    pub signer: Pubkey,
+    // paused pool, the default key when all the pools are paused
+    pub amm: Pubkey,
+    // pool status or global status before and after the pause
+    pub old_status: u64,
+    pub new_status: u64,
+}
+
 /**
  * @function encode_ray_log
  * @brief Serializes a log struct (T) using bincode, encodes it to base64,
@@ -184,34 +654,387 @@ pub fn encode_ray_log<T: Serialize>(log: T) {
 
 /**
  * @function decode_ray_log
- * @brief Decodes a base64 log string into the appropriate structured log struct.
- * @param log The base64 encoded log string (usually read from transaction metadata).
+ * @brief Decodes a ray_log line into the appropriate structured log struct.
+ * @param log The ray_log line (usually read from transaction metadata).
  */
-pub fn decode_ray_log(log: &str) {
-    // 1. Decode base64 string back to binary
-    let bytes = base64::decode_config(log, base64::STANDARD).unwrap();
-
-    // 2. Use the first byte as the discriminant to determine the struct type
-    match LogType::from_u8(bytes[0]) {
-        LogType::Init => {
-            let log: InitLog = bincode::deserialize(&bytes).unwrap();
-            println!("{:?}", log);
+#[cfg(feature = "client")]
+pub fn decode_ray_log(log: &str) -> Result<RayLog, RayLogError> {
+    RayLog::parse(log)
+}
+
+/// Prefix of the ray_log lines emitted by `encode_ray_log`.
+#[cfg(feature = "client")]
+pub const RAY_LOG_PREFIX: &str = "ray_log: ";
+
+/// Error decoding a ray_log.
+#[cfg(feature = "client")]
+#[derive(Debug, thiserror::Error)]
+pub enum RayLogError {
+    #[error("not a ray_log line")]
+    NotRayLog,
+    #[error("invalid base64: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("empty ray_log")]
+    Empty,
+    #[error("unknown log type {0}")]
+    UnknownLogType(u8),
+    #[error("unsupported event version {0}")]
+    UnsupportedVersion(u8),
+    #[error("not an EmitEvent instruction")]
+    NotEventInstruction,
+    #[error("invalid log data: {0}")]
+    Bincode(#[from] bincode::Error),
+}
+
+/// A decoded ray_log event.
+#[cfg(feature = "client")]
+#[derive(Clone, Debug, PartialEq)]
+pub enum RayLog {
+    Init(InitLog),
+    Deposit(DepositLog),
+    Withdraw(WithdrawLog),
+    SwapBaseIn(SwapBaseInLog),
+    SwapBaseOut(SwapBaseOutLog),
+    SwapRouteBaseIn(SwapRouteBaseInLog),
+    SwapRouteBaseOut(SwapRouteBaseOutLog),
+    DepositSingleSide(DepositSingleSideLog),
+    WithdrawSingleSide(WithdrawSingleSideLog),
+    FlashLoan(FlashLoanLog),
+    FlashSwap(FlashSwapLog),
+    RangeOrderFill(RangeOrderFillLog),
+    LimitOrderFill(LimitOrderFillLog),
+    QueueParams(ParamsLog),
+    ApplyParams(ParamsLog),
+    SwapV2(SwapEventV2),
+    DepositV2(LiquidityEventV2),
+    WithdrawV2(LiquidityEventV2),
+    WithdrawPnl(WithdrawPnlLog),
+    SetParams(SetParamsLog),
+    CreateConfig(CreateConfigLog),
+    UpdateConfig(UpdateConfigLog),
+    SwapBaseInV2(SwapBaseInLogV2),
+    SwapBaseOutV2(SwapBaseOutLogV2),
+    SettlePnl(SettlePnlLog),
+    ProposeAdmin(ProposeAdminLog),
+    AcceptAdmin(AcceptAdminLog),
+    Pause(PauseLog),
+}
+
+#[cfg(feature = "client")]
+impl RayLog {
+    /// Decodes the bincode bytes of a ray_log, the first byte is its LogType.
+    pub fn decode(bytes: &[u8]) -> Result<Self, RayLogError> {
+        let log_type = *bytes.first().ok_or(RayLogError::Empty)?;
+        let log_type =
+            LogType::try_from_u8(log_type).ok_or(RayLogError::UnknownLogType(log_type))?;
+        Ok(match log_type {
+            LogType::Init => RayLog::Init(bincode::deserialize(bytes)?),
+            LogType::Deposit => RayLog::Deposit(bincode::deserialize(bytes)?),
+            LogType::Withdraw => RayLog::Withdraw(bincode::deserialize(bytes)?),
+            LogType::SwapBaseIn if bytes.len() == SwapBaseInLogV1::LEN => {
+                RayLog::SwapBaseInV2(bincode::deserialize::<SwapBaseInLogV1>(bytes)?.into())
+            }
+            LogType::SwapBaseIn => RayLog::SwapBaseIn(bincode::deserialize(bytes)?),
+            LogType::SwapBaseOut if bytes.len() == SwapBaseOutLogV1::LEN => {
+                RayLog::SwapBaseOutV2(bincode::deserialize::<SwapBaseOutLogV1>(bytes)?.into())
+            }
+            LogType::SwapBaseOut => RayLog::SwapBaseOut(bincode::deserialize(bytes)?),
+            LogType::SwapRouteBaseIn => RayLog::SwapRouteBaseIn(bincode::deserialize(bytes)?),
+            LogType::SwapRouteBaseOut => RayLog::SwapRouteBaseOut(bincode::deserialize(bytes)?),
+            LogType::DepositSingleSide => RayLog::DepositSingleSide(bincode::deserialize(bytes)?),
+            LogType::WithdrawSingleSide => RayLog::WithdrawSingleSide(bincode::deserialize(bytes)?),
+            LogType::FlashLoan => RayLog::FlashLoan(bincode::deserialize(bytes)?),
+            LogType::FlashSwap => RayLog::FlashSwap(bincode::deserialize(bytes)?),
+            LogType::RangeOrderFill => RayLog::RangeOrderFill(bincode::deserialize(bytes)?),
+            LogType::LimitOrderFill => RayLog::LimitOrderFill(bincode::deserialize(bytes)?),
+            LogType::QueueParams => RayLog::QueueParams(bincode::deserialize(bytes)?),
+            LogType::ApplyParams => RayLog::ApplyParams(bincode::deserialize(bytes)?),
+            LogType::SwapV2 => RayLog::SwapV2(bincode::deserialize(Self::versioned(bytes)?)?),
+            LogType::DepositV2 => RayLog::DepositV2(bincode::deserialize(Self::versioned(bytes)?)?),
+            LogType::WithdrawV2 => {
+                RayLog::WithdrawV2(bincode::deserialize(Self::versioned(bytes)?)?)
+            }
+            LogType::WithdrawPnl => RayLog::WithdrawPnl(bincode::deserialize(bytes)?),
+            LogType::SetParams => RayLog::SetParams(bincode::deserialize(bytes)?),
+            LogType::CreateConfig => RayLog::CreateConfig(bincode::deserialize(bytes)?),
+            LogType::UpdateConfig => RayLog::UpdateConfig(bincode::deserialize(bytes)?),
+            LogType::SwapBaseInV2 => {
+                RayLog::SwapBaseInV2(bincode::deserialize(Self::versioned(bytes)?)?)
+            }
+            LogType::SwapBaseOutV2 => {
+                RayLog::SwapBaseOutV2(bincode::deserialize(Self::versioned(bytes)?)?)
+            }
+            LogType::SettlePnl => RayLog::SettlePnl(bincode::deserialize(bytes)?),
+            LogType::ProposeAdmin => RayLog::ProposeAdmin(bincode::deserialize(bytes)?),
+            LogType::AcceptAdmin => RayLog::AcceptAdmin(bincode::deserialize(bytes)?),
+            LogType::Pause => RayLog::Pause(bincode::deserialize(bytes)?),
+        })
+    }
+
+    /// Decodes the event of an EmitEvent instruction the program invoked itself with, as
+    /// found in the inner instructions of a transaction.
+    pub fn from_event_instruction(data: &[u8]) -> Result<Self, RayLogError> {
+        match data.split_first() {
+            Some((&EMIT_EVENT_TAG, event)) => Self::decode(event),
+            _ => Err(RayLogError::NotEventInstruction),
         }
-        LogType::Deposit => {
-            let log: DepositLog = bincode::deserialize(&bytes).unwrap();
-            println!("{:?}", log);
+    }
+
+    /// Checks the version byte of a v2 event, following its log_type.
+    fn versioned(bytes: &[u8]) -> Result<&[u8], RayLogError> {
+        let version = *bytes.get(1).ok_or(RayLogError::Empty)?;
+        if version < EVENT_VERSION {
+            return Err(RayLogError::UnsupportedVersion(version));
         }
-        LogType::Withdraw => {
-            let log: WithdrawLog = bincode::deserialize(&bytes).unwrap();
-            println!("{:?}", log);
+        Ok(bytes)
+    }
+
+    /// Parses a ray_log line, as emitted by `encode_ray_log` and with or without the
+    /// "Program log: " prefix of the transaction log messages.
+    pub fn parse(line: &str) -> Result<Self, RayLogError> {
+        let log = Self::payload(line).ok_or(RayLogError::NotRayLog)?;
+        Self::decode(&base64::decode_config(log, base64::STANDARD)?)
+    }
+
+    /// Base64 payload of a ray_log line. The key mismatch messages logged by
+    /// `log_keys_mismatch` share the prefix but are not events.
+    fn payload(line: &str) -> Option<&str> {
+        let line = line.strip_prefix("Program log: ").unwrap_or(line);
+        let log = line.strip_prefix(RAY_LOG_PREFIX)?;
+        if log.is_empty() || log.contains(char::is_whitespace) {
+            return None;
         }
-        LogType::SwapBaseIn => {
-            let log: SwapBaseInLog = bincode::deserialize(&bytes).unwrap();
-            println!("{:?}", log);
+        Some(log)
+    }
+}
+
+/// SwapBaseInLog extended with the transfer fee results, logged under the SwapBaseIn
+/// log_type before the extended fields moved to SwapBaseInLogV2.
+#[cfg(feature = "client")]
+#[derive(Deserialize)]
+struct SwapBaseInLogV1 {
+    legacy: SwapBaseInLog,
+    amount_in_after_transfer_fee: u64,
+    out_amount_after_transfer_fee: u64,
+}
+
+#[cfg(feature = "client")]
+impl SwapBaseInLogV1 {
+    /// Length of its bincode bytes, the legacy log and two u64.
+    const LEN: usize = 1 + 9 * 8;
+}
+
+#[cfg(feature = "client")]
+impl From<SwapBaseInLogV1> for SwapBaseInLogV2 {
+    fn from(log: SwapBaseInLogV1) -> Self {
+        let legacy = log.legacy;
+        SwapBaseInLogV2 {
+            log_type: LogType::SwapBaseInV2.into_u8(),
+            version: 1,
+            amount_in: legacy.amount_in,
+            minimum_out: legacy.minimum_out,
+            direction: legacy.direction,
+            user_source: legacy.user_source,
+            pool_coin: legacy.pool_coin,
+            pool_pc: legacy.pool_pc,
+            out_amount: legacy.out_amount,
+            amount_in_after_transfer_fee: log.amount_in_after_transfer_fee,
+            out_amount_after_transfer_fee: log.out_amount_after_transfer_fee,
+            ..Default::default()
         }
-        LogType::SwapBaseOut => {
-            let log: SwapBaseOutLog = bincode::deserialize(&bytes).unwrap();
-            println!("{:?}", log);
+    }
+}
+
+/// SwapBaseOutLog extended with the transfer fee results, logged under the SwapBaseOut
+/// log_type before the extended fields moved to SwapBaseOutLogV2.
+#[cfg(feature = "client")]
+#[derive(Deserialize)]
+struct SwapBaseOutLogV1 {
+    legacy: SwapBaseOutLog,
+    deduct_in_after_transfer_fee: u64,
+    amount_out_before_transfer_fee: u64,
+}
+
+#[cfg(feature = "client")]
+impl SwapBaseOutLogV1 {
+    /// Length of its bincode bytes, the legacy log and two u64.
+    const LEN: usize = 1 + 9 * 8;
+}
+
+#[cfg(feature = "client")]
+impl From<SwapBaseOutLogV1> for SwapBaseOutLogV2 {
+    fn from(log: SwapBaseOutLogV1) -> Self {
+        let legacy = log.legacy;
+        SwapBaseOutLogV2 {
+            log_type: LogType::SwapBaseOutV2.into_u8(),
+            version: 1,
+            max_in: legacy.max_in,
+            amount_out: legacy.amount_out,
+            direction: legacy.direction,
+            user_source: legacy.user_source,
+            pool_coin: legacy.pool_coin,
+            pool_pc: legacy.pool_pc,
+            deduct_in: legacy.deduct_in,
+            deduct_in_after_transfer_fee: log.deduct_in_after_transfer_fee,
+            amount_out_before_transfer_fee: log.amount_out_before_transfer_fee,
+            ..Default::default()
         }
     }
 }
+
+/// Decodes the events of the EmitEvent instructions among the inner instructions of a
+/// transaction, given as their program id and data, in order.
+#[cfg(feature = "client")]
+pub fn parse_event_instructions<'a, I>(
+    amm_program: &Pubkey,
+    instructions: I,
+) -> Result<Vec<RayLog>, RayLogError>
+where
+    I: IntoIterator<Item = (&'a Pubkey, &'a [u8])>,
+{
+    instructions
+        .into_iter()
+        .filter(|(program_id, data)| {
+            *program_id == amm_program && data.first() == Some(&EMIT_EVENT_TAG)
+        })
+        .map(|(_, data)| RayLog::from_event_instruction(data))
+        .collect()
+}
+
+/// Decodes every ray_log of the log messages of a transaction, in order.
+#[cfg(feature = "client")]
+pub fn parse_ray_logs<S: AsRef<str>>(log_messages: &[S]) -> Result<Vec<RayLog>, RayLogError> {
+    log_messages
+        .iter()
+        .filter(|line| RayLog::payload(line.as_ref()).is_some())
+        .map(|line| RayLog::parse(line.as_ref()))
+        .collect()
+}
+
+#[cfg(all(test, feature = "client"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ray_logs() {
+        let swap_log = SwapBaseInLogV2 {
+            log_type: LogType::SwapBaseInV2.into_u8(),
+            version: EVENT_VERSION,
+            amount_in: 1_000_000,
+            minimum_out: 900,
+            direction: 1,
+            pool_coin: 5_000_000,
+            pool_pc: 5_000,
+            out_amount: 995,
+            amount_in_after_transfer_fee: 999_000,
+            out_amount_after_transfer_fee: 994,
+            swap_fee: 2_500,
+            ..Default::default()
+        };
+        let deposit_log = DepositLog {
+            log_type: LogType::Deposit.into_u8(),
+            max_coin: 10,
+            ..Default::default()
+        };
+        let encode = |bytes: Vec<u8>| base64::encode_config(bytes, base64::STANDARD);
+        let log_messages = vec![
+            "Program 675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8 invoke [1]".to_string(),
+            format!(
+                "Program log: ray_log: {}",
+                encode(bincode::serialize(&swap_log.legacy()).unwrap())
+            ),
+            format!(
+                "Program log: ray_log: {}",
+                encode(bincode::serialize(&swap_log).unwrap())
+            ),
+            "Program log: ray_log: amm_config mismatch: input:a, expected:b".to_string(),
+            format!(
+                "ray_log: {}",
+                encode(bincode::serialize(&deposit_log).unwrap())
+            ),
+        ];
+        assert_eq!(
+            parse_ray_logs(&log_messages).unwrap(),
+            vec![
+                RayLog::SwapBaseIn(swap_log.legacy()),
+                RayLog::SwapBaseInV2(swap_log.clone()),
+                RayLog::Deposit(deposit_log)
+            ]
+        );
+
+        // swap logs of the extended layout logged under the legacy log_type
+        let extended_in = (swap_log.legacy(), 999_000u64, 994u64);
+        let decoded = decode_ray_log(&format!(
+            "ray_log: {}",
+            encode(bincode::serialize(&extended_in).unwrap())
+        ))
+        .unwrap();
+        assert_eq!(
+            decoded,
+            RayLog::SwapBaseInV2(SwapBaseInLogV2 {
+                version: 1,
+                swap_fee: 0,
+                ..swap_log
+            })
+        );
+        let swap_out_log = SwapBaseOutLog {
+            log_type: LogType::SwapBaseOut.into_u8(),
+            max_in: 1_000,
+            amount_out: 900,
+            deduct_in: 950,
+            ..Default::default()
+        };
+        let extended_out = (swap_out_log.clone(), 940u64, 905u64);
+        let decoded = decode_ray_log(&format!(
+            "ray_log: {}",
+            encode(bincode::serialize(&extended_out).unwrap())
+        ))
+        .unwrap();
+        assert_eq!(
+            decoded,
+            RayLog::SwapBaseOutV2(SwapBaseOutLogV2 {
+                log_type: LogType::SwapBaseOutV2.into_u8(),
+                version: 1,
+                max_in: 1_000,
+                amount_out: 900,
+                deduct_in: 950,
+                deduct_in_after_transfer_fee: 940,
+                amount_out_before_transfer_fee: 905,
+                ..Default::default()
+            })
+        );
+
+        let pause_log = PauseLog {
+            log_type: LogType::Pause.into_u8(),
+            signer: Pubkey::new_unique(),
+            old_status: 6,
+            new_status: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_ray_log(&format!(
+                "ray_log: {}",
+                encode(bincode::serialize(&pause_log).unwrap())
+            ))
+            .unwrap(),
+            RayLog::Pause(pause_log)
+        );
+
+        assert!(matches!(
+            RayLog::parse("Program log: Instruction: Swap"),
+            Err(RayLogError::NotRayLog)
+        ));
+        assert!(matches!(
+            RayLog::parse("ray_log: !!!!"),
+            Err(RayLogError::Base64(_))
+        ));
+        assert!(matches!(
+            decode_ray_log(&format!("ray_log: {}", encode(vec![255, 0]))),
+            Err(RayLogError::UnknownLogType(255))
+        ));
+        assert!(matches!(
+            RayLog::parse(&format!("ray_log: {}", encode(vec![3, 0]))),
+            Err(RayLogError::Bincode(_))
+        ));
+    }
+}
@@ -4,7 +4,10 @@
 #![allow(clippy::unknown_clippy_lints)]
 #![allow(clippy::manual_range_contains)]
 
-use crate::{error::AmmError, state::AmmInfo};
+use crate::{
+    error::AmmError,
+    state::{AmmInfo, TEN_THOUSAND},
+};
 use std::{cmp::Eq, convert::TryInto};
 use uint::construct_uint;
 
@@ -242,9 +245,11 @@ impl Calculator {
     ) -> Result<(u64, u64), AmmError> {
         let total_pc_without_take_pnl = pc_amount
             .checked_sub(amm.state_data.need_take_pnl_pc)
+            .and_then(|amount| amount.checked_sub(amm.state_data.protocol_fee_pc))
             .ok_or(AmmError::CheckedSubOverflow)?;
         let total_coin_without_take_pnl = coin_amount
             .checked_sub(amm.state_data.need_take_pnl_coin)
+            .and_then(|amount| amount.checked_sub(amm.state_data.protocol_fee_coin))
             .ok_or(AmmError::CheckedSubOverflow)?;
         Ok((total_pc_without_take_pnl, total_coin_without_take_pnl))
     }
@@ -286,6 +291,48 @@ impl Calculator {
         Self::to_u64(max_size.as_u128()).unwrap()
     }
 
+    /// Pc amount of coin_amount at a range order price, pc per coin scaled like the
+    /// pool price. Saturates at u64::MAX.
+    pub fn range_order_coin_to_pc(
+        coin_amount: u64,
+        price: u64,
+        amm: &AmmInfo,
+        round_direction: RoundDirection,
+    ) -> u64 {
+        // pc = coin * price * 10**pc_decimals / (10**coin_decimals * sys_decimal_value)
+        let numerator = U256::from(coin_amount)
+            * U256::from(price)
+            * U256::from(10).pow(amm.pc_decimals.into());
+        let denominator =
+            U256::from(10).pow(amm.coin_decimals.into()) * U256::from(amm.sys_decimal_value);
+        Self::range_order_div(numerator, denominator, round_direction)
+    }
+
+    /// Coin amount of pc_amount at a range order price, pc per coin scaled like the
+    /// pool price. Saturates at u64::MAX.
+    pub fn range_order_pc_to_coin(
+        pc_amount: u64,
+        price: u64,
+        amm: &AmmInfo,
+        round_direction: RoundDirection,
+    ) -> u64 {
+        // coin = pc * 10**coin_decimals * sys_decimal_value / (price * 10**pc_decimals)
+        let numerator = U256::from(pc_amount)
+            * U256::from(10).pow(amm.coin_decimals.into())
+            * U256::from(amm.sys_decimal_value);
+        let denominator = U256::from(price) * U256::from(10).pow(amm.pc_decimals.into());
+        Self::range_order_div(numerator, denominator, round_direction)
+    }
+
+    fn range_order_div(numerator: U256, denominator: U256, round_direction: RoundDirection) -> u64 {
+        let quotient = match round_direction {
+            RoundDirection::Floor => numerator.checked_div(denominator),
+            RoundDirection::Ceiling => numerator.checked_ceil_div(denominator),
+        }
+        .unwrap();
+        quotient.min(U256::from(u64::MAX)).as_u64()
+    }
+
     pub fn swap_token_amount_base_in(
         amount_in: U128,
         total_pc_without_take_pnl: U128,
@@ -365,6 +412,639 @@ impl Calculator {
         }
         return amount_in;
     }
+
+    /// Price impact in basis points of trading amount_in for amount_out against
+    /// the pool price reserve_out / reserve_in, fees excluded.
+    pub fn price_impact(amount_in: u64, amount_out: u64, reserve_in: u64, reserve_out: u64) -> u64 {
+        if amount_in == 0 || reserve_in == 0 || reserve_out == 0 {
+            return 0;
+        }
+        // 1 - (amount_out / amount_in) / (reserve_out / reserve_in)
+        let execution = U256::from(amount_out)
+            .checked_mul(reserve_in.into())
+            .unwrap()
+            .checked_mul(TEN_THOUSAND.into())
+            .unwrap()
+            .checked_div(
+                U256::from(amount_in)
+                    .checked_mul(reserve_out.into())
+                    .unwrap(),
+            )
+            .unwrap();
+        U256::from(TEN_THOUSAND).saturating_sub(execution).as_u64()
+    }
+
+    /// Part of amount_in to swap so that the rest and the swap output are in the
+    /// pool ratio after the swap, charged swap_fee_numerator / swap_fee_denominator.
+    pub fn single_side_swap_amount(
+        amount_in: u64,
+        reserve_in: u64,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> u64 {
+        // with f the swap fee, R the reserve and a the amount_in, the swapped s satisfies
+        // (1 - f) * s^2 + (2 - f) * R * s - a * R = 0
+        // s = (sqrt(((2 - f) * R)^2 + 4 * (1 - f) * a * R) - (2 - f) * R) / (2 * (1 - f))
+        let d = U256::from(swap_fee_denominator);
+        let one_minus_fee = d.checked_sub(swap_fee_numerator.into()).unwrap();
+        let two_minus_fee_r = (d + one_minus_fee).checked_mul(reserve_in.into()).unwrap();
+        let discriminant = two_minus_fee_r
+            .checked_mul(two_minus_fee_r)
+            .unwrap()
+            .checked_add(
+                U256::from(4u64)
+                    .checked_mul(one_minus_fee)
+                    .unwrap()
+                    .checked_mul(d)
+                    .unwrap()
+                    .checked_mul(amount_in.into())
+                    .unwrap()
+                    .checked_mul(reserve_in.into())
+                    .unwrap(),
+            )
+            .unwrap();
+        discriminant
+            .integer_sqrt()
+            .checked_sub(two_minus_fee_r)
+            .unwrap()
+            .checked_div(one_minus_fee * 2)
+            .unwrap()
+            .as_u64()
+            .min(amount_in)
+    }
+}
+
+/// Swap math of a pool curve, on amounts after the swap fee.
+pub trait CurveCalculator {
+    /// Amount out of swapping amount_in into the pool.
+    fn swap_token_amount_base_in(
+        &self,
+        amount_in: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError>;
+
+    /// Amount in needed to swap amount_out out of the pool.
+    fn swap_token_amount_base_out(
+        &self,
+        amount_out: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError>;
+
+    /// Marginal price of the coin in pc at the reserves, as the ratio of a pc amount
+    /// over a coin amount. None while one side of the pool is empty.
+    fn spot_price(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+    ) -> Option<(U256, U256)>;
+
+    /// Amount the other side vault receives and lp minted by a deposit whose base side
+    /// vault receives deduct_base_amount. A deposit in the reserve ratio keeps the
+    /// spot price of every curve.
+    fn deposit_amounts(
+        &self,
+        lp_amount: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        base_side: u64,
+        deduct_base_amount: u64,
+    ) -> Result<(u64, u64), AmmError> {
+        let invariant = InvariantToken {
+            token_coin: total_coin_without_take_pnl,
+            token_pc: total_pc_without_take_pnl,
+        };
+        let (deduct_other_amount, invariant_base) = if base_side == 0 {
+            (
+                invariant.exchange_coin_to_pc(deduct_base_amount, RoundDirection::Ceiling),
+                InvariantPool {
+                    token_input: deduct_base_amount,
+                    token_total: total_coin_without_take_pnl,
+                },
+            )
+        } else {
+            (
+                invariant.exchange_pc_to_coin(deduct_base_amount, RoundDirection::Ceiling),
+                InvariantPool {
+                    token_input: deduct_base_amount,
+                    token_total: total_pc_without_take_pnl,
+                },
+            )
+        };
+        let deduct_other_amount = deduct_other_amount.ok_or(AmmError::CalculationExRateFailure)?;
+        // base_amount / (total_base_amount + base_amount) = output / (lp_mint.supply + output) => output = base_amount / total_base_amount * lp_mint.supply
+        let mint_lp_amount = invariant_base
+            .exchange_token_to_pool(lp_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        Ok((deduct_other_amount, mint_lp_amount))
+    }
+
+    /// Coin and pc sent by the vaults for withdraw_lp burnt, in the reserve ratio.
+    fn withdraw_amounts(
+        &self,
+        lp_amount: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        withdraw_lp: u64,
+    ) -> Result<(u64, u64), AmmError> {
+        // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
+        let invariant = InvariantPool {
+            token_input: withdraw_lp,
+            token_total: lp_amount,
+        };
+        let coin_amount = invariant
+            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        let pc_amount = invariant
+            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        Ok((coin_amount, pc_amount))
+    }
+
+    /// Part of amount_in to swap so that the rest and the swap output are in the
+    /// pool ratio after the swap, charged swap_fee_numerator / swap_fee_denominator.
+    fn single_side_swap_amount(
+        &self,
+        amount_in: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> Result<u64, AmmError> {
+        let (reserve_in, reserve_out) = match swap_direction {
+            SwapDirection::Coin2PC => (total_coin_without_take_pnl, total_pc_without_take_pnl),
+            SwapDirection::PC2Coin => (total_pc_without_take_pnl, total_coin_without_take_pnl),
+        };
+        // the rest over the swap output decreases with the swapped amount,
+        // bisect the largest swap whose rest is still above the pool ratio
+        let (mut low, mut high) = (0u64, amount_in);
+        while low < high {
+            let swap_in = low + (high - low).div_ceil(2);
+            let swap_fee = U128::from(swap_in)
+                .checked_mul(swap_fee_numerator.into())
+                .unwrap()
+                .checked_ceil_div(swap_fee_denominator.into())
+                .unwrap();
+            let swap_out = self.swap_token_amount_base_in(
+                U128::from(swap_in).checked_sub(swap_fee).unwrap(),
+                total_pc_without_take_pnl.into(),
+                total_coin_without_take_pnl.into(),
+                swap_direction,
+            )?;
+            // (amount_in - swap_in) / (reserve_in + swap_in) >= swap_out / (reserve_out - swap_out)
+            let rest = U256::from(amount_in - swap_in)
+                .checked_mul(U256::from(reserve_out).saturating_sub(swap_out.as_u128().into()))
+                .unwrap();
+            let out = U256::from(swap_out.as_u128())
+                .checked_mul(U256::from(reserve_in) + U256::from(swap_in))
+                .unwrap();
+            if rest >= out {
+                low = swap_in;
+            } else {
+                high = swap_in - 1;
+            }
+        }
+        Ok(low)
+    }
+}
+
+/// The x * y = k curve.
+pub struct ConstantProductCurve;
+
+impl CurveCalculator for ConstantProductCurve {
+    fn swap_token_amount_base_in(
+        &self,
+        amount_in: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        Ok(Calculator::swap_token_amount_base_in(
+            amount_in,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        ))
+    }
+
+    fn swap_token_amount_base_out(
+        &self,
+        amount_out: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        Ok(Calculator::swap_token_amount_base_out(
+            amount_out,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        ))
+    }
+
+    fn spot_price(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+    ) -> Option<(U256, U256)> {
+        if total_pc_without_take_pnl.is_zero() || total_coin_without_take_pnl.is_zero() {
+            return None;
+        }
+        Some((
+            U256::from(total_pc_without_take_pnl.as_u128()),
+            U256::from(total_coin_without_take_pnl.as_u128()),
+        ))
+    }
+
+    fn single_side_swap_amount(
+        &self,
+        amount_in: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        swap_fee_numerator: u64,
+        swap_fee_denominator: u64,
+    ) -> Result<u64, AmmError> {
+        let reserve_in = match swap_direction {
+            SwapDirection::Coin2PC => total_coin_without_take_pnl,
+            SwapDirection::PC2Coin => total_pc_without_take_pnl,
+        };
+        Ok(Calculator::single_side_swap_amount(
+            amount_in,
+            reserve_in,
+            swap_fee_numerator,
+            swap_fee_denominator,
+        ))
+    }
+}
+
+/// Newton iterations of the stable invariant before giving up on convergence.
+const STABLE_MAX_ITERATIONS: u64 = 255;
+
+/// The two tokens stable invariant of Curve, 4 * amp * (x + y) + D = 4 * amp * D + D^3 / (4 * x * y)
+/// on amounts scaled to the same decimals.
+pub struct StableCurve {
+    /// Amplification coefficient
+    pub amp: u64,
+    /// Scales coin amounts to the larger decimals of the pool
+    pub coin_multiplier: u64,
+    /// Scales pc amounts to the larger decimals of the pool
+    pub pc_multiplier: u64,
+}
+
+impl StableCurve {
+    pub fn new(amp: u64, coin_decimals: u64, pc_decimals: u64) -> Self {
+        let decimals = coin_decimals.max(pc_decimals);
+        StableCurve {
+            amp,
+            coin_multiplier: 10u64.pow((decimals - coin_decimals) as u32),
+            pc_multiplier: 10u64.pow((decimals - pc_decimals) as u32),
+        }
+    }
+
+    /// The invariant D of the scaled reserves x and y.
+    pub fn compute_d(&self, x: U256, y: U256) -> U256 {
+        if x.is_zero() || y.is_zero() {
+            return U256::zero();
+        }
+        let sum = x + y;
+        let ann = U256::from(self.amp) * 4;
+        let mut d = sum;
+        for _ in 0..STABLE_MAX_ITERATIONS {
+            // d_p = D^3 / (4 * x * y)
+            let d_p = d * d / (x * 2) * d / (y * 2);
+            let d_prev = d;
+            // D = (ann * S + 2 * d_p) * D / ((ann - 1) * D + 3 * d_p)
+            d = (ann * sum + d_p * 2) * d / ((ann - 1) * d + d_p * 3);
+            if d.max(d_prev) - d.min(d_prev) <= U256::one() {
+                break;
+            }
+        }
+        d
+    }
+
+    /// The scaled reserve y keeping the invariant d with the other scaled reserve x.
+    pub fn compute_y(&self, x: U256, d: U256) -> U256 {
+        let ann = U256::from(self.amp) * 4;
+        // y^2 + (x + D / ann - D) * y = D^3 / (4 * x * ann)
+        let c = d * d / (x * 2) * d / (ann * 2);
+        let b = x + d / ann;
+        let mut y = d;
+        for _ in 0..STABLE_MAX_ITERATIONS {
+            let y_prev = y;
+            y = (y * y + c) / (y * 2 + b - d);
+            if y.max(y_prev) - y.min(y_prev) <= U256::one() {
+                break;
+            }
+        }
+        y
+    }
+
+    /// Scaled reserves in and out, and the multipliers of the in and out tokens.
+    fn scaled_reserves(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> (U256, U256, u64, u64) {
+        let pc = U256::from(total_pc_without_take_pnl.as_u128()) * self.pc_multiplier;
+        let coin = U256::from(total_coin_without_take_pnl.as_u128()) * self.coin_multiplier;
+        match swap_direction {
+            SwapDirection::Coin2PC => (coin, pc, self.coin_multiplier, self.pc_multiplier),
+            SwapDirection::PC2Coin => (pc, coin, self.pc_multiplier, self.coin_multiplier),
+        }
+    }
+}
+
+impl CurveCalculator for StableCurve {
+    fn swap_token_amount_base_in(
+        &self,
+        amount_in: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        let (reserve_in, reserve_out, in_multiplier, out_multiplier) = self.scaled_reserves(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        );
+        if reserve_in.is_zero() || reserve_out.is_zero() {
+            return Ok(U128::zero());
+        }
+        let d = self.compute_d(reserve_in, reserve_out);
+        let new_reserve_in = reserve_in + U256::from(amount_in.as_u128()) * in_multiplier;
+        let new_reserve_out = self.compute_y(new_reserve_in, d);
+        // round down by one unit against the newton precision
+        let amount_out = reserve_out.saturating_sub(new_reserve_out + 1) / out_multiplier;
+        Calculator::to_u128_checked(amount_out)
+    }
+
+    fn swap_token_amount_base_out(
+        &self,
+        amount_out: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        let (reserve_in, reserve_out, in_multiplier, out_multiplier) = self.scaled_reserves(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        );
+        let d = self.compute_d(reserve_in, reserve_out);
+        let new_reserve_out = reserve_out
+            .checked_sub(U256::from(amount_out.as_u128()) * out_multiplier)
+            .filter(|new_reserve_out| !new_reserve_out.is_zero())
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        let new_reserve_in = self.compute_y(new_reserve_out, d);
+        // round up by one unit against the newton precision
+        let amount_in = (new_reserve_in + 1)
+            .saturating_sub(reserve_in)
+            .checked_add(U256::from(in_multiplier - 1))
+            .ok_or(AmmError::CalculationExRateFailure)?
+            / in_multiplier;
+        Calculator::to_u128_checked(amount_in)
+    }
+
+    fn spot_price(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+    ) -> Option<(U256, U256)> {
+        let (x, y, _, _) = self.scaled_reserves(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            SwapDirection::Coin2PC,
+        );
+        if x.is_zero() || y.is_zero() {
+            return None;
+        }
+        // -dy/dx of the invariant is y * (4 * amp * x + d_p) / (x * (4 * amp * y + d_p))
+        // with d_p = D^3 / (4 * x * y), on the scaled reserves
+        let d = self.compute_d(x, y);
+        let d_p = (d.checked_mul(d)? / (x * 2)).checked_mul(d)? / (y * 2);
+        let ann = U256::from(self.amp) * U256::from(4u64);
+        let numerator = y.checked_mul(ann.checked_mul(x)?.checked_add(d_p)?)?;
+        let denominator = x.checked_mul(ann.checked_mul(y)?.checked_add(d_p)?)?;
+        Some((
+            numerator.checked_mul(self.coin_multiplier.into())?,
+            denominator.checked_mul(self.pc_multiplier.into())?,
+        ))
+    }
+}
+
+/// Fixed point one of ln_wad, exp_wad and pow_wad
+pub const WAD: u128 = 1_000_000_000_000_000_000;
+/// ln(2) * WAD
+const LN_2_WAD: u128 = 693_147_180_559_945_309;
+/// Upper bound of the relative error of pow_wad, in WAD
+pub const MAX_POW_RELATIVE_ERROR: u128 = 10_000;
+
+impl Calculator {
+    /// ln(x) of x >= WAD in WAD, None below WAD.
+    pub fn ln_wad(x: U256) -> Option<U256> {
+        let wad = U256::from(WAD);
+        if x < wad {
+            return None;
+        }
+        // x = 2^k * m with m in [1, 2)
+        let k = (x / wad).bits() - 1;
+        let m = x >> k;
+        // ln(m) = 2 * atanh(z) = 2 * (z + z^3 / 3 + z^5 / 5 + ...) with z = (m - 1) / (m + 1) < 1/3
+        let z = (m - wad) * wad / (m + wad);
+        let z_square = z * z / wad;
+        let mut term = z;
+        let mut sum = z;
+        let mut i = 1u64;
+        while !term.is_zero() {
+            term = term * z_square / wad;
+            sum += term / (2 * i + 1);
+            i += 1;
+        }
+        Some(sum * 2 + U256::from(LN_2_WAD) * k)
+    }
+
+    /// e^x of x >= 0 in WAD, None if it overflows.
+    pub fn exp_wad(x: U256) -> Option<U256> {
+        let wad = U256::from(WAD);
+        // x = k * ln(2) + r with r in [0, ln(2))
+        let k = x / LN_2_WAD;
+        if k >= U256::from(128u64) {
+            return None;
+        }
+        let r = x - k * U256::from(LN_2_WAD);
+        // e^r = 1 + r + r^2 / 2! + r^3 / 3! + ...
+        let mut term = wad;
+        let mut sum = wad;
+        let mut i = 1u64;
+        while !term.is_zero() {
+            term = term * r / wad / i;
+            sum += term;
+            i += 1;
+        }
+        Some(sum << k.as_usize())
+    }
+
+    /// base^(exponent_numerator / exponent_denominator) of base > 0 in WAD,
+    /// None if it overflows.
+    pub fn pow_wad(base: U256, exponent_numerator: u64, exponent_denominator: u64) -> Option<U256> {
+        let wad = U256::from(WAD);
+        if base >= wad {
+            Self::exp_wad(
+                Self::ln_wad(base)?.checked_mul(exponent_numerator.into())? / exponent_denominator,
+            )
+        } else {
+            // base^e = 1 / (1 / base)^e, which underflows to 0
+            match Self::exp_wad(
+                Self::ln_wad(wad.checked_mul(wad)?.checked_div(base)?)?
+                    .checked_mul(exponent_numerator.into())?
+                    / exponent_denominator,
+            ) {
+                Some(inverse) => Some(wad * wad / inverse),
+                None => Some(U256::zero()),
+            }
+        }
+    }
+
+    /// Liquidity of the first deposit of a weighted pool, the weighted geometric mean
+    /// coin^(coin_weight / W) * pc^(pc_weight / W) of the amounts.
+    pub fn weighted_liquidity(
+        coin_amount: u64,
+        pc_amount: u64,
+        coin_weight: u64,
+        pc_weight: u64,
+    ) -> Option<u64> {
+        let wad = U256::from(WAD);
+        let ln_coin = Self::ln_wad(U256::from(coin_amount) * wad)?;
+        let ln_pc = Self::ln_wad(U256::from(pc_amount) * wad)?;
+        let ln_liquidity = (ln_coin * coin_weight + ln_pc * pc_weight) / (coin_weight + pc_weight);
+        let liquidity = Self::exp_wad(ln_liquidity)? / wad;
+        if liquidity > U256::from(u64::MAX) {
+            return None;
+        }
+        Some(liquidity.as_u64())
+    }
+
+    /// U128 of a U256 result, an error if it does not fit.
+    pub fn to_u128_checked(val: U256) -> Result<U128, AmmError> {
+        if val > U256::from(u128::MAX) {
+            return Err(AmmError::CalculationExRateFailure);
+        }
+        Ok(U128::from(val.as_u128()))
+    }
+}
+
+/// The weighted constant product curve, coin^coin_weight * pc^pc_weight = k.
+pub struct WeightedCurve {
+    pub coin_weight: u64,
+    pub pc_weight: u64,
+}
+
+impl WeightedCurve {
+    /// Reserves and weights of the input and output tokens.
+    fn reserves_and_weights(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> (U256, U256, u64, u64) {
+        let pc = U256::from(total_pc_without_take_pnl.as_u128());
+        let coin = U256::from(total_coin_without_take_pnl.as_u128());
+        match swap_direction {
+            SwapDirection::Coin2PC => (coin, pc, self.coin_weight, self.pc_weight),
+            SwapDirection::PC2Coin => (pc, coin, self.pc_weight, self.coin_weight),
+        }
+    }
+
+    /// pow_wad rounded up by its maximum error, an error if it overflows.
+    fn pow_wad_up(
+        base: U256,
+        exponent_numerator: u64,
+        exponent_denominator: u64,
+    ) -> Result<U256, AmmError> {
+        let pow = Calculator::pow_wad(base, exponent_numerator, exponent_denominator)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        pow.checked_mul(U256::from(MAX_POW_RELATIVE_ERROR))
+            .and_then(|error| error.checked_ceil_div(WAD.into()))
+            .and_then(|error| pow.checked_add(error))
+            .ok_or(AmmError::CalculationExRateFailure)
+    }
+}
+
+impl CurveCalculator for WeightedCurve {
+    fn swap_token_amount_base_in(
+        &self,
+        amount_in: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        let (reserve_in, reserve_out, weight_in, weight_out) = self.reserves_and_weights(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        );
+        let wad = U256::from(WAD);
+        // amount_out = reserve_out * (1 - (reserve_in / (reserve_in + amount_in))^(weight_in / weight_out))
+        let base = (reserve_in * wad)
+            .checked_ceil_div(reserve_in + U256::from(amount_in.as_u128()))
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        // never swap out the whole reserve
+        let pow = Self::pow_wad_up(base, weight_in, weight_out)?
+            .max(U256::one())
+            .min(wad);
+        let amount_out = reserve_out * (wad - pow) / wad;
+        Calculator::to_u128_checked(amount_out)
+    }
+
+    fn swap_token_amount_base_out(
+        &self,
+        amount_out: U128,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+        swap_direction: SwapDirection,
+    ) -> Result<U128, AmmError> {
+        let (reserve_in, reserve_out, weight_in, weight_out) = self.reserves_and_weights(
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+        );
+        let wad = U256::from(WAD);
+        // amount_in = reserve_in * ((reserve_out / (reserve_out - amount_out))^(weight_out / weight_in) - 1)
+        let base = (reserve_out * wad)
+            .checked_ceil_div(
+                reserve_out
+                    .checked_sub(U256::from(amount_out.as_u128()))
+                    .ok_or(AmmError::CalculationExRateFailure)?,
+            )
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        let pow = Self::pow_wad_up(base, weight_out, weight_in)?;
+        let amount_in = reserve_in
+            .checked_mul(pow.saturating_sub(wad))
+            .and_then(|amount_in| amount_in.checked_ceil_div(wad))
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        Calculator::to_u128_checked(amount_in)
+    }
+
+    fn spot_price(
+        &self,
+        total_pc_without_take_pnl: U128,
+        total_coin_without_take_pnl: U128,
+    ) -> Option<(U256, U256)> {
+        if total_pc_without_take_pnl.is_zero() || total_coin_without_take_pnl.is_zero() {
+            return None;
+        }
+        // (pc / pc_weight) / (coin / coin_weight)
+        Some((
+            U256::from(total_pc_without_take_pnl.as_u128()) * self.coin_weight,
+            U256::from(total_coin_without_take_pnl.as_u128()) * self.pc_weight,
+        ))
+    }
 }
 
 /// The invariant calculator.
@@ -493,6 +1173,17 @@ impl CheckedCeilDiv for u128 {
     }
 }
 
+impl CheckedCeilDiv for U256 {
+    fn checked_ceil_div(&self, rhs: Self) -> Option<Self> {
+        let mut quotient = self.checked_div(rhs)?;
+        let remainder = self.checked_rem(rhs)?;
+        if remainder != U256::zero() {
+            quotient = quotient.checked_add(U256::one())?;
+        }
+        Some(quotient)
+    }
+}
+
 impl CheckedCeilDiv for U128 {
     fn checked_ceil_div(&self, rhs: Self) -> Option<Self> {
         let mut quotient = self.checked_div(rhs)?;
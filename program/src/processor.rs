@@ -3,16 +3,24 @@
 use crate::{
     error::AmmError,
     instruction::{
-        AmmInstruction, ConfigArgs, DepositInstruction, InitializeInstruction2,
-        SetParamsInstruction, SwapInstructionBaseIn, SwapInstructionBaseOut, WithdrawInstruction,
+        AmmInstruction, ConfigArgs, DepositInstruction, DepositSingleSideInstruction,
+        DynamicFeeInstruction, FlashLoanInstruction, FlashSwapInstruction, InitializeInstruction2,
+        PauseInstruction, PlaceLimitOrderInstruction, PlaceRangeOrderInstruction,
+        SetCurveInstruction, SetParamsInstruction, SimulateInstruction, SwapInstructionBaseIn,
+        SwapInstructionBaseOut, WithdrawInstruction, WithdrawSingleSideInstruction,
     },
     invokers::Invokers,
     math::{
-        Calculator, CheckedCeilDiv, InvariantPool, InvariantToken, RoundDirection, SwapDirection,
+        Calculator, CheckedCeilDiv, CurveCalculator, InvariantPool, RoundDirection, SwapDirection,
         U128, U256,
     },
     state::{
-        AmmConfig, AmmInfo, AmmParams, AmmResetFlag, AmmState, AmmStatus, Loadable, TargetOrders,
+        AmmConfig, AmmInfo, AmmParams, AmmResetFlag, AmmState, AmmStatus, CurveType,
+        DynamicFeeState, Fees, LimitOrderState, Loadable, ObservationState, OrderSide,
+        RangeOrderState, SimulateParams, SimulatePoolInfo, SimulateSwapInfo, TargetOrders, MAX_AMP,
+        MAX_AMP_CHANGE, MAX_FLASH_FEE_RATE, MAX_ORDER_LIMIT, MAX_PARAMS_DELAY,
+        MAX_RANGE_ORDER_FILLS, MAX_REFERRER_FEE_RATE, MIN_AMP, MIN_RAMP_DURATION, MIN_WEIGHT,
+        RANGE_ORDER_MIN_SIZE_DIVISOR, WEIGHT_DENOMINATOR,
     },
 };
 
@@ -20,8 +28,9 @@ use solana_program::{
     account_info::{next_account_info, AccountInfo},
     clock::Clock,
     entrypoint::ProgramResult,
+    instruction::{AccountMeta, Instruction},
     msg,
-    program::{invoke, invoke_signed},
+    program::{invoke, invoke_signed, set_return_data},
     // log::sol_log_compute_units,
     program_error::ProgramError,
     program_option::COption,
@@ -34,7 +43,11 @@ use solana_program::{
 
 use super::log::*;
 use arrform::{arrform, ArrForm};
-use std::{convert::identity, mem::size_of};
+use serde::Serialize;
+use spl_token_2022::extension::{
+    transfer_fee::TransferFeeConfig, BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+};
+use std::{cell::RefMut, convert::identity, mem::size_of};
 
 pub mod srm_token {
     solana_program::declare_id!("SRMuApVNdxXokk5GT7XD5cUUgXMBCoAz2LHeuAoKWRt");
@@ -106,6 +119,30 @@ pub const PC_VAULT_ASSOCIATED_SEED: &'static [u8] = b"pc_vault_associated_seed";
 pub const LP_MINT_ASSOCIATED_SEED: &'static [u8] = b"lp_mint_associated_seed";
 /// Amm config seed
 pub const AMM_CONFIG_SEED: &'static [u8] = b"amm_config_account_seed";
+/// Suffix for amm observation associated seed
+pub const OBSERVATION_ASSOCIATED_SEED: &'static [u8] = b"observation_associated_seed";
+/// Suffix for amm dynamic fee associated seed
+pub const DYNAMIC_FEE_ASSOCIATED_SEED: &'static [u8] = b"dynamic_fee_associated_seed";
+/// Suffix for range order coin vault associated seed
+pub const RANGE_ORDER_COIN_VAULT_SEED: &'static [u8] = b"range_order_coin_vault_seed";
+/// Suffix for range order pc vault associated seed
+pub const RANGE_ORDER_PC_VAULT_SEED: &'static [u8] = b"range_order_pc_vault_seed";
+/// Suffix for range order associated seed, followed by the side and the slot of the order
+pub const RANGE_ORDER_SEED: &'static [u8] = b"range_order_seed";
+/// Suffix for limit order coin vault associated seed
+pub const LIMIT_ORDER_COIN_VAULT_SEED: &'static [u8] = b"limit_order_coin_vault_seed";
+/// Suffix for limit order pc vault associated seed
+pub const LIMIT_ORDER_PC_VAULT_SEED: &'static [u8] = b"limit_order_pc_vault_seed";
+/// Limit order seed, followed by the amm, the owner and the order id
+pub const LIMIT_ORDER_SEED: &'static [u8] = b"limit_order_seed";
+/// Event authority seed, signs the EmitEvent instructions the program invokes itself with
+pub const EVENT_AUTHORITY_SEED: &'static [u8] = b"event_authority_seed";
+/// Bump of the event authority of the program id of the network
+#[cfg(feature = "devnet")]
+pub const EVENT_AUTHORITY_BUMP: u8 = 249;
+/// Bump of the event authority of the program id of the network
+#[cfg(not(feature = "devnet"))]
+pub const EVENT_AUTHORITY_BUMP: u8 = 252;
 
 pub fn get_associated_address_and_bump_seed(
     info_id: &Pubkey,
@@ -123,986 +160,6345 @@ pub fn get_associated_address_and_bump_seed(
     )
 }
 
+/// Number of trailing accounts `[token_2022_program, coin_mint, pc_mint]` that
+/// instructions must append when either pool vault is a Token-2022 account.
+pub const TOKEN_2022_ACCOUNTS_LEN: usize = 3;
+/// Number of accounts of each pool of a route swap, the `swap_base_in_v2` layout.
+pub const SWAP_ROUTE_HOP_ACCOUNTS_LEN: usize = 8;
+/// Number of trailing accounts `[amm_config, referrer token account]` that
+/// swap instructions append to pay a referrer.
+pub const REFERRER_ACCOUNTS_LEN: usize = 2;
+/// Maximum number of pools a route swap goes through.
+pub const MAX_SWAP_ROUTE_HOPS: usize = 4;
+
+/// Token program and mint used to move tokens in or out of one pool vault.
+#[derive(Clone, Copy)]
+pub struct VaultToken<'a, 'b> {
+    /// spl_token or Token-2022 program owning the vault
+    pub program: &'a AccountInfo<'b>,
+    /// vault mint, only set for Token-2022 vaults which are moved with `TransferChecked`
+    pub mint: Option<&'a AccountInfo<'b>>,
+    /// vault mint decimals
+    pub decimals: u8,
+}
+
+impl<'a, 'b> VaultToken<'a, 'b> {
+    pub fn new(program: &'a AccountInfo<'b>, mint: &'a AccountInfo<'b>, decimals: u8) -> Self {
+        Self {
+            program,
+            mint: if *program.key == spl_token_2022::id() {
+                Some(mint)
+            } else {
+                None
+            },
+            decimals,
+        }
+    }
+
+    /// Amount arriving at the destination once the transfer fee of the epoch
+    /// is withheld from `pre_fee_amount`.
+    pub fn post_fee_amount(&self, epoch: u64, pre_fee_amount: u64) -> Result<u64, ProgramError> {
+        post_transfer_fee_amount(self.transfer_fee_config()?.as_ref(), epoch, pre_fee_amount)
+    }
+
+    /// Amount to transfer so that exactly `post_fee_amount` arrives at the
+    /// destination after the transfer fee of the epoch.
+    pub fn pre_fee_amount(&self, epoch: u64, post_fee_amount: u64) -> Result<u64, ProgramError> {
+        pre_transfer_fee_amount(self.transfer_fee_config()?.as_ref(), epoch, post_fee_amount)
+    }
+
+    fn transfer_fee_config(&self) -> Result<Option<TransferFeeConfig>, ProgramError> {
+        let mint_info = match self.mint {
+            Some(mint_info) => mint_info,
+            None => return Ok(None),
+        };
+        let mint_data = mint_info.try_borrow_data()?;
+        let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+        Ok(mint.get_extension::<TransferFeeConfig>().ok().copied())
+    }
+}
+
+/// Amount arriving at the destination once the transfer fee of the epoch of a mint
+/// with `transfer_fee_config` is withheld from `pre_fee_amount`.
+pub fn post_transfer_fee_amount(
+    transfer_fee_config: Option<&TransferFeeConfig>,
+    epoch: u64,
+    pre_fee_amount: u64,
+) -> Result<u64, ProgramError> {
+    let transfer_fee = match transfer_fee_config {
+        Some(transfer_fee_config) => transfer_fee_config
+            .calculate_epoch_fee(epoch, pre_fee_amount)
+            .ok_or(AmmError::CalculationExRateFailure)?,
+        None => 0,
+    };
+    Ok(pre_fee_amount
+        .checked_sub(transfer_fee)
+        .ok_or(AmmError::CheckedSubOverflow)?)
+}
+
+/// Amount to transfer so that exactly `post_fee_amount` arrives at the destination
+/// after the transfer fee of the epoch of a mint with `transfer_fee_config`.
+pub fn pre_transfer_fee_amount(
+    transfer_fee_config: Option<&TransferFeeConfig>,
+    epoch: u64,
+    post_fee_amount: u64,
+) -> Result<u64, ProgramError> {
+    let transfer_fee = match transfer_fee_config {
+        Some(transfer_fee_config) if post_fee_amount != 0 => transfer_fee_config
+            .calculate_inverse_epoch_fee(epoch, post_fee_amount)
+            .ok_or(AmmError::CalculationExRateFailure)?,
+        _ => 0,
+    };
+    Ok(post_fee_amount
+        .checked_add(transfer_fee)
+        .ok_or(AmmError::CheckedAddOverflow)?)
+}
+
+/// Amounts moved by a swap through one pool, shared by the swap instructions
+/// and `SimulateInfo` so that quotes match execution.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SwapAmounts {
+    /// amount sent by the user, before the source mint transfer fee
+    pub amount_in: u64,
+    /// amount received by the source vault
+    pub amount_in_after_transfer_fee: u64,
+    /// swap fee charged on the source token
+    pub swap_fee: u64,
+    /// amount sent by the destination vault
+    pub amount_out: u64,
+    /// amount received by the user, after the destination mint transfer fee
+    pub amount_out_after_transfer_fee: u64,
+}
+
+/// Accounts an instruction emits its events with by invoking the program itself.
+#[derive(Clone, Copy)]
+pub struct EventAccounts<'a, 'b> {
+    pub event_authority_info: &'a AccountInfo<'b>,
+    pub program_info: &'a AccountInfo<'b>,
+    pub event_authority_bump: u8,
+}
+
+/// One pool of a route swap, with its accounts checked and its reserves loaded.
+pub struct SwapRouteHop<'a, 'b> {
+    pub amm_info: &'a AccountInfo<'b>,
+    pub amm: RefMut<'a, AmmInfo>,
+    pub amm_authority_info: &'a AccountInfo<'b>,
+    pub source_vault_info: &'a AccountInfo<'b>,
+    pub destination_vault_info: &'a AccountInfo<'b>,
+    pub source_vault_token: VaultToken<'a, 'b>,
+    pub destination_vault_token: VaultToken<'a, 'b>,
+    pub user_source_info: &'a AccountInfo<'b>,
+    pub user_destination_info: &'a AccountInfo<'b>,
+    pub user_source_owner: &'a AccountInfo<'b>,
+    pub observation_info: Option<&'a AccountInfo<'b>>,
+    pub fees: Fees,
+    pub curve: Box<dyn CurveCalculator>,
+    pub swap_direction: SwapDirection,
+    pub total_pc_without_take_pnl: u64,
+    pub total_coin_without_take_pnl: u64,
+}
+
 /// Program state handler.
 pub struct Processor {}
 impl Processor {
-    /// Unpacks a spl_token `Account`.
+    /// Whether the key is the spl_token or the Token-2022 program.
+    #[inline]
+    pub fn is_token_program(token_program_id: &Pubkey) -> bool {
+        *token_program_id == spl_token::id() || *token_program_id == spl_token_2022::id()
+    }
+
+    /// Unpacks a spl_token or Token-2022 `Account`, ignoring its extensions.
     #[inline]
     pub fn unpack_token_account(
         account_info: &AccountInfo,
         token_program_id: &Pubkey,
-    ) -> Result<spl_token::state::Account, AmmError> {
-        if account_info.owner != token_program_id {
+    ) -> Result<spl_token_2022::state::Account, AmmError> {
+        if account_info.owner != token_program_id || !Self::is_token_program(token_program_id) {
             Err(AmmError::InvalidSplTokenProgram)
         } else {
-            spl_token::state::Account::unpack(&account_info.data.borrow())
-                .map_err(|_| AmmError::ExpectedAccount)
+            StateWithExtensions::<spl_token_2022::state::Account>::unpack(
+                &account_info.data.borrow(),
+            )
+            .map(|account| account.base)
+            .map_err(|_| AmmError::ExpectedAccount)
         }
     }
 
-    /// Unpacks a spl_token `Mint`.
+    /// Unpacks a spl_token or Token-2022 `Mint`, ignoring its extensions.
     #[inline]
     pub fn unpack_mint(
         account_info: &AccountInfo,
         token_program_id: &Pubkey,
-    ) -> Result<spl_token::state::Mint, AmmError> {
-        if account_info.owner != token_program_id {
+    ) -> Result<spl_token_2022::state::Mint, AmmError> {
+        if account_info.owner != token_program_id || !Self::is_token_program(token_program_id) {
             Err(AmmError::InvalidSplTokenProgram)
         } else {
-            spl_token::state::Mint::unpack(&account_info.data.borrow())
+            StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&account_info.data.borrow())
+                .map(|mint| mint.base)
                 .map_err(|_| AmmError::ExpectedMint)
         }
     }
 
-    /// The Detailed calculation of pnl
-    /// 1. calc last_k witch dose not take pnl: last_k = calc_pnl_x * calc_pnl_y;
-    /// 2. calc current price: current_price = current_x / current_y;
-    /// 3. calc x after take pnl: x_after_take_pnl = sqrt(last_k * current_price);
-    /// 4. calc y after take pnl: y_after_take_pnl = x_after_take_pnl / current_price;
-    ///                           y_after_take_pnl = x_after_take_pnl * current_y / current_x;
-    /// 5. calc pnl_x & pnl_y:  pnl_x = current_x - x_after_take_pnl;
-    ///                         pnl_y = current_y - y_after_take_pnl;
-    pub fn calc_take_pnl(
-        target: &TargetOrders,
-        amm: &mut AmmInfo,
-        total_pc_without_take_pnl: &mut u64,
-        total_coin_without_take_pnl: &mut u64,
-        x1: U256,
-        y1: U256,
-    ) -> Result<(u128, u128), ProgramError> {
-        // calc pnl
-        let mut delta_x: u128;
-        let mut delta_y: u128;
-        let calc_pc_amount = Calculator::restore_decimal(
-            target.calc_pnl_x.into(),
-            amm.pc_decimals,
-            amm.sys_decimal_value,
-        );
-        let calc_coin_amount = Calculator::restore_decimal(
-            target.calc_pnl_y.into(),
-            amm.coin_decimals,
-            amm.sys_decimal_value,
-        );
-        let pool_pc_amount = U128::from(*total_pc_without_take_pnl);
-        let pool_coin_amount = U128::from(*total_coin_without_take_pnl);
-        if pool_pc_amount.checked_mul(pool_coin_amount).unwrap()
-            >= (calc_pc_amount).checked_mul(calc_coin_amount).unwrap()
-        {
-            // last k is
-            // let last_k: u128 = (target.calc_pnl_x as u128).checked_mul(target.calc_pnl_y as u128).unwrap();
-            // current k is
-            // let current_k: u128 = (x1 as u128).checked_mul(y1 as u128).unwrap();
-            // current p is
-            // let current_p: u128 = (x1 as u128).checked_div(y1 as u128).unwrap();
-            let x2_power = Calculator::calc_x_power(
-                target.calc_pnl_x.into(),
-                target.calc_pnl_y.into(),
-                x1,
-                y1,
-            );
-            // let x2 = Calculator::sqrt(x2_power).unwrap();
-            let x2 = x2_power.integer_sqrt();
-            // msg!(arrform!(LOG_SIZE, "calc_take_pnl x2_power:{}, x2:{}", x2_power, x2).as_str());
-            let y2 = x2.checked_mul(y1).unwrap().checked_div(x1).unwrap();
-            // msg!(arrform!(LOG_SIZE, "calc_take_pnl y2:{}", y2).as_str());
+    /// Calculates the amounts of a swap with a fixed amount_in sent by the user.
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_swap_base_in(
+        fees: &Fees,
+        curve: &dyn CurveCalculator,
+        source_vault_token: &VaultToken,
+        destination_vault_token: &VaultToken,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+        epoch: u64,
+    ) -> Result<SwapAmounts, ProgramError> {
+        // the vault only receives amount_in less the source mint transfer fee
+        let amount_in_after_transfer_fee = source_vault_token.post_fee_amount(epoch, amount_in)?;
+        let (swap_fee, swap_amount_out) = Self::calc_swap_vault_base_in(
+            fees,
+            curve,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            amount_in_after_transfer_fee,
+        )?;
+        // the user only receives swap_amount_out less the destination mint transfer fee
+        let amount_out_after_transfer_fee =
+            destination_vault_token.post_fee_amount(epoch, swap_amount_out)?;
+        Ok(SwapAmounts {
+            amount_in,
+            amount_in_after_transfer_fee,
+            swap_fee,
+            amount_out: swap_amount_out,
+            amount_out_after_transfer_fee,
+        })
+    }
 
-            // transfer to token_coin_pnl and token_pc_pnl
-            // (x1 -x2) * pnl / sys_decimal_value
-            let diff_x = U128::from(x1.checked_sub(x2).unwrap().as_u128());
-            let diff_y = U128::from(y1.checked_sub(y2).unwrap().as_u128());
-            delta_x = diff_x
-                .checked_mul(amm.fees.pnl_numerator.into())
-                .unwrap()
-                .checked_div(amm.fees.pnl_denominator.into())
-                .unwrap()
-                .as_u128();
-            delta_y = diff_y
-                .checked_mul(amm.fees.pnl_numerator.into())
-                .unwrap()
-                .checked_div(amm.fees.pnl_denominator.into())
-                .unwrap()
-                .as_u128();
+    /// Calculates the swap fee and amount_out of an amount_in received by the source vault.
+    pub fn calc_swap_vault_base_in(
+        fees: &Fees,
+        curve: &dyn CurveCalculator,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        let swap_fee = U128::from(amount_in)
+            .checked_mul(fees.swap_fee_numerator.into())
+            .unwrap()
+            .checked_ceil_div(fees.swap_fee_denominator.into())
+            .unwrap();
+        let swap_in_after_deduct_fee = U128::from(amount_in).checked_sub(swap_fee).unwrap();
+        let swap_amount_out = curve
+            .swap_token_amount_base_in(
+                swap_in_after_deduct_fee,
+                total_pc_without_take_pnl.into(),
+                total_coin_without_take_pnl.into(),
+                swap_direction,
+            )?
+            .as_u64();
+        Ok((swap_fee.as_u64(), swap_amount_out))
+    }
 
-            let diff_pc_pnl_amount =
-                Calculator::restore_decimal(diff_x, amm.pc_decimals, amm.sys_decimal_value);
-            let diff_coin_pnl_amount =
-                Calculator::restore_decimal(diff_y, amm.coin_decimals, amm.sys_decimal_value);
-            let pc_pnl_amount = diff_pc_pnl_amount
-                .checked_mul(amm.fees.pnl_numerator.into())
-                .unwrap()
-                .checked_div(amm.fees.pnl_denominator.into())
-                .unwrap()
-                .as_u64();
-            let coin_pnl_amount = diff_coin_pnl_amount
-                .checked_mul(amm.fees.pnl_numerator.into())
-                .unwrap()
-                .checked_div(amm.fees.pnl_denominator.into())
-                .unwrap()
-                .as_u64();
-            if pc_pnl_amount != 0 && coin_pnl_amount != 0 {
-                amm.state_data.need_take_pnl_pc = amm
-                    .state_data
-                    .need_take_pnl_pc
-                    .checked_add(pc_pnl_amount)
-                    .unwrap();
-                amm.state_data.need_take_pnl_coin = amm
-                    .state_data
-                    .need_take_pnl_coin
-                    .checked_add(coin_pnl_amount)
-                    .unwrap();
+    /// Calculates the amounts of a swap with a fixed amount_out received by the user.
+    #[allow(clippy::too_many_arguments)]
+    pub fn calc_swap_base_out(
+        fees: &Fees,
+        curve: &dyn CurveCalculator,
+        source_vault_token: &VaultToken,
+        destination_vault_token: &VaultToken,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_out: u64,
+        epoch: u64,
+    ) -> Result<SwapAmounts, ProgramError> {
+        // the vault has to send amount_out plus the destination mint transfer fee
+        let amount_out_before_transfer_fee =
+            destination_vault_token.pre_fee_amount(epoch, amount_out)?;
+        let (swap_fee, swap_in_after_add_fee) = Self::calc_swap_vault_base_out(
+            fees,
+            curve,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            amount_out_before_transfer_fee,
+        )?;
+        // the user has to send swap_in_after_add_fee plus the source mint transfer fee
+        let amount_in = source_vault_token.pre_fee_amount(epoch, swap_in_after_add_fee)?;
+        Ok(SwapAmounts {
+            amount_in,
+            amount_in_after_transfer_fee: swap_in_after_add_fee,
+            swap_fee,
+            amount_out: amount_out_before_transfer_fee,
+            amount_out_after_transfer_fee: amount_out,
+        })
+    }
 
-                // step3: update total_coin and total_pc without pnl
-                *total_pc_without_take_pnl = (*total_pc_without_take_pnl)
-                    .checked_sub(pc_pnl_amount)
-                    .unwrap();
-                *total_coin_without_take_pnl = (*total_coin_without_take_pnl)
-                    .checked_sub(coin_pnl_amount)
-                    .unwrap();
-            } else {
-                delta_x = 0;
-                delta_y = 0;
-            }
-        } else {
-            msg!(arrform!(
-                LOG_SIZE,
-                "calc_take_pnl error x:{}, y:{}, calc_pnl_x:{}, calc_pnl_y:{}",
-                x1,
-                y1,
-                identity(target.calc_pnl_x),
-                identity(target.calc_pnl_y)
+    /// Calculates the swap fee and amount_in the source vault has to receive for an
+    /// amount_out sent by the destination vault.
+    pub fn calc_swap_vault_base_out(
+        fees: &Fees,
+        curve: &dyn CurveCalculator,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_out: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        let total_out_without_take_pnl = match swap_direction {
+            SwapDirection::Coin2PC => total_pc_without_take_pnl,
+            SwapDirection::PC2Coin => total_coin_without_take_pnl,
+        };
+        if amount_out >= total_out_without_take_pnl {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        let swap_in_before_add_fee = curve.swap_token_amount_base_out(
+            amount_out.into(),
+            total_pc_without_take_pnl.into(),
+            total_coin_without_take_pnl.into(),
+            swap_direction,
+        )?;
+        // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
+        // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
+        let swap_in_after_add_fee = swap_in_before_add_fee
+            .checked_mul(fees.swap_fee_denominator.into())
+            .unwrap()
+            .checked_ceil_div(
+                (fees
+                    .swap_fee_denominator
+                    .checked_sub(fees.swap_fee_numerator)
+                    .unwrap())
+                .into(),
             )
-            .as_str());
-            return Err(AmmError::CalcPnlError.into());
+            .unwrap()
+            .as_u64();
+        let swap_fee = swap_in_after_add_fee
+            .checked_sub(swap_in_before_add_fee.as_u64())
+            .unwrap();
+        Ok((swap_fee, swap_in_after_add_fee))
+    }
+
+    /// Calculates the amount the other side vault receives and the lp minted by a deposit,
+    /// whose base side vault receives `deduct_base_amount`.
+    pub fn calc_deposit_vault_amounts(
+        curve: &dyn CurveCalculator,
+        lp_amount: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        base_side: u64,
+        deduct_base_amount: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        Ok(curve.deposit_amounts(
+            lp_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            base_side,
+            deduct_base_amount,
+        )?)
+    }
+
+    /// Calculates the coin and pc sent by the vaults for `withdraw_lp` burnt.
+    pub fn calc_withdraw_vault_amounts(
+        curve: &dyn CurveCalculator,
+        lp_amount: u64,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        withdraw_lp: u64,
+    ) -> Result<(u64, u64), ProgramError> {
+        Ok(curve.withdraw_amounts(
+            lp_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            withdraw_lp,
+        )?)
+    }
+
+    /// Rejects Token-2022 mints carrying extensions that would let a third party
+    /// freeze, seize or block the vault tokens.
+    pub fn check_mint_extensions(mint_info: &AccountInfo) -> ProgramResult {
+        if *mint_info.owner != spl_token_2022::id() {
+            return Ok(());
+        }
+        let mint_data = mint_info.try_borrow_data()?;
+        let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)
+            .map_err(|_| AmmError::ExpectedMint)?;
+        for extension_type in mint.get_extension_types()? {
+            match extension_type {
+                ExtensionType::TransferFeeConfig
+                | ExtensionType::InterestBearingConfig
+                | ExtensionType::MetadataPointer
+                | ExtensionType::TokenMetadata => {}
+                _ => {
+                    msg!(
+                        arrform!(LOG_SIZE, "unsupported mint extension: {:?}", extension_type)
+                            .as_str()
+                    );
+                    return Err(AmmError::UnsupportedMintExtension.into());
+                }
+            }
         }
+        Ok(())
+    }
 
-        Ok((delta_x, delta_y))
+    /// Size of a vault token account for the mint, including the extensions
+    /// Token-2022 requires every account of that mint to carry.
+    fn token_account_len(mint_info: &AccountInfo) -> Result<usize, ProgramError> {
+        if *mint_info.owner != spl_token_2022::id() {
+            return Ok(spl_token::state::Account::LEN);
+        }
+        let mint_data = mint_info.try_borrow_data()?;
+        let mint = StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&mint_data)?;
+        let account_extensions =
+            ExtensionType::get_required_init_account_extensions(&mint.get_extension_types()?);
+        ExtensionType::try_calculate_account_len::<spl_token_2022::state::Account>(
+            &account_extensions,
+        )
     }
 
-    /// Calculates the authority id by generating a program address.
-    pub fn authority_id(
+    /// Event authority of the program, derived with the bump of the network.
+    pub fn event_authority_id(program_id: &Pubkey) -> Result<Pubkey, AmmError> {
+        Pubkey::create_program_address(&[EVENT_AUTHORITY_SEED, &[EVENT_AUTHORITY_BUMP]], program_id)
+            .map_err(|_| AmmError::InvalidProgramAddress)
+    }
+
+    /// Splits the optional trailing `[event_authority, amm_program]` accounts off the
+    /// account list of an instruction emitting its events by a self invocation.
+    pub fn split_event_accounts<'a, 'b>(
         program_id: &Pubkey,
-        amm_seed: &[u8],
-        nonce: u8,
-    ) -> Result<Pubkey, AmmError> {
-        Pubkey::create_program_address(&[amm_seed, &[nonce]], program_id)
-            .map_err(|_| AmmError::InvalidProgramAddress.into())
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<EventAccounts<'a, 'b>>) {
+        if let [rest @ .., event_authority_info, program_info] = accounts {
+            if program_info.key == program_id
+                && Self::event_authority_id(program_id).as_ref() == Ok(event_authority_info.key)
+            {
+                return (
+                    rest,
+                    Some(EventAccounts {
+                        event_authority_info,
+                        program_info,
+                        event_authority_bump: EVENT_AUTHORITY_BUMP,
+                    }),
+                );
+            }
+        }
+        (accounts, None)
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn generate_amm_associated_spl_token<'a, 'b: 'a>(
+    /// Passes an event to an EmitEvent self invocation when the event accounts are given,
+    /// kept even if the transaction logs are truncated, and logs it as a ray_log otherwise.
+    pub fn emit_event<T: Serialize>(
         program_id: &Pubkey,
-        spl_token_program_id: &Pubkey,
-        market_account: &'a AccountInfo<'b>,
-        associated_token_account: &'a AccountInfo<'b>,
-        token_mint_account: &'a AccountInfo<'b>,
-        user_wallet_account: &'a AccountInfo<'b>,
-        system_program_account: &'a AccountInfo<'b>,
-        rent_sysvar_account: &'a AccountInfo<'b>,
-        spl_token_program_account: &'a AccountInfo<'b>,
-        associated_owner_account: &'a AccountInfo<'b>,
-        associated_seed: &[u8],
+        event_accounts: Option<EventAccounts>,
+        event: T,
     ) -> ProgramResult {
-        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
-            program_id,
-            &market_account.key,
-            associated_seed,
-            program_id,
-        );
-        if associated_token_address != *associated_token_account.key {
-            msg!("Error: Associated token address does not match seed derivation");
-            return Err(AmmError::ExpectedAccount.into());
-        }
-        if associated_token_account.owner == system_program_account.key {
-            let associated_account_signer_seeds: &[&[_]] = &[
-                &program_id.to_bytes(),
-                &market_account.key.to_bytes(),
-                associated_seed,
-                &[bump_seed],
-            ];
-            let rent = &Rent::from_account_info(rent_sysvar_account)?;
-            let required_lamports = rent
-                .minimum_balance(spl_token::state::Account::LEN)
-                .max(1)
-                .saturating_sub(associated_token_account.lamports());
-            if required_lamports > 0 {
-                invoke(
-                    &system_instruction::transfer(
-                        user_wallet_account.key,
-                        associated_token_account.key,
-                        required_lamports,
-                    ),
-                    &[
-                        user_wallet_account.clone(),
-                        associated_token_account.clone(),
-                        system_program_account.clone(),
-                    ],
-                )?;
-            }
-            invoke_signed(
-                &system_instruction::allocate(
-                    associated_token_account.key,
-                    spl_token::state::Account::LEN as u64,
-                ),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
-            )?;
-            invoke_signed(
-                &system_instruction::assign(associated_token_account.key, spl_token_program_id),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
-            )?;
-
-            invoke(
-                &spl_token::instruction::initialize_account(
-                    spl_token_program_id,
-                    associated_token_account.key,
-                    token_mint_account.key,
-                    associated_owner_account.key,
-                )?,
-                &[
-                    associated_token_account.clone(),
-                    token_mint_account.clone(),
-                    associated_owner_account.clone(),
-                    rent_sysvar_account.clone(),
-                    spl_token_program_account.clone(),
-                ],
-            )?;
-        } else {
-            associated_token_address.log();
-            return Err(AmmError::RepeatCreateAmm.into());
+        if let Some(event_accounts) = event_accounts {
+            let data = AmmInstruction::EmitEvent(bincode::serialize(&event).unwrap()).pack()?;
+            invoke_signed(
+                &Instruction {
+                    program_id: *program_id,
+                    accounts: vec![AccountMeta::new_readonly(
+                        *event_accounts.event_authority_info.key,
+                        true,
+                    )],
+                    data,
+                },
+                &[
+                    event_accounts.event_authority_info.clone(),
+                    event_accounts.program_info.clone(),
+                ],
+                &[&[EVENT_AUTHORITY_SEED, &[event_accounts.event_authority_bump]]],
+            )?;
+        } else {
+            encode_ray_log(event);
         }
         Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn generate_amm_associated_spl_mint<'a, 'b: 'a>(
-        program_id: &Pubkey,
-        spl_token_program_id: &Pubkey,
-        market_account: &'a AccountInfo<'b>,
-        associated_token_account: &'a AccountInfo<'b>,
-        user_wallet_account: &'a AccountInfo<'b>,
-        system_program_account: &'a AccountInfo<'b>,
-        rent_sysvar_account: &'a AccountInfo<'b>,
-        spl_token_program_account: &'a AccountInfo<'b>,
-        associated_owner_account: &'a AccountInfo<'b>,
-        associated_seed: &[u8],
-        mint_decimals: u8,
-    ) -> ProgramResult {
-        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
-            program_id,
-            &market_account.key,
-            associated_seed,
-            program_id,
-        );
-        if associated_token_address != *associated_token_account.key {
-            msg!("Error: Associated mint address does not match seed derivation");
-            return Err(AmmError::ExpectedMint.into());
-        }
-        if associated_token_account.owner == system_program_account.key {
-            let associated_account_signer_seeds: &[&[_]] = &[
-                &program_id.to_bytes(),
-                &market_account.key.to_bytes(),
-                associated_seed,
-                &[bump_seed],
-            ];
-            let rent = &Rent::from_account_info(rent_sysvar_account)?;
-            let required_lamports = rent
-                .minimum_balance(spl_token::state::Mint::LEN)
-                .max(1)
-                .saturating_sub(associated_token_account.lamports());
-            if required_lamports > 0 {
-                invoke(
-                    &system_instruction::transfer(
-                        user_wallet_account.key,
-                        associated_token_account.key,
-                        required_lamports,
-                    ),
-                    &[
-                        user_wallet_account.clone(),
-                        associated_token_account.clone(),
-                        system_program_account.clone(),
-                    ],
-                )?;
-            }
-            invoke_signed(
-                &system_instruction::allocate(
-                    associated_token_account.key,
-                    spl_token::state::Mint::LEN as u64,
-                ),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
-            )?;
-            invoke_signed(
-                &system_instruction::assign(associated_token_account.key, spl_token_program_id),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
-            )?;
-
-            invoke(
-                &spl_token::instruction::initialize_mint(
-                    spl_token_program_id,
-                    associated_token_account.key,
-                    associated_owner_account.key,
-                    None,
-                    mint_decimals,
-                )?,
-                &[
-                    associated_token_account.clone(),
-                    associated_owner_account.clone(),
-                    rent_sysvar_account.clone(),
-                    spl_token_program_account.clone(),
-                ],
-            )?;
+    /// Splits the optional trailing `[token_2022_program, coin_mint, pc_mint]`
+    /// accounts off the regular account list of an instruction.
+    pub fn split_token_2022_accounts<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<&'a [AccountInfo<'b>]>) {
+        if accounts.len() >= TOKEN_2022_ACCOUNTS_LEN
+            && *accounts[accounts.len() - TOKEN_2022_ACCOUNTS_LEN].key == spl_token_2022::id()
+        {
+            let (accounts, token_2022_accounts) =
+                accounts.split_at(accounts.len() - TOKEN_2022_ACCOUNTS_LEN);
+            (accounts, Some(token_2022_accounts))
         } else {
-            associated_token_address.log();
-            return Err(AmmError::RepeatCreateAmm.into());
+            (accounts, None)
         }
-        Ok(())
     }
 
-    #[allow(clippy::too_many_arguments)]
-    fn generate_amm_associated_account<'a, 'b: 'a>(
+    /// Splits the accounts of a route swap into its pools and checks each of them like
+    /// `swap_base_in_v2`. The user destination of a pool must be the user source of the next one.
+    pub fn load_swap_route_hops<'a, 'b>(
         program_id: &Pubkey,
-        assign_to: &Pubkey,
-        market_account: &'a AccountInfo<'b>,
-        associated_token_account: &'a AccountInfo<'b>,
-        user_wallet_account: &'a AccountInfo<'b>,
-        system_program_account: &'a AccountInfo<'b>,
-        rent_sysvar_account: &'a AccountInfo<'b>,
-        associated_seed: &[u8],
-        data_size: usize,
-    ) -> ProgramResult {
-        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
-            &program_id,
-            &market_account.key,
-            associated_seed,
-            program_id,
-        );
-        if associated_token_address != *associated_token_account.key {
-            msg!("Error: Associated token address does not match seed derivation");
-            return Err(AmmError::ExpectedAccount.into());
-        }
-        if associated_token_account.owner == system_program_account.key {
-            let associated_account_signer_seeds: &[&[_]] = &[
-                &program_id.to_bytes(),
-                &market_account.key.to_bytes(),
-                associated_seed,
-                &[bump_seed],
-            ];
-            let rent = &Rent::from_account_info(rent_sysvar_account)?;
-            let required_lamports = rent
-                .minimum_balance(data_size)
-                .max(1)
-                .saturating_sub(associated_token_account.lamports());
-            if required_lamports > 0 {
-                invoke(
-                    &system_instruction::transfer(
-                        user_wallet_account.key,
-                        associated_token_account.key,
-                        required_lamports,
-                    ),
-                    &[
-                        user_wallet_account.clone(),
-                        associated_token_account.clone(),
-                        system_program_account.clone(),
-                    ],
-                )?;
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Vec<SwapRouteHop<'a, 'b>>, ProgramError> {
+        let mut hops: Vec<SwapRouteHop> = Vec::with_capacity(MAX_SWAP_ROUTE_HOPS);
+        let mut accounts = accounts;
+        while !accounts.is_empty() {
+            if hops.len() == MAX_SWAP_ROUTE_HOPS || accounts.len() < SWAP_ROUTE_HOP_ACCOUNTS_LEN {
+                return Err(AmmError::WrongAccountsNumber.into());
             }
-            invoke_signed(
-                &system_instruction::allocate(associated_token_account.key, data_size as u64),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
-            )?;
-            invoke_signed(
-                &system_instruction::assign(associated_token_account.key, assign_to),
-                &[
-                    associated_token_account.clone(),
-                    system_program_account.clone(),
-                ],
-                &[&associated_account_signer_seeds],
+            let (hop_accounts, rest) = accounts.split_at(SWAP_ROUTE_HOP_ACCOUNTS_LEN);
+            let (token_2022_accounts, rest) =
+                if rest.len() >= TOKEN_2022_ACCOUNTS_LEN && *rest[0].key == spl_token_2022::id() {
+                    let (token_2022_accounts, rest) = rest.split_at(TOKEN_2022_ACCOUNTS_LEN);
+                    (Some(token_2022_accounts), rest)
+                } else {
+                    (None, rest)
+                };
+            let (dynamic_fee_info, rest) = match rest.split_first() {
+                Some((dynamic_fee_info, rest))
+                    if dynamic_fee_info.owner == program_id
+                        && dynamic_fee_info.data_len() == size_of::<DynamicFeeState>() =>
+                {
+                    (Some(dynamic_fee_info), rest)
+                }
+                _ => (None, rest),
+            };
+            let (observation_info, rest) = match rest.split_first() {
+                Some((observation_info, rest))
+                    if observation_info.owner == program_id
+                        && observation_info.data_len() == size_of::<ObservationState>() =>
+                {
+                    (Some(observation_info), rest)
+                }
+                _ => (None, rest),
+            };
+            if hops
+                .iter()
+                .any(|hop| hop.amm_info.key == hop_accounts[1].key)
+            {
+                msg!("swap_route: pool {} is used twice", hop_accounts[1].key);
+                return Err(AmmError::InvalidInput.into());
+            }
+            let hop = Self::load_swap_route_hop(
+                program_id,
+                hop_accounts,
+                token_2022_accounts,
+                dynamic_fee_info,
+                observation_info,
             )?;
-        } else {
-            associated_token_address.log();
-            return Err(AmmError::RepeatCreateAmm.into());
+            if let Some(prev) = hops.last() {
+                check_assert_eq!(
+                    *hop.user_source_info.key,
+                    *prev.user_destination_info.key,
+                    "route_user_source",
+                    AmmError::InvalidUserToken
+                );
+            }
+            hops.push(hop);
+            accounts = rest;
         }
-        Ok(())
+        if hops.is_empty() {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        Ok(hops)
     }
 
-    /// Processes an [Initialize](enum.Instruction.html).
-    pub fn process_initialize2(
+    fn load_swap_route_hop<'a, 'b>(
         program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        init: InitializeInstruction2,
-    ) -> ProgramResult {
-        let input_account_len = accounts.len();
-        let (
+        accounts: &'a [AccountInfo<'b>],
+        token_2022_accounts: Option<&'a [AccountInfo<'b>]>,
+        dynamic_fee_info: Option<&'a AccountInfo<'b>>,
+        observation_info: Option<&'a AccountInfo<'b>>,
+    ) -> Result<SwapRouteHop<'a, 'b>, ProgramError> {
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let user_source_owner = next_account_info(account_info_iter)?;
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
             token_program_info,
-            ata_token_program_info,
-            system_program_info,
-            rent_sysvar_info,
-            amm_info,
-            amm_authority_info,
-            amm_lp_mint_info,
-            amm_coin_mint_info,
-            amm_pc_mint_info,
             amm_coin_vault_info,
             amm_pc_vault_info,
-            amm_target_orders_info,
-            amm_config_info,
-            create_fee_destination_info,
-            market_info,
-            user_wallet_info,
-            user_token_coin_info,
-            user_token_pc_info,
-            user_token_lp_info,
-        ) = if input_account_len == 19 {
-            // Recommended use due to openbook has not supported.
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
-            let ata_token_program_info = next_account_info(account_info_iter)?;
-            let system_program_info = next_account_info(account_info_iter)?;
-            let rent_sysvar_info = next_account_info(account_info_iter)?;
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_mint_info = next_account_info(account_info_iter)?;
-            let amm_pc_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_config_info = next_account_info(account_info_iter)?;
-            let create_fee_destination_info = next_account_info(account_info_iter)?;
-            // Just a seed for AMM account.
-            // Can be any account.
-            let market_info = next_account_info(account_info_iter)?;
+            token_2022_accounts,
+        )?;
+        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
+        check_assert_eq!(
+            *amm_authority_info.key,
+            authority,
+            "authority",
+            AmmError::InvalidProgramAddress
+        );
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if *user_destination_info.key == amm.pc_vault
+            || *user_destination_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidUserToken.into());
+        }
 
-            let user_wallet_info = next_account_info(account_info_iter)?;
-            let user_token_coin_info = next_account_info(account_info_iter)?;
-            let user_token_pc_info = next_account_info(account_info_iter)?;
-            let user_token_lp_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_source = Self::unpack_token_account(user_source_info, user_source_info.owner)?;
+        let user_destination =
+            Self::unpack_token_account(user_destination_info, user_destination_info.owner)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            msg!(&format!("swap_route: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+            let clock = Clock::get()?;
+            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            } else {
+                amm.status = AmmStatus::SwapOnly.into_u64();
+                msg!("swap_route: WaitingTrade to SwapOnly");
+            }
+        }
+
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
 
+        let (swap_direction, source_vault_info, destination_vault_info) = if user_source.mint
+            == amm_coin_vault.mint
+            && user_destination.mint == amm_pc_vault.mint
+        {
             (
-                token_program_info,
-                ata_token_program_info,
-                system_program_info,
-                rent_sysvar_info,
-                amm_info,
-                amm_authority_info,
-                amm_lp_mint_info,
-                amm_coin_mint_info,
-                amm_pc_mint_info,
+                SwapDirection::Coin2PC,
                 amm_coin_vault_info,
                 amm_pc_vault_info,
-                amm_target_orders_info,
-                amm_config_info,
-                create_fee_destination_info,
-                market_info,
-                user_wallet_info,
-                user_token_coin_info,
-                user_token_pc_info,
-                user_token_lp_info,
             )
-        } else {
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
-            let ata_token_program_info = next_account_info(account_info_iter)?;
-            let system_program_info = next_account_info(account_info_iter)?;
-            let rent_sysvar_info = next_account_info(account_info_iter)?;
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            // Won't use.
-            // Can be any account.
-            let _amm_open_orders_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_mint_info = next_account_info(account_info_iter)?;
-            let amm_pc_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_config_info = next_account_info(account_info_iter)?;
-            let create_fee_destination_info = next_account_info(account_info_iter)?;
-            // Won't use.
-            // Can be any account.
-            let _market_program_info = next_account_info(account_info_iter)?;
-            // Just a seed for AMM account.
-            // Can be any account.
-            let market_info = next_account_info(account_info_iter)?;
-
-            let user_wallet_info = next_account_info(account_info_iter)?;
-            let user_token_coin_info = next_account_info(account_info_iter)?;
-            let user_token_pc_info = next_account_info(account_info_iter)?;
-            let user_token_lp_info = next_account_info(account_info_iter)?;
+        } else if user_source.mint == amm_pc_vault.mint
+            && user_destination.mint == amm_coin_vault.mint
+        {
             (
-                token_program_info,
-                ata_token_program_info,
-                system_program_info,
-                rent_sysvar_info,
-                amm_info,
-                amm_authority_info,
-                amm_lp_mint_info,
-                amm_coin_mint_info,
-                amm_pc_mint_info,
-                amm_coin_vault_info,
+                SwapDirection::PC2Coin,
                 amm_pc_vault_info,
-                amm_target_orders_info,
-                amm_config_info,
-                create_fee_destination_info,
-                market_info,
-                user_wallet_info,
-                user_token_coin_info,
-                user_token_pc_info,
-                user_token_lp_info,
+                amm_coin_vault_info,
             )
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        };
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
         };
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        Ok(SwapRouteHop {
+            amm_info,
+            amm,
+            amm_authority_info,
+            source_vault_info,
+            destination_vault_info,
+            source_vault_token,
+            destination_vault_token,
+            user_source_info,
+            user_destination_info,
+            user_source_owner,
+            observation_info,
+            fees,
+            curve,
+            swap_direction,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        })
+    }
 
-        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
-        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
-            return Err(AmmError::InvalidConfigAccount.into());
+    /// Moves the tokens of each pool of a route swap in order, the output of a pool
+    /// is received by the user account the next pool takes its input from.
+    pub fn execute_swap_route(
+        program_id: &Pubkey,
+        hops: &mut [SwapRouteHop],
+        swap_amounts: &[SwapAmounts],
+        epoch: u64,
+        event_accounts: Option<EventAccounts>,
+    ) -> ProgramResult {
+        let block_timestamp = Clock::get()?.unix_timestamp as u64;
+        for (hop, swap_amounts) in hops.iter_mut().zip(swap_amounts.iter()) {
+            let total_out_without_take_pnl = match hop.swap_direction {
+                SwapDirection::Coin2PC => hop.total_pc_without_take_pnl,
+                SwapDirection::PC2Coin => hop.total_coin_without_take_pnl,
+            };
+            if swap_amounts.amount_out >= total_out_without_take_pnl {
+                return Err(AmmError::InsufficientFunds.into());
+            }
+            if swap_amounts.amount_in == 0 || swap_amounts.amount_out == 0 {
+                return Err(AmmError::InvalidInput.into());
+            }
+            Self::accrue_protocol_fee(&mut hop.amm, hop.swap_direction, swap_amounts.swap_fee, 0);
+            Self::transfer_to_vault(
+                &hop.source_vault_token,
+                hop.user_source_info,
+                hop.source_vault_info,
+                hop.user_source_owner,
+                swap_amounts.amount_in,
+            )?;
+            Self::transfer_from_vault(
+                &hop.destination_vault_token,
+                hop.destination_vault_info,
+                hop.user_destination_info,
+                hop.amm_authority_info,
+                hop.amm.nonce as u8,
+                swap_amounts.amount_out,
+            )?;
+            Self::update_observation(
+                program_id,
+                hop.amm_info.key,
+                &hop.amm,
+                hop.observation_info,
+                hop.total_pc_without_take_pnl,
+                hop.total_coin_without_take_pnl,
+                block_timestamp,
+            )?;
+            let source_amount = Self::unpack_token_account(
+                hop.source_vault_info,
+                hop.source_vault_token.program.key,
+            )?
+            .amount;
+            let destination_amount = Self::unpack_token_account(
+                hop.destination_vault_info,
+                hop.destination_vault_token.program.key,
+            )?
+            .amount;
+            Self::log_swap_event(
+                program_id,
+                event_accounts,
+                hop.amm_info,
+                &hop.amm,
+                hop.user_source_owner,
+                hop.swap_direction,
+                swap_amounts.amount_in,
+                swap_amounts.amount_out_after_transfer_fee,
+                swap_amounts.swap_fee,
+                match hop.swap_direction {
+                    SwapDirection::Coin2PC => (source_amount, destination_amount),
+                    SwapDirection::PC2Coin => (destination_amount, source_amount),
+                },
+            )?;
+            hop.amm.recent_epoch = epoch;
         }
+        Ok(())
+    }
 
-        if *amm_coin_mint_info.key == *amm_pc_mint_info.key {
-            return Err(AmmError::InvalidCoinMint.into());
+    /// Books the pnl of a pool still taking it by calc_pnl_x & calc_pnl_y into the
+    /// protocol fee, then lets its swaps accrue the protocol fee from then on.
+    pub fn migrate_protocol_fee(
+        target: &TargetOrders,
+        amm: &mut AmmInfo,
+        total_pc_without_take_pnl: &mut u64,
+        total_coin_without_take_pnl: &mut u64,
+        x1: U256,
+        y1: U256,
+    ) -> Result<(u128, u128), ProgramError> {
+        if amm.protocol_fee_enabled != 0 {
+            return Ok((0, 0));
         }
+        let (delta_x, delta_y) = Self::calc_take_pnl(
+            target,
+            amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            x1,
+            y1,
+        )?;
+        amm.state_data.protocol_fee_coin = amm
+            .state_data
+            .protocol_fee_coin
+            .checked_add(amm.state_data.need_take_pnl_coin)
+            .unwrap();
+        amm.state_data.protocol_fee_pc = amm
+            .state_data
+            .protocol_fee_pc
+            .checked_add(amm.state_data.need_take_pnl_pc)
+            .unwrap();
+        amm.state_data.need_take_pnl_coin = 0u64;
+        amm.state_data.need_take_pnl_pc = 0u64;
+        amm.protocol_fee_enabled = 1;
+        msg!("protocol fee enabled");
+        Ok((delta_x, delta_y))
+    }
 
-        msg!(arrform!(LOG_SIZE, "initialize2: {:?}", init).as_str());
-        if !user_wallet_info.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+    /// Books the protocol share of a swap fee, in the source mint, into the pool counters.
+    /// The referrer and the protocol are paid out of the swap fee, never out of the pool.
+    pub fn accrue_protocol_fee(
+        amm: &mut AmmInfo,
+        swap_direction: SwapDirection,
+        swap_fee: u64,
+        referrer_fee: u64,
+    ) -> u64 {
+        if amm.protocol_fee_enabled == 0 {
+            return 0;
+        }
+        let protocol_fee = amm
+            .fees
+            .protocol_fee(swap_fee)
+            .min(swap_fee.saturating_sub(referrer_fee));
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                amm.state_data.protocol_fee_coin = amm
+                    .state_data
+                    .protocol_fee_coin
+                    .checked_add(protocol_fee)
+                    .unwrap();
+            }
+            SwapDirection::PC2Coin => {
+                amm.state_data.protocol_fee_pc = amm
+                    .state_data
+                    .protocol_fee_pc
+                    .checked_add(protocol_fee)
+                    .unwrap();
+            }
+        }
+        protocol_fee
+    }
+
+    /// Splits the trailing `[amm_config, referrer token account]` off the account list of a
+    /// swap setting `referrer`.
+    pub fn split_referrer_accounts<'a, 'b>(
+        accounts: &'a [AccountInfo<'b>],
+        referrer: bool,
+    ) -> Result<(&'a [AccountInfo<'b>], Option<&'a [AccountInfo<'b>]>), ProgramError> {
+        if !referrer {
+            return Ok((accounts, None));
+        }
+        if accounts.len() < REFERRER_ACCOUNTS_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
         }
+        let (accounts, referrer_accounts) =
+            accounts.split_at(accounts.len() - REFERRER_ACCOUNTS_LEN);
+        Ok((accounts, Some(referrer_accounts)))
+    }
+
+    /// Checks the referrer accounts of a swap and returns the referrer token account
+    /// with its share of swap_fee, paid in the source mint. A user cannot refer itself.
+    pub fn load_referrer<'a, 'b>(
+        program_id: &Pubkey,
+        referrer_accounts: Option<&'a [AccountInfo<'b>]>,
+        amm: &AmmInfo,
+        user_source: &spl_token_2022::state::Account,
+        user_source_owner: &Pubkey,
+        swap_fee: u64,
+    ) -> Result<Option<(&'a AccountInfo<'b>, u64)>, ProgramError> {
+        let (amm_config_info, referrer_info) = match referrer_accounts {
+            Some([amm_config_info, referrer_info]) => (amm_config_info, referrer_info),
+            _ => return Ok(None),
+        };
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
         check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
+            *amm_config_info.key,
+            pda,
+            "amm_config",
+            AmmError::InvalidConfigAccount
         );
-        let spl_token_program_id = token_program_info.key;
-        check_assert_eq!(
-            *ata_token_program_info.key,
-            spl_associated_token_account::id(),
-            "spl_associated_token_account",
-            AmmError::InvalidSplTokenProgram
+        let amm_config = AmmConfig::load_checked(amm_config_info, program_id)?;
+        if *referrer_info.key == amm.coin_vault || *referrer_info.key == amm.pc_vault {
+            return Err(AmmError::InvalidReferrerAccount.into());
+        }
+        let referrer = Self::unpack_token_account(referrer_info, referrer_info.owner)?;
+        if referrer.mint != user_source.mint
+            || referrer.owner == user_source.owner
+            || referrer.owner == *user_source_owner
+        {
+            return Err(AmmError::InvalidReferrerAccount.into());
+        }
+        Ok(Some((referrer_info, amm_config.referrer_fee(swap_fee))))
+    }
+
+    /// Pays the referrer its share of the swap fee out of the source vault.
+    pub fn pay_referrer<'a>(
+        source_vault_token: &VaultToken<'_, 'a>,
+        source_vault: &AccountInfo<'a>,
+        referrer: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        nonce: u8,
+        referrer_fee: u64,
+    ) -> ProgramResult {
+        if referrer_fee == 0 {
+            return Ok(());
+        }
+        Self::transfer_from_vault(
+            source_vault_token,
+            source_vault,
+            referrer,
+            authority,
+            nonce,
+            referrer_fee,
+        )
+    }
+
+    /// Splits the optional trailing `[limit coin vault, limit pc vault, limit orders]` off the
+    /// account list of a swap, with up to MAX_ORDER_LIMIT limit orders.
+    pub fn split_limit_order_accounts<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<&'a [AccountInfo<'b>]>) {
+        let order_num = accounts
+            .iter()
+            .rev()
+            .take(MAX_ORDER_LIMIT)
+            .take_while(|order_info| {
+                order_info.owner == program_id
+                    && order_info.data_len() == size_of::<LimitOrderState>()
+            })
+            .count();
+        if order_num > 0 && accounts.len() >= order_num + 2 {
+            let (accounts, limit_order_accounts) =
+                accounts.split_at(accounts.len() - order_num - 2);
+            (accounts, Some(limit_order_accounts))
+        } else {
+            (accounts, None)
+        }
+    }
+
+    /// Checks the limit order vaults of a pool.
+    pub fn check_limit_order_vaults(
+        program_id: &Pubkey,
+        amm_key: &Pubkey,
+        limit_coin_vault_info: &AccountInfo,
+        limit_pc_vault_info: &AccountInfo,
+    ) -> ProgramResult {
+        let (limit_coin_vault, _) = get_associated_address_and_bump_seed(
+            program_id,
+            amm_key,
+            LIMIT_ORDER_COIN_VAULT_SEED,
+            program_id,
         );
-        check_assert_eq!(
-            *system_program_info.key,
-            solana_program::system_program::id(),
-            "sys_program",
-            AmmError::InvalidSysProgramAddress
+        let (limit_pc_vault, _) = get_associated_address_and_bump_seed(
+            program_id,
+            amm_key,
+            LIMIT_ORDER_PC_VAULT_SEED,
+            program_id,
         );
-        let (expect_amm_authority, expect_nonce) =
-            Pubkey::find_program_address(&[&AUTHORITY_AMM], program_id);
-        if *amm_authority_info.key != expect_amm_authority || init.nonce != expect_nonce {
-            return Err(AmmError::InvalidProgramAddress.into());
-        }
-        if *create_fee_destination_info.key != config_feature::create_pool_fee_address::id() {
-            return Err(AmmError::InvalidFee.into());
+        if *limit_coin_vault_info.key != limit_coin_vault
+            || *limit_pc_vault_info.key != limit_pc_vault
+        {
+            return Err(AmmError::InvalidLimitOrderAccount.into());
         }
-        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
-        // Charge the fee to create a pool
-        if amm_config.create_pool_fee != 0 {
-            invoke(
-                &system_instruction::transfer(
-                    user_wallet_info.key,
-                    create_fee_destination_info.key,
-                    amm_config.create_pool_fee,
+        Ok(())
+    }
+
+    /// Executes the limit orders appended to a swap once the pool price reached their limit
+    /// price. Each order swaps its whole escrowed amount through the pool with the fees of the
+    /// swap, and stays open if the pool would pay less than the limit price for it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn match_limit_orders<'a>(
+        program_id: &Pubkey,
+        event_accounts: Option<EventAccounts>,
+        amm_info: &AccountInfo<'a>,
+        amm: &mut AmmInfo,
+        fees: &Fees,
+        amm_authority_info: &AccountInfo<'a>,
+        amm_coin_vault_info: &AccountInfo<'a>,
+        amm_pc_vault_info: &AccountInfo<'a>,
+        coin_vault_token: &VaultToken<'_, 'a>,
+        pc_vault_token: &VaultToken<'_, 'a>,
+        limit_order_accounts: Option<&[AccountInfo<'a>]>,
+        epoch: u64,
+    ) -> ProgramResult {
+        let (limit_coin_vault_info, limit_pc_vault_info, limit_order_infos) =
+            match limit_order_accounts {
+                Some([limit_coin_vault_info, limit_pc_vault_info, limit_order_infos @ ..]) => (
+                    limit_coin_vault_info,
+                    limit_pc_vault_info,
+                    limit_order_infos,
                 ),
-                &[
-                    user_wallet_info.clone(),
-                    create_fee_destination_info.clone(),
-                    system_program_info.clone(),
-                ],
+                _ => return Ok(()),
+            };
+        Self::check_limit_order_vaults(
+            program_id,
+            amm_info.key,
+            limit_coin_vault_info,
+            limit_pc_vault_info,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        for limit_order_info in limit_order_infos {
+            let mut limit_order =
+                LimitOrderState::load_mut_checked(limit_order_info, program_id, amm_info.key)?;
+            if limit_order.amount == 0 {
+                continue;
+            }
+            let amm_coin_vault =
+                Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+            let amm_pc_vault =
+                Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl_no_orderbook(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    amm,
+                )?;
+            match Self::pool_prices(
+                amm,
+                &*curve,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+            ) {
+                Some((pc_price, _)) if limit_order.limit_reached(pc_price) => {}
+                _ => continue,
+            }
+            let side = OrderSide::from_u64(limit_order.side)?;
+            let (
+                swap_direction,
+                source_vault_token,
+                destination_vault_token,
+                source_vault_info,
+                destination_vault_info,
+                escrow_vault_info,
+                proceeds_vault_info,
+                total_destination,
+            ) = match side {
+                OrderSide::Buy => (
+                    SwapDirection::PC2Coin,
+                    pc_vault_token,
+                    coin_vault_token,
+                    amm_pc_vault_info,
+                    amm_coin_vault_info,
+                    limit_pc_vault_info,
+                    limit_coin_vault_info,
+                    total_coin_without_take_pnl,
+                ),
+                OrderSide::Sell => (
+                    SwapDirection::Coin2PC,
+                    coin_vault_token,
+                    pc_vault_token,
+                    amm_coin_vault_info,
+                    amm_pc_vault_info,
+                    limit_coin_vault_info,
+                    limit_pc_vault_info,
+                    total_pc_without_take_pnl,
+                ),
+            };
+            let swap_amounts = Self::calc_swap_base_in(
+                fees,
+                &*curve,
+                source_vault_token,
+                destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                limit_order.amount,
+                epoch,
             )?;
-            invoke(
-                &spl_token::instruction::sync_native(
-                    token_program_info.key,
-                    create_fee_destination_info.key,
-                )?,
-                &[
-                    token_program_info.clone(),
-                    create_fee_destination_info.clone(),
-                ],
+            if swap_amounts.amount_out < limit_order.minimum_out(amm)
+                || swap_amounts.amount_out >= total_destination
+            {
+                continue;
+            }
+            let protocol_fee =
+                Self::accrue_protocol_fee(amm, swap_direction, swap_amounts.swap_fee, 0);
+            Self::emit_event(
+                program_id,
+                event_accounts,
+                LimitOrderFillLog {
+                    log_type: LogType::LimitOrderFill.into_u8(),
+                    owner: limit_order.owner,
+                    order_id: limit_order.order_id,
+                    side: limit_order.side,
+                    price: limit_order.price,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    amount_in: swap_amounts.amount_in,
+                    amount_out: swap_amounts.amount_out,
+                    swap_fee: swap_amounts.swap_fee,
+                    protocol_fee,
+                },
+            )?;
+            // the escrow pays the pool and the pool pays the proceeds back to the limit vaults
+            Self::transfer_from_vault(
+                source_vault_token,
+                escrow_vault_info,
+                source_vault_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                swap_amounts.amount_in,
+            )?;
+            Self::transfer_from_vault(
+                destination_vault_token,
+                destination_vault_info,
+                proceeds_vault_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                swap_amounts.amount_out,
+            )?;
+            limit_order.amount = 0;
+            limit_order.proceeds = limit_order
+                .proceeds
+                .checked_add(swap_amounts.amount_out)
+                .ok_or(AmmError::CheckedAddOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Splits the optional trailing observation account off the account list of a swap.
+    pub fn split_observation_account<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<&'a AccountInfo<'b>>) {
+        match accounts.split_last() {
+            Some((observation_info, swap_accounts))
+                if observation_info.owner == program_id
+                    && observation_info.data_len() == size_of::<ObservationState>() =>
+            {
+                (swap_accounts, Some(observation_info))
+            }
+            _ => (accounts, None),
+        }
+    }
+
+    /// Splits the amm config account appended last off the account list of an instruction
+    /// moving funds of the vaults, and checks its global status lets the instruction run.
+    /// The account is required so that the global status cannot be skipped.
+    pub fn split_amm_config_account<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+        withdraw: bool,
+    ) -> Result<&'a [AccountInfo<'b>], ProgramError> {
+        let (amm_config_info, accounts) =
+            accounts.split_last().ok_or(AmmError::WrongAccountsNumber)?;
+        // load_checked verifies it is the amm config PDA
+        AmmConfig::load_checked(amm_config_info, program_id)?.check_global_status(withdraw)?;
+        Ok(accounts)
+    }
+
+    /// Splits the optional trailing dynamic fee account off the account list of a swap.
+    pub fn split_dynamic_fee_account<'a, 'b>(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> (&'a [AccountInfo<'b>], Option<&'a AccountInfo<'b>>) {
+        match accounts.split_last() {
+            Some((dynamic_fee_info, swap_accounts))
+                if dynamic_fee_info.owner == program_id
+                    && dynamic_fee_info.data_len() == size_of::<DynamicFeeState>() =>
+            {
+                (swap_accounts, Some(dynamic_fee_info))
+            }
+            _ => (accounts, None),
+        }
+    }
+
+    /// Returns the fees a swap is charged. In dynamic fee mode the pool price before the
+    /// swap is accumulated into the volatility first, and written back if `update` is set.
+    pub fn load_swap_fees(
+        program_id: &Pubkey,
+        amm_key: &Pubkey,
+        amm: &AmmInfo,
+        dynamic_fee_info: Option<&AccountInfo>,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        update: bool,
+    ) -> Result<Fees, ProgramError> {
+        let mut fees = amm.fees;
+        if amm.dynamic_fee_enabled == 0 {
+            return Ok(fees);
+        }
+        let dynamic_fee_info = dynamic_fee_info.ok_or(AmmError::InvalidDynamicFeeAccount)?;
+        let mut dynamic_fee_state =
+            *DynamicFeeState::load_checked(dynamic_fee_info, program_id, amm_key)?;
+        fees = Self::dynamic_swap_fees(
+            amm,
+            &mut dynamic_fee_state,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        );
+        if update {
+            *DynamicFeeState::load_mut(dynamic_fee_info)? = dynamic_fee_state;
+        }
+        Ok(fees)
+    }
+
+    /// Fees of a pool in dynamic fee mode, once the pool price before the swap is
+    /// accumulated into the volatility of `dynamic_fee_state`.
+    pub fn dynamic_swap_fees(
+        amm: &AmmInfo,
+        dynamic_fee_state: &mut DynamicFeeState,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        block_timestamp: u64,
+    ) -> Fees {
+        let mut fees = amm.fees;
+        if let Some((pc_price, _)) = Self::pool_prices(
+            amm,
+            &*amm.curve(block_timestamp),
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        ) {
+            dynamic_fee_state.update(block_timestamp, pc_price);
+        }
+        fees.swap_fee_numerator = dynamic_fee_state.swap_fee_numerator(&amm.fees);
+        fees
+    }
+
+    /// Pc per coin and coin per pc spot prices of the pool curve, scaled by
+    /// sys_decimal_value. None while one side of the pool is empty.
+    pub fn pool_prices(
+        amm: &AmmInfo,
+        curve: &dyn CurveCalculator,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+    ) -> Option<(u128, u128)> {
+        let (pc, coin) = curve.spot_price(
+            total_pc_without_take_pnl.into(),
+            total_coin_without_take_pnl.into(),
+        )?;
+        // the price of the normalized amounts, pc * 10^coin_decimals / (coin * 10^pc_decimals)
+        let x = pc.checked_mul(U256::from(10u64).pow(amm.coin_decimals.into()))?;
+        let y = coin.checked_mul(U256::from(10u64).pow(amm.pc_decimals.into()))?;
+        if x.is_zero() || y.is_zero() {
+            return None;
+        }
+        let pc_price = x.checked_mul(amm.sys_decimal_value.into())? / y;
+        let coin_price = y.checked_mul(amm.sys_decimal_value.into())? / x;
+        if pc_price > U256::from(u128::MAX) || coin_price > U256::from(u128::MAX) {
+            return None;
+        }
+        Some((pc_price.as_u128(), coin_price.as_u128()))
+    }
+
+    /// Fills the range orders of a swap with a fixed amount_in, walking the curve between
+    /// them. An order is filled at its price once the pool price, moved by the part of
+    /// amount_in swapped on the curve so far, reaches it. Returns the part of amount_in
+    /// paid to the orders, the amount they paid out and the number of fills, the rest of
+    /// amount_in goes to the curve. At most MAX_RANGE_ORDER_FILLS orders are filled, which
+    /// bounds the bisections of the curve in a swap.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fill_range_orders_along_curve(
+        target_orders: &mut TargetOrders,
+        amm: &AmmInfo,
+        fees: &Fees,
+        curve: &dyn CurveCalculator,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+    ) -> Result<(u64, u64, u64), ProgramError> {
+        // pool pc price once curve_in is swapped on the curve
+        let price_after = |curve_in: u64| -> Result<Option<u128>, ProgramError> {
+            let (_, curve_out) = Self::calc_swap_vault_base_in(
+                fees,
+                curve,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                curve_in,
+            )?;
+            let (total_pc, total_coin) = match swap_direction {
+                SwapDirection::Coin2PC => (
+                    total_pc_without_take_pnl.checked_sub(curve_out),
+                    total_coin_without_take_pnl.checked_add(curve_in),
+                ),
+                SwapDirection::PC2Coin => (
+                    total_pc_without_take_pnl.checked_add(curve_in),
+                    total_coin_without_take_pnl.checked_sub(curve_out),
+                ),
+            };
+            Ok(match (total_pc, total_coin) {
+                (Some(total_pc), Some(total_coin)) => {
+                    Self::pool_prices(amm, curve, total_pc, total_coin)
+                        .map(|(pc_price, _)| pc_price)
+                }
+                _ => None,
+            })
+        };
+        let crossed = |pool_pc_price: u128, order_price: u64| match swap_direction {
+            SwapDirection::Coin2PC => order_price as u128 >= pool_pc_price,
+            SwapDirection::PC2Coin => order_price as u128 <= pool_pc_price,
+        };
+        let (mut range_in, mut range_out, mut fill_count) = (0u64, 0u64, 0u64);
+        let mut curve_in = 0u64;
+        while let Some(pool_pc_price) = price_after(curve_in)? {
+            let (fill_in, fill_out, fills) = target_orders.fill_range_orders(
+                amm,
+                swap_direction,
+                amount_in - range_in - curve_in,
+                pool_pc_price,
+                MAX_RANGE_ORDER_FILLS - fill_count,
+            );
+            range_in += fill_in;
+            range_out += fill_out;
+            fill_count += fills;
+            let amount_in_left = amount_in - range_in - curve_in;
+            let next_price = match target_orders.next_range_order_price(swap_direction) {
+                Some(price)
+                    if amount_in_left > 0
+                        && fill_count < MAX_RANGE_ORDER_FILLS
+                        && !crossed(pool_pc_price, price) =>
+                {
+                    price
+                }
+                // nothing or no fill left, or too little left to fill a crossed order
+                _ => break,
+            };
+            let reaches = |curve_in: u64| -> Result<bool, ProgramError> {
+                Ok(price_after(curve_in)?.is_some_and(|price| crossed(price, next_price)))
+            };
+            if !reaches(curve_in + amount_in_left)? {
+                break;
+            }
+            // bisect the smallest curve amount moving the pool price to the next order
+            let (mut low, mut high) = (curve_in, curve_in + amount_in_left);
+            while high - low > 1 {
+                let mid = low + (high - low) / 2;
+                if reaches(mid)? {
+                    high = mid;
+                } else {
+                    low = mid;
+                }
+            }
+            curve_in = high;
+        }
+        Ok((range_in, range_out, fill_count))
+    }
+
+    /// Accumulates the pool prices before a swap into the observation account, which
+    /// every price changing instruction must pass once the pool has one.
+    pub fn update_observation(
+        program_id: &Pubkey,
+        amm_key: &Pubkey,
+        amm: &AmmInfo,
+        observation_info: Option<&AccountInfo>,
+        total_pc_without_take_pnl: u64,
+        total_coin_without_take_pnl: u64,
+        block_timestamp: u64,
+    ) -> ProgramResult {
+        let observation_info = match observation_info {
+            Some(observation_info) => observation_info,
+            None if amm.observation_enabled == 0 => return Ok(()),
+            None => return Err(AmmError::InvalidObservationAccount.into()),
+        };
+        let mut observation_state =
+            ObservationState::load_mut_checked(observation_info, program_id, amm_key)?;
+        let observation_id = Pubkey::create_program_address(
+            &[
+                &program_id.to_bytes(),
+                &amm_key.to_bytes(),
+                OBSERVATION_ASSOCIATED_SEED,
+                &[observation_state.bump as u8],
+            ],
+            program_id,
+        )
+        .map_err(|_| AmmError::InvalidObservationAccount)?;
+        if observation_id != *observation_info.key {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        if let Some((pc_price, coin_price)) = Self::pool_prices(
+            amm,
+            &*amm.curve(block_timestamp),
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        ) {
+            observation_state.update(block_timestamp, pc_price, coin_price);
+        }
+        Ok(())
+    }
+
+    /// Resolves the token program and mint of the coin and pc vaults.
+    /// Vaults owned by Token-2022 need the trailing Token-2022 accounts.
+    pub fn load_vault_tokens<'a, 'b>(
+        amm: &AmmInfo,
+        spl_token_program_info: &'a AccountInfo<'b>,
+        amm_coin_vault_info: &AccountInfo<'b>,
+        amm_pc_vault_info: &AccountInfo<'b>,
+        token_2022_accounts: Option<&'a [AccountInfo<'b>]>,
+    ) -> Result<(VaultToken<'a, 'b>, VaultToken<'a, 'b>), ProgramError> {
+        check_assert_eq!(
+            *spl_token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        let (token_2022_program_info, coin_mint_info, pc_mint_info) = match token_2022_accounts {
+            Some([token_2022_program_info, coin_mint_info, pc_mint_info]) => {
+                check_assert_eq!(
+                    *coin_mint_info.key,
+                    amm.coin_vault_mint,
+                    "coin_mint",
+                    AmmError::InvalidCoinMint
+                );
+                check_assert_eq!(
+                    *pc_mint_info.key,
+                    amm.pc_vault_mint,
+                    "pc_mint",
+                    AmmError::InvalidPCMint
+                );
+                (
+                    Some(token_2022_program_info),
+                    Some(coin_mint_info),
+                    Some(pc_mint_info),
+                )
+            }
+            _ => (None, None, None),
+        };
+        let vault_token = |vault_info: &AccountInfo<'b>,
+                           mint_info: Option<&'a AccountInfo<'b>>,
+                           decimals: u64|
+         -> Result<VaultToken<'a, 'b>, ProgramError> {
+            if *vault_info.owner == spl_token::id() {
+                return Ok(VaultToken {
+                    program: spl_token_program_info,
+                    mint: None,
+                    decimals: decimals as u8,
+                });
+            }
+            match (token_2022_program_info, mint_info) {
+                (Some(program), Some(mint)) if *vault_info.owner == spl_token_2022::id() => {
+                    Ok(VaultToken {
+                        program,
+                        mint: Some(mint),
+                        decimals: decimals as u8,
+                    })
+                }
+                _ => Err(AmmError::InvalidSplTokenProgram.into()),
+            }
+        };
+        Ok((
+            vault_token(amm_coin_vault_info, coin_mint_info, amm.coin_decimals)?,
+            vault_token(amm_pc_vault_info, pc_mint_info, amm.pc_decimals)?,
+        ))
+    }
+
+    /// Transfers user tokens into a pool vault.
+    pub fn transfer_to_vault<'a>(
+        vault_token: &VaultToken<'_, 'a>,
+        source: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        owner: &AccountInfo<'a>,
+        amount: u64,
+    ) -> ProgramResult {
+        match vault_token.mint {
+            Some(mint) => Invokers::token_transfer_checked(
+                vault_token.program.clone(),
+                source.clone(),
+                mint.clone(),
+                vault.clone(),
+                owner.clone(),
+                amount,
+                vault_token.decimals,
+            ),
+            None => Invokers::token_transfer(
+                vault_token.program.clone(),
+                source.clone(),
+                vault.clone(),
+                owner.clone(),
+                amount,
+            ),
+        }
+    }
+
+    /// Transfers pool vault tokens out, signed by the amm authority.
+    pub fn transfer_from_vault<'a>(
+        vault_token: &VaultToken<'_, 'a>,
+        vault: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        nonce: u8,
+        amount: u64,
+    ) -> ProgramResult {
+        match vault_token.mint {
+            Some(mint) => Invokers::token_transfer_checked_with_authority(
+                vault_token.program.clone(),
+                vault.clone(),
+                mint.clone(),
+                destination.clone(),
+                authority.clone(),
+                AUTHORITY_AMM,
+                nonce,
+                amount,
+                vault_token.decimals,
+            ),
+            None => Invokers::token_transfer_with_authority(
+                vault_token.program.clone(),
+                vault.clone(),
+                destination.clone(),
+                authority.clone(),
+                AUTHORITY_AMM,
+                nonce,
+                amount,
+            ),
+        }
+    }
+
+    /// The Detailed calculation of pnl
+    /// 1. calc last_k witch dose not take pnl: last_k = calc_pnl_x * calc_pnl_y;
+    /// 2. calc current price: current_price = current_x / current_y;
+    /// 3. calc x after take pnl: x_after_take_pnl = sqrt(last_k * current_price);
+    /// 4. calc y after take pnl: y_after_take_pnl = x_after_take_pnl / current_price;
+    ///                           y_after_take_pnl = x_after_take_pnl * current_y / current_x;
+    /// 5. calc pnl_x & pnl_y:  pnl_x = current_x - x_after_take_pnl;
+    ///                         pnl_y = current_y - y_after_take_pnl;
+    pub fn calc_take_pnl(
+        target: &TargetOrders,
+        amm: &mut AmmInfo,
+        total_pc_without_take_pnl: &mut u64,
+        total_coin_without_take_pnl: &mut u64,
+        x1: U256,
+        y1: U256,
+    ) -> Result<(u128, u128), ProgramError> {
+        // calc pnl
+        let mut delta_x: u128;
+        let mut delta_y: u128;
+        let calc_pc_amount = Calculator::restore_decimal(
+            target.calc_pnl_x.into(),
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let calc_coin_amount = Calculator::restore_decimal(
+            target.calc_pnl_y.into(),
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        let pool_pc_amount = U128::from(*total_pc_without_take_pnl);
+        let pool_coin_amount = U128::from(*total_coin_without_take_pnl);
+        if pool_pc_amount.checked_mul(pool_coin_amount).unwrap()
+            >= (calc_pc_amount).checked_mul(calc_coin_amount).unwrap()
+        {
+            // last k is
+            // let last_k: u128 = (target.calc_pnl_x as u128).checked_mul(target.calc_pnl_y as u128).unwrap();
+            // current k is
+            // let current_k: u128 = (x1 as u128).checked_mul(y1 as u128).unwrap();
+            // current p is
+            // let current_p: u128 = (x1 as u128).checked_div(y1 as u128).unwrap();
+            let x2_power = Calculator::calc_x_power(
+                target.calc_pnl_x.into(),
+                target.calc_pnl_y.into(),
+                x1,
+                y1,
+            );
+            // let x2 = Calculator::sqrt(x2_power).unwrap();
+            let x2 = x2_power.integer_sqrt();
+            // msg!(arrform!(LOG_SIZE, "calc_take_pnl x2_power:{}, x2:{}", x2_power, x2).as_str());
+            let y2 = x2.checked_mul(y1).unwrap().checked_div(x1).unwrap();
+            // msg!(arrform!(LOG_SIZE, "calc_take_pnl y2:{}", y2).as_str());
+
+            // transfer to token_coin_pnl and token_pc_pnl
+            // (x1 -x2) * pnl / sys_decimal_value
+            let diff_x = U128::from(x1.checked_sub(x2).unwrap().as_u128());
+            let diff_y = U128::from(y1.checked_sub(y2).unwrap().as_u128());
+            delta_x = diff_x
+                .checked_mul(amm.fees.pnl_numerator.into())
+                .unwrap()
+                .checked_div(amm.fees.pnl_denominator.into())
+                .unwrap()
+                .as_u128();
+            delta_y = diff_y
+                .checked_mul(amm.fees.pnl_numerator.into())
+                .unwrap()
+                .checked_div(amm.fees.pnl_denominator.into())
+                .unwrap()
+                .as_u128();
+
+            let diff_pc_pnl_amount =
+                Calculator::restore_decimal(diff_x, amm.pc_decimals, amm.sys_decimal_value);
+            let diff_coin_pnl_amount =
+                Calculator::restore_decimal(diff_y, amm.coin_decimals, amm.sys_decimal_value);
+            let pc_pnl_amount = diff_pc_pnl_amount
+                .checked_mul(amm.fees.pnl_numerator.into())
+                .unwrap()
+                .checked_div(amm.fees.pnl_denominator.into())
+                .unwrap()
+                .as_u64();
+            let coin_pnl_amount = diff_coin_pnl_amount
+                .checked_mul(amm.fees.pnl_numerator.into())
+                .unwrap()
+                .checked_div(amm.fees.pnl_denominator.into())
+                .unwrap()
+                .as_u64();
+            if pc_pnl_amount != 0 && coin_pnl_amount != 0 {
+                amm.state_data.need_take_pnl_pc = amm
+                    .state_data
+                    .need_take_pnl_pc
+                    .checked_add(pc_pnl_amount)
+                    .unwrap();
+                amm.state_data.need_take_pnl_coin = amm
+                    .state_data
+                    .need_take_pnl_coin
+                    .checked_add(coin_pnl_amount)
+                    .unwrap();
+
+                // step3: update total_coin and total_pc without pnl
+                *total_pc_without_take_pnl = (*total_pc_without_take_pnl)
+                    .checked_sub(pc_pnl_amount)
+                    .unwrap();
+                *total_coin_without_take_pnl = (*total_coin_without_take_pnl)
+                    .checked_sub(coin_pnl_amount)
+                    .unwrap();
+            } else {
+                delta_x = 0;
+                delta_y = 0;
+            }
+        } else {
+            msg!(arrform!(
+                LOG_SIZE,
+                "calc_take_pnl error x:{}, y:{}, calc_pnl_x:{}, calc_pnl_y:{}",
+                x1,
+                y1,
+                identity(target.calc_pnl_x),
+                identity(target.calc_pnl_y)
+            )
+            .as_str());
+            return Err(AmmError::CalcPnlError.into());
+        }
+
+        Ok((delta_x, delta_y))
+    }
+
+    /// Calculates the authority id by generating a program address.
+    pub fn authority_id(
+        program_id: &Pubkey,
+        amm_seed: &[u8],
+        nonce: u8,
+    ) -> Result<Pubkey, AmmError> {
+        Pubkey::create_program_address(&[amm_seed, &[nonce]], program_id)
+            .map_err(|_| AmmError::InvalidProgramAddress.into())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_amm_associated_spl_token<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        spl_token_program_id: &Pubkey,
+        market_account: &'a AccountInfo<'b>,
+        associated_token_account: &'a AccountInfo<'b>,
+        token_mint_account: &'a AccountInfo<'b>,
+        user_wallet_account: &'a AccountInfo<'b>,
+        system_program_account: &'a AccountInfo<'b>,
+        rent_sysvar_account: &'a AccountInfo<'b>,
+        spl_token_program_account: &'a AccountInfo<'b>,
+        associated_owner_account: &'a AccountInfo<'b>,
+        associated_seed: &[u8],
+    ) -> ProgramResult {
+        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
+            program_id,
+            &market_account.key,
+            associated_seed,
+            program_id,
+        );
+        if associated_token_address != *associated_token_account.key {
+            msg!("Error: Associated token address does not match seed derivation");
+            return Err(AmmError::ExpectedAccount.into());
+        }
+        if associated_token_account.owner == system_program_account.key {
+            let associated_account_signer_seeds: &[&[_]] = &[
+                &program_id.to_bytes(),
+                &market_account.key.to_bytes(),
+                associated_seed,
+                &[bump_seed],
+            ];
+            let account_len = Self::token_account_len(token_mint_account)?;
+            let rent = &Rent::from_account_info(rent_sysvar_account)?;
+            let required_lamports = rent
+                .minimum_balance(account_len)
+                .max(1)
+                .saturating_sub(associated_token_account.lamports());
+            if required_lamports > 0 {
+                invoke(
+                    &system_instruction::transfer(
+                        user_wallet_account.key,
+                        associated_token_account.key,
+                        required_lamports,
+                    ),
+                    &[
+                        user_wallet_account.clone(),
+                        associated_token_account.clone(),
+                        system_program_account.clone(),
+                    ],
+                )?;
+            }
+            invoke_signed(
+                &system_instruction::allocate(associated_token_account.key, account_len as u64),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(associated_token_account.key, spl_token_program_id),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+
+            invoke(
+                &spl_token_2022::instruction::initialize_account(
+                    spl_token_program_id,
+                    associated_token_account.key,
+                    token_mint_account.key,
+                    associated_owner_account.key,
+                )?,
+                &[
+                    associated_token_account.clone(),
+                    token_mint_account.clone(),
+                    associated_owner_account.clone(),
+                    rent_sysvar_account.clone(),
+                    spl_token_program_account.clone(),
+                ],
+            )?;
+        } else {
+            associated_token_address.log();
+            return Err(AmmError::RepeatCreateAmm.into());
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_amm_associated_spl_mint<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        spl_token_program_id: &Pubkey,
+        market_account: &'a AccountInfo<'b>,
+        associated_token_account: &'a AccountInfo<'b>,
+        user_wallet_account: &'a AccountInfo<'b>,
+        system_program_account: &'a AccountInfo<'b>,
+        rent_sysvar_account: &'a AccountInfo<'b>,
+        spl_token_program_account: &'a AccountInfo<'b>,
+        associated_owner_account: &'a AccountInfo<'b>,
+        associated_seed: &[u8],
+        mint_decimals: u8,
+    ) -> ProgramResult {
+        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
+            program_id,
+            &market_account.key,
+            associated_seed,
+            program_id,
+        );
+        if associated_token_address != *associated_token_account.key {
+            msg!("Error: Associated mint address does not match seed derivation");
+            return Err(AmmError::ExpectedMint.into());
+        }
+        if associated_token_account.owner == system_program_account.key {
+            let associated_account_signer_seeds: &[&[_]] = &[
+                &program_id.to_bytes(),
+                &market_account.key.to_bytes(),
+                associated_seed,
+                &[bump_seed],
+            ];
+            let rent = &Rent::from_account_info(rent_sysvar_account)?;
+            let required_lamports = rent
+                .minimum_balance(spl_token::state::Mint::LEN)
+                .max(1)
+                .saturating_sub(associated_token_account.lamports());
+            if required_lamports > 0 {
+                invoke(
+                    &system_instruction::transfer(
+                        user_wallet_account.key,
+                        associated_token_account.key,
+                        required_lamports,
+                    ),
+                    &[
+                        user_wallet_account.clone(),
+                        associated_token_account.clone(),
+                        system_program_account.clone(),
+                    ],
+                )?;
+            }
+            invoke_signed(
+                &system_instruction::allocate(
+                    associated_token_account.key,
+                    spl_token::state::Mint::LEN as u64,
+                ),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(associated_token_account.key, spl_token_program_id),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+
+            invoke(
+                &spl_token::instruction::initialize_mint(
+                    spl_token_program_id,
+                    associated_token_account.key,
+                    associated_owner_account.key,
+                    None,
+                    mint_decimals,
+                )?,
+                &[
+                    associated_token_account.clone(),
+                    associated_owner_account.clone(),
+                    rent_sysvar_account.clone(),
+                    spl_token_program_account.clone(),
+                ],
+            )?;
+        } else {
+            associated_token_address.log();
+            return Err(AmmError::RepeatCreateAmm.into());
+        }
+        Ok(())
+    }
+
+    /// Admin of the program. Config accounts created before the admin was stored in them
+    /// are administered by config_feature::amm_owner.
+    fn config_admin(amm_config: &AmmConfig) -> Pubkey {
+        amm_config.admin
+    }
+
+    /// Checks the admin of the program signs, the admin is held by the amm config account.
+    /// config_feature::amm_owner only bootstraps the admin by CreateConfigAccount.
+    fn check_admin(
+        program_id: &Pubkey,
+        admin_info: &AccountInfo,
+        amm_config_info: &AccountInfo,
+    ) -> ProgramResult {
+        let admin = Self::config_admin(&*AmmConfig::load_checked(amm_config_info, program_id)?);
+        if !admin_info.is_signer || admin == Pubkey::default() || *admin_info.key != admin {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        Ok(())
+    }
+
+    /// Loads the target orders account of a pool, which holds its queued status and fees.
+    fn load_pending_params<'a>(
+        program_id: &Pubkey,
+        amm_info: &AccountInfo,
+        amm: &AmmInfo,
+        amm_target_orders_info: &'a AccountInfo,
+    ) -> Result<RefMut<'a, TargetOrders>, ProgramError> {
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)
+    }
+
+    /// Unix timestamp from which params queued now can be applied.
+    fn params_effective_time(
+        program_id: &Pubkey,
+        amm_config_info: &AccountInfo,
+    ) -> Result<u64, ProgramError> {
+        let amm_config = AmmConfig::load_checked(amm_config_info, program_id)?;
+        Ok((Clock::get()?.unix_timestamp as u64).saturating_add(amm_config.params_delay))
+    }
+
+    fn log_params(
+        program_id: &Pubkey,
+        event_accounts: Option<EventAccounts>,
+        log_type: LogType,
+        param: AmmParams,
+        status: u64,
+        fees: &Fees,
+        effective_time: u64,
+    ) -> ProgramResult {
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            ParamsLog {
+                log_type: log_type.into_u8(),
+                param: param.into_u64() as u8,
+                status,
+                min_separate_numerator: fees.min_separate_numerator,
+                min_separate_denominator: fees.min_separate_denominator,
+                trade_fee_numerator: fees.trade_fee_numerator,
+                trade_fee_denominator: fees.trade_fee_denominator,
+                pnl_numerator: fees.pnl_numerator,
+                pnl_denominator: fees.pnl_denominator,
+                swap_fee_numerator: fees.swap_fee_numerator,
+                swap_fee_denominator: fees.swap_fee_denominator,
+                effective_time,
+            },
+        )
+    }
+
+    fn fees_log(fees: &Fees) -> FeesLog {
+        FeesLog {
+            min_separate_numerator: fees.min_separate_numerator,
+            min_separate_denominator: fees.min_separate_denominator,
+            trade_fee_numerator: fees.trade_fee_numerator,
+            trade_fee_denominator: fees.trade_fee_denominator,
+            pnl_numerator: fees.pnl_numerator,
+            pnl_denominator: fees.pnl_denominator,
+            swap_fee_numerator: fees.swap_fee_numerator,
+            swap_fee_denominator: fees.swap_fee_denominator,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn log_set_params(
+        program_id: &Pubkey,
+        event_accounts: Option<EventAccounts>,
+        amm_info: &AccountInfo,
+        param: AmmParams,
+        old_value: u64,
+        new_value: u64,
+        old_fees: &Fees,
+        new_fees: &Fees,
+    ) -> ProgramResult {
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            SetParamsLog {
+                log_type: LogType::SetParams.into_u8(),
+                param: param.into_u64() as u8,
+                amm: *amm_info.key,
+                old_value,
+                new_value,
+                old_fees: Self::fees_log(old_fees),
+                new_fees: Self::fees_log(new_fees),
+            },
+        )
+    }
+
+    /// Coin and pc vault balances, read once the tokens of an instruction moved.
+    fn vault_amounts(
+        amm_coin_vault_info: &AccountInfo,
+        coin_vault_token: &VaultToken,
+        amm_pc_vault_info: &AccountInfo,
+        pc_vault_token: &VaultToken,
+    ) -> Result<(u64, u64), ProgramError> {
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        Ok((amm_coin_vault.amount, amm_pc_vault.amount))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn log_swap_event(
+        program_id: &Pubkey,
+        event_accounts: Option<EventAccounts>,
+        amm_info: &AccountInfo,
+        amm: &AmmInfo,
+        signer_info: &AccountInfo,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+        amount_out: u64,
+        swap_fee: u64,
+        (vault_coin, vault_pc): (u64, u64),
+    ) -> ProgramResult {
+        let (mint_in, mint_out) = match swap_direction {
+            SwapDirection::Coin2PC => (amm.coin_vault_mint, amm.pc_vault_mint),
+            SwapDirection::PC2Coin => (amm.pc_vault_mint, amm.coin_vault_mint),
+        };
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            SwapEventV2 {
+                log_type: LogType::SwapV2.into_u8(),
+                version: EVENT_VERSION,
+                amm: *amm_info.key,
+                signer: *signer_info.key,
+                mint_in,
+                mint_out,
+                amount_in,
+                amount_out,
+                swap_fee,
+                vault_coin,
+                vault_pc,
+            },
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn log_liquidity_event(
+        program_id: &Pubkey,
+        event_accounts: Option<EventAccounts>,
+        log_type: LogType,
+        amm_info: &AccountInfo,
+        amm: &AmmInfo,
+        signer_info: &AccountInfo,
+        coin_amount: u64,
+        pc_amount: u64,
+        lp_amount: u64,
+        fee: u64,
+        (vault_coin, vault_pc): (u64, u64),
+    ) -> ProgramResult {
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            LiquidityEventV2 {
+                log_type: log_type.into_u8(),
+                version: EVENT_VERSION,
+                amm: *amm_info.key,
+                signer: *signer_info.key,
+                coin_mint: amm.coin_vault_mint,
+                pc_mint: amm.pc_vault_mint,
+                coin_amount,
+                pc_amount,
+                lp_amount,
+                fee,
+                vault_coin,
+                vault_pc,
+            },
+        )
+    }
+
+    /// Closes a program account, its lamports go to destination.
+    fn close_amm_associated_account(
+        account: &AccountInfo,
+        destination: &AccountInfo,
+    ) -> ProgramResult {
+        let destination_lamports = destination.lamports();
+        **destination.try_borrow_mut_lamports()? = destination_lamports
+            .checked_add(account.lamports())
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        **account.try_borrow_mut_lamports()? = 0;
+        account.try_borrow_mut_data()?.fill(0);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_amm_associated_account<'a, 'b: 'a>(
+        program_id: &Pubkey,
+        assign_to: &Pubkey,
+        market_account: &'a AccountInfo<'b>,
+        associated_token_account: &'a AccountInfo<'b>,
+        user_wallet_account: &'a AccountInfo<'b>,
+        system_program_account: &'a AccountInfo<'b>,
+        rent_sysvar_account: &'a AccountInfo<'b>,
+        associated_seed: &[u8],
+        data_size: usize,
+    ) -> ProgramResult {
+        let (associated_token_address, bump_seed) = get_associated_address_and_bump_seed(
+            &program_id,
+            &market_account.key,
+            associated_seed,
+            program_id,
+        );
+        if associated_token_address != *associated_token_account.key {
+            msg!("Error: Associated token address does not match seed derivation");
+            return Err(AmmError::ExpectedAccount.into());
+        }
+        if associated_token_account.owner == system_program_account.key {
+            let associated_account_signer_seeds: &[&[_]] = &[
+                &program_id.to_bytes(),
+                &market_account.key.to_bytes(),
+                associated_seed,
+                &[bump_seed],
+            ];
+            let rent = &Rent::from_account_info(rent_sysvar_account)?;
+            let required_lamports = rent
+                .minimum_balance(data_size)
+                .max(1)
+                .saturating_sub(associated_token_account.lamports());
+            if required_lamports > 0 {
+                invoke(
+                    &system_instruction::transfer(
+                        user_wallet_account.key,
+                        associated_token_account.key,
+                        required_lamports,
+                    ),
+                    &[
+                        user_wallet_account.clone(),
+                        associated_token_account.clone(),
+                        system_program_account.clone(),
+                    ],
+                )?;
+            }
+            invoke_signed(
+                &system_instruction::allocate(associated_token_account.key, data_size as u64),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+            invoke_signed(
+                &system_instruction::assign(associated_token_account.key, assign_to),
+                &[
+                    associated_token_account.clone(),
+                    system_program_account.clone(),
+                ],
+                &[&associated_account_signer_seeds],
+            )?;
+        } else {
+            associated_token_address.log();
+            return Err(AmmError::RepeatCreateAmm.into());
+        }
+        Ok(())
+    }
+
+    /// Processes an [Initialize](enum.Instruction.html).
+    pub fn process_initialize2(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        init: InitializeInstruction2,
+    ) -> ProgramResult {
+        // Token-2022 program is appended when either mint is a Token-2022 mint.
+        let (accounts, token_2022_program_info) = match accounts.split_last() {
+            Some((last_info, init_accounts)) if *last_info.key == spl_token_2022::id() => {
+                (init_accounts, Some(last_info))
+            }
+            _ => (accounts, None),
+        };
+        let input_account_len = accounts.len();
+        let (
+            token_program_info,
+            ata_token_program_info,
+            system_program_info,
+            rent_sysvar_info,
+            amm_info,
+            amm_authority_info,
+            amm_lp_mint_info,
+            amm_coin_mint_info,
+            amm_pc_mint_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            amm_target_orders_info,
+            amm_config_info,
+            create_fee_destination_info,
+            market_info,
+            user_wallet_info,
+            user_token_coin_info,
+            user_token_pc_info,
+            user_token_lp_info,
+        ) = if input_account_len == 19 {
+            // Recommended use due to openbook has not supported.
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+            let ata_token_program_info = next_account_info(account_info_iter)?;
+            let system_program_info = next_account_info(account_info_iter)?;
+            let rent_sysvar_info = next_account_info(account_info_iter)?;
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_mint_info = next_account_info(account_info_iter)?;
+            let amm_pc_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_config_info = next_account_info(account_info_iter)?;
+            let create_fee_destination_info = next_account_info(account_info_iter)?;
+            // Just a seed for AMM account.
+            // Can be any account.
+            let market_info = next_account_info(account_info_iter)?;
+
+            let user_wallet_info = next_account_info(account_info_iter)?;
+            let user_token_coin_info = next_account_info(account_info_iter)?;
+            let user_token_pc_info = next_account_info(account_info_iter)?;
+            let user_token_lp_info = next_account_info(account_info_iter)?;
+
+            (
+                token_program_info,
+                ata_token_program_info,
+                system_program_info,
+                rent_sysvar_info,
+                amm_info,
+                amm_authority_info,
+                amm_lp_mint_info,
+                amm_coin_mint_info,
+                amm_pc_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                amm_target_orders_info,
+                amm_config_info,
+                create_fee_destination_info,
+                market_info,
+                user_wallet_info,
+                user_token_coin_info,
+                user_token_pc_info,
+                user_token_lp_info,
+            )
+        } else {
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+            let ata_token_program_info = next_account_info(account_info_iter)?;
+            let system_program_info = next_account_info(account_info_iter)?;
+            let rent_sysvar_info = next_account_info(account_info_iter)?;
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            // Won't use.
+            // Can be any account.
+            let _amm_open_orders_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_mint_info = next_account_info(account_info_iter)?;
+            let amm_pc_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_config_info = next_account_info(account_info_iter)?;
+            let create_fee_destination_info = next_account_info(account_info_iter)?;
+            // Won't use.
+            // Can be any account.
+            let _market_program_info = next_account_info(account_info_iter)?;
+            // Just a seed for AMM account.
+            // Can be any account.
+            let market_info = next_account_info(account_info_iter)?;
+
+            let user_wallet_info = next_account_info(account_info_iter)?;
+            let user_token_coin_info = next_account_info(account_info_iter)?;
+            let user_token_pc_info = next_account_info(account_info_iter)?;
+            let user_token_lp_info = next_account_info(account_info_iter)?;
+            (
+                token_program_info,
+                ata_token_program_info,
+                system_program_info,
+                rent_sysvar_info,
+                amm_info,
+                amm_authority_info,
+                amm_lp_mint_info,
+                amm_coin_mint_info,
+                amm_pc_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                amm_target_orders_info,
+                amm_config_info,
+                create_fee_destination_info,
+                market_info,
+                user_wallet_info,
+                user_token_coin_info,
+                user_token_pc_info,
+                user_token_lp_info,
+            )
+        };
+
+        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+
+        if *amm_coin_mint_info.key == *amm_pc_mint_info.key {
+            return Err(AmmError::InvalidCoinMint.into());
+        }
+
+        msg!(arrform!(LOG_SIZE, "initialize2: {:?}", init).as_str());
+        if !user_wallet_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        let spl_token_program_id = token_program_info.key;
+        check_assert_eq!(
+            *ata_token_program_info.key,
+            spl_associated_token_account::id(),
+            "spl_associated_token_account",
+            AmmError::InvalidSplTokenProgram
+        );
+        check_assert_eq!(
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
+        );
+        let (expect_amm_authority, expect_nonce) =
+            Pubkey::find_program_address(&[&AUTHORITY_AMM], program_id);
+        if *amm_authority_info.key != expect_amm_authority || init.nonce != expect_nonce {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        if *create_fee_destination_info.key != config_feature::create_pool_fee_address::id() {
+            return Err(AmmError::InvalidFee.into());
+        }
+        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+        amm_config.check_global_status(false)?;
+        // Charge the fee to create a pool
+        if amm_config.create_pool_fee != 0 {
+            invoke(
+                &system_instruction::transfer(
+                    user_wallet_info.key,
+                    create_fee_destination_info.key,
+                    amm_config.create_pool_fee,
+                ),
+                &[
+                    user_wallet_info.clone(),
+                    create_fee_destination_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+            invoke(
+                &spl_token::instruction::sync_native(
+                    token_program_info.key,
+                    create_fee_destination_info.key,
+                )?,
+                &[
+                    token_program_info.clone(),
+                    create_fee_destination_info.clone(),
+                ],
+            )?;
+        }
+
+        // resolve the token program of each mint
+        let mint_token_program = |mint_info: &AccountInfo| {
+            if *mint_info.owner == spl_token::id() {
+                Ok(token_program_info)
+            } else {
+                match token_2022_program_info {
+                    Some(token_2022_program_info) if *mint_info.owner == spl_token_2022::id() => {
+                        Ok(token_2022_program_info)
+                    }
+                    _ => Err(AmmError::InvalidSplTokenProgram),
+                }
+            }
+        };
+        let coin_token_program_info = mint_token_program(amm_coin_mint_info)?;
+        let pc_token_program_info = mint_token_program(amm_pc_mint_info)?;
+
+        // unpack and check coin_mint
+        let coin_mint = Self::unpack_mint(&amm_coin_mint_info, coin_token_program_info.key)?;
+        Self::check_mint_extensions(amm_coin_mint_info)?;
+        // unpack and check pc_mint
+        let pc_mint = Self::unpack_mint(&amm_pc_mint_info, pc_token_program_info.key)?;
+        Self::check_mint_extensions(amm_pc_mint_info)?;
+
+        // create target_order account
+        Self::generate_amm_associated_account(
+            program_id,
+            program_id,
+            market_info,
+            amm_target_orders_info,
+            user_wallet_info,
+            system_program_info,
+            rent_sysvar_info,
+            TARGET_ASSOCIATED_SEED,
+            size_of::<TargetOrders>(),
+        )?;
+
+        // create lp mint account
+        let lp_decimals = coin_mint.decimals;
+        Self::generate_amm_associated_spl_mint(
+            program_id,
+            spl_token_program_id,
+            market_info,
+            amm_lp_mint_info,
+            user_wallet_info,
+            system_program_info,
+            rent_sysvar_info,
+            token_program_info,
+            amm_authority_info,
+            LP_MINT_ASSOCIATED_SEED,
+            lp_decimals,
+        )?;
+        // create coin vault account
+        Self::generate_amm_associated_spl_token(
+            program_id,
+            coin_token_program_info.key,
+            market_info,
+            amm_coin_vault_info,
+            amm_coin_mint_info,
+            user_wallet_info,
+            system_program_info,
+            rent_sysvar_info,
+            coin_token_program_info,
+            amm_authority_info,
+            COIN_VAULT_ASSOCIATED_SEED,
+        )?;
+        // create pc vault account
+        Self::generate_amm_associated_spl_token(
+            program_id,
+            pc_token_program_info.key,
+            market_info,
+            amm_pc_vault_info,
+            amm_pc_mint_info,
+            user_wallet_info,
+            system_program_info,
+            rent_sysvar_info,
+            pc_token_program_info,
+            amm_authority_info,
+            PC_VAULT_ASSOCIATED_SEED,
+        )?;
+        // create amm account
+        Self::generate_amm_associated_account(
+            program_id,
+            program_id,
+            market_info,
+            amm_info,
+            user_wallet_info,
+            system_program_info,
+            rent_sysvar_info,
+            AMM_ASSOCIATED_SEED,
+            size_of::<AmmInfo>(),
+        )?;
+
+        // create user ata lp token
+        Invokers::create_ata_spl_token(
+            user_token_lp_info.clone(),
+            user_wallet_info.clone(),
+            user_wallet_info.clone(),
+            amm_lp_mint_info.clone(),
+            token_program_info.clone(),
+            ata_token_program_info.clone(),
+            system_program_info.clone(),
+        )?;
+
+        // transfer user tokens to vault
+        let coin_vault_token = VaultToken::new(
+            coin_token_program_info,
+            amm_coin_mint_info,
+            coin_mint.decimals,
+        );
+        let pc_vault_token =
+            VaultToken::new(pc_token_program_info, amm_pc_mint_info, pc_mint.decimals);
+        Self::transfer_to_vault(
+            &coin_vault_token,
+            user_token_coin_info,
+            amm_coin_vault_info,
+            user_wallet_info,
+            init.init_coin_amount,
+        )?;
+        Self::transfer_to_vault(
+            &pc_vault_token,
+            user_token_pc_info,
+            amm_pc_vault_info,
+            user_wallet_info,
+            init.init_pc_amount,
+        )?;
+
+        // load AmmInfo
+        let mut amm = AmmInfo::load_mut(&amm_info)?;
+        if amm.status != AmmStatus::Uninitialized.into_u64() {
+            return Err(AmmError::AlreadyInUse.into());
+        }
+
+        // unpack and check token_coin
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_token_program_info.key)?;
+        check_assert_eq!(
+            amm_coin_vault.owner,
+            *amm_authority_info.key,
+            "coin_vault_owner",
+            AmmError::InvalidOwner
+        );
+        if amm_coin_vault.amount == 0 {
+            return Err(AmmError::InvalidSupply.into());
+        }
+        if amm_coin_vault.delegate.is_some() {
+            return Err(AmmError::InvalidDelegate.into());
+        }
+        if amm_coin_vault.close_authority.is_some() {
+            return Err(AmmError::InvalidCloseAuthority.into());
+        }
+        check_assert_eq!(
+            *amm_coin_mint_info.key,
+            amm_coin_vault.mint,
+            "coin_mint",
+            AmmError::InvalidCoinMint
+        );
+        // unpack and check token_pc
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_token_program_info.key)?;
+        check_assert_eq!(
+            amm_pc_vault.owner,
+            *amm_authority_info.key,
+            "pc_vault_owner",
+            AmmError::InvalidOwner
+        );
+        if amm_pc_vault.amount == 0 {
+            return Err(AmmError::InvalidSupply.into());
+        }
+        if amm_pc_vault.delegate.is_some() {
+            return Err(AmmError::InvalidDelegate.into());
+        }
+        if amm_pc_vault.close_authority.is_some() {
+            return Err(AmmError::InvalidCloseAuthority.into());
+        }
+        check_assert_eq!(
+            *amm_pc_mint_info.key,
+            amm_pc_vault.mint,
+            "pc_mint",
+            AmmError::InvalidPCMint
+        );
+
+        let lp_mint = Self::unpack_mint(&amm_lp_mint_info, spl_token_program_id)?;
+        if lp_mint.supply != 0 {
+            return Err(AmmError::InvalidSupply.into());
+        }
+        if COption::Some(*amm_authority_info.key) != lp_mint.mint_authority {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        if lp_mint.freeze_authority.is_some() {
+            return Err(AmmError::InvalidFreezeAuthority.into());
+        }
+
+        let liquidity = match init.weights {
+            Some((coin_weight, pc_weight)) => {
+                if coin_weight < MIN_WEIGHT
+                    || pc_weight < MIN_WEIGHT
+                    || coin_weight.checked_add(pc_weight) != Some(WEIGHT_DENOMINATOR)
+                {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                Calculator::weighted_liquidity(
+                    amm_coin_vault.amount,
+                    amm_pc_vault.amount,
+                    coin_weight,
+                    pc_weight,
+                )
+                .ok_or(AmmError::CalculationExRateFailure)?
+            }
+            None => Calculator::to_u64(
+                U128::from(amm_pc_vault.amount)
+                    .checked_mul(amm_coin_vault.amount.into())
+                    .unwrap()
+                    .integer_sqrt()
+                    .as_u128(),
+            )?,
+        };
+        let user_lp_amount = liquidity
+            .checked_sub((10u64).checked_pow(lp_mint.decimals.into()).unwrap())
+            .ok_or(AmmError::InitLpAmountTooLess)?;
+
+        // liquidity is measured in terms of token_a's value since both sides of
+        // the pool are equal
+        Invokers::token_mint_to(
+            token_program_info.clone(),
+            amm_lp_mint_info.clone(),
+            user_token_lp_info.clone(),
+            amm_authority_info.clone(),
+            AUTHORITY_AMM,
+            init.nonce,
+            user_lp_amount,
+        )?;
+
+        amm.initialize(
+            init.nonce,
+            init.open_time,
+            coin_mint.decimals,
+            pc_mint.decimals,
+            0,
+            0,
+        )?;
+        encode_ray_log(InitLog {
+            log_type: LogType::Init.into_u8(),
+            time: init.open_time,
+            pc_decimals: amm.pc_decimals as u8,
+            coin_decimals: amm.coin_decimals as u8,
+            pc_lot_size: 0,
+            coin_lot_size: 0,
+            pc_amount: amm_pc_vault.amount,
+            coin_amount: amm_coin_vault.amount,
+            market: *market_info.key,
+        });
+        let x = Calculator::normalize_decimal_v2(
+            amm_pc_vault.amount,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y = Calculator::normalize_decimal_v2(
+            amm_coin_vault.amount,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        // check and init target orders account
+        if amm_target_orders_info.owner != program_id {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let mut target_order = TargetOrders::load_mut(amm_target_orders_info)?;
+        target_order.check_init(x.as_u128(), y.as_u128(), amm_info.key)?;
+
+        amm.coin_vault = *amm_coin_vault_info.key;
+        amm.pc_vault = *amm_pc_vault_info.key;
+        amm.coin_vault_mint = *amm_coin_mint_info.key;
+        amm.pc_vault_mint = *amm_pc_mint_info.key;
+        amm.lp_mint = *amm_lp_mint_info.key;
+        amm.open_orders = Pubkey::default();
+        amm.market = *market_info.key;
+        amm.market_program = Pubkey::default();
+        amm.target_orders = *amm_target_orders_info.key;
+        amm.amm_owner = Self::config_admin(&amm_config);
+        amm.lp_amount = liquidity;
+        if let Some((coin_weight, _pc_weight)) = init.weights {
+            amm.curve_type = CurveType::Weighted.into_u64();
+            amm.coin_weight = coin_weight;
+        }
+        amm.status = if init.open_time > (Clock::get()?.unix_timestamp as u64) {
+            AmmStatus::WaitingTrade.into_u64()
+        } else {
+            AmmStatus::SwapOnly.into_u64()
+        };
+        amm.reset_flag = AmmResetFlag::ResetYes.into_u64();
+
+        Ok(())
+    }
+
+    /// Processes an [Deposit](enum.Instruction.html).
+    pub fn process_deposit(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit: DepositInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let input_account_len = accounts.len();
+        let (
+            token_program_info,
+            amm_info,
+            amm_authority_info,
+            amm_target_orders_info,
+            amm_lp_mint_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            user_source_coin_info,
+            user_source_pc_info,
+            user_dest_lp_info,
+            source_owner_info,
+        ) = if input_account_len == 11 {
+            // Recommended use due to openbook has not supported.
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+            let user_source_coin_info = next_account_info(account_info_iter)?;
+            let user_source_pc_info = next_account_info(account_info_iter)?;
+            let user_dest_lp_info = next_account_info(account_info_iter)?;
+            let source_owner_info = next_account_info(account_info_iter)?;
+
+            (
+                token_program_info,
+                amm_info,
+                amm_authority_info,
+                amm_target_orders_info,
+                amm_lp_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                user_source_coin_info,
+                user_source_pc_info,
+                user_dest_lp_info,
+                source_owner_info,
+            )
+        } else {
+            const ACCOUNT_LEN: usize = 14;
+            if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            let _amm_open_orders_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+            let _market_info = next_account_info(account_info_iter)?;
+
+            let user_source_coin_info = next_account_info(account_info_iter)?;
+            let user_source_pc_info = next_account_info(account_info_iter)?;
+            let user_dest_lp_info = next_account_info(account_info_iter)?;
+            let source_owner_info = next_account_info(account_info_iter)?;
+            let _market_event_queue_info = next_account_info(account_info_iter)?;
+
+            (
+                token_program_info,
+                amm_info,
+                amm_authority_info,
+                amm_target_orders_info,
+                amm_lp_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                user_source_coin_info,
+                user_source_pc_info,
+                user_dest_lp_info,
+                source_owner_info,
+            )
+        };
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        if deposit.max_coin_amount == 0 || deposit.max_pc_amount == 0 {
+            encode_ray_log(DepositLog {
+                log_type: LogType::Deposit.into_u8(),
+                max_coin: deposit.max_coin_amount,
+                max_pc: deposit.max_pc_amount,
+                base: deposit.base_side,
+                pool_coin: 0,
+                pool_pc: 0,
+                pool_lp: 0,
+                calc_pnl_x: 0,
+                calc_pnl_y: 0,
+                deduct_coin: 0,
+                deduct_pc: 0,
+                mint_lp: 0,
+            });
+            return Err(AmmError::InvalidInput.into());
+        }
+        if !source_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+
+        if !AmmStatus::from_u64(amm.status).deposit_permission() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        // token_coin must be amm.coin_vault or token_source_coin must not be amm.coin_vault
+        if *amm_coin_vault_info.key != amm.coin_vault
+            || *user_source_coin_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidCoinVault.into());
+        }
+        // token_pc must be amm.pc_vault or token_source_pc must not be amm.pc_vault
+        if *amm_pc_vault_info.key != amm.pc_vault || *user_source_pc_info.key == amm.pc_vault {
+            return Err(AmmError::InvalidPCVault.into());
+        }
+        check_assert_eq!(
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
+        );
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_source_coin =
+            Self::unpack_token_account(&user_source_coin_info, user_source_coin_info.owner)?;
+        let user_source_pc =
+            Self::unpack_token_account(&user_source_pc_info, user_source_pc_info.owner)?;
+        let mut target_orders =
+            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+        // calc the remaining total_pc & total_coin
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        // calc and update pnl
+        let (delta_x, delta_y) = Self::migrate_protocol_fee(
+            &target_orders,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )?;
+        // let lp_mint  = Self::unpack_mint(&lp_mint_info, spl_token_program_id)?;
+        if amm.lp_amount == 0 {
+            encode_ray_log(DepositLog {
+                log_type: LogType::Deposit.into_u8(),
+                max_coin: deposit.max_coin_amount,
+                max_pc: deposit.max_pc_amount,
+                base: deposit.base_side,
+                pool_coin: total_coin_without_take_pnl,
+                pool_pc: total_pc_without_take_pnl,
+                pool_lp: amm.lp_amount,
+                calc_pnl_x: target_orders.calc_pnl_x,
+                calc_pnl_y: target_orders.calc_pnl_y,
+                deduct_coin: 0,
+                deduct_pc: 0,
+                mint_lp: 0,
+            });
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let clock = Clock::get()?;
+        let epoch = clock.epoch;
+        let curve = amm.curve(clock.unix_timestamp as u64);
+        // deduct amounts are received by the vaults, transfer amounts are sent by the user
+        let deduct_pc_amount;
+        let deduct_coin_amount;
+        let transfer_pc_amount;
+        let transfer_coin_amount;
+        let mint_lp_amount;
+        if deposit.base_side == 0 {
+            // base coin
+            transfer_coin_amount = deposit.max_coin_amount;
+            deduct_coin_amount = coin_vault_token.post_fee_amount(epoch, transfer_coin_amount)?;
+            (deduct_pc_amount, mint_lp_amount) = Self::calc_deposit_vault_amounts(
+                &*curve,
+                amm.lp_amount,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                deposit.base_side,
+                deduct_coin_amount,
+            )?;
+            transfer_pc_amount = pc_vault_token.pre_fee_amount(epoch, deduct_pc_amount)?;
+            if transfer_pc_amount > deposit.max_pc_amount {
+                encode_ray_log(DepositLog {
+                    log_type: LogType::Deposit.into_u8(),
+                    max_coin: deposit.max_coin_amount,
+                    max_pc: deposit.max_pc_amount,
+                    base: deposit.base_side,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    pool_lp: amm.lp_amount,
+                    calc_pnl_x: target_orders.calc_pnl_x,
+                    calc_pnl_y: target_orders.calc_pnl_y,
+                    deduct_coin: deduct_coin_amount,
+                    deduct_pc: deduct_pc_amount,
+                    mint_lp: 0,
+                });
+                return Err(AmmError::ExceededSlippage.into());
+            }
+            // base coin, check other_amount_min if need
+            if deposit.other_amount_min.is_some() {
+                if deduct_pc_amount < deposit.other_amount_min.unwrap() {
+                    encode_ray_log(DepositLog {
+                        log_type: LogType::Deposit.into_u8(),
+                        max_coin: deposit.max_coin_amount,
+                        max_pc: deposit.max_pc_amount,
+                        base: deposit.base_side,
+                        pool_coin: total_coin_without_take_pnl,
+                        pool_pc: total_pc_without_take_pnl,
+                        pool_lp: amm.lp_amount,
+                        calc_pnl_x: target_orders.calc_pnl_x,
+                        calc_pnl_y: target_orders.calc_pnl_y,
+                        deduct_coin: deduct_coin_amount,
+                        deduct_pc: deduct_pc_amount,
+                        mint_lp: 0,
+                    });
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+            }
+        } else {
+            // base pc
+            transfer_pc_amount = deposit.max_pc_amount;
+            deduct_pc_amount = pc_vault_token.post_fee_amount(epoch, transfer_pc_amount)?;
+            (deduct_coin_amount, mint_lp_amount) = Self::calc_deposit_vault_amounts(
+                &*curve,
+                amm.lp_amount,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                deposit.base_side,
+                deduct_pc_amount,
+            )?;
+            transfer_coin_amount = coin_vault_token.pre_fee_amount(epoch, deduct_coin_amount)?;
+            if transfer_coin_amount > deposit.max_coin_amount {
+                encode_ray_log(DepositLog {
+                    log_type: LogType::Deposit.into_u8(),
+                    max_coin: deposit.max_coin_amount,
+                    max_pc: deposit.max_pc_amount,
+                    base: deposit.base_side,
+                    pool_coin: total_coin_without_take_pnl,
+                    pool_pc: total_pc_without_take_pnl,
+                    pool_lp: amm.lp_amount,
+                    calc_pnl_x: target_orders.calc_pnl_x,
+                    calc_pnl_y: target_orders.calc_pnl_y,
+                    deduct_coin: deduct_coin_amount,
+                    deduct_pc: deduct_pc_amount,
+                    mint_lp: 0,
+                });
+                return Err(AmmError::ExceededSlippage.into());
+            }
+            // base pc, check other_amount_min if need
+            if deposit.other_amount_min.is_some() {
+                if deduct_coin_amount < deposit.other_amount_min.unwrap() {
+                    encode_ray_log(DepositLog {
+                        log_type: LogType::Deposit.into_u8(),
+                        max_coin: deposit.max_coin_amount,
+                        max_pc: deposit.max_pc_amount,
+                        base: deposit.base_side,
+                        pool_coin: total_coin_without_take_pnl,
+                        pool_pc: total_pc_without_take_pnl,
+                        pool_lp: amm.lp_amount,
+                        calc_pnl_x: target_orders.calc_pnl_x,
+                        calc_pnl_y: target_orders.calc_pnl_y,
+                        deduct_coin: deduct_coin_amount,
+                        deduct_pc: deduct_pc_amount,
+                        mint_lp: 0,
+                    });
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+            }
+        }
+        encode_ray_log(DepositLog {
+            log_type: LogType::Deposit.into_u8(),
+            max_coin: deposit.max_coin_amount,
+            max_pc: deposit.max_pc_amount,
+            base: deposit.base_side,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            pool_lp: amm.lp_amount,
+            calc_pnl_x: target_orders.calc_pnl_x,
+            calc_pnl_y: target_orders.calc_pnl_y,
+            deduct_coin: deduct_coin_amount,
+            deduct_pc: deduct_pc_amount,
+            mint_lp: mint_lp_amount,
+        });
+
+        if transfer_coin_amount > user_source_coin.amount
+            || transfer_pc_amount > user_source_pc.amount
+        {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if mint_lp_amount == 0 || deduct_coin_amount == 0 || deduct_pc_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        Self::transfer_to_vault(
+            &coin_vault_token,
+            user_source_coin_info,
+            amm_coin_vault_info,
+            source_owner_info,
+            transfer_coin_amount,
+        )?;
+        Self::transfer_to_vault(
+            &pc_vault_token,
+            user_source_pc_info,
+            amm_pc_vault_info,
+            source_owner_info,
+            transfer_pc_amount,
+        )?;
+        Invokers::token_mint_to(
+            token_program_info.clone(),
+            amm_lp_mint_info.clone(),
+            user_dest_lp_info.clone(),
+            amm_authority_info.clone(),
+            AUTHORITY_AMM,
+            amm.nonce as u8,
+            mint_lp_amount,
+        )?;
+        amm.lp_amount = amm.lp_amount.checked_add(mint_lp_amount).unwrap();
+
+        target_orders.calc_pnl_x = x1
+            .checked_add(Calculator::normalize_decimal_v2(
+                deduct_pc_amount,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_x))
+            .unwrap()
+            .as_u128();
+        target_orders.calc_pnl_y = y1
+            .checked_add(Calculator::normalize_decimal_v2(
+                deduct_coin_amount,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_y))
+            .unwrap()
+            .as_u128();
+        Self::log_liquidity_event(
+            program_id,
+            event_accounts,
+            LogType::DepositV2,
+            amm_info,
+            &amm,
+            source_owner_info,
+            transfer_coin_amount,
+            transfer_pc_amount,
+            mint_lp_amount,
+            0,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        amm.recent_epoch = epoch;
+        Ok(())
+    }
+
+    /// Processes `process_deposit_single_side` instruction.
+    pub fn process_deposit_single_side(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        deposit: DepositSingleSideInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        const ACCOUNT_LEN: usize = 10;
+        if accounts.len() != ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_lp_mint_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_dest_lp_info = next_account_info(account_info_iter)?;
+        let source_owner_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        if deposit.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        if !source_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        // part of the deposit is swapped, the pool has to allow both
+        let clock = Clock::get()?;
+        let status = AmmStatus::from_u64(amm.status);
+        if !status.deposit_permission()
+            || !status.swap_permission()
+            || (amm.status == AmmStatus::WaitingTrade.into_u64()
+                && (clock.unix_timestamp as u64) < amm.state_data.pool_open_time)
+        {
+            msg!(&format!(
+                "deposit_single_side: status {}",
+                identity(amm.status)
+            ));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        check_assert_eq!(
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
+        );
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_source = Self::unpack_token_account(user_source_info, user_source_info.owner)?;
+        let swap_direction = if user_source.mint == amm_coin_vault.mint {
+            SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint {
+            SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        };
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+        // calc the remaining total_pc & total_coin
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        // calc and update pnl
+        let (delta_x, delta_y) = Self::migrate_protocol_fee(
+            &target_orders,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )?;
+        if amm.lp_amount == 0 {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        let (source_vault_token, source_vault_info, total_in, total_out) = match swap_direction {
+            SwapDirection::Coin2PC => (
+                &coin_vault_token,
+                amm_coin_vault_info,
+                total_coin_without_take_pnl,
+                total_pc_without_take_pnl,
+            ),
+            SwapDirection::PC2Coin => (
+                &pc_vault_token,
+                amm_pc_vault_info,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+            ),
+        };
+        // the vault only receives amount_in less the source mint transfer fee
+        let amount_in_after_transfer_fee =
+            source_vault_token.post_fee_amount(clock.epoch, deposit.amount_in)?;
+        let swap_in = curve.single_side_swap_amount(
+            amount_in_after_transfer_fee,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            fees.swap_fee_numerator,
+            fees.swap_fee_denominator,
+        )?;
+        let (swap_fee, swap_out) = Self::calc_swap_vault_base_in(
+            &fees,
+            &*curve,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            swap_in,
+        )?;
+        if swap_out >= total_out {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        let protocol_fee = Self::accrue_protocol_fee(&mut amm, swap_direction, swap_fee, 0);
+        // mint lp against the rest of amount_in and the swap output, in the pool after the swap
+        let invariant_in = InvariantPool {
+            token_input: amount_in_after_transfer_fee.checked_sub(swap_in).unwrap(),
+            token_total: total_in
+                .checked_add(swap_in)
+                .ok_or(AmmError::CheckedAddOverflow)?
+                .checked_sub(protocol_fee)
+                .unwrap(),
+        };
+        let invariant_out = InvariantPool {
+            token_input: swap_out,
+            token_total: total_out.checked_sub(swap_out).unwrap(),
+        };
+        let mint_lp_amount = invariant_in
+            .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?
+            .min(
+                invariant_out
+                    .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
+                    .ok_or(AmmError::CalculationExRateFailure)?,
+            );
+        encode_ray_log(DepositSingleSideLog {
+            log_type: LogType::DepositSingleSide.into_u8(),
+            amount_in: deposit.amount_in,
+            minimum_lp_out: deposit.minimum_lp_out,
+            direction: swap_direction as u64,
+            user_source: user_source.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            pool_lp: amm.lp_amount,
+            swap_in,
+            swap_out,
+            swap_fee,
+            amount_in_after_transfer_fee,
+            mint_lp: mint_lp_amount,
+        });
+        if user_source.amount < deposit.amount_in {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if mint_lp_amount < deposit.minimum_lp_out {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if mint_lp_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        Self::transfer_to_vault(
+            source_vault_token,
+            user_source_info,
+            source_vault_info,
+            source_owner_info,
+            deposit.amount_in,
+        )?;
+        Invokers::token_mint_to(
+            token_program_info.clone(),
+            amm_lp_mint_info.clone(),
+            user_dest_lp_info.clone(),
+            amm_authority_info.clone(),
+            AUTHORITY_AMM,
+            amm.nonce as u8,
+            mint_lp_amount,
+        )?;
+        amm.lp_amount = amm.lp_amount.checked_add(mint_lp_amount).unwrap();
+
+        // the whole amount stays in the source vault, less the protocol fee of the swap
+        let deduct_in_amount = amount_in_after_transfer_fee
+            .checked_sub(protocol_fee)
+            .unwrap();
+        let (deduct_pc_amount, deduct_coin_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (0, deduct_in_amount),
+            SwapDirection::PC2Coin => (deduct_in_amount, 0),
+        };
+        target_orders.calc_pnl_x = x1
+            .checked_add(Calculator::normalize_decimal_v2(
+                deduct_pc_amount,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_x))
+            .unwrap()
+            .as_u128();
+        target_orders.calc_pnl_y = y1
+            .checked_add(Calculator::normalize_decimal_v2(
+                deduct_coin_amount,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_y))
+            .unwrap()
+            .as_u128();
+        Self::update_observation(
+            program_id,
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        )?;
+        let (coin_amount, pc_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (deposit.amount_in, 0),
+            SwapDirection::PC2Coin => (0, deposit.amount_in),
+        };
+        Self::log_liquidity_event(
+            program_id,
+            event_accounts,
+            LogType::DepositV2,
+            amm_info,
+            &amm,
+            source_owner_info,
+            coin_amount,
+            pc_amount,
+            mint_lp_amount,
+            swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        amm.recent_epoch = clock.epoch;
+        Ok(())
+    }
+
+    pub fn process_withdrawpnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let user_pnl_coin_info = next_account_info(account_info_iter)?;
+        let user_pnl_pc_info = next_account_info(account_info_iter)?;
+        let pnl_owner_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        if amm_info.owner != program_id {
+            return Err(AmmError::InvalidOwner.into());
+        }
+
+        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+        amm_config.check_global_status(true)?;
+
+        if !pnl_owner_info.is_signer
+            || (*pnl_owner_info.key != Self::config_admin(&amm_config)
+                && *pnl_owner_info.key != amm_config.pnl_owner)
+        {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        // withdrawpnl in all status except Uninitialized
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            msg!(&format!("withdrawpnl: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_pnl_coin =
+            Self::unpack_token_account(&user_pnl_coin_info, user_pnl_coin_info.owner)?;
+        let user_pnl_pc = Self::unpack_token_account(&user_pnl_pc_info, user_pnl_pc_info.owner)?;
+        let mut target_orders =
+            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+        if amm_coin_vault.mint != amm.coin_vault_mint || user_pnl_coin.mint != amm.coin_vault_mint {
+            return Err(AmmError::InvalidCoinMint.into());
+        }
+        if amm_pc_vault.mint != amm.pc_vault_mint || user_pnl_pc.mint != amm.pc_vault_mint {
+            return Err(AmmError::InvalidPCMint.into());
+        }
+
+        // calc the remaining total_pc & total_coin
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        msg!(arrform!(
+            LOG_SIZE,
+            "withdrawpnl need_take_coin:{}, need_take_pc:{}, protocol_fee_coin:{}, protocol_fee_pc:{}",
+            identity(amm.state_data.need_take_pnl_coin),
+            identity(amm.state_data.need_take_pnl_pc),
+            identity(amm.state_data.protocol_fee_coin),
+            identity(amm.state_data.protocol_fee_pc)
+        )
+        .as_str());
+
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        msg!(arrform!(
+            LOG_SIZE,
+            "withdrawpnl total_pc:{}, total_coin:{}, x:{}, y:{}",
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            x1,
+            y1
+        )
+        .as_str());
+
+        // calc and update pnl of the pools not accruing the protocol fee yet
+        let (delta_x, delta_y) = Self::migrate_protocol_fee(
+            &target_orders,
+            &mut amm,
+            &mut total_pc_without_take_pnl,
+            &mut total_coin_without_take_pnl,
+            x1.as_u128().into(),
+            y1.as_u128().into(),
+        )?;
+        msg!(arrform!(LOG_SIZE, "withdrawpnl total_pc:{}, total_pc:{}, delta_x:{}, delta_y:{}, protocol_fee_coin:{}, protocol_fee_pc:{}",total_pc_without_take_pnl, total_coin_without_take_pnl, delta_x, delta_y, identity(amm.state_data.protocol_fee_coin), identity(amm.state_data.protocol_fee_pc)).as_str());
+
+        if amm.state_data.protocol_fee_coin <= amm_coin_vault.amount
+            && amm.state_data.protocol_fee_pc <= amm_pc_vault.amount
+        {
+            // coin & pc is enough, transfer directly
+            Self::transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                user_pnl_coin_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                amm.state_data.protocol_fee_coin,
+            )?;
+            Self::transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                user_pnl_pc_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                amm.state_data.protocol_fee_pc,
+            )?;
+            let epoch = Clock::get()?.epoch;
+            encode_ray_log(WithdrawPnlLog {
+                log_type: LogType::WithdrawPnl.into_u8(),
+                amm: *amm_info.key,
+                pnl_owner: *pnl_owner_info.key,
+                coin_amount: coin_vault_token
+                    .post_fee_amount(epoch, amm.state_data.protocol_fee_coin)?,
+                pc_amount: pc_vault_token.post_fee_amount(epoch, amm.state_data.protocol_fee_pc)?,
+            });
+            // clear the accrued protocol fee
+            amm.state_data.protocol_fee_coin = 0u64;
+            amm.state_data.protocol_fee_pc = 0u64;
+            // update target_orders.calc_pnl_x & target_orders.calc_pnl_y
+            target_orders.calc_pnl_x = x1.checked_sub(U128::from(delta_x)).unwrap().as_u128();
+            target_orders.calc_pnl_y = y1.checked_sub(U128::from(delta_y)).unwrap().as_u128();
+        } else {
+            // calc error
+            return Err(AmmError::TakePnlError.into());
+        }
+        amm.recent_epoch = Clock::get()?.epoch;
+
+        Ok(())
+    }
+
+    /// Processes `process_settle_pnl` instruction.
+    pub fn process_settle_pnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, amm_coin_vault_info.owner)?;
+        let amm_pc_vault = Self::unpack_token_account(amm_pc_vault_info, amm_pc_vault_info.owner)?;
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+
+        // calc the remaining total_pc & total_coin
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+        Self::settle_pnl(
+            &mut target_orders,
+            &mut amm,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+        )?;
+        amm.recent_epoch = Clock::get()?.epoch;
+        encode_ray_log(SettlePnlLog {
+            log_type: LogType::SettlePnl.into_u8(),
+            amm: *amm_info.key,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            need_take_pnl_coin: amm.state_data.need_take_pnl_coin,
+            need_take_pnl_pc: amm.state_data.need_take_pnl_pc,
+            calc_pnl_x: target_orders.calc_pnl_x,
+            calc_pnl_y: target_orders.calc_pnl_y,
+        });
+
+        Ok(())
+    }
+
+    /// Books the pnl of a pool without protocol fee into need_take_pnl_coin &
+    /// need_take_pnl_pc, and moves calc_pnl_x & calc_pnl_y to the reserves left. A pool
+    /// with protocol fee books its fees on each swap and only moves calc_pnl.
+    pub fn settle_pnl(
+        target_orders: &mut TargetOrders,
+        amm: &mut AmmInfo,
+        mut total_pc_without_take_pnl: u64,
+        mut total_coin_without_take_pnl: u64,
+    ) -> ProgramResult {
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+        // calc and update pnl
+        let (delta_x, delta_y) = if amm.protocol_fee_enabled == 0 {
+            Self::calc_take_pnl(
+                target_orders,
+                amm,
+                &mut total_pc_without_take_pnl,
+                &mut total_coin_without_take_pnl,
+                x1.as_u128().into(),
+                y1.as_u128().into(),
+            )?
+        } else {
+            (0, 0)
+        };
+        // update target_orders.calc_pnl_x & target_orders.calc_pnl_y
+        target_orders.calc_pnl_x = x1.checked_sub(U128::from(delta_x)).unwrap().as_u128();
+        target_orders.calc_pnl_y = y1.checked_sub(U128::from(delta_y)).unwrap().as_u128();
+        Ok(())
+    }
+
+    /// Processes an [Withdraw](enum.Instruction.html).
+    pub fn process_withdraw(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw: WithdrawInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, true)?;
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let input_account_len = accounts.len();
+        let (
+            token_program_info,
+            amm_info,
+            amm_authority_info,
+            amm_target_orders_info,
+            amm_lp_mint_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            user_source_lp_info,
+            user_dest_coin_info,
+            user_dest_pc_info,
+            source_lp_owner_info,
+        ) = if input_account_len == 11 {
+            // Recommended use due to openbook has not supported.
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+            let user_source_lp_info = next_account_info(account_info_iter)?;
+            let user_dest_coin_info = next_account_info(account_info_iter)?;
+            let user_dest_pc_info = next_account_info(account_info_iter)?;
+            let source_lp_owner_info = next_account_info(account_info_iter)?;
+
+            (
+                token_program_info,
+                amm_info,
+                amm_authority_info,
+                amm_target_orders_info,
+                amm_lp_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                user_source_lp_info,
+                user_dest_coin_info,
+                user_dest_pc_info,
+                source_lp_owner_info,
+            )
+        } else {
+            const ACCOUNT_LEN: usize = 20;
+            if input_account_len != ACCOUNT_LEN
+                && input_account_len != ACCOUNT_LEN + 1
+                && input_account_len != ACCOUNT_LEN + 2
+                && input_account_len != ACCOUNT_LEN + 3
+            {
+                return Err(AmmError::WrongAccountsNumber.into());
+            }
+            let account_info_iter = &mut accounts.iter();
+            let token_program_info = next_account_info(account_info_iter)?;
+
+            let amm_info = next_account_info(account_info_iter)?;
+            let amm_authority_info = next_account_info(account_info_iter)?;
+            let _amm_open_orders_info = next_account_info(account_info_iter)?;
+            let amm_target_orders_info = next_account_info(account_info_iter)?;
+            let amm_lp_mint_info = next_account_info(account_info_iter)?;
+            let amm_coin_vault_info = next_account_info(account_info_iter)?;
+            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+            if input_account_len == ACCOUNT_LEN + 2 || input_account_len == ACCOUNT_LEN + 3 {
+                let _padding_account_info1 = next_account_info(account_info_iter)?;
+                let _padding_account_info2 = next_account_info(account_info_iter)?;
+            }
+
+            let _market_program_info = next_account_info(account_info_iter)?;
+            let _market_info = next_account_info(account_info_iter)?;
+            let _market_coin_vault_info = next_account_info(account_info_iter)?;
+            let _market_pc_vault_info = next_account_info(account_info_iter)?;
+            let _market_vault_signer = next_account_info(account_info_iter)?;
+
+            let user_source_lp_info = next_account_info(account_info_iter)?;
+            let user_dest_coin_info = next_account_info(account_info_iter)?;
+            let user_dest_pc_info = next_account_info(account_info_iter)?;
+            let source_lp_owner_info = next_account_info(account_info_iter)?;
+
+            let _market_event_q_info = next_account_info(account_info_iter)?;
+            let _market_bids_info = next_account_info(account_info_iter)?;
+            let _market_asks_info = next_account_info(account_info_iter)?;
+
+            (
+                token_program_info,
+                amm_info,
+                amm_authority_info,
+                amm_target_orders_info,
+                amm_lp_mint_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                user_source_lp_info,
+                user_dest_coin_info,
+                user_dest_pc_info,
+                source_lp_owner_info,
+            )
+        };
+
+        if !source_lp_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        let mut target_orders =
+            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+
+        if !AmmStatus::from_u64(amm.status).withdraw_permission() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        let spl_token_program_id = token_program_info.key;
+        // token_coin must be amm.coin_vault or token_dest_coin must not be amm.coin_vault
+        if *amm_coin_vault_info.key != amm.coin_vault || *user_dest_coin_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidCoinVault.into());
+        }
+        // token_pc must be amm.pc_vault or token_dest_pc must not be amm.pc_vault
+        if *amm_pc_vault_info.key != amm.pc_vault || *user_dest_pc_info.key == amm.pc_vault {
+            return Err(AmmError::InvalidPCVault.into());
+        }
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        check_assert_eq!(
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
+        );
+
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_dest_coin =
+            Self::unpack_token_account(&user_dest_coin_info, user_dest_coin_info.owner)?;
+        let user_dest_pc = Self::unpack_token_account(&user_dest_pc_info, user_dest_pc_info.owner)?;
+
+        if user_dest_coin.mint != amm_coin_vault.mint || amm.coin_vault_mint != user_dest_coin.mint
+        {
+            return Err(AmmError::InvalidCoinMint.into());
+        }
+        if user_dest_pc.mint != amm_pc_vault.mint || amm.pc_vault_mint != user_dest_pc.mint {
+            return Err(AmmError::InvalidPCMint.into());
+        }
+
+        let lp_mint = Self::unpack_mint(&amm_lp_mint_info, spl_token_program_id)?;
+        let user_source_lp =
+            Self::unpack_token_account(&user_source_lp_info, spl_token_program_id)?;
+        if user_source_lp.owner != *source_lp_owner_info.key {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        if user_source_lp.mint != *amm_lp_mint_info.key {
+            return Err(AmmError::InvalidTokenLP.into());
+        }
+        if withdraw.amount > user_source_lp.amount {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if withdraw.amount > lp_mint.supply || withdraw.amount >= amm.lp_amount {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+
+        // calc and update pnl
+        let mut delta_x: u128 = 0;
+        let mut delta_y: u128 = 0;
+        if amm.status != AmmStatus::WithdrawOnly.into_u64() {
+            (delta_x, delta_y) = Self::migrate_protocol_fee(
+                &target_orders,
+                &mut amm,
+                &mut total_pc_without_take_pnl,
+                &mut total_coin_without_take_pnl,
+                x1.as_u128().into(),
+                y1.as_u128().into(),
+            )?;
+        }
+
+        let (coin_amount, pc_amount) = Self::calc_withdraw_vault_amounts(
+            &*amm.curve(Clock::get()?.unix_timestamp as u64),
+            amm.lp_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            withdraw.amount,
+        )?;
+
+        encode_ray_log(WithdrawLog {
+            log_type: LogType::Withdraw.into_u8(),
+            withdraw_lp: withdraw.amount,
+            user_lp: user_source_lp.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            pool_lp: amm.lp_amount,
+            calc_pnl_x: target_orders.calc_pnl_x,
+            calc_pnl_y: target_orders.calc_pnl_y,
+            out_coin: coin_amount,
+            out_pc: pc_amount,
+        });
+        if withdraw.amount == 0 || coin_amount == 0 || pc_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        // the user receives the vault amounts less the transfer fees
+        let epoch = Clock::get()?.epoch;
+        let coin_amount_after_transfer_fee =
+            coin_vault_token.post_fee_amount(epoch, coin_amount)?;
+        let pc_amount_after_transfer_fee = pc_vault_token.post_fee_amount(epoch, pc_amount)?;
+
+        if coin_amount < amm_coin_vault.amount && pc_amount < amm_pc_vault.amount {
+            if withdraw.min_coin_amount.is_some() && withdraw.min_pc_amount.is_some() {
+                if withdraw.min_coin_amount.unwrap() > coin_amount_after_transfer_fee
+                    || withdraw.min_pc_amount.unwrap() > pc_amount_after_transfer_fee
+                {
+                    return Err(AmmError::ExceededSlippage.into());
+                }
+            }
+            Self::transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                user_dest_coin_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                coin_amount,
+            )?;
+            Self::transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                user_dest_pc_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                pc_amount,
+            )?;
+            Invokers::token_burn(
+                token_program_info.clone(),
+                user_source_lp_info.clone(),
+                amm_lp_mint_info.clone(),
+                source_lp_owner_info.clone(),
+                withdraw.amount,
+            )?;
+            amm.lp_amount = amm.lp_amount.checked_sub(withdraw.amount).unwrap();
+        } else {
+            // calc error
+            return Err(AmmError::TakePnlError.into());
+        }
+
+        // step4: update target_orders.calc_pnl_x & target_orders.calc_pnl_y
+        target_orders.calc_pnl_x = x1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                pc_amount,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_x))
+            .unwrap()
+            .as_u128();
+        target_orders.calc_pnl_y = y1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                coin_amount,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_y))
+            .unwrap()
+            .as_u128();
+        Self::log_liquidity_event(
+            program_id,
+            event_accounts,
+            LogType::WithdrawV2,
+            amm_info,
+            &amm,
+            source_lp_owner_info,
+            coin_amount_after_transfer_fee,
+            pc_amount_after_transfer_fee,
+            withdraw.amount,
+            0,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        amm.recent_epoch = epoch;
+        Ok(())
+    }
+
+    /// Processes `process_withdraw_single_side` instruction.
+    pub fn process_withdraw_single_side(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        withdraw: WithdrawSingleSideInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, true)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        const ACCOUNT_LEN: usize = 10;
+        if accounts.len() != ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_lp_mint_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let user_source_lp_info = next_account_info(account_info_iter)?;
+        let user_dest_info = next_account_info(account_info_iter)?;
+        let source_lp_owner_info = next_account_info(account_info_iter)?;
+
+        if !source_lp_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+
+        // the other share is swapped, the pool has to allow both and be open
+        let clock = Clock::get()?;
+        let status = AmmStatus::from_u64(amm.status);
+        if !status.withdraw_permission()
+            || !status.swap_permission()
+            || (amm.status == AmmStatus::WaitingTrade.into_u64()
+                && (clock.unix_timestamp as u64) < amm.state_data.pool_open_time)
+        {
+            msg!(&format!(
+                "withdraw_single_side: status {}",
+                identity(amm.status)
+            ));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        let spl_token_program_id = token_program_info.key;
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        if *user_dest_info.key == amm.pc_vault || *user_dest_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        check_assert_eq!(
+            *amm_lp_mint_info.key,
+            amm.lp_mint,
+            "lp_mint",
+            AmmError::InvalidPoolMint
+        );
+
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        let user_dest = Self::unpack_token_account(user_dest_info, user_dest_info.owner)?;
+        // the direction of the implied swap of the unwanted share
+        let swap_direction = if user_dest.mint == amm_pc_vault.mint {
+            SwapDirection::Coin2PC
+        } else if user_dest.mint == amm_coin_vault.mint {
+            SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        };
+
+        let lp_mint = Self::unpack_mint(amm_lp_mint_info, spl_token_program_id)?;
+        let user_source_lp = Self::unpack_token_account(user_source_lp_info, spl_token_program_id)?;
+        if user_source_lp.owner != *source_lp_owner_info.key {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        if user_source_lp.mint != *amm_lp_mint_info.key {
+            return Err(AmmError::InvalidTokenLP.into());
+        }
+        if withdraw.amount > user_source_lp.amount {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if withdraw.amount > lp_mint.supply || withdraw.amount >= amm.lp_amount {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let x1 = Calculator::normalize_decimal_v2(
+            total_pc_without_take_pnl,
+            amm.pc_decimals,
+            amm.sys_decimal_value,
+        );
+        let y1 = Calculator::normalize_decimal_v2(
+            total_coin_without_take_pnl,
+            amm.coin_decimals,
+            amm.sys_decimal_value,
+        );
+
+        // calc and update pnl
+        let mut delta_x: u128 = 0;
+        let mut delta_y: u128 = 0;
+        if amm.status != AmmStatus::WithdrawOnly.into_u64() {
+            (delta_x, delta_y) = Self::migrate_protocol_fee(
+                &target_orders,
+                &mut amm,
+                &mut total_pc_without_take_pnl,
+                &mut total_coin_without_take_pnl,
+                x1.as_u128().into(),
+                y1.as_u128().into(),
+            )?;
+        }
+
+        let invariant = InvariantPool {
+            token_input: withdraw.amount,
+            token_total: amm.lp_amount,
+        };
+        let share_coin = invariant
+            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+        let share_pc = invariant
+            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
+            .ok_or(AmmError::CalculationExRateFailure)?;
+
+        // swap the unwanted share against the reserves left after the withdrawal
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        let (swap_in, share_out) = match swap_direction {
+            SwapDirection::Coin2PC => (share_coin, share_pc),
+            SwapDirection::PC2Coin => (share_pc, share_coin),
+        };
+        let (swap_fee, swap_out) = Self::calc_swap_vault_base_in(
+            &fees,
+            &*curve,
+            total_pc_without_take_pnl.checked_sub(share_pc).unwrap(),
+            total_coin_without_take_pnl.checked_sub(share_coin).unwrap(),
+            swap_direction,
+            swap_in,
+        )?;
+        let protocol_fee = Self::accrue_protocol_fee(&mut amm, swap_direction, swap_fee, 0);
+        let amount_out = share_out
+            .checked_add(swap_out)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        let (dest_vault_token, dest_vault_info, dest_vault_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (&pc_vault_token, amm_pc_vault_info, amm_pc_vault.amount),
+            SwapDirection::PC2Coin => (
+                &coin_vault_token,
+                amm_coin_vault_info,
+                amm_coin_vault.amount,
+            ),
+        };
+        // the user only receives amount_out less the destination mint transfer fee
+        let amount_out_after_transfer_fee =
+            dest_vault_token.post_fee_amount(clock.epoch, amount_out)?;
+
+        encode_ray_log(WithdrawSingleSideLog {
+            log_type: LogType::WithdrawSingleSide.into_u8(),
+            withdraw_lp: withdraw.amount,
+            min_amount_out: withdraw.min_amount_out,
+            direction: swap_direction as u64,
+            user_lp: user_source_lp.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            pool_lp: amm.lp_amount,
+            share_coin,
+            share_pc,
+            swap_out,
+            swap_fee,
+            amount_out,
+            amount_out_after_transfer_fee,
+        });
+        if withdraw.amount == 0 || amount_out_after_transfer_fee == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        if amount_out_after_transfer_fee < withdraw.min_amount_out {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if amount_out >= dest_vault_amount {
+            // calc error
+            return Err(AmmError::TakePnlError.into());
+        }
+
+        Self::transfer_from_vault(
+            dest_vault_token,
+            dest_vault_info,
+            user_dest_info,
+            amm_authority_info,
+            amm.nonce as u8,
+            amount_out,
+        )?;
+        Invokers::token_burn(
+            token_program_info.clone(),
+            user_source_lp_info.clone(),
+            amm_lp_mint_info.clone(),
+            source_lp_owner_info.clone(),
+            withdraw.amount,
+        )?;
+        amm.lp_amount = amm.lp_amount.checked_sub(withdraw.amount).unwrap();
+
+        // the swapped share stays in its vault, less the protocol fee of the swap
+        let (deduct_pc_amount, deduct_coin_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (amount_out, protocol_fee),
+            SwapDirection::PC2Coin => (protocol_fee, amount_out),
+        };
+        target_orders.calc_pnl_x = x1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                deduct_pc_amount,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_x))
+            .unwrap()
+            .as_u128();
+        target_orders.calc_pnl_y = y1
+            .checked_sub(Calculator::normalize_decimal_v2(
+                deduct_coin_amount,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            ))
+            .unwrap()
+            .checked_sub(U128::from(delta_y))
+            .unwrap()
+            .as_u128();
+        Self::update_observation(
+            program_id,
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        )?;
+        let (coin_amount, pc_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (0, amount_out_after_transfer_fee),
+            SwapDirection::PC2Coin => (amount_out_after_transfer_fee, 0),
+        };
+        Self::log_liquidity_event(
+            program_id,
+            event_accounts,
+            LogType::WithdrawV2,
+            amm_info,
+            &amm,
+            source_lp_owner_info,
+            coin_amount,
+            pc_amount,
+            withdraw.amount,
+            swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        amm.recent_epoch = clock.epoch;
+        Ok(())
+    }
+
+    /// Processes `process_flash_loan` instruction.
+    pub fn process_flash_loan(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        flash_loan: FlashLoanInstruction,
+    ) -> ProgramResult {
+        const ACCOUNT_LEN: usize = 9;
+        if accounts.len() < ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let (accounts, borrower_accounts) = accounts.split_at(ACCOUNT_LEN);
+        let (token_2022_accounts, borrower_accounts) = if borrower_accounts.len()
+            >= TOKEN_2022_ACCOUNTS_LEN
+            && *borrower_accounts[0].key == spl_token_2022::id()
+        {
+            let (token_2022_accounts, borrower_accounts) =
+                borrower_accounts.split_at(TOKEN_2022_ACCOUNTS_LEN);
+            (Some(token_2022_accounts), borrower_accounts)
+        } else {
+            (None, borrower_accounts)
+        };
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let borrower_coin_info = next_account_info(account_info_iter)?;
+        let borrower_pc_info = next_account_info(account_info_iter)?;
+        let borrower_program_info = next_account_info(account_info_iter)?;
+
+        if flash_loan.coin_amount == 0 && flash_loan.pc_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
+        check_assert_eq!(
+            *amm_config_info.key,
+            pda,
+            "amm_config",
+            AmmError::InvalidConfigAccount
+        );
+        let amm_config = AmmConfig::load_checked(amm_config_info, program_id)?;
+        amm_config.check_global_status(false)?;
+        let coin_fee = amm_config.flash_fee(flash_loan.coin_amount);
+        let pc_fee = amm_config.flash_fee(flash_loan.pc_amount);
+
+        let (coin_vault_token, pc_vault_token, nonce, vault_coin, vault_pc) = {
+            let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+            amm.check_flash_loan_unlocked()?;
+            let clock = Clock::get()?;
+            if !AmmStatus::from_u64(amm.status).swap_permission()
+                || (amm.status == AmmStatus::WaitingTrade.into_u64()
+                    && (clock.unix_timestamp as u64) < amm.state_data.pool_open_time)
+            {
+                msg!(&format!("flash_loan: status {}", identity(amm.status)));
+                return Err(AmmError::InvalidStatus.into());
+            }
+            if *amm_authority_info.key
+                != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+            {
+                return Err(AmmError::InvalidProgramAddress.into());
+            }
+            check_assert_eq!(
+                *amm_coin_vault_info.key,
+                amm.coin_vault,
+                "coin_vault",
+                AmmError::InvalidCoinVault
+            );
+            check_assert_eq!(
+                *amm_pc_vault_info.key,
+                amm.pc_vault,
+                "pc_vault",
+                AmmError::InvalidPCVault
+            );
+            if *borrower_coin_info.key == amm.pc_vault || *borrower_coin_info.key == amm.coin_vault
+            {
+                return Err(AmmError::InvalidUserToken.into());
+            }
+            if *borrower_pc_info.key == amm.pc_vault || *borrower_pc_info.key == amm.coin_vault {
+                return Err(AmmError::InvalidUserToken.into());
+            }
+            let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+                &amm,
+                token_program_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                token_2022_accounts,
+            )?;
+            let amm_coin_vault =
+                Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+            let amm_pc_vault =
+                Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+            if flash_loan.coin_amount >= amm_coin_vault.amount
+                || flash_loan.pc_amount >= amm_pc_vault.amount
+            {
+                return Err(AmmError::InsufficientFunds.into());
+            }
+            amm.flash_loan_locked = 1;
+            (
+                coin_vault_token,
+                pc_vault_token,
+                amm.nonce as u8,
+                amm_coin_vault.amount,
+                amm_pc_vault.amount,
+            )
+        };
+        encode_ray_log(FlashLoanLog {
+            log_type: LogType::FlashLoan.into_u8(),
+            coin_amount: flash_loan.coin_amount,
+            pc_amount: flash_loan.pc_amount,
+            vault_coin,
+            vault_pc,
+            coin_fee,
+            pc_fee,
+        });
+
+        if flash_loan.coin_amount != 0 {
+            Self::transfer_from_vault(
+                &coin_vault_token,
+                amm_coin_vault_info,
+                borrower_coin_info,
+                amm_authority_info,
+                nonce,
+                flash_loan.coin_amount,
+            )?;
+        }
+        if flash_loan.pc_amount != 0 {
+            Self::transfer_from_vault(
+                &pc_vault_token,
+                amm_pc_vault_info,
+                borrower_pc_info,
+                amm_authority_info,
+                nonce,
+                flash_loan.pc_amount,
+            )?;
+        }
+        // the amm account is not borrowed here, the borrower program may be passed it
+        let borrower_instruction = Instruction {
+            program_id: *borrower_program_info.key,
+            accounts: borrower_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data: flash_loan.data,
+        };
+        let mut borrower_account_infos = borrower_accounts.to_vec();
+        borrower_account_infos.push(borrower_program_info.clone());
+        invoke(&borrower_instruction, &borrower_account_infos)?;
+
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        if amm_coin_vault.amount < vault_coin.checked_add(coin_fee).unwrap()
+            || amm_pc_vault.amount < vault_pc.checked_add(pc_fee).unwrap()
+        {
+            return Err(AmmError::FlashLoanNotRepaid.into());
+        }
+        // the flash fee stays with the lp, less the protocol share
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.flash_loan_locked = 0;
+        Self::accrue_protocol_fee(&mut amm, SwapDirection::Coin2PC, coin_fee, 0);
+        Self::accrue_protocol_fee(&mut amm, SwapDirection::PC2Coin, pc_fee, 0);
+        amm.recent_epoch = Clock::get()?.epoch;
+        Ok(())
+    }
+
+    /// Processes `process_flash_swap` instruction.
+    pub fn process_flash_swap(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: FlashSwapInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        const ACCOUNT_LEN: usize = 8;
+        if accounts.len() < ACCOUNT_LEN {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let (accounts, callback_accounts) = accounts.split_at(ACCOUNT_LEN);
+        let (token_2022_accounts, callback_accounts) = if callback_accounts.len()
+            >= TOKEN_2022_ACCOUNTS_LEN
+            && *callback_accounts[0].key == spl_token_2022::id()
+        {
+            let (token_2022_accounts, callback_accounts) =
+                callback_accounts.split_at(TOKEN_2022_ACCOUNTS_LEN);
+            (Some(token_2022_accounts), callback_accounts)
+        } else {
+            (None, callback_accounts)
+        };
+        let (dynamic_fee_info, callback_accounts) = match callback_accounts.split_first() {
+            Some((dynamic_fee_info, callback_accounts))
+                if dynamic_fee_info.owner == program_id
+                    && dynamic_fee_info.data_len() == size_of::<DynamicFeeState>() =>
+            {
+                (Some(dynamic_fee_info), callback_accounts)
+            }
+            _ => (None, callback_accounts),
+        };
+        let (observation_info, callback_accounts) = match callback_accounts.split_first() {
+            Some((observation_info, callback_accounts))
+                if observation_info.owner == program_id
+                    && observation_info.data_len() == size_of::<ObservationState>() =>
+            {
+                (Some(observation_info), callback_accounts)
+            }
+            _ => (None, callback_accounts),
+        };
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let callback_program_info = next_account_info(account_info_iter)?;
+
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
+        check_assert_eq!(
+            *amm_config_info.key,
+            pda,
+            "amm_config",
+            AmmError::InvalidConfigAccount
+        );
+        AmmConfig::load_checked(amm_config_info, program_id)?.check_global_status(false)?;
+
+        let (
+            swap_direction,
+            source_vault_token,
+            destination_vault_token,
+            source_vault_info,
+            destination_vault_info,
+            source_vault_amount,
+            swap_amounts,
+            nonce,
+        ) = {
+            let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+            amm.check_flash_loan_unlocked()?;
+            if amm.pc_vault_mint == amm.coin_vault_mint {
+                return Err(AmmError::NotAllowed.into());
+            }
+            let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+                &amm,
+                token_program_info,
+                amm_coin_vault_info,
+                amm_pc_vault_info,
+                token_2022_accounts,
+            )?;
+            if *amm_authority_info.key
+                != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+            {
+                return Err(AmmError::InvalidProgramAddress.into());
+            }
+            check_assert_eq!(
+                *amm_coin_vault_info.key,
+                amm.coin_vault,
+                "coin_vault",
+                AmmError::InvalidCoinVault
+            );
+            check_assert_eq!(
+                *amm_pc_vault_info.key,
+                amm.pc_vault,
+                "pc_vault",
+                AmmError::InvalidPCVault
+            );
+            if *user_destination_info.key == amm.pc_vault
+                || *user_destination_info.key == amm.coin_vault
+            {
+                return Err(AmmError::InvalidUserToken.into());
+            }
+
+            let amm_coin_vault =
+                Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+            let amm_pc_vault =
+                Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+            let user_destination =
+                Self::unpack_token_account(user_destination_info, user_destination_info.owner)?;
+
+            if !AmmStatus::from_u64(amm.status).swap_permission() {
+                msg!(&format!("flash_swap: status {}", identity(amm.status)));
+                return Err(AmmError::InvalidStatus.into());
+            } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+                let clock = Clock::get()?;
+                if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                    return Err(AmmError::InvalidStatus.into());
+                } else {
+                    amm.status = AmmStatus::SwapOnly.into_u64();
+                    msg!("flash_swap: WaitingTrade to SwapOnly");
+                }
+            }
+
+            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl_no_orderbook(
+                    amm_pc_vault.amount,
+                    amm_coin_vault.amount,
+                    &amm,
+                )?;
+
+            let (
+                swap_direction,
+                source_vault_token,
+                destination_vault_token,
+                source_vault_info,
+                destination_vault_info,
+                source_vault_amount,
+            ) = if user_destination.mint == amm_pc_vault.mint {
+                (
+                    SwapDirection::Coin2PC,
+                    coin_vault_token,
+                    pc_vault_token,
+                    amm_coin_vault_info,
+                    amm_pc_vault_info,
+                    amm_coin_vault.amount,
+                )
+            } else if user_destination.mint == amm_coin_vault.mint {
+                (
+                    SwapDirection::PC2Coin,
+                    pc_vault_token,
+                    coin_vault_token,
+                    amm_pc_vault_info,
+                    amm_coin_vault_info,
+                    amm_pc_vault.amount,
+                )
+            } else {
+                return Err(AmmError::InvalidUserToken.into());
+            };
+            let epoch = Clock::get()?.epoch;
+            let fees = Self::load_swap_fees(
+                program_id,
+                amm_info.key,
+                &amm,
+                dynamic_fee_info,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                true,
+            )?;
+            let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+            let swap_amounts = Self::calc_swap_base_out(
+                &fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                swap.amount_out,
+                epoch,
+            )?;
+            let protocol_fee =
+                Self::accrue_protocol_fee(&mut amm, swap_direction, swap_amounts.swap_fee, 0);
+            encode_ray_log(FlashSwapLog {
+                log_type: LogType::FlashSwap.into_u8(),
+                max_in: swap.max_amount_in,
+                amount_out: swap.amount_out,
+                direction: swap_direction as u64,
+                pool_coin: total_coin_without_take_pnl,
+                pool_pc: total_pc_without_take_pnl,
+                deduct_in: swap_amounts.amount_in,
+                deduct_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+                amount_out_before_transfer_fee: swap_amounts.amount_out,
+                swap_fee: swap_amounts.swap_fee,
+                protocol_fee,
+            });
+            if swap_amounts.amount_in > swap.max_amount_in {
+                return Err(AmmError::ExceededSlippage.into());
+            }
+            if swap_amounts.amount_in == 0 || swap.amount_out == 0 {
+                return Err(AmmError::InvalidInput.into());
+            }
+            Self::update_observation(
+                program_id,
+                amm_info.key,
+                &amm,
+                observation_info,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                Clock::get()?.unix_timestamp as u64,
+            )?;
+            amm.flash_loan_locked = 1;
+            amm.recent_epoch = epoch;
+            (
+                swap_direction,
+                source_vault_token,
+                destination_vault_token,
+                source_vault_info,
+                destination_vault_info,
+                source_vault_amount,
+                swap_amounts,
+                amm.nonce as u8,
+            )
+        };
+
+        Self::transfer_from_vault(
+            &destination_vault_token,
+            destination_vault_info,
+            user_destination_info,
+            amm_authority_info,
+            nonce,
+            swap_amounts.amount_out,
+        )?;
+        // the amm account is not borrowed here, the callback program may be passed it
+        let mut data = Vec::with_capacity(size_of::<u64>() + swap.data.len());
+        data.extend_from_slice(&swap_amounts.amount_in.to_le_bytes());
+        data.extend_from_slice(&swap.data);
+        let callback_instruction = Instruction {
+            program_id: *callback_program_info.key,
+            accounts: callback_accounts
+                .iter()
+                .map(|account| AccountMeta {
+                    pubkey: *account.key,
+                    is_signer: account.is_signer,
+                    is_writable: account.is_writable,
+                })
+                .collect(),
+            data,
+        };
+        let mut callback_account_infos = callback_accounts.to_vec();
+        callback_account_infos.push(callback_program_info.clone());
+        invoke(&callback_instruction, &callback_account_infos)?;
+
+        let source_vault =
+            Self::unpack_token_account(source_vault_info, source_vault_token.program.key)?;
+        if source_vault.amount
+            < source_vault_amount
+                .checked_add(swap_amounts.amount_in_after_transfer_fee)
+                .unwrap()
+        {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        amm.flash_loan_locked = 0;
+        // the callback program repaid the swap, there is no user signer
+        let (coin_vault_token, pc_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (&source_vault_token, &destination_vault_token),
+            SwapDirection::PC2Coin => (&destination_vault_token, &source_vault_token),
+        };
+        Self::log_swap_event(
+            program_id,
+            event_accounts,
+            amm_info,
+            &amm,
+            callback_program_info,
+            swap_direction,
+            swap_amounts.amount_in,
+            swap.amount_out,
+            swap_amounts.swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                coin_vault_token,
+                amm_pc_vault_info,
+                pc_vault_token,
+            )?,
+        )?;
+        Ok(())
+    }
+
+    pub fn process_swap_base_in(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, referrer_accounts) = Self::split_referrer_accounts(accounts, swap.referrer)?;
+        let (accounts, limit_order_accounts) =
+            Self::split_limit_order_accounts(program_id, accounts);
+        const ACCOUNT_LEN: usize = 17;
+        let input_account_len = accounts.len();
+        if input_account_len != ACCOUNT_LEN
+            && input_account_len != ACCOUNT_LEN + 1
+            && input_account_len != ACCOUNT_LEN + 3
+        {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let _amm_open_orders_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = if input_account_len > ACCOUNT_LEN {
+            Some(next_account_info(account_info_iter)?)
+        } else {
+            None
+        };
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let _market_program_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let _market_info = next_account_info(account_info_iter)?;
+        let _market_bids_info = next_account_info(account_info_iter)?;
+        let _market_asks_info = next_account_info(account_info_iter)?;
+        let _market_event_queue_info = next_account_info(account_info_iter)?;
+        let _market_coin_vault_info = next_account_info(account_info_iter)?;
+        let _market_pc_vault_info = next_account_info(account_info_iter)?;
+        let _market_vault_signer = next_account_info(account_info_iter)?;
+
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let user_source_owner = next_account_info(account_info_iter)?;
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        let range_order_vault_infos = if input_account_len == ACCOUNT_LEN + 3 {
+            Some((
+                next_account_info(account_info_iter)?,
+                next_account_info(account_info_iter)?,
+            ))
+        } else {
+            None
+        };
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if *user_destination_info.key == amm.pc_vault
+            || *user_destination_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        let mut range_target_orders = match (amm_target_orders_info, range_order_vault_infos) {
+            (Some(amm_target_orders_info), Some((range_coin_vault_info, range_pc_vault_info))) => {
+                check_assert_eq!(
+                    *amm_target_orders_info.key,
+                    amm.target_orders,
+                    "target_orders",
+                    AmmError::InvalidTargetOrders
+                );
+                let target_orders = TargetOrders::load_mut_checked(
+                    amm_target_orders_info,
+                    program_id,
+                    amm_info.key,
+                )?;
+                if !target_orders.range_orders_enabled()
+                    || *range_coin_vault_info.key != target_orders.range_coin_vault
+                    || *range_pc_vault_info.key != target_orders.range_pc_vault
+                {
+                    return Err(AmmError::InvalidRangeOrderAccount.into());
+                }
+                Some(target_orders)
+            }
+            _ => None,
+        };
+
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+
+        let user_source = Self::unpack_token_account(&user_source_info, user_source_info.owner)?;
+        let user_destination =
+            Self::unpack_token_account(&user_destination_info, user_destination_info.owner)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            msg!(&format!("swap_base_in: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+            let clock = Clock::get()?;
+            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            } else {
+                amm.status = AmmStatus::SwapOnly.into_u64();
+                msg!("swap_base_in: WaitingTrade to SwapOnly");
+            }
+        }
+
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let swap_direction;
+        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
+            swap_direction = SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint
+            && user_destination.mint == amm_coin_vault.mint
+        {
+            swap_direction = SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if user_source.amount < swap.amount_in {
+            encode_ray_log(SwapBaseInLog {
+                log_type: LogType::SwapBaseIn.into_u8(),
+                amount_in: swap.amount_in,
+                minimum_out: swap.minimum_amount_out,
+                direction: swap_direction as u64,
+                user_source: user_source.amount,
+                pool_coin: total_coin_without_take_pnl,
+                pool_pc: total_pc_without_take_pnl,
+                out_amount: 0,
+            });
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        let epoch = Clock::get()?.epoch;
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+        };
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        // the range orders crossed by the pool price along the swap are filled at their price
+        let (range_in, range_out, range_fill_count) = match range_target_orders.as_mut() {
+            Some(target_orders) => Self::fill_range_orders_along_curve(
+                target_orders,
+                &amm,
+                &fees,
+                &*curve,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                swap.amount_in,
+            )?,
+            None => (0, 0, 0),
+        };
+        let curve_amount_in = swap.amount_in - range_in;
+        let swap_amounts = if curve_amount_in == 0 {
+            SwapAmounts::default()
+        } else {
+            Self::calc_swap_base_in(
+                &fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                curve_amount_in,
+                epoch,
+            )?
+        };
+        let swap_amount_out = swap_amounts.amount_out;
+        let referrer = Self::load_referrer(
+            program_id,
+            referrer_accounts,
+            &amm,
+            &user_source,
+            user_source_owner.key,
+            swap_amounts.swap_fee,
+        )?;
+        let referrer_fee = referrer.map_or(0, |(_, referrer_fee)| referrer_fee);
+        let protocol_fee = Self::accrue_protocol_fee(
+            &mut amm,
+            swap_direction,
+            swap_amounts.swap_fee,
+            referrer_fee,
+        );
+        let swap_log = SwapBaseInLogV2 {
+            log_type: LogType::SwapBaseInV2.into_u8(),
+            version: EVENT_VERSION,
+            amount_in: swap.amount_in,
+            minimum_out: swap.minimum_amount_out,
+            direction: swap_direction as u64,
+            user_source: user_source.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            out_amount: swap_amount_out,
+            amount_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+            out_amount_after_transfer_fee: swap_amounts.amount_out_after_transfer_fee,
+            swap_fee: swap_amounts.swap_fee,
+            referrer_fee,
+            protocol_fee,
+        };
+        encode_ray_log(swap_log.legacy());
+        if range_fill_count > 0 {
+            Self::emit_event(
+                program_id,
+                event_accounts,
+                RangeOrderFillLog {
+                    log_type: LogType::RangeOrderFill.into_u8(),
+                    direction: swap_direction as u64,
+                    amount_in: range_in,
+                    amount_out: range_out,
+                    fill_count: range_fill_count,
+                },
+            )?;
+        }
+        // the fills of the range orders are a transfer of their own, charged its transfer fee
+        let range_out_after_transfer_fee =
+            destination_vault_token.post_fee_amount(epoch, range_out)?;
+        let amount_out = swap_amounts
+            .amount_out_after_transfer_fee
+            .checked_add(range_out_after_transfer_fee)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        if amount_out < swap.minimum_amount_out {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if amount_out == 0 || swap.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                if swap_amount_out >= total_pc_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source coin to amm_coin_vault
+                Self::transfer_to_vault(
+                    &coin_vault_token,
+                    user_source_info,
+                    amm_coin_vault_info,
+                    user_source_owner,
+                    curve_amount_in,
+                )?;
+                // withdraw amm_pc_vault to destination pc
+                Self::transfer_from_vault(
+                    &pc_vault_token,
+                    amm_pc_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    swap_amount_out,
+                )?;
+            }
+            SwapDirection::PC2Coin => {
+                if swap_amount_out >= total_coin_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source pc to amm_pc_vault
+                Self::transfer_to_vault(
+                    &pc_vault_token,
+                    user_source_info,
+                    amm_pc_vault_info,
+                    user_source_owner,
+                    curve_amount_in,
+                )?;
+                // withdraw amm_coin_vault to destination coin
+                Self::transfer_from_vault(
+                    &coin_vault_token,
+                    amm_coin_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    swap_amount_out,
+                )?;
+            }
+        };
+        if let (Some((range_coin_vault_info, range_pc_vault_info)), true) =
+            (range_order_vault_infos, range_fill_count > 0)
+        {
+            let (range_source_vault_info, range_destination_vault_info) = match swap_direction {
+                SwapDirection::Coin2PC => (range_coin_vault_info, range_pc_vault_info),
+                SwapDirection::PC2Coin => (range_pc_vault_info, range_coin_vault_info),
+            };
+            // pay the range orders and withdraw their fills to the user
+            Self::transfer_to_vault(
+                &source_vault_token,
+                user_source_info,
+                range_source_vault_info,
+                user_source_owner,
+                range_in,
+            )?;
+            Self::transfer_from_vault(
+                &destination_vault_token,
+                range_destination_vault_info,
+                user_destination_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                range_out,
+            )?;
+        }
+        if let Some((referrer_info, referrer_fee)) = referrer {
+            let source_vault_info = match swap_direction {
+                SwapDirection::Coin2PC => amm_coin_vault_info,
+                SwapDirection::PC2Coin => amm_pc_vault_info,
+            };
+            Self::pay_referrer(
+                &source_vault_token,
+                source_vault_info,
+                referrer_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                referrer_fee,
+            )?;
+        }
+        Self::update_observation(
+            program_id,
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        )?;
+        Self::log_swap_event(
+            program_id,
+            event_accounts,
+            amm_info,
+            &amm,
+            user_source_owner,
+            swap_direction,
+            swap.amount_in,
+            amount_out,
+            swap_amounts.swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        Self::match_limit_orders(
+            program_id,
+            event_accounts,
+            amm_info,
+            &mut amm,
+            &fees,
+            amm_authority_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            &coin_vault_token,
+            &pc_vault_token,
+            limit_order_accounts,
+            epoch,
+        )?;
+        amm.recent_epoch = epoch;
+
+        Ok(())
+    }
+
+    pub fn process_swap_base_out(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseOut,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, referrer_accounts) = Self::split_referrer_accounts(accounts, swap.referrer)?;
+        let (accounts, limit_order_accounts) =
+            Self::split_limit_order_accounts(program_id, accounts);
+        const SWAP_ACCOUNT_NUM: usize = 17;
+        let input_account_len = accounts.len();
+        if input_account_len != SWAP_ACCOUNT_NUM && input_account_len != SWAP_ACCOUNT_NUM + 1 {
+            return Err(AmmError::WrongAccountsNumber.into());
+        }
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let _amm_open_orders_info = next_account_info(account_info_iter)?;
+        if input_account_len == SWAP_ACCOUNT_NUM + 1 {
+            let _amm_target_orders_info = next_account_info(account_info_iter)?;
+        }
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let _market_program_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+
+        let _market_info = next_account_info(account_info_iter)?;
+        let _market_bids_info = next_account_info(account_info_iter)?;
+        let _market_asks_info = next_account_info(account_info_iter)?;
+        let _market_event_queue_info = next_account_info(account_info_iter)?;
+        let _market_coin_vault_info = next_account_info(account_info_iter)?;
+        let _market_pc_vault_info = next_account_info(account_info_iter)?;
+        let _market_vault_signer = next_account_info(account_info_iter)?;
+
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let user_source_owner = next_account_info(account_info_iter)?;
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
+        check_assert_eq!(
+            *amm_authority_info.key,
+            authority,
+            "authority",
+            AmmError::InvalidProgramAddress
+        );
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if *user_destination_info.key == amm.pc_vault
+            || *user_destination_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+
+        let user_source = Self::unpack_token_account(&user_source_info, user_source_info.owner)?;
+        let user_destination =
+            Self::unpack_token_account(&user_destination_info, user_destination_info.owner)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            msg!(&format!("swap_base_out: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+            let clock = Clock::get()?;
+            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            } else {
+                amm.status = AmmStatus::SwapOnly.into_u64();
+                msg!("swap_base_out: WaitingTrade to SwapOnly");
+            }
+        }
+
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let swap_direction;
+        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
+            swap_direction = SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint
+            && user_destination.mint == amm_coin_vault.mint
+        {
+            swap_direction = SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+
+        let epoch = Clock::get()?.epoch;
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+        };
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        let swap_amounts = Self::calc_swap_base_out(
+            &fees,
+            &*curve,
+            &source_vault_token,
+            &destination_vault_token,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            swap.amount_out,
+            epoch,
+        )?;
+        let deduct_in = swap_amounts.amount_in;
+        let amount_out_before_transfer_fee = swap_amounts.amount_out;
+        let referrer = Self::load_referrer(
+            program_id,
+            referrer_accounts,
+            &amm,
+            &user_source,
+            user_source_owner.key,
+            swap_amounts.swap_fee,
+        )?;
+        let referrer_fee = referrer.map_or(0, |(_, referrer_fee)| referrer_fee);
+        let protocol_fee = Self::accrue_protocol_fee(
+            &mut amm,
+            swap_direction,
+            swap_amounts.swap_fee,
+            referrer_fee,
+        );
+        let swap_log = SwapBaseOutLogV2 {
+            log_type: LogType::SwapBaseOutV2.into_u8(),
+            version: EVENT_VERSION,
+            max_in: swap.max_amount_in,
+            amount_out: swap.amount_out,
+            direction: swap_direction as u64,
+            user_source: user_source.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            deduct_in,
+            deduct_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+            amount_out_before_transfer_fee,
+            swap_fee: swap_amounts.swap_fee,
+            referrer_fee,
+            protocol_fee,
+        };
+        encode_ray_log(swap_log.legacy());
+        if user_source.amount < deduct_in {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if swap.max_amount_in < deduct_in {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if deduct_in == 0 || swap.amount_out == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                if amount_out_before_transfer_fee >= total_pc_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source coin to amm_coin_vault
+                Self::transfer_to_vault(
+                    &coin_vault_token,
+                    user_source_info,
+                    amm_coin_vault_info,
+                    user_source_owner,
+                    deduct_in,
+                )?;
+                // withdraw amm_pc_vault to destination pc
+                Self::transfer_from_vault(
+                    &pc_vault_token,
+                    amm_pc_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    amount_out_before_transfer_fee,
+                )?;
+            }
+            SwapDirection::PC2Coin => {
+                if amount_out_before_transfer_fee >= total_coin_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+
+                // deposit source pc to amm_pc_vault
+                Self::transfer_to_vault(
+                    &pc_vault_token,
+                    user_source_info,
+                    amm_pc_vault_info,
+                    user_source_owner,
+                    deduct_in,
+                )?;
+                // withdraw amm_coin_vault to destination coin
+                Self::transfer_from_vault(
+                    &coin_vault_token,
+                    amm_coin_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    amount_out_before_transfer_fee,
+                )?;
+            }
+        };
+        if let Some((referrer_info, referrer_fee)) = referrer {
+            let source_vault_info = match swap_direction {
+                SwapDirection::Coin2PC => amm_coin_vault_info,
+                SwapDirection::PC2Coin => amm_pc_vault_info,
+            };
+            Self::pay_referrer(
+                &source_vault_token,
+                source_vault_info,
+                referrer_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                referrer_fee,
+            )?;
+        }
+        Self::update_observation(
+            program_id,
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        )?;
+        Self::log_swap_event(
+            program_id,
+            event_accounts,
+            amm_info,
+            &amm,
+            user_source_owner,
+            swap_direction,
+            deduct_in,
+            swap_amounts.amount_out_after_transfer_fee,
+            swap_amounts.swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        Self::match_limit_orders(
+            program_id,
+            event_accounts,
+            amm_info,
+            &mut amm,
+            &fees,
+            amm_authority_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            &coin_vault_token,
+            &pc_vault_token,
+            limit_order_accounts,
+            epoch,
+        )?;
+        amm.recent_epoch = epoch;
+
+        Ok(())
+    }
+
+    pub fn process_swap_base_in_v2(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, referrer_accounts) = Self::split_referrer_accounts(accounts, swap.referrer)?;
+        let (accounts, limit_order_accounts) =
+            Self::split_limit_order_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let user_source_owner = next_account_info(account_info_iter)?;
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if *user_destination_info.key == amm.pc_vault
+            || *user_destination_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+
+        let user_source = Self::unpack_token_account(&user_source_info, user_source_info.owner)?;
+        let user_destination =
+            Self::unpack_token_account(&user_destination_info, user_destination_info.owner)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            msg!(&format!("swap_base_in_v2: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+            let clock = Clock::get()?;
+            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            } else {
+                amm.status = AmmStatus::SwapOnly.into_u64();
+                msg!("swap_base_in_v2: WaitingTrade to SwapOnly");
+            }
+        }
+
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let swap_direction;
+        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
+            swap_direction = SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint
+            && user_destination.mint == amm_coin_vault.mint
+        {
+            swap_direction = SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
+        }
+        if user_source.amount < swap.amount_in {
+            encode_ray_log(SwapBaseInLog {
+                log_type: LogType::SwapBaseIn.into_u8(),
+                amount_in: swap.amount_in,
+                minimum_out: swap.minimum_amount_out,
+                direction: swap_direction as u64,
+                user_source: user_source.amount,
+                pool_coin: total_coin_without_take_pnl,
+                pool_pc: total_pc_without_take_pnl,
+                out_amount: 0,
+            });
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        let epoch = Clock::get()?.epoch;
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+        };
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        let swap_amounts = Self::calc_swap_base_in(
+            &fees,
+            &*curve,
+            &source_vault_token,
+            &destination_vault_token,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            swap.amount_in,
+            epoch,
+        )?;
+        let swap_amount_out = swap_amounts.amount_out;
+        let referrer = Self::load_referrer(
+            program_id,
+            referrer_accounts,
+            &amm,
+            &user_source,
+            user_source_owner.key,
+            swap_amounts.swap_fee,
+        )?;
+        let referrer_fee = referrer.map_or(0, |(_, referrer_fee)| referrer_fee);
+        let protocol_fee = Self::accrue_protocol_fee(
+            &mut amm,
+            swap_direction,
+            swap_amounts.swap_fee,
+            referrer_fee,
+        );
+        let swap_log = SwapBaseInLogV2 {
+            log_type: LogType::SwapBaseInV2.into_u8(),
+            version: EVENT_VERSION,
+            amount_in: swap.amount_in,
+            minimum_out: swap.minimum_amount_out,
+            direction: swap_direction as u64,
+            user_source: user_source.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            out_amount: swap_amount_out,
+            amount_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+            out_amount_after_transfer_fee: swap_amounts.amount_out_after_transfer_fee,
+            swap_fee: swap_amounts.swap_fee,
+            referrer_fee,
+            protocol_fee,
+        };
+        encode_ray_log(swap_log.legacy());
+        if swap_amounts.amount_out_after_transfer_fee < swap.minimum_amount_out {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if swap_amounts.amount_out_after_transfer_fee == 0 || swap.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                if swap_amount_out >= total_pc_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source coin to amm_coin_vault
+                Self::transfer_to_vault(
+                    &coin_vault_token,
+                    user_source_info,
+                    amm_coin_vault_info,
+                    user_source_owner,
+                    swap.amount_in,
+                )?;
+                // withdraw amm_pc_vault to destination pc
+                Self::transfer_from_vault(
+                    &pc_vault_token,
+                    amm_pc_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    swap_amount_out,
+                )?;
+            }
+            SwapDirection::PC2Coin => {
+                if swap_amount_out >= total_coin_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source pc to amm_pc_vault
+                Self::transfer_to_vault(
+                    &pc_vault_token,
+                    user_source_info,
+                    amm_pc_vault_info,
+                    user_source_owner,
+                    swap.amount_in,
+                )?;
+                // withdraw amm_coin_vault to destination coin
+                Self::transfer_from_vault(
+                    &coin_vault_token,
+                    amm_coin_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    swap_amount_out,
+                )?;
+            }
+        };
+        if let Some((referrer_info, referrer_fee)) = referrer {
+            let source_vault_info = match swap_direction {
+                SwapDirection::Coin2PC => amm_coin_vault_info,
+                SwapDirection::PC2Coin => amm_pc_vault_info,
+            };
+            Self::pay_referrer(
+                &source_vault_token,
+                source_vault_info,
+                referrer_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                referrer_fee,
             )?;
         }
-
-        // unpack and check coin_mint
-        let coin_mint = Self::unpack_mint(&amm_coin_mint_info, spl_token_program_id)?;
-        // unpack and check pc_mint
-        let pc_mint = Self::unpack_mint(&amm_pc_mint_info, spl_token_program_id)?;
-
-        // create target_order account
-        Self::generate_amm_associated_account(
+        Self::update_observation(
             program_id,
-            program_id,
-            market_info,
-            amm_target_orders_info,
-            user_wallet_info,
-            system_program_info,
-            rent_sysvar_info,
-            TARGET_ASSOCIATED_SEED,
-            size_of::<TargetOrders>(),
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
         )?;
-
-        // create lp mint account
-        let lp_decimals = coin_mint.decimals;
-        Self::generate_amm_associated_spl_mint(
+        Self::log_swap_event(
             program_id,
-            spl_token_program_id,
-            market_info,
-            amm_lp_mint_info,
-            user_wallet_info,
-            system_program_info,
-            rent_sysvar_info,
-            token_program_info,
-            amm_authority_info,
-            LP_MINT_ASSOCIATED_SEED,
-            lp_decimals,
+            event_accounts,
+            amm_info,
+            &amm,
+            user_source_owner,
+            swap_direction,
+            swap.amount_in,
+            swap_amounts.amount_out_after_transfer_fee,
+            swap_amounts.swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
         )?;
-        // create coin vault account
-        Self::generate_amm_associated_spl_token(
+        Self::match_limit_orders(
             program_id,
-            spl_token_program_id,
-            market_info,
-            amm_coin_vault_info,
-            amm_coin_mint_info,
-            user_wallet_info,
-            system_program_info,
-            rent_sysvar_info,
-            token_program_info,
+            event_accounts,
+            amm_info,
+            &mut amm,
+            &fees,
             amm_authority_info,
-            COIN_VAULT_ASSOCIATED_SEED,
-        )?;
-        // create pc vault account
-        Self::generate_amm_associated_spl_token(
-            program_id,
-            spl_token_program_id,
-            market_info,
+            amm_coin_vault_info,
             amm_pc_vault_info,
-            amm_pc_mint_info,
-            user_wallet_info,
-            system_program_info,
-            rent_sysvar_info,
-            token_program_info,
-            amm_authority_info,
-            PC_VAULT_ASSOCIATED_SEED,
-        )?;
-        // create amm account
-        Self::generate_amm_associated_account(
-            program_id,
-            program_id,
-            market_info,
-            amm_info,
-            user_wallet_info,
-            system_program_info,
-            rent_sysvar_info,
-            AMM_ASSOCIATED_SEED,
-            size_of::<AmmInfo>(),
-        )?;
-
-        // create user ata lp token
-        Invokers::create_ata_spl_token(
-            user_token_lp_info.clone(),
-            user_wallet_info.clone(),
-            user_wallet_info.clone(),
-            amm_lp_mint_info.clone(),
-            token_program_info.clone(),
-            ata_token_program_info.clone(),
-            system_program_info.clone(),
+            &coin_vault_token,
+            &pc_vault_token,
+            limit_order_accounts,
+            epoch,
         )?;
+        amm.recent_epoch = epoch;
 
-        // transfer user tokens to vault
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_token_coin_info.clone(),
-            amm_coin_vault_info.clone(),
-            user_wallet_info.clone(),
-            init.init_coin_amount,
-        )?;
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_token_pc_info.clone(),
-            amm_pc_vault_info.clone(),
-            user_wallet_info.clone(),
-            init.init_pc_amount,
-        )?;
+        Ok(())
+    }
 
-        // load AmmInfo
-        let mut amm = AmmInfo::load_mut(&amm_info)?;
-        if amm.status != AmmStatus::Uninitialized.into_u64() {
-            return Err(AmmError::AlreadyInUse.into());
+    pub fn process_swap_base_out_v2(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseOut,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let (accounts, observation_info) = Self::split_observation_account(program_id, accounts);
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let (accounts, referrer_accounts) = Self::split_referrer_accounts(accounts, swap.referrer)?;
+        let (accounts, limit_order_accounts) =
+            Self::split_limit_order_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        amm.check_flash_loan_unlocked()?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_destination_info = next_account_info(account_info_iter)?;
+        let user_source_owner = next_account_info(account_info_iter)?;
+        if !user_source_owner.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
         }
 
-        // unpack and check token_coin
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
+            token_program_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            token_2022_accounts,
+        )?;
+        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
         check_assert_eq!(
-            amm_coin_vault.owner,
             *amm_authority_info.key,
-            "coin_vault_owner",
-            AmmError::InvalidOwner
+            authority,
+            "authority",
+            AmmError::InvalidProgramAddress
         );
-        if amm_coin_vault.amount == 0 {
-            return Err(AmmError::InvalidSupply.into());
-        }
-        if amm_coin_vault.delegate.is_some() {
-            return Err(AmmError::InvalidDelegate.into());
-        }
-        if amm_coin_vault.close_authority.is_some() {
-            return Err(AmmError::InvalidCloseAuthority.into());
-        }
         check_assert_eq!(
-            *amm_coin_mint_info.key,
-            amm_coin_vault.mint,
-            "coin_mint",
-            AmmError::InvalidCoinMint
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
         );
-        // unpack and check token_pc
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
         check_assert_eq!(
-            amm_pc_vault.owner,
-            *amm_authority_info.key,
-            "pc_vault_owner",
-            AmmError::InvalidOwner
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
         );
-        if amm_pc_vault.amount == 0 {
-            return Err(AmmError::InvalidSupply.into());
-        }
-        if amm_pc_vault.delegate.is_some() {
-            return Err(AmmError::InvalidDelegate.into());
+
+        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
+            return Err(AmmError::InvalidUserToken.into());
         }
-        if amm_pc_vault.close_authority.is_some() {
-            return Err(AmmError::InvalidCloseAuthority.into());
+        if *user_destination_info.key == amm.pc_vault
+            || *user_destination_info.key == amm.coin_vault
+        {
+            return Err(AmmError::InvalidUserToken.into());
         }
-        check_assert_eq!(
-            *amm_pc_mint_info.key,
-            amm_pc_vault.mint,
-            "pc_mint",
-            AmmError::InvalidPCMint
-        );
 
-        let lp_mint = Self::unpack_mint(&amm_lp_mint_info, spl_token_program_id)?;
-        if lp_mint.supply != 0 {
-            return Err(AmmError::InvalidSupply.into());
-        }
-        if COption::Some(*amm_authority_info.key) != lp_mint.mint_authority {
-            return Err(AmmError::InvalidOwner.into());
+        let amm_coin_vault =
+            Self::unpack_token_account(&amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(&amm_pc_vault_info, pc_vault_token.program.key)?;
+
+        let user_source = Self::unpack_token_account(&user_source_info, user_source_info.owner)?;
+        let user_destination =
+            Self::unpack_token_account(&user_destination_info, user_destination_info.owner)?;
+
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            return Err(AmmError::InvalidStatus.into());
+        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
+            let clock = Clock::get()?;
+            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
+                return Err(AmmError::InvalidStatus.into());
+            } else {
+                amm.status = AmmStatus::SwapOnly.into_u64();
+                msg!("swap_base_out_v2: WaitingTrade to SwapOnly");
+            }
         }
-        if lp_mint.freeze_authority.is_some() {
-            return Err(AmmError::InvalidFreezeAuthority.into());
+
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
+
+        let swap_direction;
+        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
+            swap_direction = SwapDirection::Coin2PC
+        } else if user_source.mint == amm_pc_vault.mint
+            && user_destination.mint == amm_coin_vault.mint
+        {
+            swap_direction = SwapDirection::PC2Coin
+        } else {
+            return Err(AmmError::InvalidUserToken.into());
         }
 
-        let liquidity = Calculator::to_u64(
-            U128::from(amm_pc_vault.amount)
-                .checked_mul(amm_coin_vault.amount.into())
-                .unwrap()
-                .integer_sqrt()
-                .as_u128(),
+        let epoch = Clock::get()?.epoch;
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+        };
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            true,
         )?;
-        let user_lp_amount = liquidity
-            .checked_sub((10u64).checked_pow(lp_mint.decimals.into()).unwrap())
-            .ok_or(AmmError::InitLpAmountTooLess)?;
-
-        // liquidity is measured in terms of token_a's value since both sides of
-        // the pool are equal
-        Invokers::token_mint_to(
-            token_program_info.clone(),
-            amm_lp_mint_info.clone(),
-            user_token_lp_info.clone(),
-            amm_authority_info.clone(),
-            AUTHORITY_AMM,
-            init.nonce,
-            user_lp_amount,
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+        let swap_amounts = Self::calc_swap_base_out(
+            &fees,
+            &*curve,
+            &source_vault_token,
+            &destination_vault_token,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            swap_direction,
+            swap.amount_out,
+            epoch,
         )?;
-
-        amm.initialize(
-            init.nonce,
-            init.open_time,
-            coin_mint.decimals,
-            pc_mint.decimals,
-            0,
-            0,
+        let deduct_in = swap_amounts.amount_in;
+        let amount_out_before_transfer_fee = swap_amounts.amount_out;
+        let referrer = Self::load_referrer(
+            program_id,
+            referrer_accounts,
+            &amm,
+            &user_source,
+            user_source_owner.key,
+            swap_amounts.swap_fee,
         )?;
-        encode_ray_log(InitLog {
-            log_type: LogType::Init.into_u8(),
-            time: init.open_time,
-            pc_decimals: amm.pc_decimals as u8,
-            coin_decimals: amm.coin_decimals as u8,
-            pc_lot_size: 0,
-            coin_lot_size: 0,
-            pc_amount: amm_pc_vault.amount,
-            coin_amount: amm_coin_vault.amount,
-            market: *market_info.key,
-        });
-        let x = Calculator::normalize_decimal_v2(
-            amm_pc_vault.amount,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
-        );
-        let y = Calculator::normalize_decimal_v2(
-            amm_coin_vault.amount,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        let referrer_fee = referrer.map_or(0, |(_, referrer_fee)| referrer_fee);
+        let protocol_fee = Self::accrue_protocol_fee(
+            &mut amm,
+            swap_direction,
+            swap_amounts.swap_fee,
+            referrer_fee,
         );
-        // check and init target orders account
-        if amm_target_orders_info.owner != program_id {
-            return Err(AmmError::InvalidProgramAddress.into());
+        let swap_log = SwapBaseOutLogV2 {
+            log_type: LogType::SwapBaseOutV2.into_u8(),
+            version: EVENT_VERSION,
+            max_in: swap.max_amount_in,
+            amount_out: swap.amount_out,
+            direction: swap_direction as u64,
+            user_source: user_source.amount,
+            pool_coin: total_coin_without_take_pnl,
+            pool_pc: total_pc_without_take_pnl,
+            deduct_in,
+            deduct_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+            amount_out_before_transfer_fee,
+            swap_fee: swap_amounts.swap_fee,
+            referrer_fee,
+            protocol_fee,
+        };
+        encode_ray_log(swap_log.legacy());
+        if user_source.amount < deduct_in {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if swap.max_amount_in < deduct_in {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if deduct_in == 0 || swap.amount_out == 0 {
+            return Err(AmmError::InvalidInput.into());
         }
-        let mut target_order = TargetOrders::load_mut(amm_target_orders_info)?;
-        target_order.check_init(x.as_u128(), y.as_u128(), amm_info.key)?;
 
-        amm.coin_vault = *amm_coin_vault_info.key;
-        amm.pc_vault = *amm_pc_vault_info.key;
-        amm.coin_vault_mint = *amm_coin_mint_info.key;
-        amm.pc_vault_mint = *amm_pc_mint_info.key;
-        amm.lp_mint = *amm_lp_mint_info.key;
-        amm.open_orders = Pubkey::default();
-        amm.market = *market_info.key;
-        amm.market_program = Pubkey::default();
-        amm.target_orders = *amm_target_orders_info.key;
-        amm.amm_owner = config_feature::amm_owner::ID;
-        amm.lp_amount = liquidity;
-        amm.status = if init.open_time > (Clock::get()?.unix_timestamp as u64) {
-            AmmStatus::WaitingTrade.into_u64()
-        } else {
-            AmmStatus::SwapOnly.into_u64()
+        match swap_direction {
+            SwapDirection::Coin2PC => {
+                if amount_out_before_transfer_fee >= total_pc_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source coin to amm_coin_vault
+                Self::transfer_to_vault(
+                    &coin_vault_token,
+                    user_source_info,
+                    amm_coin_vault_info,
+                    user_source_owner,
+                    deduct_in,
+                )?;
+                // withdraw amm_pc_vault to destination pc
+                Self::transfer_from_vault(
+                    &pc_vault_token,
+                    amm_pc_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    amount_out_before_transfer_fee,
+                )?;
+            }
+            SwapDirection::PC2Coin => {
+                if amount_out_before_transfer_fee >= total_coin_without_take_pnl {
+                    return Err(AmmError::InsufficientFunds.into());
+                }
+                // deposit source pc to amm_pc_vault
+                Self::transfer_to_vault(
+                    &pc_vault_token,
+                    user_source_info,
+                    amm_pc_vault_info,
+                    user_source_owner,
+                    deduct_in,
+                )?;
+                // withdraw amm_coin_vault to destination coin
+                Self::transfer_from_vault(
+                    &coin_vault_token,
+                    amm_coin_vault_info,
+                    user_destination_info,
+                    amm_authority_info,
+                    amm.nonce as u8,
+                    amount_out_before_transfer_fee,
+                )?;
+            }
         };
-        amm.reset_flag = AmmResetFlag::ResetYes.into_u64();
+        if let Some((referrer_info, referrer_fee)) = referrer {
+            let source_vault_info = match swap_direction {
+                SwapDirection::Coin2PC => amm_coin_vault_info,
+                SwapDirection::PC2Coin => amm_pc_vault_info,
+            };
+            Self::pay_referrer(
+                &source_vault_token,
+                source_vault_info,
+                referrer_info,
+                amm_authority_info,
+                amm.nonce as u8,
+                referrer_fee,
+            )?;
+        }
+        Self::update_observation(
+            program_id,
+            amm_info.key,
+            &amm,
+            observation_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            Clock::get()?.unix_timestamp as u64,
+        )?;
+        Self::log_swap_event(
+            program_id,
+            event_accounts,
+            amm_info,
+            &amm,
+            user_source_owner,
+            swap_direction,
+            deduct_in,
+            swap_amounts.amount_out_after_transfer_fee,
+            swap_amounts.swap_fee,
+            Self::vault_amounts(
+                amm_coin_vault_info,
+                &coin_vault_token,
+                amm_pc_vault_info,
+                &pc_vault_token,
+            )?,
+        )?;
+        Self::match_limit_orders(
+            program_id,
+            event_accounts,
+            amm_info,
+            &mut amm,
+            &fees,
+            amm_authority_info,
+            amm_coin_vault_info,
+            amm_pc_vault_info,
+            &coin_vault_token,
+            &pc_vault_token,
+            limit_order_accounts,
+            epoch,
+        )?;
+        amm.recent_epoch = epoch;
 
         Ok(())
     }
 
-    /// Processes an [Deposit](enum.Instruction.html).
-    pub fn process_deposit(
+    /// Processes an [SimulateInfo](enum.Instruction.html).
+    pub fn process_simulate_info(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        deposit: DepositInstruction,
+        simulate: SimulateInstruction,
     ) -> ProgramResult {
-        let input_account_len = accounts.len();
-        let (
+        let (accounts, dynamic_fee_info) = Self::split_dynamic_fee_account(program_id, accounts);
+        let (accounts, token_2022_accounts) = Self::split_token_2022_accounts(accounts);
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_coin_vault_info = next_account_info(account_info_iter)?;
+        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
+        check_assert_eq!(
+            *amm_coin_vault_info.key,
+            amm.coin_vault,
+            "coin_vault",
+            AmmError::InvalidCoinVault
+        );
+        check_assert_eq!(
+            *amm_pc_vault_info.key,
+            amm.pc_vault,
+            "pc_vault",
+            AmmError::InvalidPCVault
+        );
+        let (coin_vault_token, pc_vault_token) = Self::load_vault_tokens(
+            &amm,
             token_program_info,
-            amm_info,
-            amm_authority_info,
-            amm_target_orders_info,
-            amm_lp_mint_info,
             amm_coin_vault_info,
             amm_pc_vault_info,
-            user_source_coin_info,
-            user_source_pc_info,
-            user_dest_lp_info,
-            source_owner_info,
-        ) = if input_account_len == 11 {
-            // Recommended use due to openbook has not supported.
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
-
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+            token_2022_accounts,
+        )?;
+        let amm_coin_vault =
+            Self::unpack_token_account(amm_coin_vault_info, coin_vault_token.program.key)?;
+        let amm_pc_vault =
+            Self::unpack_token_account(amm_pc_vault_info, pc_vault_token.program.key)?;
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                amm_pc_vault.amount,
+                amm_coin_vault.amount,
+                &amm,
+            )?;
 
-            let user_source_coin_info = next_account_info(account_info_iter)?;
-            let user_source_pc_info = next_account_info(account_info_iter)?;
-            let user_dest_lp_info = next_account_info(account_info_iter)?;
-            let source_owner_info = next_account_info(account_info_iter)?;
+        // the fees the next swap would be charged, without writing the volatility
+        let fees = Self::load_swap_fees(
+            program_id,
+            amm_info.key,
+            &amm,
+            dynamic_fee_info,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            false,
+        )?;
+        let curve = amm.curve(Clock::get()?.unix_timestamp as u64);
+
+        let param = SimulateParams::from_u64(simulate.param as u64)?;
+        if param == SimulateParams::PoolInfo {
+            let pool_info = SimulatePoolInfo {
+                status: amm.status,
+                coin_decimals: amm.coin_decimals,
+                pc_decimals: amm.pc_decimals,
+                lp_amount: amm.lp_amount,
+                pool_coin_amount: total_coin_without_take_pnl,
+                pool_pc_amount: total_pc_without_take_pnl,
+                need_take_pnl_coin: amm.pending_take_pnl_coin(),
+                need_take_pnl_pc: amm.pending_take_pnl_pc(),
+                swap_fee_numerator: fees.swap_fee_numerator,
+                swap_fee_denominator: fees.swap_fee_denominator,
+                pool_open_time: amm.state_data.pool_open_time,
+            };
+            set_return_data(bytemuck::bytes_of(&pool_info));
+            return Ok(());
+        }
 
-            (
-                token_program_info,
-                amm_info,
-                amm_authority_info,
-                amm_target_orders_info,
-                amm_lp_mint_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                user_source_coin_info,
-                user_source_pc_info,
-                user_dest_lp_info,
-                source_owner_info,
-            )
+        let source_mint_info = next_account_info(account_info_iter)?;
+        if amm.pc_vault_mint == amm.coin_vault_mint {
+            return Err(AmmError::NotAllowed.into());
+        }
+        let clock = Clock::get()?;
+        if !AmmStatus::from_u64(amm.status).swap_permission()
+            || (amm.status == AmmStatus::WaitingTrade.into_u64()
+                && (clock.unix_timestamp as u64) < amm.state_data.pool_open_time)
+        {
+            msg!(&format!("simulate_info: status {}", identity(amm.status)));
+            return Err(AmmError::InvalidStatus.into());
+        }
+        let swap_direction = if *source_mint_info.key == amm.coin_vault_mint {
+            SwapDirection::Coin2PC
+        } else if *source_mint_info.key == amm.pc_vault_mint {
+            SwapDirection::PC2Coin
         } else {
-            const ACCOUNT_LEN: usize = 14;
-            if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
+            return Err(AmmError::InvalidUserToken.into());
+        };
+        let (source_vault_token, destination_vault_token) = match swap_direction {
+            SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+            SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+        };
+        let swap_amounts = match (
+            param,
+            simulate.swap_base_in_value,
+            simulate.swap_base_out_value,
+        ) {
+            (SimulateParams::SwapBaseInInfo, Some(swap), _) => Self::calc_swap_base_in(
+                &fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                swap.amount_in,
+                clock.epoch,
+            )?,
+            (SimulateParams::SwapBaseOutInfo, _, Some(swap)) => Self::calc_swap_base_out(
+                &fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                swap.amount_out,
+                clock.epoch,
+            )?,
+            _ => return Err(AmmError::InvalidInput.into()),
+        };
 
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            let _amm_open_orders_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let (total_in, total_out) = match swap_direction {
+            SwapDirection::Coin2PC => (total_coin_without_take_pnl, total_pc_without_take_pnl),
+            SwapDirection::PC2Coin => (total_pc_without_take_pnl, total_coin_without_take_pnl),
+        };
+        let total_in_after_swap = total_in
+            .checked_add(swap_amounts.amount_in_after_transfer_fee)
+            .ok_or(AmmError::CheckedAddOverflow)?;
+        let total_out_after_swap = total_out
+            .checked_sub(swap_amounts.amount_out)
+            .ok_or(AmmError::InsufficientFunds)?;
+        let (pool_coin_amount, pool_pc_amount) = match swap_direction {
+            SwapDirection::Coin2PC => (total_in_after_swap, total_out_after_swap),
+            SwapDirection::PC2Coin => (total_out_after_swap, total_in_after_swap),
+        };
+        let swap_info = SimulateSwapInfo {
+            amount_in: swap_amounts.amount_in,
+            amount_in_after_transfer_fee: swap_amounts.amount_in_after_transfer_fee,
+            swap_fee: swap_amounts.swap_fee,
+            amount_out: swap_amounts.amount_out,
+            amount_out_after_transfer_fee: swap_amounts.amount_out_after_transfer_fee,
+            price_impact: Calculator::price_impact(
+                swap_amounts
+                    .amount_in_after_transfer_fee
+                    .saturating_sub(swap_amounts.swap_fee),
+                swap_amounts.amount_out,
+                total_in,
+                total_out,
+            ),
+            pool_coin_amount,
+            pool_pc_amount,
+            need_take_pnl_coin: amm.pending_take_pnl_coin(),
+            need_take_pnl_pc: amm.pending_take_pnl_pc(),
+        };
+        set_return_data(bytemuck::bytes_of(&swap_info));
 
-            let _market_info = next_account_info(account_info_iter)?;
+        Ok(())
+    }
 
-            let user_source_coin_info = next_account_info(account_info_iter)?;
-            let user_source_pc_info = next_account_info(account_info_iter)?;
-            let user_dest_lp_info = next_account_info(account_info_iter)?;
-            let source_owner_info = next_account_info(account_info_iter)?;
-            let _market_event_queue_info = next_account_info(account_info_iter)?;
+    /// Processes `process_create_observation` instruction.
+    pub fn process_create_observation(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let observation_info = next_account_info(account_info_iter)?;
+        let payer_info = next_account_info(account_info_iter)?;
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
 
-            (
-                token_program_info,
-                amm_info,
-                amm_authority_info,
-                amm_target_orders_info,
-                amm_lp_mint_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                user_source_coin_info,
-                user_source_pc_info,
-                user_dest_lp_info,
-                source_owner_info,
-            )
-        };
+        if !payer_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        // the observation account changes the swap accounts of the pool
+        Self::check_admin(program_id, admin_info, amm_config_info)?;
+        check_assert_eq!(
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
+        );
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        // create observation account
+        Self::generate_amm_associated_account(
+            program_id,
+            program_id,
+            amm_info,
+            observation_info,
+            payer_info,
+            system_program_info,
+            rent_sysvar_info,
+            OBSERVATION_ASSOCIATED_SEED,
+            size_of::<ObservationState>(),
+        )?;
+        let (_, bump) = get_associated_address_and_bump_seed(
+            program_id,
+            amm_info.key,
+            OBSERVATION_ASSOCIATED_SEED,
+            program_id,
+        );
+        let mut observation_state = ObservationState::load_mut(observation_info)?;
+        observation_state.initialize(amm_info.key, bump, Clock::get()?.unix_timestamp as u64)?;
+        // the swaps of the pool must pass the observation account from now on
+        amm.observation_enabled = 1;
+
+        Ok(())
+    }
+
+    /// Processes `process_set_dynamic_fee` instruction.
+    pub fn process_set_dynamic_fee(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        dynamic_fee: DynamicFeeInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let dynamic_fee_info = next_account_info(account_info_iter)?;
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
 
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if amm.pc_vault_mint == amm.coin_vault_mint {
-            return Err(AmmError::NotAllowed.into());
-        }
-        if deposit.max_coin_amount == 0 || deposit.max_pc_amount == 0 {
-            encode_ray_log(DepositLog {
-                log_type: LogType::Deposit.into_u8(),
-                max_coin: deposit.max_coin_amount,
-                max_pc: deposit.max_pc_amount,
-                base: deposit.base_side,
-                pool_coin: 0,
-                pool_pc: 0,
-                pool_lp: 0,
-                calc_pnl_x: 0,
-                calc_pnl_y: 0,
-                deduct_coin: 0,
-                deduct_pc: 0,
-                mint_lp: 0,
-            });
+        Self::check_admin(program_id, admin_info, amm_config_info)?;
+        check_assert_eq!(
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
+        );
+        if dynamic_fee.enabled > 1 {
             return Err(AmmError::InvalidInput.into());
         }
-        if !source_owner_info.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        amm.fees.validate(Some(&dynamic_fee.params))?;
+        // create dynamic fee account
+        if dynamic_fee_info.owner == system_program_info.key {
+            Self::generate_amm_associated_account(
+                program_id,
+                program_id,
+                amm_info,
+                dynamic_fee_info,
+                admin_info,
+                system_program_info,
+                rent_sysvar_info,
+                DYNAMIC_FEE_ASSOCIATED_SEED,
+                size_of::<DynamicFeeState>(),
+            )?;
+            DynamicFeeState::load_mut(dynamic_fee_info)?.initialize(amm_info.key)?;
         }
+        let mut dynamic_fee_state =
+            DynamicFeeState::load_mut_checked(dynamic_fee_info, program_id, amm_info.key)?;
+        dynamic_fee_state.params = dynamic_fee.params;
+        amm.dynamic_fee_enabled = dynamic_fee.enabled as u64;
+        amm.recent_epoch = Clock::get()?.epoch;
+        Ok(())
+    }
 
-        if !AmmStatus::from_u64(amm.status).deposit_permission() {
+    pub fn process_set_curve(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        set_curve: SetCurveInstruction,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+
+        Self::check_admin(program_id, admin_info, amm_config_info)?;
+        if !CurveType::valid_curve_type(set_curve.curve_type as u64) {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
             return Err(AmmError::InvalidStatus.into());
         }
-        if *amm_authority_info.key
-            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
-        {
-            return Err(AmmError::InvalidProgramAddress.into());
+        let now = Clock::get()?.unix_timestamp as u64;
+        let curve_type = CurveType::from_u64(set_curve.curve_type as u64);
+        if set_curve.curve_type as u64 != amm.curve_type {
+            // the pool price jumps with the curve, only before any swap
+            if amm.status != AmmStatus::WaitingTrade.into_u64()
+                || now >= amm.state_data.pool_open_time
+                || amm.curve_type == CurveType::Weighted.into_u64()
+            {
+                return Err(AmmError::NotAllowed.into());
+            }
+            let amp = match curve_type {
+                CurveType::ConstantProduct => 0,
+                CurveType::Stable => {
+                    if set_curve.amp < MIN_AMP || set_curve.amp > MAX_AMP {
+                        return Err(AmmError::InvalidInput.into());
+                    }
+                    set_curve.amp
+                }
+                CurveType::Weighted => return Err(AmmError::NotAllowed.into()),
+            };
+            amm.curve_type = curve_type.into_u64();
+            amm.initial_amp = amp;
+            amm.target_amp = amp;
+            amm.ramp_start_timestamp = now;
+            amm.ramp_stop_timestamp = now;
+        } else if curve_type == CurveType::Stable {
+            let current_amp = amm.amp(now);
+            if set_curve.amp == 0 {
+                // stop the ramp
+                amm.initial_amp = current_amp;
+                amm.target_amp = current_amp;
+                amm.ramp_start_timestamp = now;
+                amm.ramp_stop_timestamp = now;
+            } else {
+                if set_curve.amp < MIN_AMP
+                    || set_curve.amp > MAX_AMP
+                    || set_curve.amp > current_amp.saturating_mul(MAX_AMP_CHANGE)
+                    || current_amp > set_curve.amp.saturating_mul(MAX_AMP_CHANGE)
+                {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                if set_curve.ramp_stop_timestamp < now.saturating_add(MIN_RAMP_DURATION) {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                amm.initial_amp = current_amp;
+                amm.target_amp = set_curve.amp;
+                amm.ramp_start_timestamp = now;
+                amm.ramp_stop_timestamp = set_curve.ramp_stop_timestamp;
+            }
+        } else {
+            return Err(AmmError::InvalidInput.into());
+        }
+        amm.recent_epoch = Clock::get()?.epoch;
+        Ok(())
+    }
+
+    /// Processes `process_init_range_orders` instruction.
+    pub fn process_init_range_orders(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let amm_coin_mint_info = next_account_info(account_info_iter)?;
+        let amm_pc_mint_info = next_account_info(account_info_iter)?;
+        let range_coin_vault_info = next_account_info(account_info_iter)?;
+        let range_pc_vault_info = next_account_info(account_info_iter)?;
+        let payer_info = next_account_info(account_info_iter)?;
+
+        if !payer_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
         }
         check_assert_eq!(
             *token_program_info.key,
@@ -1110,1770 +6506,3630 @@ impl Processor {
             "spl_token_program",
             AmmError::InvalidSplTokenProgram
         );
-        let spl_token_program_id = token_program_info.key;
-        // token_coin must be amm.coin_vault or token_source_coin must not be amm.coin_vault
-        if *amm_coin_vault_info.key != amm.coin_vault
-            || *user_source_coin_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidCoinVault.into());
-        }
-        // token_pc must be amm.pc_vault or token_source_pc must not be amm.pc_vault
-        if *amm_pc_vault_info.key != amm.pc_vault || *user_source_pc_info.key == amm.pc_vault {
-            return Err(AmmError::InvalidPCVault.into());
-        }
         check_assert_eq!(
-            *amm_lp_mint_info.key,
-            amm.lp_mint,
-            "lp_mint",
-            AmmError::InvalidPoolMint
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
         );
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
+        if amm.status == AmmStatus::Uninitialized.into_u64() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
         check_assert_eq!(
             *amm_target_orders_info.key,
             amm.target_orders,
             "target_orders",
             AmmError::InvalidTargetOrders
         );
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-        let user_source_coin =
-            Self::unpack_token_account(&user_source_coin_info, spl_token_program_id)?;
-        let user_source_pc =
-            Self::unpack_token_account(&user_source_pc_info, spl_token_program_id)?;
-        let mut target_orders =
-            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-        // calc the remaining total_pc & total_coin
-        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
-
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
+        check_assert_eq!(
+            *amm_coin_mint_info.key,
+            amm.coin_vault_mint,
+            "coin_mint",
+            AmmError::InvalidCoinMint
         );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
+        check_assert_eq!(
+            *amm_pc_mint_info.key,
+            amm.pc_vault_mint,
+            "pc_mint",
+            AmmError::InvalidPCMint
         );
-        // calc and update pnl
-        let (delta_x, delta_y) = Self::calc_take_pnl(
-            &target_orders,
-            &mut amm,
-            &mut total_pc_without_take_pnl,
-            &mut total_coin_without_take_pnl,
-            x1.as_u128().into(),
-            y1.as_u128().into(),
-        )?;
-        let invariant = InvariantToken {
-            token_coin: total_coin_without_take_pnl,
-            token_pc: total_pc_without_take_pnl,
-        };
-
-        // let lp_mint  = Self::unpack_mint(&lp_mint_info, spl_token_program_id)?;
-        if amm.lp_amount == 0 {
-            encode_ray_log(DepositLog {
-                log_type: LogType::Deposit.into_u8(),
-                max_coin: deposit.max_coin_amount,
-                max_pc: deposit.max_pc_amount,
-                base: deposit.base_side,
-                pool_coin: total_coin_without_take_pnl,
-                pool_pc: total_pc_without_take_pnl,
-                pool_lp: amm.lp_amount,
-                calc_pnl_x: target_orders.calc_pnl_x,
-                calc_pnl_y: target_orders.calc_pnl_y,
-                deduct_coin: 0,
-                deduct_pc: 0,
-                mint_lp: 0,
-            });
-            return Err(AmmError::NotAllowZeroLP.into());
+        // the range orders are moved with spl_token only
+        if *amm_coin_mint_info.owner != spl_token::id()
+            || *amm_pc_mint_info.owner != spl_token::id()
+        {
+            return Err(AmmError::InvalidSplTokenProgram.into());
         }
-        let deduct_pc_amount;
-        let deduct_coin_amount;
-        let mint_lp_amount;
-        if deposit.base_side == 0 {
-            // base coin
-            deduct_pc_amount = invariant
-                .exchange_coin_to_pc(deposit.max_coin_amount, RoundDirection::Ceiling)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-            deduct_coin_amount = deposit.max_coin_amount;
-            if deduct_pc_amount > deposit.max_pc_amount {
-                encode_ray_log(DepositLog {
-                    log_type: LogType::Deposit.into_u8(),
-                    max_coin: deposit.max_coin_amount,
-                    max_pc: deposit.max_pc_amount,
-                    base: deposit.base_side,
-                    pool_coin: total_coin_without_take_pnl,
-                    pool_pc: total_pc_without_take_pnl,
-                    pool_lp: amm.lp_amount,
-                    calc_pnl_x: target_orders.calc_pnl_x,
-                    calc_pnl_y: target_orders.calc_pnl_y,
-                    deduct_coin: deduct_coin_amount,
-                    deduct_pc: deduct_pc_amount,
-                    mint_lp: 0,
-                });
-                return Err(AmmError::ExceededSlippage.into());
-            }
-            // base coin, check other_amount_min if need
-            if deposit.other_amount_min.is_some() {
-                if deduct_pc_amount < deposit.other_amount_min.unwrap() {
-                    encode_ray_log(DepositLog {
-                        log_type: LogType::Deposit.into_u8(),
-                        max_coin: deposit.max_coin_amount,
-                        max_pc: deposit.max_pc_amount,
-                        base: deposit.base_side,
-                        pool_coin: total_coin_without_take_pnl,
-                        pool_pc: total_pc_without_take_pnl,
-                        pool_lp: amm.lp_amount,
-                        calc_pnl_x: target_orders.calc_pnl_x,
-                        calc_pnl_y: target_orders.calc_pnl_y,
-                        deduct_coin: deduct_coin_amount,
-                        deduct_pc: deduct_pc_amount,
-                        mint_lp: 0,
-                    });
-                    return Err(AmmError::ExceededSlippage.into());
-                }
-            }
-            // coin_amount/ (total_coin_amount + coin_amount)  = output / (lp_mint.supply + output) =>  output = coin_amount / total_coin_amount * lp_mint.supply
-            let invariant_coin = InvariantPool {
-                token_input: deduct_coin_amount,
-                token_total: total_coin_without_take_pnl,
-            };
-            mint_lp_amount = invariant_coin
-                .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-        } else {
-            // base pc
-            deduct_coin_amount = invariant
-                .exchange_pc_to_coin(deposit.max_pc_amount, RoundDirection::Ceiling)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-            deduct_pc_amount = deposit.max_pc_amount;
-            if deduct_coin_amount > deposit.max_coin_amount {
-                encode_ray_log(DepositLog {
-                    log_type: LogType::Deposit.into_u8(),
-                    max_coin: deposit.max_coin_amount,
-                    max_pc: deposit.max_pc_amount,
-                    base: deposit.base_side,
-                    pool_coin: total_coin_without_take_pnl,
-                    pool_pc: total_pc_without_take_pnl,
-                    pool_lp: amm.lp_amount,
-                    calc_pnl_x: target_orders.calc_pnl_x,
-                    calc_pnl_y: target_orders.calc_pnl_y,
-                    deduct_coin: deduct_coin_amount,
-                    deduct_pc: deduct_pc_amount,
-                    mint_lp: 0,
-                });
-                return Err(AmmError::ExceededSlippage.into());
-            }
-            // base pc, check other_amount_min if need
-            if deposit.other_amount_min.is_some() {
-                if deduct_coin_amount < deposit.other_amount_min.unwrap() {
-                    encode_ray_log(DepositLog {
-                        log_type: LogType::Deposit.into_u8(),
-                        max_coin: deposit.max_coin_amount,
-                        max_pc: deposit.max_pc_amount,
-                        base: deposit.base_side,
-                        pool_coin: total_coin_without_take_pnl,
-                        pool_pc: total_pc_without_take_pnl,
-                        pool_lp: amm.lp_amount,
-                        calc_pnl_x: target_orders.calc_pnl_x,
-                        calc_pnl_y: target_orders.calc_pnl_y,
-                        deduct_coin: deduct_coin_amount,
-                        deduct_pc: deduct_pc_amount,
-                        mint_lp: 0,
-                    });
-                    return Err(AmmError::ExceededSlippage.into());
-                }
-            }
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+        // create range order coin vault account
+        Self::generate_amm_associated_spl_token(
+            program_id,
+            token_program_info.key,
+            amm_info,
+            range_coin_vault_info,
+            amm_coin_mint_info,
+            payer_info,
+            system_program_info,
+            rent_sysvar_info,
+            token_program_info,
+            amm_authority_info,
+            RANGE_ORDER_COIN_VAULT_SEED,
+        )?;
+        // create range order pc vault account
+        Self::generate_amm_associated_spl_token(
+            program_id,
+            token_program_info.key,
+            amm_info,
+            range_pc_vault_info,
+            amm_pc_mint_info,
+            payer_info,
+            system_program_info,
+            rent_sysvar_info,
+            token_program_info,
+            amm_authority_info,
+            RANGE_ORDER_PC_VAULT_SEED,
+        )?;
+        target_orders.init_range_orders(range_coin_vault_info.key, range_pc_vault_info.key)?;
+        Ok(())
+    }
 
-            let invariant_pc = InvariantPool {
-                token_input: deduct_pc_amount,
-                token_total: total_pc_without_take_pnl,
-            };
-            // pc_amount/ (total_pc_amount + pc_amount)  = output / (lp_mint.supply + output) =>  output = pc_amount / total_pc_amount * lp_mint.supply
-            mint_lp_amount = invariant_pc
-                .exchange_token_to_pool(amm.lp_amount, RoundDirection::Floor)
-                .ok_or(AmmError::CalculationExRateFailure)?;
-        }
-        encode_ray_log(DepositLog {
-            log_type: LogType::Deposit.into_u8(),
-            max_coin: deposit.max_coin_amount,
-            max_pc: deposit.max_pc_amount,
-            base: deposit.base_side,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            pool_lp: amm.lp_amount,
-            calc_pnl_x: target_orders.calc_pnl_x,
-            calc_pnl_y: target_orders.calc_pnl_y,
-            deduct_coin: deduct_coin_amount,
-            deduct_pc: deduct_pc_amount,
-            mint_lp: mint_lp_amount,
-        });
+    /// Processes `process_place_range_order` instruction.
+    pub fn process_place_range_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        place: PlaceRangeOrderInstruction,
+    ) -> ProgramResult {
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+        let range_vault_info = next_account_info(account_info_iter)?;
+        let range_order_info = next_account_info(account_info_iter)?;
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_owner_info = next_account_info(account_info_iter)?;
+        let amm_vault_info = next_account_info(account_info_iter)?;
 
-        if deduct_coin_amount > user_source_coin.amount || deduct_pc_amount > user_source_pc.amount
-        {
-            return Err(AmmError::InsufficientFunds.into());
+        if !user_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
         }
-        if mint_lp_amount == 0 || deduct_coin_amount == 0 || deduct_pc_amount == 0 {
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        check_assert_eq!(
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
+        );
+        let side = OrderSide::from_u64(place.side as u64)?;
+        if place.price == 0 || place.amount == 0 {
             return Err(AmmError::InvalidInput.into());
         }
-
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_source_coin_info.clone(),
-            amm_coin_vault_info.clone(),
-            source_owner_info.clone(),
-            deduct_coin_amount,
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+        if !target_orders.range_orders_enabled()
+            || *range_vault_info.key != target_orders.range_order_vault(side)
+        {
+            return Err(AmmError::InvalidRangeOrderAccount.into());
+        }
+        // a minimum size against the pool reserve makes taking the slots costly
+        let amm_vault = match side {
+            OrderSide::Buy => amm.pc_vault,
+            OrderSide::Sell => amm.coin_vault,
+        };
+        if *amm_vault_info.key != amm_vault {
+            return Err(AmmError::InvalidRangeOrderAccount.into());
+        }
+        let amm_vault = Self::unpack_token_account(amm_vault_info, &spl_token::id())?;
+        if place.amount < amm_vault.amount / RANGE_ORDER_MIN_SIZE_DIVISOR {
+            return Err(AmmError::RangeOrderTooSmall.into());
+        }
+        target_orders.take_range_order_slot(
+            side,
+            place.slot as usize,
+            place.price,
+            place.amount,
         )?;
-        Invokers::token_transfer(
-            token_program_info.clone(),
-            user_source_pc_info.clone(),
-            amm_pc_vault_info.clone(),
-            source_owner_info.clone(),
-            deduct_pc_amount,
+        // create range order account of the slot
+        let range_order_seed = [RANGE_ORDER_SEED, &[place.side, place.slot]].concat();
+        Self::generate_amm_associated_account(
+            program_id,
+            program_id,
+            amm_info,
+            range_order_info,
+            user_owner_info,
+            system_program_info,
+            rent_sysvar_info,
+            &range_order_seed,
+            size_of::<RangeOrderState>(),
         )?;
-        Invokers::token_mint_to(
+        RangeOrderState::load_mut(range_order_info)?.initialize(
+            amm_info.key,
+            user_owner_info.key,
+            side,
+            place.slot as u64,
+            place.price,
+            place.amount,
+        );
+        Invokers::token_transfer(
             token_program_info.clone(),
-            amm_lp_mint_info.clone(),
-            user_dest_lp_info.clone(),
-            amm_authority_info.clone(),
-            AUTHORITY_AMM,
-            amm.nonce as u8,
-            mint_lp_amount,
+            user_source_info.clone(),
+            range_vault_info.clone(),
+            user_owner_info.clone(),
+            place.amount,
         )?;
-        amm.lp_amount = amm.lp_amount.checked_add(mint_lp_amount).unwrap();
-
-        target_orders.calc_pnl_x = x1
-            .checked_add(Calculator::normalize_decimal_v2(
-                deduct_pc_amount,
-                amm.pc_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_x))
-            .unwrap()
-            .as_u128();
-        target_orders.calc_pnl_y = y1
-            .checked_add(Calculator::normalize_decimal_v2(
-                deduct_coin_amount,
-                amm.coin_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_y))
-            .unwrap()
-            .as_u128();
-        amm.recent_epoch = Clock::get()?.epoch;
         Ok(())
     }
 
-    pub fn process_withdrawpnl(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    /// Processes `process_cancel_range_order` and `process_claim_range_order` instructions.
+    /// A cancelled or fully filled order frees its slot and closes its account.
+    pub fn process_claim_range_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        cancel: bool,
+    ) -> ProgramResult {
+        let accounts = Self::split_amm_config_account(program_id, accounts, true)?;
         let account_info_iter = &mut accounts.iter();
         let token_program_info = next_account_info(account_info_iter)?;
-
         let amm_info = next_account_info(account_info_iter)?;
-        let amm_config_info = next_account_info(account_info_iter)?;
         let amm_authority_info = next_account_info(account_info_iter)?;
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
-        let user_pnl_coin_info = next_account_info(account_info_iter)?;
-        let user_pnl_pc_info = next_account_info(account_info_iter)?;
-        let pnl_owner_info = next_account_info(account_info_iter)?;
         let amm_target_orders_info = next_account_info(account_info_iter)?;
-
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        let range_coin_vault_info = next_account_info(account_info_iter)?;
+        let range_pc_vault_info = next_account_info(account_info_iter)?;
+        let range_order_info = next_account_info(account_info_iter)?;
+        let user_coin_info = next_account_info(account_info_iter)?;
+        let user_pc_info = next_account_info(account_info_iter)?;
+        let user_owner_info = next_account_info(account_info_iter)?;
+
+        if !user_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
         if *amm_authority_info.key
             != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
         {
             return Err(AmmError::InvalidProgramAddress.into());
         }
-        if amm_info.owner != program_id {
+        check_assert_eq!(
+            *amm_target_orders_info.key,
+            amm.target_orders,
+            "target_orders",
+            AmmError::InvalidTargetOrders
+        );
+        let mut target_orders =
+            TargetOrders::load_mut_checked(amm_target_orders_info, program_id, amm_info.key)?;
+        if *range_coin_vault_info.key != target_orders.range_coin_vault
+            || *range_pc_vault_info.key != target_orders.range_pc_vault
+        {
+            return Err(AmmError::InvalidRangeOrderAccount.into());
+        }
+        let mut range_order =
+            RangeOrderState::load_mut_checked(range_order_info, program_id, amm_info.key)?;
+        if range_order.owner != *user_owner_info.key {
             return Err(AmmError::InvalidOwner.into());
         }
+        let side = OrderSide::from_u64(range_order.side)?;
+        let slot = range_order.slot as usize;
+        let vol = target_orders.range_order_mut(side, slot).vol;
+        let filled = range_order
+            .amount
+            .checked_sub(vol)
+            .ok_or(AmmError::CheckedSubOverflow)?;
+        let proceeds = range_order.claimable(&amm, filled);
+        range_order.claimed_fill = filled;
+        // buy orders are paid in coin and refunded in pc, sell orders the other way round
+        let (proceeds_vault_info, proceeds_user_info, refund_vault_info, refund_user_info) =
+            match side {
+                OrderSide::Buy => (
+                    range_coin_vault_info,
+                    user_coin_info,
+                    range_pc_vault_info,
+                    user_pc_info,
+                ),
+                OrderSide::Sell => (
+                    range_pc_vault_info,
+                    user_pc_info,
+                    range_coin_vault_info,
+                    user_coin_info,
+                ),
+            };
+        if proceeds > 0 {
+            Invokers::token_transfer_with_authority(
+                token_program_info.clone(),
+                proceeds_vault_info.clone(),
+                proceeds_user_info.clone(),
+                amm_authority_info.clone(),
+                AUTHORITY_AMM,
+                amm.nonce as u8,
+                proceeds,
+            )?;
+        }
+        if cancel && vol > 0 {
+            Invokers::token_transfer_with_authority(
+                token_program_info.clone(),
+                refund_vault_info.clone(),
+                refund_user_info.clone(),
+                amm_authority_info.clone(),
+                AUTHORITY_AMM,
+                amm.nonce as u8,
+                vol,
+            )?;
+        }
+        if cancel || vol == 0 {
+            target_orders.free_range_order_slot(side, slot);
+            drop(range_order);
+            Self::close_amm_associated_account(range_order_info, user_owner_info)?;
+        }
+        Ok(())
+    }
 
-        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
-        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
-            return Err(AmmError::InvalidConfigAccount.into());
+    /// Processes `process_place_limit_order` instruction.
+    pub fn process_place_limit_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        place: PlaceLimitOrderInstruction,
+    ) -> ProgramResult {
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let amm_coin_mint_info = next_account_info(account_info_iter)?;
+        let amm_pc_mint_info = next_account_info(account_info_iter)?;
+        let limit_coin_vault_info = next_account_info(account_info_iter)?;
+        let limit_pc_vault_info = next_account_info(account_info_iter)?;
+        let limit_order_info = next_account_info(account_info_iter)?;
+        let user_source_info = next_account_info(account_info_iter)?;
+        let user_owner_info = next_account_info(account_info_iter)?;
+
+        if !user_owner_info.is_signer {
+            return Err(AmmError::InvalidSignAccount.into());
         }
-        let amm_config = AmmConfig::load_checked(&amm_config_info, program_id)?;
+        check_assert_eq!(
+            *token_program_info.key,
+            spl_token::id(),
+            "spl_token_program",
+            AmmError::InvalidSplTokenProgram
+        );
+        check_assert_eq!(
+            *system_program_info.key,
+            solana_program::system_program::id(),
+            "sys_program",
+            AmmError::InvalidSysProgramAddress
+        );
+        let side = OrderSide::from_u64(place.side as u64)?;
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
+        if !AmmStatus::from_u64(amm.status).swap_permission() {
+            return Err(AmmError::InvalidStatus.into());
+        }
+        if place.price == 0 || place.amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
+        }
+        check_assert_eq!(
+            *amm_coin_mint_info.key,
+            amm.coin_vault_mint,
+            "coin_mint",
+            AmmError::InvalidCoinMint
+        );
+        check_assert_eq!(
+            *amm_pc_mint_info.key,
+            amm.pc_vault_mint,
+            "pc_mint",
+            AmmError::InvalidPCMint
+        );
+        // the limit orders are moved with spl_token only
+        if *amm_coin_mint_info.owner != spl_token::id()
+            || *amm_pc_mint_info.owner != spl_token::id()
+        {
+            return Err(AmmError::InvalidSplTokenProgram.into());
+        }
+        // create the limit order vaults of the pool with its first order
+        for (vault_info, mint_info, seed) in [
+            (
+                limit_coin_vault_info,
+                amm_coin_mint_info,
+                LIMIT_ORDER_COIN_VAULT_SEED,
+            ),
+            (
+                limit_pc_vault_info,
+                amm_pc_mint_info,
+                LIMIT_ORDER_PC_VAULT_SEED,
+            ),
+        ] {
+            if vault_info.owner == system_program_info.key {
+                Self::generate_amm_associated_spl_token(
+                    program_id,
+                    token_program_info.key,
+                    amm_info,
+                    vault_info,
+                    mint_info,
+                    user_owner_info,
+                    system_program_info,
+                    rent_sysvar_info,
+                    token_program_info,
+                    amm_authority_info,
+                    seed,
+                )?;
+            }
+        }
+        Self::check_limit_order_vaults(
+            program_id,
+            amm_info.key,
+            limit_coin_vault_info,
+            limit_pc_vault_info,
+        )?;
+
+        // create limit order account
+        let order_id = place.order_id.to_le_bytes();
+        let (pda, bump_seed) = Pubkey::find_program_address(
+            &[
+                LIMIT_ORDER_SEED,
+                amm_info.key.as_ref(),
+                user_owner_info.key.as_ref(),
+                &order_id,
+            ],
+            program_id,
+        );
+        if pda != *limit_order_info.key {
+            return Err(AmmError::InvalidLimitOrderAccount.into());
+        }
+        if limit_order_info.owner != system_program_info.key {
+            return Err(AmmError::AlreadyInUse.into());
+        }
+        let pda_signer_seeds: &[&[_]] = &[
+            LIMIT_ORDER_SEED,
+            amm_info.key.as_ref(),
+            user_owner_info.key.as_ref(),
+            &order_id,
+            &[bump_seed],
+        ];
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+        let data_size = size_of::<LimitOrderState>();
+        let required_lamports = rent
+            .minimum_balance(data_size)
+            .max(1)
+            .saturating_sub(limit_order_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    user_owner_info.key,
+                    limit_order_info.key,
+                    required_lamports,
+                ),
+                &[
+                    user_owner_info.clone(),
+                    limit_order_info.clone(),
+                    system_program_info.clone(),
+                ],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(limit_order_info.key, data_size as u64),
+            &[limit_order_info.clone(), system_program_info.clone()],
+            &[pda_signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(limit_order_info.key, program_id),
+            &[limit_order_info.clone(), system_program_info.clone()],
+            &[pda_signer_seeds],
+        )?;
+        LimitOrderState::load_mut(limit_order_info)?.initialize(
+            amm_info.key,
+            user_owner_info.key,
+            place.order_id,
+            side,
+            place.price,
+            place.amount,
+        );
+
+        let escrow_vault_info = match side {
+            OrderSide::Buy => limit_pc_vault_info,
+            OrderSide::Sell => limit_coin_vault_info,
+        };
+        Invokers::token_transfer(
+            token_program_info.clone(),
+            user_source_info.clone(),
+            escrow_vault_info.clone(),
+            user_owner_info.clone(),
+            place.amount,
+        )?;
+        Ok(())
+    }
 
-        if !pnl_owner_info.is_signer
-            || (*pnl_owner_info.key != config_feature::amm_owner::ID
-                && *pnl_owner_info.key != amm_config.pnl_owner)
-        {
+    /// Processes `process_cancel_limit_order` and `process_claim_limit_order` instructions.
+    /// A cancelled or executed order closes its account.
+    pub fn process_claim_limit_order(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        cancel: bool,
+    ) -> ProgramResult {
+        let accounts = Self::split_amm_config_account(program_id, accounts, true)?;
+        let account_info_iter = &mut accounts.iter();
+        let token_program_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_authority_info = next_account_info(account_info_iter)?;
+        let limit_coin_vault_info = next_account_info(account_info_iter)?;
+        let limit_pc_vault_info = next_account_info(account_info_iter)?;
+        let limit_order_info = next_account_info(account_info_iter)?;
+        let user_coin_info = next_account_info(account_info_iter)?;
+        let user_pc_info = next_account_info(account_info_iter)?;
+        let user_owner_info = next_account_info(account_info_iter)?;
+
+        if !user_owner_info.is_signer {
             return Err(AmmError::InvalidSignAccount.into());
         }
-        // withdrawpnl in all status except Uninitialized
-        if amm.status == AmmStatus::Uninitialized.into_u64() {
-            msg!(&format!("withdrawpnl: status {}", identity(amm.status)));
-            return Err(AmmError::InvalidStatus.into());
-        }
-        check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
-        );
-        check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
-        );
         check_assert_eq!(
             *token_program_info.key,
             spl_token::id(),
             "spl_token_program",
             AmmError::InvalidSplTokenProgram
         );
-
-        let spl_token_program_id = token_program_info.key;
-
-        check_assert_eq!(
-            *amm_target_orders_info.key,
-            amm.target_orders,
-            "target_orders",
-            AmmError::InvalidTargetOrders
-        );
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-        let user_pnl_coin = Self::unpack_token_account(&user_pnl_coin_info, spl_token_program_id)?;
-        let user_pnl_pc = Self::unpack_token_account(&user_pnl_pc_info, spl_token_program_id)?;
-        let mut target_orders =
-            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
-        if amm_coin_vault.mint != amm.coin_vault_mint || user_pnl_coin.mint != amm.coin_vault_mint {
-            return Err(AmmError::InvalidCoinMint.into());
-        }
-        if amm_pc_vault.mint != amm.pc_vault_mint || user_pnl_pc.mint != amm.pc_vault_mint {
-            return Err(AmmError::InvalidPCMint.into());
+        let amm = AmmInfo::load_checked(amm_info, program_id)?;
+        if *amm_authority_info.key
+            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
+        {
+            return Err(AmmError::InvalidProgramAddress.into());
         }
-
-        // calc the remaining total_pc & total_coin
-        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
-
-        msg!(arrform!(
-            LOG_SIZE,
-            "withdrawpnl need_take_coin:{}, need_take_pc:{}",
-            identity(amm.state_data.need_take_pnl_coin),
-            identity(amm.state_data.need_take_pnl_pc)
-        )
-        .as_str());
-
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
-        );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
-        );
-        msg!(arrform!(
-            LOG_SIZE,
-            "withdrawpnl total_pc:{}, total_coin:{}, x:{}, y:{}",
-            total_pc_without_take_pnl,
-            total_coin_without_take_pnl,
-            x1,
-            y1
-        )
-        .as_str());
-
-        // calc and update pnl
-        let (delta_x, delta_y) = Self::calc_take_pnl(
-            &target_orders,
-            &mut amm,
-            &mut total_pc_without_take_pnl,
-            &mut total_coin_without_take_pnl,
-            x1.as_u128().into(),
-            y1.as_u128().into(),
+        Self::check_limit_order_vaults(
+            program_id,
+            amm_info.key,
+            limit_coin_vault_info,
+            limit_pc_vault_info,
         )?;
-        msg!(arrform!(LOG_SIZE, "withdrawpnl total_pc:{}, total_pc:{}, delta_x:{}, delta_y:{}, need_take_coin:{}, need_take_pc:{}",total_pc_without_take_pnl, total_coin_without_take_pnl, delta_x, delta_y, identity(amm.state_data.need_take_pnl_coin), identity(amm.state_data.need_take_pnl_pc)).as_str());
-
-        if amm.state_data.need_take_pnl_coin <= amm_coin_vault.amount
-            && amm.state_data.need_take_pnl_pc <= amm_pc_vault.amount
-        {
-            // coin & pc is enough, transfer directly
+        let mut limit_order =
+            LimitOrderState::load_mut_checked(limit_order_info, program_id, amm_info.key)?;
+        if limit_order.owner != *user_owner_info.key {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        // buy orders are paid in coin and refunded in pc, sell orders the other way round
+        let (proceeds_vault_info, proceeds_user_info, refund_vault_info, refund_user_info) =
+            match OrderSide::from_u64(limit_order.side)? {
+                OrderSide::Buy => (
+                    limit_coin_vault_info,
+                    user_coin_info,
+                    limit_pc_vault_info,
+                    user_pc_info,
+                ),
+                OrderSide::Sell => (
+                    limit_pc_vault_info,
+                    user_pc_info,
+                    limit_coin_vault_info,
+                    user_coin_info,
+                ),
+            };
+        let (proceeds, refund) = (limit_order.proceeds, limit_order.amount);
+        if proceeds > 0 {
             Invokers::token_transfer_with_authority(
                 token_program_info.clone(),
-                amm_coin_vault_info.clone(),
-                user_pnl_coin_info.clone(),
+                proceeds_vault_info.clone(),
+                proceeds_user_info.clone(),
                 amm_authority_info.clone(),
                 AUTHORITY_AMM,
                 amm.nonce as u8,
-                amm.state_data.need_take_pnl_coin,
+                proceeds,
             )?;
+            limit_order.proceeds = 0;
+        }
+        if cancel && refund > 0 {
             Invokers::token_transfer_with_authority(
                 token_program_info.clone(),
-                amm_pc_vault_info.clone(),
-                user_pnl_pc_info.clone(),
+                refund_vault_info.clone(),
+                refund_user_info.clone(),
                 amm_authority_info.clone(),
                 AUTHORITY_AMM,
                 amm.nonce as u8,
-                amm.state_data.need_take_pnl_pc,
+                refund,
             )?;
-            // clear need take pnl
-            amm.state_data.need_take_pnl_coin = 0u64;
-            amm.state_data.need_take_pnl_pc = 0u64;
-            // update target_orders.calc_pnl_x & target_orders.calc_pnl_y
-            target_orders.calc_pnl_x = x1.checked_sub(U128::from(delta_x)).unwrap().as_u128();
-            target_orders.calc_pnl_y = y1.checked_sub(U128::from(delta_y)).unwrap().as_u128();
-        } else {
-            // calc error
-            return Err(AmmError::TakePnlError.into());
+            limit_order.amount = 0;
+        }
+        if limit_order.amount == 0 {
+            drop(limit_order);
+            Self::close_amm_associated_account(limit_order_info, user_owner_info)?;
         }
-        amm.recent_epoch = Clock::get()?.epoch;
-
         Ok(())
     }
 
-    /// Processes an [Withdraw](enum.Instruction.html).
-    pub fn process_withdraw(
+    pub fn process_set_params(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        withdraw: WithdrawInstruction,
+        setparams: SetParamsInstruction,
     ) -> ProgramResult {
-        let input_account_len = accounts.len();
-        let (
-            token_program_info,
-            amm_info,
-            amm_authority_info,
-            amm_target_orders_info,
-            amm_lp_mint_info,
-            amm_coin_vault_info,
-            amm_pc_vault_info,
-            user_source_lp_info,
-            user_dest_coin_info,
-            user_dest_pc_info,
-            source_lp_owner_info,
-        ) = if input_account_len == 11 {
-            // Recommended use due to openbook has not supported.
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
 
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_owner_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        // only the timelocked params need the target orders
+        let amm_target_orders_info = account_info_iter.next();
 
-            let user_source_lp_info = next_account_info(account_info_iter)?;
-            let user_dest_coin_info = next_account_info(account_info_iter)?;
-            let user_dest_pc_info = next_account_info(account_info_iter)?;
-            let source_lp_owner_info = next_account_info(account_info_iter)?;
+        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
 
-            (
-                token_program_info,
-                amm_info,
-                amm_authority_info,
-                amm_target_orders_info,
-                amm_lp_mint_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                user_source_lp_info,
-                user_dest_coin_info,
-                user_dest_pc_info,
-                source_lp_owner_info,
-            )
-        } else {
-            const ACCOUNT_LEN: usize = 20;
-            if input_account_len != ACCOUNT_LEN
-                && input_account_len != ACCOUNT_LEN + 1
-                && input_account_len != ACCOUNT_LEN + 2
-                && input_account_len != ACCOUNT_LEN + 3
-            {
-                return Err(AmmError::WrongAccountsNumber.into());
-            }
-            let account_info_iter = &mut accounts.iter();
-            let token_program_info = next_account_info(account_info_iter)?;
+        if amm_info.owner != program_id {
+            return Err(AmmError::InvalidOwner.into());
+        }
+        Self::check_admin(program_id, amm_owner_info, amm_config_info)?;
+        let target_orders_account =
+            || amm_target_orders_info.ok_or(ProgramError::NotEnoughAccountKeys);
 
-            let amm_info = next_account_info(account_info_iter)?;
-            let amm_authority_info = next_account_info(account_info_iter)?;
-            let _amm_open_orders_info = next_account_info(account_info_iter)?;
-            let amm_target_orders_info = next_account_info(account_info_iter)?;
-            let amm_lp_mint_info = next_account_info(account_info_iter)?;
-            let amm_coin_vault_info = next_account_info(account_info_iter)?;
-            let amm_pc_vault_info = next_account_info(account_info_iter)?;
-            if input_account_len == ACCOUNT_LEN + 2 || input_account_len == ACCOUNT_LEN + 3 {
-                let _padding_account_info1 = next_account_info(account_info_iter)?;
-                let _padding_account_info2 = next_account_info(account_info_iter)?;
+        let old_fees = amm.fees;
+        let param = setparams.param;
+        match AmmParams::from_u64(param as u64).unwrap() {
+            AmmParams::Status => {
+                let status = match setparams.value {
+                    Some(status) => {
+                        if AmmStatus::valid_status(status) {
+                            status
+                        } else {
+                            return Err(AmmError::InvalidInput.into());
+                        }
+                    }
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+                let amm_target_orders_info = target_orders_account()?;
+                Self::log_set_params(
+                    program_id,
+                    event_accounts,
+                    amm_info,
+                    AmmParams::Status,
+                    amm.status,
+                    status,
+                    &Fees::default(),
+                    &Fees::default(),
+                )?;
+                let mut target_orders =
+                    Self::load_pending_params(program_id, amm_info, &amm, amm_target_orders_info)?;
+                if status == AmmStatus::Disabled.into_u64()
+                    || status == AmmStatus::WithdrawOnly.into_u64()
+                {
+                    // emergency stop, a queued status must not reopen the pool later
+                    target_orders.clear_pending_status();
+                    amm.status = status;
+                    Self::log_params(
+                        program_id,
+                        event_accounts,
+                        LogType::ApplyParams,
+                        AmmParams::Status,
+                        status,
+                        &Fees::default(),
+                        Clock::get()?.unix_timestamp as u64,
+                    )?;
+                } else {
+                    let effective_time = Self::params_effective_time(program_id, amm_config_info)?;
+                    target_orders.queue_status(status, effective_time);
+                    Self::log_params(
+                        program_id,
+                        event_accounts,
+                        LogType::QueueParams,
+                        AmmParams::Status,
+                        status,
+                        &Fees::default(),
+                        effective_time,
+                    )?;
+                }
+            }
+            AmmParams::State => {
+                match setparams.value {
+                    Some(state) => {
+                        if AmmState::valid_state(state) {
+                            Self::log_set_params(
+                                program_id,
+                                event_accounts,
+                                amm_info,
+                                AmmParams::State,
+                                amm.state,
+                                state,
+                                &Fees::default(),
+                                &Fees::default(),
+                            )?;
+                            amm.state = state as u64;
+                        } else {
+                            return Err(AmmError::InvalidInput.into());
+                        }
+                    }
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+            }
+            AmmParams::Fees => {
+                let fees = match setparams.fees {
+                    Some(a) => a,
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
+                let amm_target_orders_info = target_orders_account()?;
+                let dynamic_fee_params = if amm.dynamic_fee_enabled == 0 {
+                    None
+                } else {
+                    let dynamic_fee_info = next_account_info(account_info_iter)?;
+                    Some(
+                        DynamicFeeState::load_checked(dynamic_fee_info, program_id, amm_info.key)?
+                            .params,
+                    )
+                };
+                fees.validate(dynamic_fee_params.as_ref())?;
+                let mut target_orders =
+                    Self::load_pending_params(program_id, amm_info, &amm, amm_target_orders_info)?;
+                let effective_time = Self::params_effective_time(program_id, amm_config_info)?;
+                target_orders.queue_fees(fees, effective_time);
+                Self::log_set_params(
+                    program_id,
+                    event_accounts,
+                    amm_info,
+                    AmmParams::Fees,
+                    0,
+                    0,
+                    &old_fees,
+                    &fees,
+                )?;
+                Self::log_params(
+                    program_id,
+                    event_accounts,
+                    LogType::QueueParams,
+                    AmmParams::Fees,
+                    0,
+                    &fees,
+                    effective_time,
+                )?;
+            }
+            AmmParams::SetOpenTime => {
+                match setparams.value {
+                    Some(time) => {
+                        Self::log_set_params(
+                            program_id,
+                            event_accounts,
+                            amm_info,
+                            AmmParams::SetOpenTime,
+                            amm.state_data.pool_open_time,
+                            time,
+                            &Fees::default(),
+                            &Fees::default(),
+                        )?;
+                        amm.state_data.pool_open_time = time as u64;
+                    }
+                    None => return Err(AmmError::InvalidInput.into()),
+                };
             }
-
-            let _market_program_info = next_account_info(account_info_iter)?;
-            let _market_info = next_account_info(account_info_iter)?;
-            let _market_coin_vault_info = next_account_info(account_info_iter)?;
-            let _market_pc_vault_info = next_account_info(account_info_iter)?;
-            let _market_vault_signer = next_account_info(account_info_iter)?;
-
-            let user_source_lp_info = next_account_info(account_info_iter)?;
-            let user_dest_coin_info = next_account_info(account_info_iter)?;
-            let user_dest_pc_info = next_account_info(account_info_iter)?;
-            let source_lp_owner_info = next_account_info(account_info_iter)?;
-
-            let _market_event_q_info = next_account_info(account_info_iter)?;
-            let _market_bids_info = next_account_info(account_info_iter)?;
-            let _market_asks_info = next_account_info(account_info_iter)?;
-
-            (
-                token_program_info,
-                amm_info,
-                amm_authority_info,
-                amm_target_orders_info,
-                amm_lp_mint_info,
-                amm_coin_vault_info,
-                amm_pc_vault_info,
-                user_source_lp_info,
-                user_dest_coin_info,
-                user_dest_pc_info,
-                source_lp_owner_info,
-            )
-        };
-
-        if !source_lp_owner_info.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
         }
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        let mut target_orders =
-            TargetOrders::load_mut_checked(&amm_target_orders_info, program_id, amm_info.key)?;
+        amm.recent_epoch = Clock::get()?.epoch;
+        Ok(())
+    }
 
-        if !AmmStatus::from_u64(amm.status).withdraw_permission() {
-            return Err(AmmError::InvalidStatus.into());
-        }
-        if *amm_authority_info.key
-            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
-        {
-            return Err(AmmError::InvalidProgramAddress.into());
+    /// Processes `process_apply_params` instruction.
+    pub fn process_apply_params(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let amm_info = next_account_info(account_info_iter)?;
+        let amm_target_orders_info = next_account_info(account_info_iter)?;
+
+        let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+        let mut target_orders =
+            Self::load_pending_params(program_id, amm_info, &amm, amm_target_orders_info)?;
+        let now = Clock::get()?.unix_timestamp as u64;
+        let mut applied = false;
+        if let Some(status) = target_orders.take_effective_status(now) {
+            amm.status = status;
+            Self::log_params(
+                program_id,
+                event_accounts,
+                LogType::ApplyParams,
+                AmmParams::Status,
+                status,
+                &Fees::default(),
+                now,
+            )?;
+            applied = true;
         }
-        check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
-        );
-        let spl_token_program_id = token_program_info.key;
-        // token_coin must be amm.coin_vault or token_dest_coin must not be amm.coin_vault
-        if *amm_coin_vault_info.key != amm.coin_vault || *user_dest_coin_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidCoinVault.into());
+        if let Some(fees) = target_orders.take_effective_fees(now) {
+            // the dynamic fee bounds may have changed since the fees were queued
+            let dynamic_fee_params = if amm.dynamic_fee_enabled == 0 {
+                None
+            } else {
+                let dynamic_fee_info = next_account_info(account_info_iter)?;
+                Some(
+                    DynamicFeeState::load_checked(dynamic_fee_info, program_id, amm_info.key)?
+                        .params,
+                )
+            };
+            fees.validate(dynamic_fee_params.as_ref())?;
+            amm.fees = fees;
+            Self::log_params(
+                program_id,
+                event_accounts,
+                LogType::ApplyParams,
+                AmmParams::Fees,
+                0,
+                &fees,
+                now,
+            )?;
+            applied = true;
         }
-        // token_pc must be amm.pc_vault or token_dest_pc must not be amm.pc_vault
-        if *amm_pc_vault_info.key != amm.pc_vault || *user_dest_pc_info.key == amm.pc_vault {
-            return Err(AmmError::InvalidPCVault.into());
+        if !applied {
+            return Err(AmmError::PendingParamsNotEffective.into());
         }
-        check_assert_eq!(
-            *amm_target_orders_info.key,
-            amm.target_orders,
-            "target_orders",
-            AmmError::InvalidTargetOrders
-        );
-        check_assert_eq!(
-            *amm_lp_mint_info.key,
-            amm.lp_mint,
-            "lp_mint",
-            AmmError::InvalidPoolMint
-        );
+        amm.recent_epoch = Clock::get()?.epoch;
+        Ok(())
+    }
 
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-        let user_dest_coin =
-            Self::unpack_token_account(&user_dest_coin_info, spl_token_program_id)?;
-        let user_dest_pc = Self::unpack_token_account(&user_dest_pc_info, spl_token_program_id)?;
+    /// Processes `process_pause` instruction.
+    pub fn process_pause(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        pause: PauseInstruction,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let guardian_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
 
-        if user_dest_coin.mint != amm_coin_vault.mint || amm.coin_vault_mint != user_dest_coin.mint
+        let status = pause.status as u64;
+        if status != AmmStatus::Disabled.into_u64() && status != AmmStatus::WithdrawOnly.into_u64()
         {
-            return Err(AmmError::InvalidCoinMint.into());
+            return Err(AmmError::InvalidInput.into());
         }
-        if user_dest_pc.mint != amm_pc_vault.mint || amm.pc_vault_mint != user_dest_pc.mint {
-            return Err(AmmError::InvalidPCMint.into());
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
         }
-
-        let lp_mint = Self::unpack_mint(&amm_lp_mint_info, spl_token_program_id)?;
-        let user_source_lp =
-            Self::unpack_token_account(&user_source_lp_info, spl_token_program_id)?;
-        if user_source_lp.owner != *source_lp_owner_info.key {
-            return Err(AmmError::InvalidOwner.into());
+        let guardian = AmmConfig::load_checked(amm_config_info, program_id)?.guardian;
+        if !guardian_info.is_signer
+            || guardian == Pubkey::default()
+            || *guardian_info.key != guardian
+        {
+            // the admin can do what the guardian does
+            Self::check_admin(program_id, guardian_info, amm_config_info)?;
         }
-        if user_source_lp.mint != *amm_lp_mint_info.key {
-            return Err(AmmError::InvalidTokenLP.into());
+        let pause_level = AmmStatus::from_u64(status).pause_level();
+        match account_info_iter.next() {
+            Some(amm_info) => {
+                let amm_target_orders_info = next_account_info(account_info_iter)?;
+                let mut amm = AmmInfo::load_mut_checked(amm_info, program_id)?;
+                if amm.status == AmmStatus::Uninitialized.into_u64() {
+                    return Err(AmmError::InvalidStatus.into());
+                }
+                if pause_level < AmmStatus::from_u64(amm.status).pause_level() {
+                    return Err(AmmError::NotAllowed.into());
+                }
+                // a queued status must not reopen the pool later
+                let mut target_orders =
+                    Self::load_pending_params(program_id, amm_info, &amm, amm_target_orders_info)?;
+                target_orders.clear_pending_status();
+                Self::emit_event(
+                    program_id,
+                    event_accounts,
+                    PauseLog {
+                        log_type: LogType::Pause.into_u8(),
+                        signer: *guardian_info.key,
+                        amm: *amm_info.key,
+                        old_status: amm.status,
+                        new_status: status,
+                    },
+                )?;
+                amm.status = status;
+                Self::log_params(
+                    program_id,
+                    event_accounts,
+                    LogType::ApplyParams,
+                    AmmParams::Status,
+                    status,
+                    &Fees::default(),
+                    Clock::get()?.unix_timestamp as u64,
+                )?;
+                amm.recent_epoch = Clock::get()?.epoch;
+            }
+            None => {
+                let mut amm_config = AmmConfig::load_mut_checked(amm_config_info, program_id)?;
+                if pause_level < AmmStatus::from_u64(amm_config.global_status).pause_level() {
+                    return Err(AmmError::NotAllowed.into());
+                }
+                Self::emit_event(
+                    program_id,
+                    event_accounts,
+                    PauseLog {
+                        log_type: LogType::Pause.into_u8(),
+                        signer: *guardian_info.key,
+                        amm: Pubkey::default(),
+                        old_status: amm_config.global_status,
+                        new_status: status,
+                    },
+                )?;
+                amm_config.global_status = status;
+            }
         }
-        if withdraw.amount > user_source_lp.amount {
-            return Err(AmmError::InsufficientFunds.into());
+        Ok(())
+    }
+
+    /// Processes `process_emit_event` instruction.
+    pub fn process_emit_event(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let event_authority_info = next_account_info(account_info_iter)?;
+
+        if !event_authority_info.is_signer
+            || *event_authority_info.key != Self::event_authority_id(program_id)?
+        {
+            return Err(AmmError::InvalidSignAccount.into());
         }
-        if withdraw.amount > lp_mint.supply || withdraw.amount >= amm.lp_amount {
-            return Err(AmmError::NotAllowZeroLP.into());
+        Ok(())
+    }
+
+    /// Processes `process_propose_admin` instruction.
+    pub fn process_propose_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let new_admin_info = next_account_info(account_info_iter)?;
+
+        if amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
         }
-        let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
+        Self::check_admin(program_id, admin_info, amm_config_info)?;
+        let mut amm_config = AmmConfig::load_mut_checked(amm_config_info, program_id)?;
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            ProposeAdminLog {
+                log_type: LogType::ProposeAdmin.into_u8(),
+                admin: *admin_info.key,
+                old_pending_admin: amm_config.pending_admin,
+                new_pending_admin: *new_admin_info.key,
+            },
+        )?;
+        amm_config.pending_admin = *new_admin_info.key;
+        Ok(())
+    }
 
-        let x1 = Calculator::normalize_decimal_v2(
-            total_pc_without_take_pnl,
-            amm.pc_decimals,
-            amm.sys_decimal_value,
-        );
-        let y1 = Calculator::normalize_decimal_v2(
-            total_coin_without_take_pnl,
-            amm.coin_decimals,
-            amm.sys_decimal_value,
-        );
+    /// Processes `process_accept_admin` instruction.
+    pub fn process_accept_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let pending_admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
 
-        // calc and update pnl
-        let mut delta_x: u128 = 0;
-        let mut delta_y: u128 = 0;
-        if amm.status != AmmStatus::WithdrawOnly.into_u64() {
-            (delta_x, delta_y) = Self::calc_take_pnl(
-                &target_orders,
-                &mut amm,
-                &mut total_pc_without_take_pnl,
-                &mut total_coin_without_take_pnl,
-                x1.as_u128().into(),
-                y1.as_u128().into(),
-            )?;
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        let mut amm_config = AmmConfig::load_mut_checked(amm_config_info, program_id)?;
+        if !pending_admin_info.is_signer
+            || amm_config.pending_admin == Pubkey::default()
+            || *pending_admin_info.key != amm_config.pending_admin
+        {
+            return Err(AmmError::InvalidSignAccount.into());
         }
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            AcceptAdminLog {
+                log_type: LogType::AcceptAdmin.into_u8(),
+                old_admin: amm_config.admin,
+                new_admin: amm_config.pending_admin,
+            },
+        )?;
+        amm_config.admin = amm_config.pending_admin;
+        amm_config.pending_admin = Pubkey::default();
+        Ok(())
+    }
 
-        // coin_amount / total_coin_amount = amount / lp_mint.supply => coin_amount = total_coin_amount * amount / pool_mint.supply
-        let invariant = InvariantPool {
-            token_input: withdraw.amount,
-            token_total: amm.lp_amount,
-        };
-        let coin_amount = invariant
-            .exchange_pool_to_token(total_coin_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)?;
-        let pc_amount = invariant
-            .exchange_pool_to_token(total_pc_without_take_pnl, RoundDirection::Floor)
-            .ok_or(AmmError::CalculationExRateFailure)?;
+    /// Processes `process_create_config` instruction.
+    pub fn process_create_config(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let account_info_iter = &mut accounts.iter();
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        let pnl_owner_info = next_account_info(account_info_iter)?;
+        let system_program_info = next_account_info(account_info_iter)?;
+        let rent_sysvar_info = next_account_info(account_info_iter)?;
 
-        encode_ray_log(WithdrawLog {
-            log_type: LogType::Withdraw.into_u8(),
-            withdraw_lp: withdraw.amount,
-            user_lp: user_source_lp.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            pool_lp: amm.lp_amount,
-            calc_pnl_x: target_orders.calc_pnl_x,
-            calc_pnl_y: target_orders.calc_pnl_y,
-            out_coin: coin_amount,
-            out_pc: pc_amount,
-        });
-        if withdraw.amount == 0 || coin_amount == 0 || pc_amount == 0 {
-            return Err(AmmError::InvalidInput.into());
+        // the compile-time owner bootstraps the admin of the program
+        if !admin_info.is_signer || *admin_info.key != config_feature::amm_owner::ID {
+            return Err(AmmError::InvalidSignAccount.into());
+        }
+        if *system_program_info.key != solana_program::system_program::id() {
+            return Err(AmmError::InvalidSysProgramAddress.into());
         }
 
-        if coin_amount < amm_coin_vault.amount && pc_amount < amm_pc_vault.amount {
-            if withdraw.min_coin_amount.is_some() && withdraw.min_pc_amount.is_some() {
-                if withdraw.min_coin_amount.unwrap() > coin_amount
-                    || withdraw.min_pc_amount.unwrap() > pc_amount
-                {
-                    return Err(AmmError::ExceededSlippage.into());
-                }
+        let (pda, bump_seed) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
+        if pda != *amm_config_info.key {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        if amm_config_info.owner == program_id {
+            // a config created before it held the admin gets the compile-time owner once
+            let mut amm_config = AmmConfig::load_mut_checked(amm_config_info, program_id)?;
+            if amm_config.admin != Pubkey::default() {
+                return Err(AmmError::RepeatCreateConfigAccount.into());
             }
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_coin_vault_info.clone(),
-                user_dest_coin_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
-                amm.nonce as u8,
-                coin_amount,
-            )?;
-            Invokers::token_transfer_with_authority(
-                token_program_info.clone(),
-                amm_pc_vault_info.clone(),
-                user_dest_pc_info.clone(),
-                amm_authority_info.clone(),
-                AUTHORITY_AMM,
-                amm.nonce as u8,
-                pc_amount,
-            )?;
-            Invokers::token_burn(
-                token_program_info.clone(),
-                user_source_lp_info.clone(),
-                amm_lp_mint_info.clone(),
-                source_lp_owner_info.clone(),
-                withdraw.amount,
+            amm_config.admin = config_feature::amm_owner::ID;
+            return Self::emit_event(
+                program_id,
+                event_accounts,
+                CreateConfigLog {
+                    log_type: LogType::CreateConfig.into_u8(),
+                    admin: amm_config.admin,
+                    pnl_owner: amm_config.pnl_owner,
+                    create_pool_fee: amm_config.create_pool_fee,
+                },
+            );
+        }
+        if amm_config_info.owner != system_program_info.key {
+            return Err(AmmError::RepeatCreateConfigAccount.into());
+        }
+        let pda_signer_seeds: &[&[_]] = &[&AMM_CONFIG_SEED, &[bump_seed]];
+        let rent = &Rent::from_account_info(rent_sysvar_info)?;
+        let data_size = size_of::<AmmConfig>();
+        let required_lamports = rent
+            .minimum_balance(data_size)
+            .max(1)
+            .saturating_sub(amm_config_info.lamports());
+        if required_lamports > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    admin_info.key,
+                    amm_config_info.key,
+                    required_lamports,
+                ),
+                &[
+                    admin_info.clone(),
+                    amm_config_info.clone(),
+                    system_program_info.clone(),
+                ],
             )?;
-            amm.lp_amount = amm.lp_amount.checked_sub(withdraw.amount).unwrap();
-        } else {
-            // calc error
-            return Err(AmmError::TakePnlError.into());
         }
+        invoke_signed(
+            &system_instruction::allocate(amm_config_info.key, data_size as u64),
+            &[amm_config_info.clone(), system_program_info.clone()],
+            &[&pda_signer_seeds],
+        )?;
+        invoke_signed(
+            &system_instruction::assign(amm_config_info.key, &program_id),
+            &[amm_config_info.clone(), system_program_info.clone()],
+            &[&pda_signer_seeds],
+        )?;
+
+        let mut amm_config = AmmConfig::load_mut_checked(&amm_config_info, program_id)?;
+        amm_config.pnl_owner = *pnl_owner_info.key;
+        amm_config.create_pool_fee = 0;
+        amm_config.admin = config_feature::amm_owner::ID;
+        Self::emit_event(
+            program_id,
+            event_accounts,
+            CreateConfigLog {
+                log_type: LogType::CreateConfig.into_u8(),
+                admin: amm_config.admin,
+                pnl_owner: amm_config.pnl_owner,
+                create_pool_fee: amm_config.create_pool_fee,
+            },
+        )?;
 
-        // step4: update target_orders.calc_pnl_x & target_orders.calc_pnl_y
-        target_orders.calc_pnl_x = x1
-            .checked_sub(Calculator::normalize_decimal_v2(
-                pc_amount,
-                amm.pc_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_x))
-            .unwrap()
-            .as_u128();
-        target_orders.calc_pnl_y = y1
-            .checked_sub(Calculator::normalize_decimal_v2(
-                coin_amount,
-                amm.coin_decimals,
-                amm.sys_decimal_value,
-            ))
-            .unwrap()
-            .checked_sub(U128::from(delta_y))
-            .unwrap()
-            .as_u128();
-        amm.recent_epoch = Clock::get()?.epoch;
         Ok(())
     }
 
-    pub fn process_swap_base_in(
+    /// Processes `process_update_config` instruction.
+    pub fn process_update_config(
         program_id: &Pubkey,
         accounts: &[AccountInfo],
-        swap: SwapInstructionBaseIn,
+        config_args: ConfigArgs,
     ) -> ProgramResult {
-        const ACCOUNT_LEN: usize = 17;
-        let input_account_len = accounts.len();
-        if input_account_len != ACCOUNT_LEN && input_account_len != ACCOUNT_LEN + 1 {
-            return Err(AmmError::WrongAccountsNumber.into());
-        }
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
         let account_info_iter = &mut accounts.iter();
-        let token_program_info = next_account_info(account_info_iter)?;
+        let admin_info = next_account_info(account_info_iter)?;
+        let amm_config_info = next_account_info(account_info_iter)?;
+        if amm_config_info.owner != program_id {
+            return Err(AmmError::InvalidConfigAccount.into());
+        }
+        Self::check_admin(program_id, admin_info, amm_config_info)?;
 
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_authority_info = next_account_info(account_info_iter)?;
-        let _amm_open_orders_info = next_account_info(account_info_iter)?;
-        if input_account_len == ACCOUNT_LEN + 1 {
-            let _amm_target_orders_info = next_account_info(account_info_iter)?;
+        let mut amm_config = AmmConfig::load_mut_checked(&amm_config_info, program_id)?;
+        let mut log = UpdateConfigLog {
+            log_type: LogType::UpdateConfig.into_u8(),
+            param: config_args.param,
+            ..Default::default()
+        };
+        match config_args.param {
+            0 => {
+                let pnl_owner = config_args.owner.unwrap();
+                if pnl_owner == Pubkey::default() {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_owner = amm_config.pnl_owner;
+                log.new_owner = pnl_owner;
+                amm_config.pnl_owner = pnl_owner;
+            }
+            1 => {
+                let cancel_owner = config_args.owner.unwrap();
+                if cancel_owner == Pubkey::default() {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_owner = amm_config.cancel_owner;
+                log.new_owner = cancel_owner;
+                amm_config.cancel_owner = cancel_owner;
+            }
+            2 => {
+                let create_pool_fee = config_args.create_pool_fee.unwrap();
+                log.old_value = amm_config.create_pool_fee;
+                log.new_value = create_pool_fee;
+                amm_config.create_pool_fee = create_pool_fee;
+            }
+            3 => {
+                let referrer_fee_rate = config_args.referrer_fee_rate.unwrap();
+                if referrer_fee_rate > MAX_REFERRER_FEE_RATE {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_value = amm_config.referrer_fee_rate;
+                log.new_value = referrer_fee_rate;
+                amm_config.referrer_fee_rate = referrer_fee_rate;
+            }
+            4 => {
+                let flash_fee_rate = config_args.flash_fee_rate.unwrap();
+                if flash_fee_rate > MAX_FLASH_FEE_RATE {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_value = amm_config.flash_fee_rate;
+                log.new_value = flash_fee_rate;
+                amm_config.flash_fee_rate = flash_fee_rate;
+            }
+            5 => {
+                let params_delay = config_args.params_delay.unwrap();
+                if params_delay > MAX_PARAMS_DELAY {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_value = amm_config.params_delay;
+                log.new_value = params_delay;
+                amm_config.params_delay = params_delay;
+            }
+            6 => {
+                let global_status = config_args.global_status.unwrap();
+                if global_status != AmmStatus::Uninitialized.into_u64()
+                    && global_status != AmmStatus::Disabled.into_u64()
+                    && global_status != AmmStatus::WithdrawOnly.into_u64()
+                {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_value = amm_config.global_status;
+                log.new_value = global_status;
+                amm_config.global_status = global_status;
+            }
+            7 => {
+                let guardian = config_args.owner.unwrap();
+                if guardian == Pubkey::default() {
+                    return Err(AmmError::InvalidInput.into());
+                }
+                log.old_owner = amm_config.guardian;
+                log.new_owner = guardian;
+                amm_config.guardian = guardian;
+            }
+            _ => {
+                return Err(AmmError::InvalidInput.into());
+            }
         }
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+        Self::emit_event(program_id, event_accounts, log)?;
 
-        let _market_program_info = next_account_info(account_info_iter)?;
+        return Ok(());
+    }
 
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if amm.pc_vault_mint == amm.coin_vault_mint {
-            return Err(AmmError::NotAllowed.into());
+    pub fn process_swap_route_base_in(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseIn,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        if swap.referrer {
+            return Err(AmmError::InvalidReferrerAccount.into());
         }
-        let _market_info = next_account_info(account_info_iter)?;
-        let _market_bids_info = next_account_info(account_info_iter)?;
-        let _market_asks_info = next_account_info(account_info_iter)?;
-        let _market_event_queue_info = next_account_info(account_info_iter)?;
-        let _market_coin_vault_info = next_account_info(account_info_iter)?;
-        let _market_pc_vault_info = next_account_info(account_info_iter)?;
-        let _market_vault_signer = next_account_info(account_info_iter)?;
-
-        let user_source_info = next_account_info(account_info_iter)?;
-        let user_destination_info = next_account_info(account_info_iter)?;
-        let user_source_owner = next_account_info(account_info_iter)?;
-        if !user_source_owner.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+        let mut hops = Self::load_swap_route_hops(program_id, accounts)?;
+        let user_source =
+            Self::unpack_token_account(hops[0].user_source_info, hops[0].user_source_info.owner)?;
+        let epoch = Clock::get()?.epoch;
+        // each pool swaps what the user received from the previous one
+        let mut swap_amounts = Vec::with_capacity(hops.len());
+        let mut amount_in = swap.amount_in;
+        for hop in hops.iter() {
+            let hop_swap_amounts = Self::calc_swap_base_in(
+                &hop.fees,
+                &*hop.curve,
+                &hop.source_vault_token,
+                &hop.destination_vault_token,
+                hop.total_pc_without_take_pnl,
+                hop.total_coin_without_take_pnl,
+                hop.swap_direction,
+                amount_in,
+                epoch,
+            )?;
+            amount_in = hop_swap_amounts.amount_out_after_transfer_fee;
+            swap_amounts.push(hop_swap_amounts);
         }
-        check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
-        );
-        let spl_token_program_id = token_program_info.key;
-        if *amm_authority_info.key
-            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
-        {
-            return Err(AmmError::InvalidProgramAddress.into());
+        let out_amount = amount_in;
+        encode_ray_log(SwapRouteBaseInLog {
+            log_type: LogType::SwapRouteBaseIn.into_u8(),
+            amount_in: swap.amount_in,
+            minimum_out: swap.minimum_amount_out,
+            hops: hops.len() as u64,
+            user_source: user_source.amount,
+            out_amount,
+        });
+        if user_source.amount < swap.amount_in {
+            return Err(AmmError::InsufficientFunds.into());
         }
-        check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
-        );
-        check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
-        );
-
-        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
-            return Err(AmmError::InvalidUserToken.into());
+        if out_amount < swap.minimum_amount_out {
+            return Err(AmmError::ExceededSlippage.into());
         }
-        if *user_destination_info.key == amm.pc_vault
-            || *user_destination_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidUserToken.into());
+        if out_amount == 0 || swap.amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
         }
+        Self::execute_swap_route(program_id, &mut hops, &swap_amounts, epoch, event_accounts)
+    }
 
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
-
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
-
-        if !AmmStatus::from_u64(amm.status).swap_permission() {
-            msg!(&format!("swap_base_in: status {}", identity(amm.status)));
-            return Err(AmmError::InvalidStatus.into());
-        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
-            let clock = Clock::get()?;
-            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
-                return Err(AmmError::InvalidStatus.into());
-            } else {
-                amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_in: WaitingTrade to SwapOnly");
-            }
+    pub fn process_swap_route_base_out(
+        program_id: &Pubkey,
+        accounts: &[AccountInfo],
+        swap: SwapInstructionBaseOut,
+    ) -> ProgramResult {
+        let (accounts, event_accounts) = Self::split_event_accounts(program_id, accounts);
+        let accounts = Self::split_amm_config_account(program_id, accounts, false)?;
+        if swap.referrer {
+            return Err(AmmError::InvalidReferrerAccount.into());
         }
-
-        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
+        let mut hops = Self::load_swap_route_hops(program_id, accounts)?;
+        let user_source =
+            Self::unpack_token_account(hops[0].user_source_info, hops[0].user_source_info.owner)?;
+        let epoch = Clock::get()?.epoch;
+        // walk the route backwards, each pool has to output what the next one takes in
+        let mut swap_amounts = Vec::with_capacity(hops.len());
+        let mut amount_out = swap.amount_out;
+        for hop in hops.iter().rev() {
+            let hop_swap_amounts = Self::calc_swap_base_out(
+                &hop.fees,
+                &*hop.curve,
+                &hop.source_vault_token,
+                &hop.destination_vault_token,
+                hop.total_pc_without_take_pnl,
+                hop.total_coin_without_take_pnl,
+                hop.swap_direction,
+                amount_out,
+                epoch,
             )?;
-
-        let swap_direction;
-        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
-            swap_direction = SwapDirection::Coin2PC
-        } else if user_source.mint == amm_pc_vault.mint
-            && user_destination.mint == amm_coin_vault.mint
-        {
-            swap_direction = SwapDirection::PC2Coin
-        } else {
-            return Err(AmmError::InvalidUserToken.into());
-        }
-        if user_source.amount < swap.amount_in {
-            encode_ray_log(SwapBaseInLog {
-                log_type: LogType::SwapBaseIn.into_u8(),
-                amount_in: swap.amount_in,
-                minimum_out: swap.minimum_amount_out,
-                direction: swap_direction as u64,
-                user_source: user_source.amount,
-                pool_coin: total_coin_without_take_pnl,
-                pool_pc: total_pc_without_take_pnl,
-                out_amount: 0,
-            });
-            return Err(AmmError::InsufficientFunds.into());
+            amount_out = hop_swap_amounts.amount_in;
+            swap_amounts.push(hop_swap_amounts);
         }
-        let swap_fee = U128::from(swap.amount_in)
-            .checked_mul(amm.fees.swap_fee_numerator.into())
-            .unwrap()
-            .checked_ceil_div(amm.fees.swap_fee_denominator.into())
-            .unwrap();
-        let swap_in_after_deduct_fee = U128::from(swap.amount_in).checked_sub(swap_fee).unwrap();
-        let swap_amount_out = Calculator::swap_token_amount_base_in(
-            swap_in_after_deduct_fee,
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        )
-        .as_u64();
-        encode_ray_log(SwapBaseInLog {
-            log_type: LogType::SwapBaseIn.into_u8(),
-            amount_in: swap.amount_in,
-            minimum_out: swap.minimum_amount_out,
-            direction: swap_direction as u64,
+        swap_amounts.reverse();
+        let deduct_in = amount_out;
+        encode_ray_log(SwapRouteBaseOutLog {
+            log_type: LogType::SwapRouteBaseOut.into_u8(),
+            max_in: swap.max_amount_in,
+            amount_out: swap.amount_out,
+            hops: hops.len() as u64,
             user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            out_amount: swap_amount_out,
+            deduct_in,
         });
-        if swap_amount_out < swap.minimum_amount_out {
+        if user_source.amount < deduct_in {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        if deduct_in > swap.max_amount_in {
             return Err(AmmError::ExceededSlippage.into());
         }
-        if swap_amount_out == 0 || swap.amount_in == 0 {
+        if deduct_in == 0 || swap.amount_out == 0 {
             return Err(AmmError::InvalidInput.into());
         }
+        Self::execute_swap_route(program_id, &mut hops, &swap_amounts, epoch, event_accounts)
+    }
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap_amount_out >= total_pc_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap.amount_in,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
+    /// Processes an [Instruction](enum.Instruction.html).
+    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
+        let instruction = AmmInstruction::unpack(input)?;
+        match instruction {
+            AmmInstruction::PreInitialize(_init_arg) => {
+                unimplemented!("This instruction is not supported, please use Initialize2")
+            }
+            AmmInstruction::Initialize(_init1) => {
+                unimplemented!("This instruction is not supported, please use Initialize2")
+            }
+            AmmInstruction::Initialize2(init2) => {
+                Self::process_initialize2(program_id, accounts, init2)
+            }
+            AmmInstruction::MonitorStep(_monitor) => {
+                unimplemented!("This instruction is not supported")
+            }
+            AmmInstruction::Deposit(deposit) => {
+                Self::process_deposit(program_id, accounts, deposit)
+            }
+            AmmInstruction::Withdraw(withdraw) => {
+                Self::process_withdraw(program_id, accounts, withdraw)
             }
-            SwapDirection::PC2Coin => {
-                if swap_amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap.amount_in,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
+            AmmInstruction::MigrateToOpenBook => {
+                unimplemented!("This instruction is not supported")
             }
-        };
-        amm.recent_epoch = Clock::get()?.epoch;
-
-        Ok(())
+            AmmInstruction::SetParams(setparams) => {
+                Self::process_set_params(program_id, accounts, setparams)
+            }
+            AmmInstruction::WithdrawPnl => Self::process_withdrawpnl(program_id, accounts),
+            AmmInstruction::WithdrawSrm(_withdrawsrm) => {
+                unimplemented!("This instruction is not supported")
+            }
+            AmmInstruction::SwapBaseIn(swap) => {
+                Self::process_swap_base_in(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseOut(swap) => {
+                Self::process_swap_base_out(program_id, accounts, swap)
+            }
+            AmmInstruction::SimulateInfo(simulate) => {
+                Self::process_simulate_info(program_id, accounts, simulate)
+            }
+            AmmInstruction::AdminCancelOrders(_cancel) => {
+                unimplemented!("This instruction is not supported")
+            }
+            AmmInstruction::CreateConfigAccount => {
+                Self::process_create_config(program_id, accounts)
+            }
+            AmmInstruction::UpdateConfigAccount(config_args) => {
+                Self::process_update_config(program_id, accounts, config_args)
+            }
+            AmmInstruction::SwapBaseInV2(swap) => {
+                Self::process_swap_base_in_v2(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapBaseOutV2(swap) => {
+                Self::process_swap_base_out_v2(program_id, accounts, swap)
+            }
+            AmmInstruction::CreateObservation => {
+                Self::process_create_observation(program_id, accounts)
+            }
+            AmmInstruction::SwapRouteBaseIn(swap) => {
+                Self::process_swap_route_base_in(program_id, accounts, swap)
+            }
+            AmmInstruction::SwapRouteBaseOut(swap) => {
+                Self::process_swap_route_base_out(program_id, accounts, swap)
+            }
+            AmmInstruction::SetDynamicFee(dynamic_fee) => {
+                Self::process_set_dynamic_fee(program_id, accounts, dynamic_fee)
+            }
+            AmmInstruction::DepositSingleSide(deposit) => {
+                Self::process_deposit_single_side(program_id, accounts, deposit)
+            }
+            AmmInstruction::WithdrawSingleSide(withdraw) => {
+                Self::process_withdraw_single_side(program_id, accounts, withdraw)
+            }
+            AmmInstruction::FlashLoan(flash_loan) => {
+                Self::process_flash_loan(program_id, accounts, flash_loan)
+            }
+            AmmInstruction::FlashSwap(swap) => Self::process_flash_swap(program_id, accounts, swap),
+            AmmInstruction::SetCurve(set_curve) => {
+                Self::process_set_curve(program_id, accounts, set_curve)
+            }
+            AmmInstruction::InitRangeOrders => {
+                Self::process_init_range_orders(program_id, accounts)
+            }
+            AmmInstruction::PlaceRangeOrder(place) => {
+                Self::process_place_range_order(program_id, accounts, place)
+            }
+            AmmInstruction::CancelRangeOrder => {
+                Self::process_claim_range_order(program_id, accounts, true)
+            }
+            AmmInstruction::ClaimRangeOrder => {
+                Self::process_claim_range_order(program_id, accounts, false)
+            }
+            AmmInstruction::PlaceLimitOrder(place) => {
+                Self::process_place_limit_order(program_id, accounts, place)
+            }
+            AmmInstruction::CancelLimitOrder => {
+                Self::process_claim_limit_order(program_id, accounts, true)
+            }
+            AmmInstruction::ClaimLimitOrder => {
+                Self::process_claim_limit_order(program_id, accounts, false)
+            }
+            AmmInstruction::SettlePnl => Self::process_settle_pnl(program_id, accounts),
+            AmmInstruction::ProposeAdmin => Self::process_propose_admin(program_id, accounts),
+            AmmInstruction::AcceptAdmin => Self::process_accept_admin(program_id, accounts),
+            AmmInstruction::ApplyParams => Self::process_apply_params(program_id, accounts),
+            AmmInstruction::Pause(pause) => Self::process_pause(program_id, accounts, pause),
+            AmmInstruction::EmitEvent(_event) => Self::process_emit_event(program_id, accounts),
+        }
     }
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::math::{ConstantProductCurve, StableCurve, WeightedCurve, WAD};
+    use proptest::prelude::*;
 
-    pub fn process_swap_base_out(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        swap: SwapInstructionBaseOut,
-    ) -> ProgramResult {
-        const SWAP_ACCOUNT_NUM: usize = 17;
-        let input_account_len = accounts.len();
-        if input_account_len != SWAP_ACCOUNT_NUM && input_account_len != SWAP_ACCOUNT_NUM + 1 {
-            return Err(AmmError::WrongAccountsNumber.into());
+    #[test]
+    fn test_trailing_account_sizes() {
+        // the optional trailing accounts of a swap are told apart by their size
+        let sizes = [
+            size_of::<LimitOrderState>(),
+            size_of::<RangeOrderState>(),
+            size_of::<AmmConfig>(),
+            size_of::<DynamicFeeState>(),
+            size_of::<ObservationState>(),
+            size_of::<AmmInfo>(),
+            size_of::<TargetOrders>(),
+        ];
+        for (i, size) in sizes.iter().enumerate() {
+            assert!(!sizes[i + 1..].contains(size));
         }
-        let account_info_iter = &mut accounts.iter();
-        let token_program_info = next_account_info(account_info_iter)?;
+    }
 
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_authority_info = next_account_info(account_info_iter)?;
-        let _amm_open_orders_info = next_account_info(account_info_iter)?;
-        if input_account_len == SWAP_ACCOUNT_NUM + 1 {
-            let _amm_target_orders_info = next_account_info(account_info_iter)?;
-        }
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
+    #[test]
+    fn test_event_authority_bump() {
+        let (event_authority, bump) =
+            Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &crate::id());
+        assert_eq!(bump, EVENT_AUTHORITY_BUMP);
+        assert_eq!(
+            Processor::event_authority_id(&crate::id()).unwrap(),
+            event_authority
+        );
+    }
 
-        let _market_program_info = next_account_info(account_info_iter)?;
+    #[test]
+    fn test_event_v2_size() {
+        let swap_event = SwapEventV2 {
+            log_type: LogType::SwapV2.into_u8(),
+            version: EVENT_VERSION,
+            amm: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            mint_in: Pubkey::new_unique(),
+            mint_out: Pubkey::new_unique(),
+            amount_in: u64::MAX,
+            amount_out: u64::MAX,
+            swap_fee: u64::MAX,
+            vault_coin: u64::MAX,
+            vault_pc: u64::MAX,
+        };
+        let deposit_event = LiquidityEventV2 {
+            log_type: LogType::DepositV2.into_u8(),
+            version: EVENT_VERSION,
+            amm: Pubkey::new_unique(),
+            signer: Pubkey::new_unique(),
+            coin_mint: Pubkey::new_unique(),
+            pc_mint: Pubkey::new_unique(),
+            coin_amount: u64::MAX,
+            pc_amount: u64::MAX,
+            lp_amount: u64::MAX,
+            fee: u64::MAX,
+            vault_coin: u64::MAX,
+            vault_pc: u64::MAX,
+        };
+        let lines = [
+            bincode::serialize(&swap_event).unwrap(),
+            bincode::serialize(&deposit_event).unwrap(),
+        ]
+        .map(|bytes| {
+            format!(
+                "ray_log: {}",
+                base64::encode_config(bytes, base64::STANDARD)
+            )
+        });
+        for line in lines.iter() {
+            assert!(line.len() <= LOG_SIZE);
+        }
 
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if amm.pc_vault_mint == amm.coin_vault_mint {
-            return Err(AmmError::NotAllowed.into());
+        #[cfg(feature = "client")]
+        {
+            assert_eq!(
+                RayLog::parse(&lines[0]).unwrap(),
+                RayLog::SwapV2(swap_event)
+            );
+            assert_eq!(
+                RayLog::parse(&lines[1]).unwrap(),
+                RayLog::DepositV2(deposit_event)
+            );
+            // events older than EVENT_VERSION are not decoded as v2 ones
+            let encode = |bytes: Vec<u8>| base64::encode_config(bytes, base64::STANDARD);
+            assert!(matches!(
+                RayLog::parse(&format!("ray_log: {}", encode(vec![15, 1]))),
+                Err(RayLogError::UnsupportedVersion(1))
+            ));
         }
+    }
 
-        let _market_info = next_account_info(account_info_iter)?;
-        let _market_bids_info = next_account_info(account_info_iter)?;
-        let _market_asks_info = next_account_info(account_info_iter)?;
-        let _market_event_queue_info = next_account_info(account_info_iter)?;
-        let _market_coin_vault_info = next_account_info(account_info_iter)?;
-        let _market_pc_vault_info = next_account_info(account_info_iter)?;
-        let _market_vault_signer = next_account_info(account_info_iter)?;
+    #[test]
+    fn test_set_params_log() {
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        let log = SetParamsLog {
+            log_type: LogType::SetParams.into_u8(),
+            param: AmmParams::Fees.into_u64() as u8,
+            amm: Pubkey::new_unique(),
+            old_value: u64::MAX,
+            new_value: u64::MAX,
+            old_fees: Processor::fees_log(&fees),
+            new_fees: Processor::fees_log(&Fees::default()),
+        };
+        assert_eq!(log.old_fees.swap_fee_numerator, { fees.swap_fee_numerator });
+        let line = format!(
+            "ray_log: {}",
+            base64::encode_config(bincode::serialize(&log).unwrap(), base64::STANDARD)
+        );
+        assert!(line.len() <= LOG_SIZE);
 
-        let user_source_info = next_account_info(account_info_iter)?;
-        let user_destination_info = next_account_info(account_info_iter)?;
-        let user_source_owner = next_account_info(account_info_iter)?;
-        if !user_source_owner.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
-        }
+        #[cfg(feature = "client")]
+        assert_eq!(RayLog::parse(&line).unwrap(), RayLog::SetParams(log));
+    }
 
-        check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
+    #[test]
+    fn test_update_observation() {
+        let program_id = Pubkey::new_unique();
+        let amm_key = Pubkey::new_unique();
+        let mut amm = AmmInfo::default();
+        Processor::update_observation(&program_id, &amm_key, &amm, None, 100, 100, 10).unwrap();
+        // the pools with an observation account must pass it
+        amm.observation_enabled = 1;
+        assert_eq!(
+            Processor::update_observation(&program_id, &amm_key, &amm, None, 100, 100, 10),
+            Err(AmmError::InvalidObservationAccount.into())
         );
-        let spl_token_program_id = token_program_info.key;
-        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
-        check_assert_eq!(
-            *amm_authority_info.key,
-            authority,
-            "authority",
-            AmmError::InvalidProgramAddress
+
+        let (observation_key, bump) = get_associated_address_and_bump_seed(
+            &program_id,
+            &amm_key,
+            OBSERVATION_ASSOCIATED_SEED,
+            &program_id,
         );
-        check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
+        let fake_key = Pubkey::new_unique();
+        for (key, result) in [
+            (observation_key, Ok(())),
+            (fake_key, Err(AmmError::InvalidObservationAccount.into())),
+        ] {
+            let mut lamports = 0u64;
+            let mut data = vec![0u8; size_of::<ObservationState>()];
+            let observation_info = AccountInfo::new(
+                &key,
+                false,
+                true,
+                &mut lamports,
+                &mut data,
+                &program_id,
+                false,
+                0,
+            );
+            ObservationState::load_mut(&observation_info)
+                .unwrap()
+                .initialize(&amm_key, bump, 0)
+                .unwrap();
+            assert_eq!(
+                Processor::update_observation(
+                    &program_id,
+                    &amm_key,
+                    &amm,
+                    Some(&observation_info),
+                    100,
+                    100,
+                    10,
+                ),
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn test_event_instruction() {
+        let program_id = crate::id();
+        let (event_authority, _) =
+            Pubkey::find_program_address(&[EVENT_AUTHORITY_SEED], &program_id);
+        let user = Pubkey::new_unique();
+        let owner = Pubkey::default();
+        let (mut lamports, mut data) = ([0u64; 3], [[0u8; 0]; 3]);
+        let [user_lamports, authority_lamports, program_lamports] = &mut lamports;
+        let [user_data, authority_data, program_data] = &mut data;
+        let accounts = [
+            AccountInfo::new(
+                &user,
+                true,
+                false,
+                user_lamports,
+                user_data,
+                &owner,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &event_authority,
+                false,
+                false,
+                authority_lamports,
+                authority_data,
+                &owner,
+                false,
+                0,
+            ),
+            AccountInfo::new(
+                &program_id,
+                false,
+                false,
+                program_lamports,
+                program_data,
+                &owner,
+                true,
+                0,
+            ),
+        ];
+        let (rest, event_accounts) = Processor::split_event_accounts(&program_id, &accounts);
+        assert_eq!(rest.len(), 1);
+        assert_eq!(
+            *event_accounts.unwrap().event_authority_info.key,
+            event_authority
         );
-        check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
+        // accounts not ending with the event authority and the program are left alone
+        let (rest, event_accounts) = Processor::split_event_accounts(&program_id, &accounts[..2]);
+        assert_eq!(rest.len(), 2);
+        assert!(event_accounts.is_none());
+
+        let swap_event = SwapEventV2 {
+            log_type: LogType::SwapV2.into_u8(),
+            version: EVENT_VERSION,
+            amm: Pubkey::new_unique(),
+            amount_in: 100,
+            ..Default::default()
+        };
+        let data = AmmInstruction::EmitEvent(bincode::serialize(&swap_event).unwrap())
+            .pack()
+            .unwrap();
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::EmitEvent(bincode::serialize(&swap_event).unwrap())
         );
 
-        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
-            return Err(AmmError::InvalidUserToken.into());
-        }
-        if *user_destination_info.key == amm.pc_vault
-            || *user_destination_info.key == amm.coin_vault
+        #[cfg(feature = "client")]
         {
-            return Err(AmmError::InvalidUserToken.into());
+            let other_data = AmmInstruction::ApplyParams.pack().unwrap();
+            let other_program = Pubkey::new_unique();
+            let inner_instructions = [
+                (&program_id, other_data.as_slice()),
+                (&other_program, data.as_slice()),
+                (&program_id, data.as_slice()),
+            ];
+            assert_eq!(
+                parse_event_instructions(&program_id, inner_instructions).unwrap(),
+                vec![RayLog::SwapV2(swap_event)]
+            );
+            assert!(matches!(
+                RayLog::from_event_instruction(&other_data),
+                Err(RayLogError::NotEventInstruction)
+            ));
         }
+    }
 
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+    /// Data of a Token-2022 mint charging a transfer fee of basis_points, capped at
+    /// maximum_fee, in every epoch.
+    fn transfer_fee_mint_data(decimals: u8, basis_points: u16, maximum_fee: u64) -> Vec<u8> {
+        use spl_token_2022::extension::{
+            transfer_fee::TransferFee, BaseStateWithExtensionsMut, StateWithExtensionsMut,
+        };
 
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
+        let mut data = vec![
+            0u8;
+            ExtensionType::try_calculate_account_len::<spl_token_2022::state::Mint>(
+                &[ExtensionType::TransferFeeConfig]
+            )
+            .unwrap()
+        ];
+        let mut mint =
+            StateWithExtensionsMut::<spl_token_2022::state::Mint>::unpack_uninitialized(&mut data)
+                .unwrap();
+        let transfer_fee = TransferFee {
+            epoch: 0.into(),
+            maximum_fee: maximum_fee.into(),
+            transfer_fee_basis_points: basis_points.into(),
+        };
+        let transfer_fee_config = mint.init_extension::<TransferFeeConfig>(true).unwrap();
+        transfer_fee_config.older_transfer_fee = transfer_fee;
+        transfer_fee_config.newer_transfer_fee = transfer_fee;
+        mint.base = spl_token_2022::state::Mint {
+            decimals,
+            is_initialized: true,
+            ..Default::default()
+        };
+        mint.pack_base();
+        mint.init_account_type().unwrap();
+        data
+    }
 
-        if !AmmStatus::from_u64(amm.status).swap_permission() {
-            msg!(&format!("swap_base_out: status {}", identity(amm.status)));
-            return Err(AmmError::InvalidStatus.into());
-        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
-            let clock = Clock::get()?;
-            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
-                return Err(AmmError::InvalidStatus.into());
-            } else {
-                amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_out: WaitingTrade to SwapOnly");
+    const TEST_EPOCH: u64 = 500;
+    const TEST_TIMESTAMP: i64 = 1_700_000_000;
+    /// Transfer fee of the Token-2022 mints of the handler tests, in basis points.
+    const TEST_TRANSFER_FEE_BASIS_POINTS: u16 = 100;
+
+    /// A program the handlers can invoke in the handler tests, by its program id.
+    type TestProgram = fn(&[AccountInfo], &[u8]) -> ProgramResult;
+
+    thread_local! {
+        static TEST_PROGRAMS: std::cell::RefCell<std::collections::HashMap<Pubkey, TestProgram>> =
+            Default::default();
+        static EMITTED_EVENTS: std::cell::RefCell<Vec<Vec<u8>>> = Default::default();
+    }
+
+    /// Syscalls of the handler tests: a fixed clock, and the invocations dispatched to
+    /// spl_token, Token-2022, the amm itself or a test program.
+    struct TestSyscallStubs;
+
+    impl solana_program::program_stubs::SyscallStubs for TestSyscallStubs {
+        fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+            unsafe {
+                *(var_addr as *mut Clock) = Clock {
+                    epoch: TEST_EPOCH,
+                    unix_timestamp: TEST_TIMESTAMP,
+                    ..Clock::default()
+                };
             }
+            solana_program::entrypoint::SUCCESS
         }
 
-        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
+        fn sol_invoke_signed(
+            &self,
+            instruction: &Instruction,
+            account_infos: &[AccountInfo],
+            signers_seeds: &[&[&[u8]]],
+        ) -> ProgramResult {
+            // only the amm signs for program addresses
+            let signers = signers_seeds
+                .iter()
+                .map(|seeds| Pubkey::create_program_address(seeds, &crate::id()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ProgramError::InvalidSeeds)?;
+            let account_infos = instruction
+                .accounts
+                .iter()
+                .map(|meta| {
+                    let mut account_info = account_infos
+                        .iter()
+                        .find(|account_info| *account_info.key == meta.pubkey)
+                        .ok_or(ProgramError::NotEnoughAccountKeys)?
+                        .clone();
+                    if meta.is_signer && !account_info.is_signer && !signers.contains(&meta.pubkey)
+                    {
+                        return Err(ProgramError::MissingRequiredSignature);
+                    }
+                    account_info.is_signer = meta.is_signer;
+                    account_info.is_writable = meta.is_writable;
+                    Ok(account_info)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            process_test_instruction(instruction, &account_infos)
+        }
+    }
 
-        let swap_direction;
-        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
-            swap_direction = SwapDirection::Coin2PC
-        } else if user_source.mint == amm_pc_vault.mint
-            && user_destination.mint == amm_coin_vault.mint
-        {
-            swap_direction = SwapDirection::PC2Coin
+    fn process_test_instruction(
+        instruction: &Instruction,
+        account_infos: &[AccountInfo],
+    ) -> ProgramResult {
+        let program_id = &instruction.program_id;
+        if *program_id == spl_token::id() {
+            spl_token::processor::Processor::process(program_id, account_infos, &instruction.data)
+        } else if *program_id == spl_token_2022::id() {
+            spl_token_2022::processor::Processor::process(
+                program_id,
+                account_infos,
+                &instruction.data,
+            )
+        } else if *program_id == crate::id() {
+            Processor::process(program_id, account_infos, &instruction.data)?;
+            if let Ok(AmmInstruction::EmitEvent(event)) = AmmInstruction::unpack(&instruction.data)
+            {
+                EMITTED_EVENTS.with(|events| events.borrow_mut().push(event));
+            }
+            Ok(())
         } else {
-            return Err(AmmError::InvalidUserToken.into());
+            let program = TEST_PROGRAMS.with(|programs| programs.borrow().get(program_id).copied());
+            program.ok_or(ProgramError::IncorrectProgramId)?(account_infos, &instruction.data)
         }
+    }
 
-        let swap_in_before_add_fee = Calculator::swap_token_amount_base_out(
-            swap.amount_out.into(),
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        );
-        // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
-        // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
-        let swap_in_after_add_fee = swap_in_before_add_fee
-            .checked_mul(amm.fees.swap_fee_denominator.into())
-            .unwrap()
-            .checked_ceil_div(
-                (amm.fees
-                    .swap_fee_denominator
-                    .checked_sub(amm.fees.swap_fee_numerator)
-                    .unwrap())
-                .into(),
-            )
-            .unwrap()
-            .as_u64();
-        encode_ray_log(SwapBaseOutLog {
-            log_type: LogType::SwapBaseOut.into_u8(),
-            max_in: swap.max_amount_in,
-            amount_out: swap.amount_out,
-            direction: swap_direction as u64,
-            user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            deduct_in: swap_in_after_add_fee,
+    /// Test program sending the amount its data starts with, less the shortfall that may
+    /// follow it, from accounts[1] to accounts[2] owned by accounts[3], by spl_token.
+    fn test_repay_program(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+        let amount = u64::from_le_bytes(data[..8].try_into().unwrap());
+        let shortfall = data.get(8..16).map_or(0, |shortfall| {
+            u64::from_le_bytes(shortfall.try_into().unwrap())
         });
-        if user_source.amount < swap_in_after_add_fee {
-            return Err(AmmError::InsufficientFunds.into());
+        invoke(
+            &spl_token::instruction::transfer(
+                &spl_token::id(),
+                accounts[1].key,
+                accounts[2].key,
+                accounts[3].key,
+                &[],
+                amount - shortfall,
+            )?,
+            accounts,
+        )
+    }
+
+    /// Test program invoking the amm with its own data and accounts, re-entering the pool.
+    fn test_reentry_program(accounts: &[AccountInfo], data: &[u8]) -> ProgramResult {
+        invoke(
+            &Instruction {
+                program_id: crate::id(),
+                accounts: accounts
+                    .iter()
+                    .map(|account| AccountMeta {
+                        pubkey: *account.key,
+                        is_signer: account.is_signer,
+                        is_writable: account.is_writable,
+                    })
+                    .collect(),
+                data: data.to_vec(),
+            },
+            accounts,
+        )
+    }
+
+    /// Accounts of a handler test. They are leaked, the AccountInfos the handlers and the
+    /// token programs share by their RefCells are 'static.
+    struct TestAccounts {
+        accounts: Vec<AccountInfo<'static>>,
+    }
+
+    /// Pool of a handler test, by the keys of its accounts.
+    struct TestPool {
+        amm: Pubkey,
+        authority: Pubkey,
+        target_orders: Pubkey,
+        lp_mint: Pubkey,
+        coin_mint: Pubkey,
+        pc_mint: Pubkey,
+        coin_vault: Pubkey,
+        pc_vault: Pubkey,
+        token_program: Pubkey,
+    }
+
+    /// User of a pool of a handler test, by the keys of its accounts.
+    struct TestUser {
+        owner: Pubkey,
+        coin: Pubkey,
+        pc: Pubkey,
+        lp: Pubkey,
+    }
+
+    impl TestAccounts {
+        fn new() -> Self {
+            static SYSCALL_STUBS: std::sync::Once = std::sync::Once::new();
+            SYSCALL_STUBS.call_once(|| {
+                solana_program::program_stubs::set_syscall_stubs(Box::new(TestSyscallStubs));
+            });
+            EMITTED_EVENTS.with(|events| events.borrow_mut().clear());
+            let mut accounts = Self {
+                accounts: Vec::new(),
+            };
+            for program_id in [spl_token::id(), spl_token_2022::id(), crate::id()] {
+                accounts.add(program_id, Pubkey::default(), Vec::new(), true);
+            }
+            let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], &crate::id());
+            accounts.add(
+                amm_config,
+                crate::id(),
+                vec![0; size_of::<AmmConfig>()],
+                false,
+            );
+            let event_authority = Processor::event_authority_id(&crate::id()).unwrap();
+            accounts.add(event_authority, Pubkey::default(), Vec::new(), false);
+            let (authority, _) = Pubkey::find_program_address(&[AUTHORITY_AMM], &crate::id());
+            accounts.add(authority, Pubkey::default(), Vec::new(), false);
+            accounts
+        }
+
+        fn add(&mut self, key: Pubkey, owner: Pubkey, data: Vec<u8>, executable: bool) -> Pubkey {
+            self.accounts.push(AccountInfo::new(
+                Box::leak(Box::new(key)),
+                false,
+                true,
+                Box::leak(Box::new(1_000_000_000)),
+                Box::leak(data.into_boxed_slice()),
+                Box::leak(Box::new(owner)),
+                executable,
+                0,
+            ));
+            key
         }
-        if swap.max_amount_in < swap_in_after_add_fee {
-            return Err(AmmError::ExceededSlippage.into());
+
+        fn account(&self, key: &Pubkey) -> &AccountInfo<'static> {
+            self.accounts
+                .iter()
+                .find(|account| account.key == key)
+                .unwrap()
         }
-        if swap_in_after_add_fee == 0 || swap.amount_out == 0 {
-            return Err(AmmError::InvalidInput.into());
+
+        fn program(&mut self, program: TestProgram) -> Pubkey {
+            let program_id = self.add(Pubkey::new_unique(), Pubkey::default(), Vec::new(), true);
+            TEST_PROGRAMS.with(|programs| programs.borrow_mut().insert(program_id, program));
+            program_id
         }
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap.amount_out >= total_pc_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-            }
-            SwapDirection::PC2Coin => {
-                if swap.amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
+        fn signer(&mut self) -> Pubkey {
+            self.add(Pubkey::new_unique(), Pubkey::default(), Vec::new(), false)
+        }
+
+        /// Token-2022 mints charge TEST_TRANSFER_FEE_BASIS_POINTS on their transfers.
+        fn mint(&mut self, token_program: Pubkey, decimals: u8, supply: u64) -> Pubkey {
+            let data = if token_program == spl_token_2022::id() {
+                transfer_fee_mint_data(decimals, TEST_TRANSFER_FEE_BASIS_POINTS, u64::MAX)
+            } else {
+                let (authority, _) = Pubkey::find_program_address(&[AUTHORITY_AMM], &crate::id());
+                let mut data = vec![0; spl_token_2022::state::Mint::LEN];
+                spl_token_2022::state::Mint {
+                    mint_authority: COption::Some(authority),
+                    supply,
+                    decimals,
+                    is_initialized: true,
+                    freeze_authority: COption::None,
                 }
+                .pack_into_slice(&mut data);
+                data
+            };
+            self.add(Pubkey::new_unique(), token_program, data, false)
+        }
 
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-            }
-        };
-        amm.recent_epoch = Clock::get()?.epoch;
+        fn token_account(
+            &mut self,
+            token_program: Pubkey,
+            mint: Pubkey,
+            owner: Pubkey,
+            amount: u64,
+        ) -> Pubkey {
+            use spl_token_2022::extension::{
+                transfer_fee::TransferFeeAmount, BaseStateWithExtensionsMut, StateWithExtensionsMut,
+            };
 
-        Ok(())
-    }
+            let account = spl_token_2022::state::Account {
+                mint,
+                owner,
+                amount,
+                state: spl_token_2022::state::AccountState::Initialized,
+                ..Default::default()
+            };
+            let data = if token_program == spl_token_2022::id() {
+                let mut data = vec![
+                    0;
+                    ExtensionType::try_calculate_account_len::<
+                        spl_token_2022::state::Account,
+                    >(&[ExtensionType::TransferFeeAmount])
+                    .unwrap()
+                ];
+                let mut state =
+                    StateWithExtensionsMut::<spl_token_2022::state::Account>::unpack_uninitialized(
+                        &mut data,
+                    )
+                    .unwrap();
+                state.init_extension::<TransferFeeAmount>(true).unwrap();
+                state.base = account;
+                state.pack_base();
+                state.init_account_type().unwrap();
+                data
+            } else {
+                let mut data = vec![0; spl_token_2022::state::Account::LEN];
+                account.pack_into_slice(&mut data);
+                data
+            };
+            self.add(Pubkey::new_unique(), token_program, data, false)
+        }
 
-    pub fn process_swap_base_in_v2(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        swap: SwapInstructionBaseIn,
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let token_program_info = next_account_info(account_info_iter)?;
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_authority_info = next_account_info(account_info_iter)?;
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if amm.pc_vault_mint == amm.coin_vault_mint {
-            return Err(AmmError::NotAllowed.into());
+        /// An open pool holding the vault amounts and lp_amount, its lp mint supply.
+        fn pool(
+            &mut self,
+            token_program: Pubkey,
+            coin_mint: Pubkey,
+            pc_mint: Pubkey,
+            coin_amount: u64,
+            pc_amount: u64,
+            lp_amount: u64,
+        ) -> TestPool {
+            let (authority, nonce) = Pubkey::find_program_address(&[AUTHORITY_AMM], &crate::id());
+            let amm = Pubkey::new_unique();
+            let coin_decimals = self.mint_state(&coin_mint).decimals;
+            let pc_decimals = self.mint_state(&pc_mint).decimals;
+            let coin_vault = self.token_account(token_program, coin_mint, authority, coin_amount);
+            let pc_vault = self.token_account(token_program, pc_mint, authority, pc_amount);
+            let lp_mint = self.mint(spl_token::id(), coin_decimals, lp_amount);
+            let mut target_orders = <TargetOrders as bytemuck::Zeroable>::zeroed();
+            target_orders.owner = amm;
+            let target_orders = self.add(
+                Pubkey::new_unique(),
+                crate::id(),
+                bytemuck::bytes_of(&target_orders).to_vec(),
+                false,
+            );
+            let mut amm_info = AmmInfo::default();
+            amm_info
+                .initialize(nonce, 0, coin_decimals, pc_decimals, 0, 0)
+                .unwrap();
+            amm_info.status = AmmStatus::Initialized.into_u64();
+            amm_info.coin_vault = coin_vault;
+            amm_info.pc_vault = pc_vault;
+            amm_info.coin_vault_mint = coin_mint;
+            amm_info.pc_vault_mint = pc_mint;
+            amm_info.lp_mint = lp_mint;
+            amm_info.target_orders = target_orders;
+            amm_info.lp_amount = lp_amount;
+            self.add(
+                amm,
+                crate::id(),
+                bytemuck::bytes_of(&amm_info).to_vec(),
+                false,
+            );
+            TestPool {
+                amm,
+                authority,
+                target_orders,
+                lp_mint,
+                coin_mint,
+                pc_mint,
+                coin_vault,
+                pc_vault,
+                token_program,
+            }
         }
-        let user_source_info = next_account_info(account_info_iter)?;
-        let user_destination_info = next_account_info(account_info_iter)?;
-        let user_source_owner = next_account_info(account_info_iter)?;
-        if !user_source_owner.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+
+        /// A user of the pool holding amount of coin and pc, and lp_amount of lp.
+        fn user(&mut self, pool: &TestPool, amount: u64, lp_amount: u64) -> TestUser {
+            let owner = self.signer();
+            TestUser {
+                owner,
+                coin: self.token_account(pool.token_program, pool.coin_mint, owner, amount),
+                pc: self.token_account(pool.token_program, pool.pc_mint, owner, amount),
+                lp: self.token_account(spl_token::id(), pool.lp_mint, owner, lp_amount),
+            }
         }
-        check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
-        );
-        let spl_token_program_id = token_program_info.key;
-        if *amm_authority_info.key
-            != Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?
-        {
-            return Err(AmmError::InvalidProgramAddress.into());
+
+        fn amount(&self, key: &Pubkey) -> u64 {
+            let account = self.account(key);
+            Processor::unpack_token_account(account, account.owner)
+                .unwrap()
+                .amount
         }
-        check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
-        );
-        check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
-        );
 
-        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
-            return Err(AmmError::InvalidUserToken.into());
+        fn mint_state(&self, key: &Pubkey) -> spl_token_2022::state::Mint {
+            let account = self.account(key);
+            Processor::unpack_mint(account, account.owner).unwrap()
         }
-        if *user_destination_info.key == amm.pc_vault
-            || *user_destination_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidUserToken.into());
+
+        fn amm(&self, pool: &TestPool) -> AmmInfo {
+            *AmmInfo::load(self.account(&pool.amm)).unwrap()
         }
 
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+        fn amm_config(&self) -> RefMut<'_, AmmConfig> {
+            let (amm_config, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], &crate::id());
+            AmmConfig::load_mut(self.account(&amm_config)).unwrap()
+        }
 
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
+        /// Processes instruction as the transaction signed by its signer accounts, the
+        /// accounts and the events are rolled back if it fails.
+        fn process(&self, instruction: &Instruction) -> ProgramResult {
+            let snapshot = self
+                .accounts
+                .iter()
+                .map(|account| (account.lamports(), account.data.borrow().to_vec()))
+                .collect::<Vec<_>>();
+            let event_count = EMITTED_EVENTS.with(|events| events.borrow().len());
+            let account_infos = instruction
+                .accounts
+                .iter()
+                .map(|meta| {
+                    let mut account_info = self.account(&meta.pubkey).clone();
+                    account_info.is_signer = meta.is_signer;
+                    account_info.is_writable = meta.is_writable;
+                    account_info
+                })
+                .collect::<Vec<_>>();
+            let result = process_test_instruction(instruction, &account_infos);
+            if result.is_err() {
+                for (account, (lamports, data)) in self.accounts.iter().zip(snapshot) {
+                    **account.lamports.borrow_mut() = lamports;
+                    account.data.borrow_mut().copy_from_slice(&data);
+                }
+                EMITTED_EVENTS.with(|events| events.borrow_mut().truncate(event_count));
+            }
+            result
+        }
 
-        if !AmmStatus::from_u64(amm.status).swap_permission() {
-            msg!(&format!("swap_base_in_v2: status {}", identity(amm.status)));
-            return Err(AmmError::InvalidStatus.into());
-        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
-            let clock = Clock::get()?;
-            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
-                return Err(AmmError::InvalidStatus.into());
-            } else {
-                amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_in_v2: WaitingTrade to SwapOnly");
+        /// The events passed to EmitEvent self invocations since the accounts were created.
+        fn emitted_events(&self) -> Vec<Vec<u8>> {
+            EMITTED_EVENTS.with(|events| events.borrow().clone())
+        }
+    }
+
+    impl TestPool {
+        /// Appends the `token_2022_accounts` of the pool and the config account.
+        fn with_trailing_accounts(&self, mut instruction: Instruction) -> Instruction {
+            if self.token_program == spl_token_2022::id() {
+                instruction
+                    .accounts
+                    .extend(crate::instruction::token_2022_accounts(
+                        &self.coin_mint,
+                        &self.pc_mint,
+                    ));
             }
+            instruction
+                .accounts
+                .push(crate::instruction::amm_config_account(&crate::id()));
+            instruction
         }
 
-        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
+        fn swap_base_in(
+            &self,
+            user_source: &Pubkey,
+            user_destination: &Pubkey,
+            owner: &Pubkey,
+            amount_in: u64,
+            minimum_amount_out: u64,
+        ) -> Instruction {
+            self.with_trailing_accounts(
+                crate::instruction::swap_base_in_v2(
+                    &crate::id(),
+                    &self.amm,
+                    &self.authority,
+                    &self.coin_vault,
+                    &self.pc_vault,
+                    user_source,
+                    user_destination,
+                    owner,
+                    amount_in,
+                    minimum_amount_out,
+                )
+                .unwrap(),
+            )
+        }
 
-        let swap_direction;
-        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
-            swap_direction = SwapDirection::Coin2PC
-        } else if user_source.mint == amm_pc_vault.mint
-            && user_destination.mint == amm_coin_vault.mint
-        {
-            swap_direction = SwapDirection::PC2Coin
-        } else {
-            return Err(AmmError::InvalidUserToken.into());
+        fn swap_base_out(
+            &self,
+            user_source: &Pubkey,
+            user_destination: &Pubkey,
+            owner: &Pubkey,
+            max_amount_in: u64,
+            amount_out: u64,
+        ) -> Instruction {
+            self.with_trailing_accounts(
+                crate::instruction::swap_base_out_v2(
+                    &crate::id(),
+                    &self.amm,
+                    &self.authority,
+                    &self.coin_vault,
+                    &self.pc_vault,
+                    user_source,
+                    user_destination,
+                    owner,
+                    max_amount_in,
+                    amount_out,
+                )
+                .unwrap(),
+            )
         }
-        if user_source.amount < swap.amount_in {
-            encode_ray_log(SwapBaseInLog {
-                log_type: LogType::SwapBaseIn.into_u8(),
-                amount_in: swap.amount_in,
-                minimum_out: swap.minimum_amount_out,
-                direction: swap_direction as u64,
-                user_source: user_source.amount,
-                pool_coin: total_coin_without_take_pnl,
-                pool_pc: total_pc_without_take_pnl,
-                out_amount: 0,
-            });
-            return Err(AmmError::InsufficientFunds.into());
+
+        fn withdraw_single_side(
+            &self,
+            user: &TestUser,
+            user_destination: &Pubkey,
+            amount: u64,
+            min_amount_out: u64,
+        ) -> Instruction {
+            self.with_trailing_accounts(
+                crate::instruction::withdraw_single_side(
+                    &crate::id(),
+                    &self.amm,
+                    &self.authority,
+                    &self.target_orders,
+                    &self.lp_mint,
+                    &self.coin_vault,
+                    &self.pc_vault,
+                    &user.lp,
+                    user_destination,
+                    &user.owner,
+                    amount,
+                    min_amount_out,
+                )
+                .unwrap(),
+            )
         }
-        let swap_fee = U128::from(swap.amount_in)
-            .checked_mul(amm.fees.swap_fee_numerator.into())
-            .unwrap()
-            .checked_ceil_div(amm.fees.swap_fee_denominator.into())
-            .unwrap();
-        let swap_in_after_deduct_fee = U128::from(swap.amount_in).checked_sub(swap_fee).unwrap();
-        let swap_amount_out = Calculator::swap_token_amount_base_in(
-            swap_in_after_deduct_fee,
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
-        )
-        .as_u64();
-        encode_ray_log(SwapBaseInLog {
-            log_type: LogType::SwapBaseIn.into_u8(),
-            amount_in: swap.amount_in,
-            minimum_out: swap.minimum_amount_out,
-            direction: swap_direction as u64,
-            user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            out_amount: swap_amount_out,
-        });
-        if swap_amount_out < swap.minimum_amount_out {
-            return Err(AmmError::ExceededSlippage.into());
+
+        fn deposit(
+            &self,
+            user: &TestUser,
+            max_coin_amount: u64,
+            max_pc_amount: u64,
+            base_side: u64,
+        ) -> Instruction {
+            self.with_trailing_accounts(Instruction {
+                program_id: crate::id(),
+                accounts: vec![
+                    AccountMeta::new_readonly(spl_token::id(), false),
+                    AccountMeta::new(self.amm, false),
+                    AccountMeta::new_readonly(self.authority, false),
+                    AccountMeta::new(self.target_orders, false),
+                    AccountMeta::new(self.lp_mint, false),
+                    AccountMeta::new(self.coin_vault, false),
+                    AccountMeta::new(self.pc_vault, false),
+                    AccountMeta::new(user.coin, false),
+                    AccountMeta::new(user.pc, false),
+                    AccountMeta::new(user.lp, false),
+                    AccountMeta::new_readonly(user.owner, true),
+                ],
+                data: AmmInstruction::Deposit(DepositInstruction {
+                    max_coin_amount,
+                    max_pc_amount,
+                    base_side,
+                    other_amount_min: None,
+                })
+                .pack()
+                .unwrap(),
+            })
         }
-        if swap_amount_out == 0 || swap.amount_in == 0 {
-            return Err(AmmError::InvalidInput.into());
+
+        #[cfg(feature = "client")]
+        fn withdraw(&self, user: &TestUser, amount: u64) -> Instruction {
+            self.with_trailing_accounts(Instruction {
+                program_id: crate::id(),
+                accounts: vec![
+                    AccountMeta::new_readonly(spl_token::id(), false),
+                    AccountMeta::new(self.amm, false),
+                    AccountMeta::new_readonly(self.authority, false),
+                    AccountMeta::new(self.target_orders, false),
+                    AccountMeta::new(self.lp_mint, false),
+                    AccountMeta::new(self.coin_vault, false),
+                    AccountMeta::new(self.pc_vault, false),
+                    AccountMeta::new(user.lp, false),
+                    AccountMeta::new(user.coin, false),
+                    AccountMeta::new(user.pc, false),
+                    AccountMeta::new_readonly(user.owner, true),
+                ],
+                data: AmmInstruction::Withdraw(WithdrawInstruction {
+                    amount,
+                    min_coin_amount: None,
+                    min_pc_amount: None,
+                })
+                .pack()
+                .unwrap(),
+            })
         }
+    }
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap_amount_out >= total_pc_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap.amount_in,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
+    #[cfg(feature = "client")]
+    proptest! {
+        #[test]
+        fn test_pool_quoter(
+            curve_type in 0u64..3,
+            coin_vault in 1_000_000_000u64..1_000_000_000_000_000,
+            pc_vault in 1_000_000_000u64..1_000_000_000_000_000,
+            pnl_ratio in 0u64..1_000,
+            lp_amount in 1_000u64..1_000_000_000_000,
+            amount in 1u64..1_000_000_000_000,
+            coin2pc in any::<bool>(),
+            token_2022 in any::<bool>(),
+            coin_fee_basis_points in 0u16..1_000,
+            pc_fee_basis_points in 0u16..1_000,
+            maximum_fee in 1u64..1_000_000_000,
+        ) {
+            use crate::quoter::PoolQuoter;
+
+            let mut amm = AmmInfo::default();
+            amm.initialize(0, 0, 6, 9, 1000000, 1).unwrap();
+            amm.curve_type = curve_type;
+            amm.initial_amp = 100;
+            amm.target_amp = 100;
+            amm.coin_weight = 80;
+            amm.lp_amount = lp_amount;
+            amm.protocol_fee_enabled = 1;
+            amm.state_data.need_take_pnl_coin = coin_vault / 10_000 * pnl_ratio;
+            amm.state_data.protocol_fee_pc = pc_vault / 10_000 * pnl_ratio;
+            let swap_direction = if coin2pc {
+                SwapDirection::Coin2PC
+            } else {
+                SwapDirection::PC2Coin
+            };
+            let timestamp = 1_700_000_000;
+
+            // the processor path, with spl_token vaults or Token-2022 vaults of mints
+            // charging a transfer fee
+            let token_program = if token_2022 {
+                spl_token_2022::id()
+            } else {
+                spl_token::id()
+            };
+            let (coin_mint, pc_mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+            let (mut lamports, mut data) = (0u64, [0u8; 0]);
+            let (mut coin_mint_lamports, mut pc_mint_lamports) = (0u64, 0u64);
+            let mut coin_mint_data = transfer_fee_mint_data(6, coin_fee_basis_points, maximum_fee);
+            let mut pc_mint_data = transfer_fee_mint_data(9, pc_fee_basis_points, maximum_fee);
+            let token_program_info = AccountInfo::new(
+                &token_program,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                &token_program,
+                true,
+                0,
+            );
+            let coin_mint_info = AccountInfo::new(
+                &coin_mint,
+                false,
+                false,
+                &mut coin_mint_lamports,
+                &mut coin_mint_data,
+                &token_program,
+                false,
+                0,
+            );
+            let pc_mint_info = AccountInfo::new(
+                &pc_mint,
+                false,
+                false,
+                &mut pc_mint_lamports,
+                &mut pc_mint_data,
+                &token_program,
+                false,
+                0,
+            );
+            let coin_vault_token = VaultToken::new(&token_program_info, &coin_mint_info, 6);
+            let pc_vault_token = VaultToken::new(&token_program_info, &pc_mint_info, 9);
+            let quoter = PoolQuoter::new(&amm, coin_vault, pc_vault, timestamp)
+                .unwrap()
+                .with_transfer_fees(
+                    coin_vault_token.transfer_fee_config().unwrap().as_ref(),
+                    pc_vault_token.transfer_fee_config().unwrap().as_ref(),
+                    0,
+                );
+            let (source_vault_token, destination_vault_token) = match swap_direction {
+                SwapDirection::Coin2PC => (coin_vault_token, pc_vault_token),
+                SwapDirection::PC2Coin => (pc_vault_token, coin_vault_token),
+            };
+            let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+                Calculator::calc_total_without_take_pnl_no_orderbook(pc_vault, coin_vault, &amm)
+                    .unwrap();
+            let total_out_without_take_pnl = match swap_direction {
+                SwapDirection::Coin2PC => total_pc_without_take_pnl,
+                SwapDirection::PC2Coin => total_coin_without_take_pnl,
+            };
+            let curve = amm.curve(timestamp);
+
+            let swap_amounts = Processor::calc_swap_base_in(
+                &amm.fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                amount,
+                0,
+            )
+            .unwrap();
+            let executes = swap_amounts.amount_out_after_transfer_fee != 0
+                && swap_amounts.amount_out < total_out_without_take_pnl;
+            match quoter.swap_base_in(swap_direction, amount) {
+                Ok(quote) => prop_assert!(executes && quote == swap_amounts),
+                Err(_) => prop_assert!(!executes),
             }
-            SwapDirection::PC2Coin => {
-                if swap_amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
+
+            let swap_amounts = Processor::calc_swap_base_out(
+                &amm.fees,
+                &*curve,
+                &source_vault_token,
+                &destination_vault_token,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                swap_direction,
+                amount,
+                0,
+            );
+            match (quoter.swap_base_out(swap_direction, amount), swap_amounts) {
+                (Ok(quote), Ok(swap_amounts)) => {
+                    prop_assert!(swap_amounts.amount_in != 0 && quote == swap_amounts)
                 }
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap.amount_in,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap_amount_out,
-                )?;
+                (Err(_), Ok(swap_amounts)) => prop_assert_eq!(swap_amounts.amount_in, 0),
+                (Ok(_), Err(_)) => prop_assert!(false),
+                (Err(_), Err(_)) => {}
             }
-        };
-        amm.recent_epoch = Clock::get()?.epoch;
 
-        Ok(())
-    }
+            let base_side = coin2pc as u64;
+            let deduct_base_amount = if base_side == 0 {
+                coin_vault_token.post_fee_amount(0, amount).unwrap()
+            } else {
+                pc_vault_token.post_fee_amount(0, amount).unwrap()
+            };
+            let deposit_amounts = Processor::calc_deposit_vault_amounts(
+                &*curve,
+                amm.lp_amount,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                base_side,
+                deduct_base_amount,
+            );
+            let (max_coin_amount, max_pc_amount) = if base_side == 0 {
+                (amount, u64::MAX)
+            } else {
+                (u64::MAX, amount)
+            };
+            match (
+                quoter.deposit(max_coin_amount, max_pc_amount, base_side),
+                deposit_amounts,
+            ) {
+                (Ok(quote), Ok((deduct_other_amount, mint_lp_amount))) => {
+                    let other_amount = if base_side == 0 {
+                        pc_vault_token.pre_fee_amount(0, deduct_other_amount).unwrap()
+                    } else {
+                        coin_vault_token.pre_fee_amount(0, deduct_other_amount).unwrap()
+                    };
+                    prop_assert_eq!(quote.lp_amount, mint_lp_amount);
+                    prop_assert_eq!(
+                        (quote.coin_amount, quote.pc_amount),
+                        if base_side == 0 {
+                            (amount, other_amount)
+                        } else {
+                            (other_amount, amount)
+                        }
+                    );
+                }
+                (Err(_), Ok((deduct_other_amount, mint_lp_amount))) => {
+                    prop_assert!(deduct_other_amount == 0 || mint_lp_amount == 0)
+                }
+                (Ok(_), Err(_)) => prop_assert!(false),
+                (Err(_), Err(_)) => {}
+            }
 
-    pub fn process_swap_base_out_v2(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        swap: SwapInstructionBaseOut,
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let token_program_info = next_account_info(account_info_iter)?;
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_authority_info = next_account_info(account_info_iter)?;
-        let amm_coin_vault_info = next_account_info(account_info_iter)?;
-        let amm_pc_vault_info = next_account_info(account_info_iter)?;
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
-        if amm.pc_vault_mint == amm.coin_vault_mint {
-            return Err(AmmError::NotAllowed.into());
+            let withdraw_lp = amount % lp_amount;
+            let withdraw_amounts = Processor::calc_withdraw_vault_amounts(
+                &*curve,
+                amm.lp_amount,
+                total_pc_without_take_pnl,
+                total_coin_without_take_pnl,
+                withdraw_lp,
+            )
+            .unwrap();
+            let executes = withdraw_lp != 0
+                && withdraw_amounts.0 != 0
+                && withdraw_amounts.1 != 0
+                && withdraw_amounts.0 < coin_vault
+                && withdraw_amounts.1 < pc_vault;
+            match quoter.withdraw(withdraw_lp) {
+                Ok(quote) => {
+                    prop_assert!(
+                        executes && (quote.coin_amount, quote.pc_amount) == withdraw_amounts
+                    );
+                    prop_assert_eq!(
+                        (
+                            quote.coin_amount_after_transfer_fee,
+                            quote.pc_amount_after_transfer_fee
+                        ),
+                        (
+                            coin_vault_token.post_fee_amount(0, quote.coin_amount).unwrap(),
+                            pc_vault_token.post_fee_amount(0, quote.pc_amount).unwrap()
+                        )
+                    );
+                }
+                Err(_) => prop_assert!(!executes),
+            }
         }
-        let user_source_info = next_account_info(account_info_iter)?;
-        let user_destination_info = next_account_info(account_info_iter)?;
-        let user_source_owner = next_account_info(account_info_iter)?;
-        if !user_source_owner.is_signer {
-            return Err(AmmError::InvalidSignAccount.into());
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_pool_quoter_handlers() {
+        use crate::quoter::PoolQuoter;
+
+        for token_program in [spl_token::id(), spl_token_2022::id()] {
+            let mut accounts = TestAccounts::new();
+            let coin_mint = accounts.mint(token_program, 6, 0);
+            let pc_mint = accounts.mint(token_program, 9, 0);
+            let pool = accounts.pool(
+                token_program,
+                coin_mint,
+                pc_mint,
+                1_000_000_000,
+                3_000_000_000_000,
+                1_000_000_000,
+            );
+            let user = accounts.user(&pool, 1_000_000_000_000, 100_000_000);
+            let transfer_fee = |mint: &Pubkey| {
+                let data = accounts.account(mint).data.borrow();
+                StateWithExtensions::<spl_token_2022::state::Mint>::unpack(&data)
+                    .unwrap()
+                    .get_extension::<TransferFeeConfig>()
+                    .ok()
+                    .copied()
+            };
+            let (coin_transfer_fee, pc_transfer_fee) =
+                (transfer_fee(&coin_mint), transfer_fee(&pc_mint));
+            assert_eq!(
+                coin_transfer_fee.is_some(),
+                token_program == spl_token_2022::id()
+            );
+            let quoter = |accounts: &TestAccounts| {
+                PoolQuoter::new(
+                    &accounts.amm(&pool),
+                    accounts.amount(&pool.coin_vault),
+                    accounts.amount(&pool.pc_vault),
+                    TEST_TIMESTAMP as u64,
+                )
+                .unwrap()
+                .with_transfer_fees(
+                    coin_transfer_fee.as_ref(),
+                    pc_transfer_fee.as_ref(),
+                    TEST_EPOCH,
+                )
+            };
+            let keys = [user.coin, user.pc, user.lp, pool.coin_vault, pool.pc_vault];
+            let amounts = |accounts: &TestAccounts| keys.map(|key| accounts.amount(&key));
+            let deltas = |before: [u64; 5], after: [u64; 5]| {
+                [0, 1, 2, 3, 4].map(|i| after[i] as i128 - before[i] as i128)
+            };
+
+            // swap base in, coin to pc
+            let quote = quoter(&accounts)
+                .swap_base_in(SwapDirection::Coin2PC, 1_000_000)
+                .unwrap();
+            let before = amounts(&accounts);
+            accounts
+                .process(&pool.swap_base_in(
+                    &user.coin,
+                    &user.pc,
+                    &user.owner,
+                    1_000_000,
+                    quote.amount_out_after_transfer_fee,
+                ))
+                .unwrap();
+            assert_eq!(
+                deltas(before, amounts(&accounts)),
+                [
+                    -(quote.amount_in as i128),
+                    quote.amount_out_after_transfer_fee as i128,
+                    0,
+                    quote.amount_in_after_transfer_fee as i128,
+                    -(quote.amount_out as i128),
+                ]
+            );
+
+            // swap base out, pc to coin
+            let quote = quoter(&accounts)
+                .swap_base_out(SwapDirection::PC2Coin, 1_000_000)
+                .unwrap();
+            let before = amounts(&accounts);
+            accounts
+                .process(&pool.swap_base_out(
+                    &user.pc,
+                    &user.coin,
+                    &user.owner,
+                    quote.amount_in,
+                    1_000_000,
+                ))
+                .unwrap();
+            assert_eq!(
+                deltas(before, amounts(&accounts)),
+                [
+                    quote.amount_out_after_transfer_fee as i128,
+                    -(quote.amount_in as i128),
+                    0,
+                    -(quote.amount_out as i128),
+                    quote.amount_in_after_transfer_fee as i128,
+                ]
+            );
+
+            // deposit of a fixed coin amount
+            let quote = quoter(&accounts).deposit(1_000_000, u64::MAX, 0).unwrap();
+            let before = amounts(&accounts);
+            accounts
+                .process(&pool.deposit(&user, quote.coin_amount, quote.pc_amount, 0))
+                .unwrap();
+            assert_eq!(
+                deltas(before, amounts(&accounts)),
+                [
+                    -(quote.coin_amount as i128),
+                    -(quote.pc_amount as i128),
+                    quote.lp_amount as i128,
+                    post_transfer_fee_amount(
+                        coin_transfer_fee.as_ref(),
+                        TEST_EPOCH,
+                        quote.coin_amount
+                    )
+                    .unwrap() as i128,
+                    post_transfer_fee_amount(pc_transfer_fee.as_ref(), TEST_EPOCH, quote.pc_amount)
+                        .unwrap() as i128,
+                ]
+            );
+
+            // withdraw
+            let quote = quoter(&accounts).withdraw(10_000_000).unwrap();
+            let before = amounts(&accounts);
+            accounts.process(&pool.withdraw(&user, 10_000_000)).unwrap();
+            assert_eq!(
+                deltas(before, amounts(&accounts)),
+                [
+                    quote.coin_amount_after_transfer_fee as i128,
+                    quote.pc_amount_after_transfer_fee as i128,
+                    -10_000_000,
+                    -(quote.coin_amount as i128),
+                    -(quote.pc_amount as i128),
+                ]
+            );
+            if token_program == spl_token_2022::id() {
+                assert!(quote.coin_amount_after_transfer_fee < quote.coin_amount);
+            }
         }
+    }
 
-        check_assert_eq!(
-            *token_program_info.key,
-            spl_token::id(),
-            "spl_token_program",
-            AmmError::InvalidSplTokenProgram
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_pool_quoter_pnl() {
+        use crate::quoter::PoolQuoter;
+
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 6, 9, 1000000, 1).unwrap();
+        amm.lp_amount = 1_000_000;
+        amm.protocol_fee_enabled = 0;
+        // the pnl the vaults still hold is not part of the pool
+        amm.state_data.need_take_pnl_coin = 1_000;
+        amm.state_data.protocol_fee_pc = 2_000;
+        let quoter = PoolQuoter::new(&amm, 101_000, 202_000, 0).unwrap();
+        assert_eq!(quoter.total_without_take_pnl(), (200_000, 100_000));
+        assert!(quoter.swap_base_in(SwapDirection::Coin2PC, 1_000).is_ok());
+        // deposits and withdraws book the pnl by calc_pnl_x & calc_pnl_y first
+        assert_eq!(
+            quoter.deposit(1_000, u64::MAX, 0),
+            Err(AmmError::InvalidTargetOrders.into())
         );
-        let spl_token_program_id = token_program_info.key;
-        let authority = Self::authority_id(program_id, AUTHORITY_AMM, amm.nonce as u8)?;
-        check_assert_eq!(
-            *amm_authority_info.key,
-            authority,
-            "authority",
-            AmmError::InvalidProgramAddress
+        amm.protocol_fee_enabled = 1;
+        let quoter = PoolQuoter::new(&amm, 101_000, 202_000, 0).unwrap();
+        let deposit = quoter.deposit(1_000, u64::MAX, 0).unwrap();
+        assert_eq!((deposit.coin_amount, deposit.pc_amount), (1_000, 2_000));
+        assert_eq!(deposit.lp_amount, 10_000);
+        assert_eq!(
+            quoter.deposit(1_000, 1_999, 0),
+            Err(AmmError::ExceededSlippage.into())
         );
-        check_assert_eq!(
-            *amm_coin_vault_info.key,
-            amm.coin_vault,
-            "coin_vault",
-            AmmError::InvalidCoinVault
+        // dynamic fee pools need their DynamicFeeState to quote a swap
+        amm.dynamic_fee_enabled = 1;
+        let quoter = PoolQuoter::new(&amm, 101_000, 202_000, 0).unwrap();
+        assert!(quoter.swap_base_in(SwapDirection::Coin2PC, 1_000).is_err());
+    }
+
+    #[test]
+    fn test_config_admin() {
+        let mut amm_config = AmmConfig::default();
+        // config accounts without a stored admin have no admin until CreateConfigAccount
+        assert_eq!(Processor::config_admin(&amm_config), Pubkey::default());
+        let admin = Pubkey::new_unique();
+        amm_config.admin = admin;
+        amm_config.pending_admin = Pubkey::new_unique();
+        assert_eq!(Processor::config_admin(&amm_config), admin);
+    }
+
+    #[test]
+    fn test_split_amm_config_account() {
+        let program_id = crate::id();
+        // the global status cannot be skipped by leaving the config account out
+        assert_eq!(
+            Processor::split_amm_config_account(&program_id, &[], false).unwrap_err(),
+            AmmError::WrongAccountsNumber.into()
         );
-        check_assert_eq!(
-            *amm_pc_vault_info.key,
-            amm.pc_vault,
-            "pc_vault",
-            AmmError::InvalidPCVault
+        let key = Pubkey::new_unique();
+        let mut lamports = 0u64;
+        let mut data = vec![0u8; size_of::<AmmConfig>()];
+        let amm_config_info = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            0,
         );
+        assert_eq!(
+            Processor::split_amm_config_account(&program_id, &[amm_config_info], false)
+                .unwrap_err(),
+            AmmError::InvalidConfigAccount.into()
+        );
+    }
 
-        if *user_source_info.key == amm.pc_vault || *user_source_info.key == amm.coin_vault {
-            return Err(AmmError::InvalidUserToken.into());
-        }
-        if *user_destination_info.key == amm.pc_vault
-            || *user_destination_info.key == amm.coin_vault
-        {
-            return Err(AmmError::InvalidUserToken.into());
-        }
-
-        let amm_coin_vault =
-            Self::unpack_token_account(&amm_coin_vault_info, spl_token_program_id)?;
-        let amm_pc_vault = Self::unpack_token_account(&amm_pc_vault_info, spl_token_program_id)?;
+    #[test]
+    fn test_referrer_flag() {
+        let swap = SwapInstructionBaseIn {
+            amount_in: 100,
+            minimum_amount_out: 90,
+            referrer: false,
+        };
+        // swaps without referrer keep their packed layout
+        let mut data = AmmInstruction::SwapBaseInV2(swap).pack().unwrap();
+        assert_eq!(data.len(), 17);
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::SwapBaseInV2(swap)
+        );
+        data.push(1);
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap(),
+            AmmInstruction::SwapBaseInV2(SwapInstructionBaseIn {
+                referrer: true,
+                ..swap
+            })
+        );
+        *data.last_mut().unwrap() = 2;
+        assert_eq!(
+            AmmInstruction::unpack(&data).unwrap_err(),
+            ProgramError::InvalidInstructionData
+        );
 
-        let user_source = Self::unpack_token_account(&user_source_info, spl_token_program_id)?;
-        let user_destination =
-            Self::unpack_token_account(&user_destination_info, spl_token_program_id)?;
+        let program_id = crate::id();
+        let mut instruction = crate::instruction::swap_base_in_v2(
+            &program_id,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            100,
+            90,
+        )
+        .unwrap();
+        let referrer = Pubkey::new_unique();
+        crate::instruction::append_referrer_accounts(&mut instruction, &referrer).unwrap();
+        assert_eq!(instruction.data.last(), Some(&1));
+        assert_eq!(instruction.accounts.len(), 10);
+        assert_eq!(instruction.accounts[9].pubkey, referrer);
+
+        // the referrer accounts are only split off a swap setting referrer
+        let key = Pubkey::new_unique();
+        let mut lamports = 0u64;
+        let mut data = vec![0u8; size_of::<AmmConfig>()];
+        let accounts = [AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &program_id,
+            false,
+            0,
+        )];
+        let (rest, referrer_accounts) =
+            Processor::split_referrer_accounts(&accounts, false).unwrap();
+        assert_eq!(rest.len(), 1);
+        assert!(referrer_accounts.is_none());
+        assert_eq!(
+            Processor::split_referrer_accounts(&accounts, true).unwrap_err(),
+            AmmError::WrongAccountsNumber.into()
+        );
+    }
 
-        if !AmmStatus::from_u64(amm.status).swap_permission() {
-            return Err(AmmError::InvalidStatus.into());
-        } else if amm.status == AmmStatus::WaitingTrade.into_u64() {
-            let clock = Clock::get()?;
-            if (clock.unix_timestamp as u64) < amm.state_data.pool_open_time {
-                return Err(AmmError::InvalidStatus.into());
-            } else {
-                amm.status = AmmStatus::SwapOnly.into_u64();
-                msg!("swap_base_out_v2: WaitingTrade to SwapOnly");
+    #[test]
+    fn test_load_referrer() {
+        let program_id = crate::id();
+        let (amm_config_key, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], &program_id);
+        let mut amm_config_lamports = 0u64;
+        let mut amm_config_data = vec![0u8; size_of::<AmmConfig>()];
+        let amm_config_info = AccountInfo::new(
+            &amm_config_key,
+            false,
+            false,
+            &mut amm_config_lamports,
+            &mut amm_config_data,
+            &program_id,
+            false,
+            0,
+        );
+        let (mint, user) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let user_source = spl_token_2022::state::Account {
+            mint,
+            owner: user,
+            amount: 1_000_000,
+            state: spl_token_2022::state::AccountState::Initialized,
+            ..Default::default()
+        };
+        let referrer_data = |owner: Pubkey| {
+            let mut data = vec![0u8; spl_token::state::Account::LEN];
+            spl_token::state::Account {
+                mint,
+                owner,
+                state: spl_token::state::AccountState::Initialized,
+                ..Default::default()
             }
-        }
+            .pack_into_slice(&mut data);
+            data
+        };
+        let referrer_key = Pubkey::new_unique();
+        let token_program = spl_token::id();
+        let mut referrer_lamports = 0u64;
+        // the user cannot refer itself
+        let mut data = referrer_data(user);
+        let referrer_info = AccountInfo::new(
+            &referrer_key,
+            false,
+            true,
+            &mut referrer_lamports,
+            &mut data,
+            &token_program,
+            false,
+            0,
+        );
+        let referrer_accounts = [amm_config_info.clone(), referrer_info];
+        assert_eq!(
+            Processor::load_referrer(
+                &program_id,
+                Some(&referrer_accounts),
+                &AmmInfo::default(),
+                &user_source,
+                &user,
+                10_000,
+            )
+            .unwrap_err(),
+            AmmError::InvalidReferrerAccount.into()
+        );
+        let mut other_lamports = 0u64;
+        let mut data = referrer_data(Pubkey::new_unique());
+        let referrer_info = AccountInfo::new(
+            &referrer_key,
+            false,
+            true,
+            &mut other_lamports,
+            &mut data,
+            &token_program,
+            false,
+            0,
+        );
+        let referrer_accounts = [amm_config_info, referrer_info];
+        let referrer = Processor::load_referrer(
+            &program_id,
+            Some(&referrer_accounts),
+            &AmmInfo::default(),
+            &user_source,
+            &user,
+            10_000,
+        )
+        .unwrap();
+        assert_eq!(referrer.map(|(info, _)| *info.key), Some(referrer_key));
+    }
 
-        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
-            Calculator::calc_total_without_take_pnl_no_orderbook(
-                amm_pc_vault.amount,
-                amm_coin_vault.amount,
-                &amm,
-            )?;
+    #[test]
+    fn test_accrue_protocol_fee() {
+        let mut amm = AmmInfo::default();
+        amm.fees.initialize().unwrap();
+        // pools still taking pnl by calc_pnl_x & calc_pnl_y accrue nothing
+        assert_eq!(
+            Processor::accrue_protocol_fee(&mut amm, SwapDirection::Coin2PC, 10000, 0),
+            0
+        );
+        amm.protocol_fee_enabled = 1;
+        assert_eq!(
+            Processor::accrue_protocol_fee(&mut amm, SwapDirection::Coin2PC, 10000, 0),
+            1200
+        );
+        assert_eq!(
+            Processor::accrue_protocol_fee(&mut amm, SwapDirection::PC2Coin, 10000, 9000),
+            1000
+        );
+        let protocol_fee_coin = amm.state_data.protocol_fee_coin;
+        let protocol_fee_pc = amm.state_data.protocol_fee_pc;
+        assert_eq!(protocol_fee_coin, 1200);
+        assert_eq!(protocol_fee_pc, 1000);
+    }
 
-        let swap_direction;
-        if user_source.mint == amm_coin_vault.mint && user_destination.mint == amm_pc_vault.mint {
-            swap_direction = SwapDirection::Coin2PC
-        } else if user_source.mint == amm_pc_vault.mint
-            && user_destination.mint == amm_coin_vault.mint
-        {
-            swap_direction = SwapDirection::PC2Coin
-        } else {
-            return Err(AmmError::InvalidUserToken.into());
+    #[test]
+    fn test_single_side_swap_amount() {
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        for (amount_in, total_coin, total_pc) in [
+            (1_000_000u64, 1_000_000_000u64, 5_000_000_000u64),
+            (500_000_000, 1_000_000_000, 5_000_000_000),
+            (1_000_000, 123_456_789, 987_654_321_000),
+            (u64::MAX / 4, u64::MAX / 2, u64::MAX / 3),
+        ] {
+            let swap_in = Calculator::single_side_swap_amount(
+                amount_in,
+                total_coin,
+                fees.swap_fee_numerator,
+                fees.swap_fee_denominator,
+            );
+            assert!(swap_in < amount_in);
+            let (_swap_fee, swap_out) = Processor::calc_swap_vault_base_in(
+                &fees,
+                &ConstantProductCurve,
+                total_pc,
+                total_coin,
+                SwapDirection::Coin2PC,
+                swap_in,
+            )
+            .unwrap();
+            // the rest of amount_in and swap_out are in the pool ratio after the swap
+            let rest = U256::from(amount_in - swap_in) * U256::from(total_pc - swap_out);
+            let out = U256::from(swap_out) * U256::from(total_coin + swap_in);
+            let diff = if rest > out { rest - out } else { out - rest };
+            assert!(diff * 100_000 <= out);
         }
+    }
 
-        let swap_in_before_add_fee = Calculator::swap_token_amount_base_out(
-            swap.amount_out.into(),
-            total_pc_without_take_pnl.into(),
-            total_coin_without_take_pnl.into(),
-            swap_direction,
+    /// A pool lending coin by flash loans at a 0.3% fee, and its user.
+    fn flash_loan_pool() -> (TestAccounts, TestPool, TestUser) {
+        let mut accounts = TestAccounts::new();
+        accounts.amm_config().flash_fee_rate = 30;
+        let coin_mint = accounts.mint(spl_token::id(), 6, 0);
+        let pc_mint = accounts.mint(spl_token::id(), 6, 0);
+        let pool = accounts.pool(
+            spl_token::id(),
+            coin_mint,
+            pc_mint,
+            1_000_000_000,
+            1_000_000_000,
+            1_000_000_000,
         );
-        // swap_in_after_add_fee * (1 - 0.0025) = swap_in_before_add_fee
-        // swap_in_after_add_fee = swap_in_before_add_fee / (1 - 0.0025)
-        let swap_in_after_add_fee = swap_in_before_add_fee
-            .checked_mul(amm.fees.swap_fee_denominator.into())
-            .unwrap()
-            .checked_ceil_div(
-                (amm.fees
-                    .swap_fee_denominator
-                    .checked_sub(amm.fees.swap_fee_numerator)
-                    .unwrap())
-                .into(),
-            )
-            .unwrap()
-            .as_u64();
-        encode_ray_log(SwapBaseOutLog {
-            log_type: LogType::SwapBaseOut.into_u8(),
-            max_in: swap.max_amount_in,
-            amount_out: swap.amount_out,
-            direction: swap_direction as u64,
-            user_source: user_source.amount,
-            pool_coin: total_coin_without_take_pnl,
-            pool_pc: total_pc_without_take_pnl,
-            deduct_in: swap_in_after_add_fee,
-        });
-        if user_source.amount < swap_in_after_add_fee {
-            return Err(AmmError::InsufficientFunds.into());
-        }
-        if swap.max_amount_in < swap_in_after_add_fee {
-            return Err(AmmError::ExceededSlippage.into());
-        }
-        if swap_in_after_add_fee == 0 || swap.amount_out == 0 {
-            return Err(AmmError::InvalidInput.into());
-        }
+        let user = accounts.user(&pool, 1_000_000_000, 1_000_000);
+        (accounts, pool, user)
+    }
 
-        match swap_direction {
-            SwapDirection::Coin2PC => {
-                if swap.amount_out >= total_pc_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source coin to amm_coin_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_pc_vault to destination pc
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-            }
-            SwapDirection::PC2Coin => {
-                if swap.amount_out >= total_coin_without_take_pnl {
-                    return Err(AmmError::InsufficientFunds.into());
-                }
-                // deposit source pc to amm_pc_vault
-                Invokers::token_transfer(
-                    token_program_info.clone(),
-                    user_source_info.clone(),
-                    amm_pc_vault_info.clone(),
-                    user_source_owner.clone(),
-                    swap_in_after_add_fee,
-                )?;
-                // withdraw amm_coin_vault to destination coin
-                Invokers::token_transfer_with_authority(
-                    token_program_info.clone(),
-                    amm_coin_vault_info.clone(),
-                    user_destination_info.clone(),
-                    amm_authority_info.clone(),
-                    AUTHORITY_AMM,
-                    amm.nonce as u8,
-                    swap.amount_out,
-                )?;
-            }
-        };
-        amm.recent_epoch = Clock::get()?.epoch;
+    fn flash_loan_instruction(
+        pool: &TestPool,
+        user: &TestUser,
+        borrower_program: &Pubkey,
+        borrower_accounts: Vec<AccountMeta>,
+        coin_amount: u64,
+        data: Vec<u8>,
+    ) -> Instruction {
+        crate::instruction::flash_loan(
+            &crate::id(),
+            &pool.amm,
+            &pool.authority,
+            &pool.coin_vault,
+            &pool.pc_vault,
+            &user.coin,
+            &user.pc,
+            borrower_program,
+            borrower_accounts,
+            coin_amount,
+            0,
+            data,
+        )
+        .unwrap()
+    }
 
-        Ok(())
+    /// Accounts of test_repay_program paying the coin vault back from the user.
+    fn repay_accounts(pool: &TestPool, user: &TestUser) -> Vec<AccountMeta> {
+        vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(user.coin, false),
+            AccountMeta::new(pool.coin_vault, false),
+            AccountMeta::new_readonly(user.owner, true),
+        ]
     }
 
-    pub fn process_set_params(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        setparams: SetParamsInstruction,
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
+    #[test]
+    fn test_flash_loan_handler() {
+        let coin_amount = 1_000_000;
+        let fee = 3_000;
+        // repaid with the fee
+        let (mut accounts, pool, user) = flash_loan_pool();
+        let repay_program = accounts.program(test_repay_program);
+        let repaid = flash_loan_instruction(
+            &pool,
+            &user,
+            &repay_program,
+            repay_accounts(&pool, &user),
+            coin_amount,
+            (coin_amount + fee).to_le_bytes().to_vec(),
+        );
+        accounts.process(&repaid).unwrap();
+        assert_eq!(accounts.amount(&pool.coin_vault), 1_000_000_000 + fee);
+        assert_eq!(accounts.amount(&user.coin), 1_000_000_000 - fee);
+        let amm = accounts.amm(&pool);
+        assert_eq!({ amm.flash_loan_locked }, 0);
+        assert_eq!(
+            { amm.state_data.protocol_fee_coin },
+            amm.fees.protocol_fee(fee)
+        );
+        // the pool is unlocked for the next swap
+        accounts
+            .process(&pool.swap_base_in(&user.coin, &user.pc, &user.owner, 1_000, 1))
+            .unwrap();
 
-        let amm_info = next_account_info(account_info_iter)?;
-        let amm_owner_info = next_account_info(account_info_iter)?;
+        // one short of the fee
+        let (mut accounts, pool, user) = flash_loan_pool();
+        let repay_program = accounts.program(test_repay_program);
+        let mut data = (coin_amount + fee).to_le_bytes().to_vec();
+        data.extend_from_slice(&1u64.to_le_bytes());
+        let underpaid = flash_loan_instruction(
+            &pool,
+            &user,
+            &repay_program,
+            repay_accounts(&pool, &user),
+            coin_amount,
+            data,
+        );
+        assert_eq!(
+            accounts.process(&underpaid),
+            Err(AmmError::FlashLoanNotRepaid.into())
+        );
 
-        let mut amm = AmmInfo::load_mut_checked(&amm_info, program_id)?;
+        // the borrower swaps or deposits in the pool it borrows from
+        let (mut accounts, pool, user) = flash_loan_pool();
+        let reentry_program = accounts.program(test_reentry_program);
+        let swap = pool.swap_base_in(&user.coin, &user.pc, &user.owner, 1_000, 1);
+        let reentered_swap = flash_loan_instruction(
+            &pool,
+            &user,
+            &reentry_program,
+            swap.accounts,
+            coin_amount,
+            swap.data,
+        );
+        assert_eq!(
+            accounts.process(&reentered_swap),
+            Err(AmmError::FlashLoanLocked.into())
+        );
+        let (mut accounts, pool, user) = flash_loan_pool();
+        let reentry_program = accounts.program(test_reentry_program);
+        let deposit = pool.deposit(&user, 1_000, u64::MAX, 0);
+        let reentered_deposit = flash_loan_instruction(
+            &pool,
+            &user,
+            &reentry_program,
+            deposit.accounts,
+            coin_amount,
+            deposit.data,
+        );
+        assert_eq!(
+            accounts.process(&reentered_deposit),
+            Err(AmmError::FlashLoanLocked.into())
+        );
+    }
 
-        if amm_info.owner != program_id {
-            return Err(AmmError::InvalidOwner.into());
-        }
-        if !amm_owner_info.is_signer || *amm_owner_info.key != config_feature::amm_owner::ID {
-            return Err(AmmError::InvalidSignAccount.into());
-        }
+    /// A pool of coin and pc at par, and its user.
+    fn flash_swap_pool() -> (TestAccounts, TestPool, TestUser) {
+        let mut accounts = TestAccounts::new();
+        let coin_mint = accounts.mint(spl_token::id(), 6, 0);
+        let pc_mint = accounts.mint(spl_token::id(), 6, 0);
+        let pool = accounts.pool(
+            spl_token::id(),
+            coin_mint,
+            pc_mint,
+            1_000_000_000,
+            1_000_000_000,
+            1_000_000_000,
+        );
+        let user = accounts.user(&pool, 1_000_000_000, 0);
+        (accounts, pool, user)
+    }
 
-        let param = setparams.param;
-        match AmmParams::from_u64(param as u64).unwrap() {
-            AmmParams::Status => {
-                match setparams.value {
-                    Some(status) => {
-                        if AmmStatus::valid_status(status) {
-                            amm.status = status as u64;
-                        } else {
-                            return Err(AmmError::InvalidInput.into());
-                        }
-                    }
-                    None => return Err(AmmError::InvalidInput.into()),
-                };
-            }
-            AmmParams::State => {
-                match setparams.value {
-                    Some(state) => {
-                        if AmmState::valid_state(state) {
-                            amm.state = state as u64;
-                        } else {
-                            return Err(AmmError::InvalidInput.into());
-                        }
-                    }
-                    None => return Err(AmmError::InvalidInput.into()),
-                };
-            }
-            AmmParams::Fees => {
-                let fees = match setparams.fees {
-                    Some(a) => a,
-                    None => return Err(AmmError::InvalidInput.into()),
-                };
-                fees.validate()?;
-                amm.fees = fees;
-            }
-            AmmParams::SetOpenTime => {
-                match setparams.value {
-                    Some(time) => {
-                        amm.state_data.pool_open_time = time as u64;
-                    }
-                    None => return Err(AmmError::InvalidInput.into()),
-                };
+    /// A flash swap sending amount_out of pc to the user, the callback repays it in coin
+    /// from the user, less the shortfall of data.
+    fn flash_swap_instruction(
+        pool: &TestPool,
+        user: &TestUser,
+        callback_program: &Pubkey,
+        amount_out: u64,
+        data: Vec<u8>,
+    ) -> Instruction {
+        let mut instruction = crate::instruction::flash_swap(
+            &crate::id(),
+            &pool.amm,
+            &pool.authority,
+            &pool.coin_vault,
+            &pool.pc_vault,
+            &user.pc,
+            callback_program,
+            repay_accounts(pool, user),
+            u64::MAX,
+            amount_out,
+            data,
+        )
+        .unwrap();
+        instruction
+            .accounts
+            .extend(crate::instruction::event_accounts(&crate::id()));
+        instruction
+    }
+
+    #[test]
+    fn test_flash_swap_handler() {
+        let amount_out = 1_000_000;
+        // the same swap by swap_base_out_v2
+        let (accounts, pool, user) = flash_swap_pool();
+        accounts
+            .process(&pool.swap_base_out(&user.coin, &user.pc, &user.owner, u64::MAX, amount_out))
+            .unwrap();
+        let swap_amount_in = 1_000_000_000 - accounts.amount(&user.coin);
+        assert!(swap_amount_in > amount_out);
+
+        let (mut accounts, pool, user) = flash_swap_pool();
+        let callback_program = accounts.program(test_repay_program);
+        accounts
+            .process(&flash_swap_instruction(
+                &pool,
+                &user,
+                &callback_program,
+                amount_out,
+                Vec::new(),
+            ))
+            .unwrap();
+        // pc is sent, the owed coin is paid back by the callback
+        assert_eq!(accounts.amount(&user.pc), 1_000_000_000 + amount_out);
+        assert_eq!(accounts.amount(&pool.pc_vault), 1_000_000_000 - amount_out);
+        assert_eq!(accounts.amount(&user.coin), 1_000_000_000 - swap_amount_in);
+        assert_eq!(
+            accounts.amount(&pool.coin_vault),
+            1_000_000_000 + swap_amount_in
+        );
+        let amm = accounts.amm(&pool);
+        assert_eq!({ amm.flash_loan_locked }, 0);
+        let events = accounts.emitted_events();
+        assert_eq!(events.len(), 1);
+        let event: SwapEventV2 = bincode::deserialize(&events[0]).unwrap();
+        assert!(event.swap_fee > 0);
+        assert_eq!(
+            event,
+            SwapEventV2 {
+                log_type: LogType::SwapV2.into_u8(),
+                version: EVENT_VERSION,
+                amm: pool.amm,
+                signer: callback_program,
+                mint_in: pool.coin_mint,
+                mint_out: pool.pc_mint,
+                amount_in: swap_amount_in,
+                amount_out,
+                swap_fee: event.swap_fee,
+                vault_coin: 1_000_000_000 + swap_amount_in,
+                vault_pc: 1_000_000_000 - amount_out,
             }
-        }
-        amm.recent_epoch = Clock::get()?.epoch;
-        Ok(())
+        );
+        assert_eq!(
+            { amm.state_data.protocol_fee_coin },
+            amm.fees.protocol_fee(event.swap_fee)
+        );
+
+        // the callback pays one short of the owed coin
+        let (mut accounts, pool, user) = flash_swap_pool();
+        let callback_program = accounts.program(test_repay_program);
+        assert_eq!(
+            accounts.process(&flash_swap_instruction(
+                &pool,
+                &user,
+                &callback_program,
+                amount_out,
+                1u64.to_le_bytes().to_vec(),
+            )),
+            Err(AmmError::InsufficientFunds.into())
+        );
+        assert!(accounts.emitted_events().is_empty());
     }
 
-    /// Processes `process_create_config` instruction.
-    pub fn process_create_config(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let admin_info = next_account_info(account_info_iter)?;
-        let amm_config_info = next_account_info(account_info_iter)?;
-        let pnl_owner_info = next_account_info(account_info_iter)?;
-        let system_program_info = next_account_info(account_info_iter)?;
-        let rent_sysvar_info = next_account_info(account_info_iter)?;
+    #[test]
+    fn test_withdraw_single_side_handler() {
+        let withdraw_pool = || {
+            let mut accounts = TestAccounts::new();
+            let coin_mint = accounts.mint(spl_token::id(), 6, 0);
+            let pc_mint = accounts.mint(spl_token::id(), 6, 0);
+            let pool = accounts.pool(
+                spl_token::id(),
+                coin_mint,
+                pc_mint,
+                1_000_000_000,
+                1_000_000_000,
+                1_000_000_000,
+            );
+            let user = accounts.user(&pool, 0, 1_000_000_000);
+            (accounts, pool, user)
+        };
+        // a tenth of the pool, its coin share swapped to pc against the nine tenths left
+        let amount = 100_000_000;
+        let (accounts, pool, user) = withdraw_pool();
+        accounts
+            .process(&pool.withdraw_single_side(&user, &user.pc, amount, 0))
+            .unwrap();
+        assert_eq!(accounts.amount(&user.lp), 1_000_000_000 - amount);
+        assert_eq!(
+            accounts.mint_state(&pool.lp_mint).supply,
+            1_000_000_000 - amount
+        );
+        assert_eq!({ accounts.amm(&pool).lp_amount }, 1_000_000_000 - amount);
+        let amount_out = accounts.amount(&user.pc);
+        let swap_out = amount_out - 100_000_000;
+        assert!(swap_out < 90_000_000 && swap_out > 90_000_000 * 997 / 1000);
+        assert_eq!(accounts.amount(&pool.pc_vault), 1_000_000_000 - amount_out);
+        assert_eq!(accounts.amount(&pool.coin_vault), 1_000_000_000);
+        assert_eq!(accounts.amount(&user.coin), 0);
+
+        // the minimum out is checked against the share and the swap together
+        let (accounts, pool, user) = withdraw_pool();
+        assert_eq!(
+            accounts.process(&pool.withdraw_single_side(&user, &user.pc, amount, amount_out + 1)),
+            Err(AmmError::ExceededSlippage.into())
+        );
+        assert_eq!(accounts.amount(&user.lp), 1_000_000_000);
+        accounts
+            .process(&pool.withdraw_single_side(&user, &user.pc, amount, amount_out))
+            .unwrap();
+        assert_eq!(accounts.amount(&user.pc), amount_out);
+
+        // the pool keeps some lp, the swap of all but a unit of the coin gets nothing
+        // out of the unit of pc left
+        let (accounts, pool, user) = withdraw_pool();
+        assert_eq!(
+            accounts.process(&pool.withdraw_single_side(&user, &user.pc, 1_000_000_000, 0)),
+            Err(AmmError::NotAllowZeroLP.into())
+        );
+        accounts
+            .process(&pool.withdraw_single_side(&user, &user.pc, 999_999_999, 0))
+            .unwrap();
+        assert_eq!(accounts.amount(&user.pc), 999_999_999);
+        assert_eq!(accounts.amount(&pool.pc_vault), 1);
+        assert_eq!(accounts.amount(&pool.coin_vault), 1_000_000_000);
+        assert_eq!({ accounts.amm(&pool).lp_amount }, 1);
+    }
 
-        if !admin_info.is_signer || config_feature::amm_owner::id() != *admin_info.key {
-            return Err(AmmError::InvalidSignAccount.into());
-        }
-        if *system_program_info.key != solana_program::system_program::id() {
-            return Err(AmmError::InvalidSysProgramAddress.into());
-        }
+    /// Pools of the mints x/y at par, y/z at 1:100 and z/w, their user owning the token
+    /// accounts of x, y, z and w, holding x.
+    fn route_pools() -> (TestAccounts, [TestPool; 3], Pubkey, [Pubkey; 4]) {
+        let mut accounts = TestAccounts::new();
+        let mints = [0; 4].map(|_| accounts.mint(spl_token::id(), 6, 0));
+        let [x, y, z, w] = mints;
+        let pools = [(x, y, 1), (y, z, 100), (z, w, 1)].map(|(coin_mint, pc_mint, price)| {
+            accounts.pool(
+                spl_token::id(),
+                coin_mint,
+                pc_mint,
+                1_000_000_000,
+                1_000_000_000 * price,
+                1_000_000_000,
+            )
+        });
+        let owner = accounts.signer();
+        let user_accounts = mints.map(|mint| {
+            let amount = if mint == x { 1_000_000_000 } else { 0 };
+            accounts.token_account(spl_token::id(), mint, owner, amount)
+        });
+        (accounts, pools, owner, user_accounts)
+    }
 
-        let (pda, bump_seed) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
-        if pda != *amm_config_info.key {
-            return Err(AmmError::InvalidConfigAccount.into());
-        }
-        if amm_config_info.owner != system_program_info.key {
-            return Err(AmmError::RepeatCreateConfigAccount.into());
-        }
-        let pda_signer_seeds: &[&[_]] = &[&AMM_CONFIG_SEED, &[bump_seed]];
-        let rent = &Rent::from_account_info(rent_sysvar_info)?;
-        let data_size = size_of::<AmmConfig>();
-        let required_lamports = rent
-            .minimum_balance(data_size)
-            .max(1)
-            .saturating_sub(amm_config_info.lamports());
-        if required_lamports > 0 {
-            invoke(
-                &system_instruction::transfer(
-                    admin_info.key,
-                    amm_config_info.key,
-                    required_lamports,
-                ),
-                &[
-                    admin_info.clone(),
-                    amm_config_info.clone(),
-                    system_program_info.clone(),
-                ],
-            )?;
-        }
-        invoke_signed(
-            &system_instruction::allocate(amm_config_info.key, data_size as u64),
-            &[amm_config_info.clone(), system_program_info.clone()],
-            &[&pda_signer_seeds],
-        )?;
-        invoke_signed(
-            &system_instruction::assign(amm_config_info.key, &program_id),
-            &[amm_config_info.clone(), system_program_info.clone()],
-            &[&pda_signer_seeds],
-        )?;
+    fn route_hop(
+        pool: &TestPool,
+        user_source: &Pubkey,
+        user_destination: &Pubkey,
+        owner: &Pubkey,
+    ) -> Vec<AccountMeta> {
+        crate::instruction::swap_route_hop(
+            &pool.amm,
+            &pool.authority,
+            &pool.coin_vault,
+            &pool.pc_vault,
+            user_source,
+            user_destination,
+            owner,
+        )
+    }
 
-        let mut amm_config = AmmConfig::load_mut_checked(&amm_config_info, program_id)?;
-        amm_config.pnl_owner = *pnl_owner_info.key;
-        amm_config.create_pool_fee = 0;
+    fn route_base_in(hops: Vec<Vec<AccountMeta>>, amount_in: u64, minimum_out: u64) -> Instruction {
+        let mut instruction =
+            crate::instruction::swap_route_base_in(&crate::id(), hops, amount_in, minimum_out)
+                .unwrap();
+        instruction
+            .accounts
+            .push(crate::instruction::amm_config_account(&crate::id()));
+        instruction
+    }
 
-        Ok(())
+    #[test]
+    fn test_swap_route_handler() {
+        let amount_in = 1_000_000;
+        // x to y to z, the y received by the first pool is all swapped by the second
+        let (accounts, [xy, yz, _], owner, [x, y, z, _]) = route_pools();
+        let hops = vec![
+            route_hop(&xy, &x, &y, &owner),
+            route_hop(&yz, &y, &z, &owner),
+        ];
+        accounts
+            .process(&route_base_in(hops, amount_in, 0))
+            .unwrap();
+        let out_amount = accounts.amount(&z);
+        let y_amount = accounts.amount(&yz.coin_vault) - 1_000_000_000;
+        assert_eq!(accounts.amount(&x), 1_000_000_000 - amount_in);
+        assert_eq!(accounts.amount(&y), 0);
+        assert_eq!(accounts.amount(&xy.pc_vault), 1_000_000_000 - y_amount);
+        assert!(y_amount < amount_in && out_amount > 99 * amount_in);
+
+        // the minimum out only applies to the output of the last pool
+        let (accounts, [xy, yz, _], owner, [x, y, z, _]) = route_pools();
+        let hops = vec![
+            route_hop(&xy, &x, &y, &owner),
+            route_hop(&yz, &y, &z, &owner),
+        ];
+        assert_eq!(
+            accounts.process(&route_base_in(hops.clone(), amount_in, out_amount + 1)),
+            Err(AmmError::ExceededSlippage.into())
+        );
+        accounts
+            .process(&route_base_in(hops, amount_in, out_amount))
+            .unwrap();
+        assert_eq!(accounts.amount(&z), out_amount);
+
+        // the next pool has to take the mint the previous one sent, from the same account
+        let (accounts, [xy, yz, zw], owner, [x, y, z, w]) = route_pools();
+        let hops = vec![
+            route_hop(&xy, &x, &y, &owner),
+            route_hop(&zw, &y, &w, &owner),
+        ];
+        assert_eq!(
+            accounts.process(&route_base_in(hops, amount_in, 0)),
+            Err(AmmError::InvalidUserToken.into())
+        );
+        let hops = vec![
+            route_hop(&xy, &x, &y, &owner),
+            route_hop(&yz, &z, &y, &owner),
+        ];
+        assert_eq!(
+            accounts.process(&route_base_in(hops, amount_in, 0)),
+            Err(AmmError::InvalidUserToken.into())
+        );
+
+        // a pool can not be swapped twice
+        let hops = vec![
+            route_hop(&xy, &x, &y, &owner),
+            route_hop(&xy, &y, &x, &owner),
+        ];
+        assert_eq!(
+            accounts.process(&route_base_in(hops, amount_in, 0)),
+            Err(AmmError::InvalidInput.into())
+        );
+
+        // no pool, or the accounts of a pool cut short
+        assert_eq!(
+            accounts.process(&route_base_in(Vec::new(), amount_in, 0)),
+            Err(AmmError::WrongAccountsNumber.into())
+        );
+        let mut hop = route_hop(&yz, &y, &z, &owner);
+        hop.pop();
+        let hops = vec![route_hop(&xy, &x, &y, &owner), hop];
+        assert_eq!(
+            accounts.process(&route_base_in(hops, amount_in, 0)),
+            Err(AmmError::WrongAccountsNumber.into())
+        );
+        assert_eq!(accounts.amount(&x), 1_000_000_000);
     }
 
-    /// Processes `process_update_config` instruction.
-    pub fn process_update_config(
-        program_id: &Pubkey,
-        accounts: &[AccountInfo],
-        config_args: ConfigArgs,
-    ) -> ProgramResult {
-        let account_info_iter = &mut accounts.iter();
-        let admin_info = next_account_info(account_info_iter)?;
-        let amm_config_info = next_account_info(account_info_iter)?;
-        if !admin_info.is_signer || config_feature::amm_owner::id() != *admin_info.key {
-            return Err(AmmError::InvalidSignAccount.into());
-        }
-        let (pda, _) = Pubkey::find_program_address(&[&AMM_CONFIG_SEED], program_id);
-        if pda != *amm_config_info.key || amm_config_info.owner != program_id {
-            return Err(AmmError::InvalidConfigAccount.into());
-        }
+    #[test]
+    fn test_stable_curve() {
+        let stable = StableCurve::new(100, 6, 6);
+        let total_coin = 1_000_000_000_000u64;
+        let total_pc = 1_000_000_000_000u64;
+        let amount_in = 10_000_000_000u64;
+        let stable_out = stable
+            .swap_token_amount_base_in(
+                amount_in.into(),
+                total_pc.into(),
+                total_coin.into(),
+                SwapDirection::Coin2PC,
+            )
+            .unwrap()
+            .as_u64();
+        let constant_product_out = ConstantProductCurve
+            .swap_token_amount_base_in(
+                amount_in.into(),
+                total_pc.into(),
+                total_coin.into(),
+                SwapDirection::Coin2PC,
+            )
+            .unwrap()
+            .as_u64();
+        // pegged tokens swap close to 1:1, far better than x * y = k
+        assert!(stable_out < amount_in);
+        assert!(stable_out * 10_000 > amount_in * 9_999);
+        assert!(stable_out > constant_product_out);
+
+        // base out charges at least the amount_in giving the amount_out
+        let amount_in_needed = stable
+            .swap_token_amount_base_out(
+                stable_out.into(),
+                total_pc.into(),
+                total_coin.into(),
+                SwapDirection::Coin2PC,
+            )
+            .unwrap()
+            .as_u64();
+        assert!(amount_in_needed >= amount_in);
+        assert!(amount_in_needed - amount_in <= 2);
+
+        // amounts are scaled to the same decimals
+        let stable_decimals = StableCurve::new(100, 9, 6);
+        let out = stable_decimals
+            .swap_token_amount_base_in(
+                (amount_in * 1000).into(),
+                total_pc.into(),
+                (total_coin * 1000).into(),
+                SwapDirection::Coin2PC,
+            )
+            .unwrap()
+            .as_u64();
+        assert!(out.abs_diff(stable_out) <= 1);
 
-        let mut amm_config = AmmConfig::load_mut_checked(&amm_config_info, program_id)?;
-        match config_args.param {
-            0 => {
-                let pnl_owner = config_args.owner.unwrap();
-                if pnl_owner == Pubkey::default() {
-                    return Err(AmmError::InvalidInput.into());
-                }
-                amm_config.pnl_owner = pnl_owner;
-            }
-            1 => {
-                let cancel_owner = config_args.owner.unwrap();
-                if cancel_owner == Pubkey::default() {
-                    return Err(AmmError::InvalidInput.into());
-                }
-                amm_config.cancel_owner = cancel_owner;
+        // the invariant never decreases through a swap
+        let d_before = stable.compute_d(U256::from(total_coin), U256::from(total_pc));
+        let d_after = stable.compute_d(
+            U256::from(total_coin + amount_in),
+            U256::from(total_pc - stable_out),
+        );
+        assert!(d_after >= d_before);
+
+        // the spot price of an imbalanced pool is the rate of a small swap, between
+        // the reserve ratio and 1:1
+        let (imbalanced_coin, imbalanced_pc) = (3_000_000_000_000u64, 1_000_000_000_000u64);
+        let (pc, coin) = stable
+            .spot_price(imbalanced_pc.into(), imbalanced_coin.into())
+            .unwrap();
+        let spot = (pc * U256::from(1_000_000u64) / coin).as_u64();
+        let small_out = stable
+            .swap_token_amount_base_in(
+                1_000_000u64.into(),
+                imbalanced_pc.into(),
+                imbalanced_coin.into(),
+                SwapDirection::Coin2PC,
+            )
+            .unwrap()
+            .as_u64();
+        assert!(spot.abs_diff(small_out) <= small_out / 10_000 + 2);
+        assert!(spot < 1_000_000 && spot * 3 > 1_000_000);
+
+        // the bisected single side swap matches the closed form of x * y = k
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        let closed_form = ConstantProductCurve
+            .single_side_swap_amount(
+                amount_in,
+                total_pc,
+                total_coin,
+                SwapDirection::Coin2PC,
+                fees.swap_fee_numerator,
+                fees.swap_fee_denominator,
+            )
+            .unwrap();
+        struct BisectedConstantProduct;
+        impl CurveCalculator for BisectedConstantProduct {
+            fn swap_token_amount_base_in(
+                &self,
+                amount_in: U128,
+                total_pc_without_take_pnl: U128,
+                total_coin_without_take_pnl: U128,
+                swap_direction: SwapDirection,
+            ) -> Result<U128, AmmError> {
+                ConstantProductCurve.swap_token_amount_base_in(
+                    amount_in,
+                    total_pc_without_take_pnl,
+                    total_coin_without_take_pnl,
+                    swap_direction,
+                )
             }
-            2 => {
-                let create_pool_fee = config_args.create_pool_fee.unwrap();
-                amm_config.create_pool_fee = create_pool_fee;
+            fn swap_token_amount_base_out(
+                &self,
+                amount_out: U128,
+                total_pc_without_take_pnl: U128,
+                total_coin_without_take_pnl: U128,
+                swap_direction: SwapDirection,
+            ) -> Result<U128, AmmError> {
+                ConstantProductCurve.swap_token_amount_base_out(
+                    amount_out,
+                    total_pc_without_take_pnl,
+                    total_coin_without_take_pnl,
+                    swap_direction,
+                )
             }
-            _ => {
-                return Err(AmmError::InvalidInput.into());
+            fn spot_price(
+                &self,
+                total_pc_without_take_pnl: U128,
+                total_coin_without_take_pnl: U128,
+            ) -> Option<(U256, U256)> {
+                ConstantProductCurve
+                    .spot_price(total_pc_without_take_pnl, total_coin_without_take_pnl)
             }
         }
+        let bisected = BisectedConstantProduct
+            .single_side_swap_amount(
+                amount_in,
+                total_pc,
+                total_coin,
+                SwapDirection::Coin2PC,
+                fees.swap_fee_numerator,
+                fees.swap_fee_denominator,
+            )
+            .unwrap();
+        assert!(closed_form.abs_diff(bisected) * 100_000 <= closed_form);
+    }
 
-        return Ok(());
+    fn wad_to_f64(value: U256) -> f64 {
+        value.as_u128() as f64 / WAD as f64
     }
 
-    /// Processes an [Instruction](enum.Instruction.html).
-    pub fn process(program_id: &Pubkey, accounts: &[AccountInfo], input: &[u8]) -> ProgramResult {
-        let instruction = AmmInstruction::unpack(input)?;
-        match instruction {
-            AmmInstruction::PreInitialize(_init_arg) => {
-                unimplemented!("This instruction is not supported, please use Initialize2")
-            }
-            AmmInstruction::Initialize(_init1) => {
-                unimplemented!("This instruction is not supported, please use Initialize2")
-            }
-            AmmInstruction::Initialize2(init2) => {
-                Self::process_initialize2(program_id, accounts, init2)
-            }
-            AmmInstruction::MonitorStep(_monitor) => {
-                unimplemented!("This instruction is not supported")
-            }
-            AmmInstruction::Deposit(deposit) => {
-                Self::process_deposit(program_id, accounts, deposit)
-            }
-            AmmInstruction::Withdraw(withdraw) => {
-                Self::process_withdraw(program_id, accounts, withdraw)
-            }
-            AmmInstruction::MigrateToOpenBook => {
-                unimplemented!("This instruction is not supported")
-            }
-            AmmInstruction::SetParams(setparams) => {
-                Self::process_set_params(program_id, accounts, setparams)
-            }
-            AmmInstruction::WithdrawPnl => Self::process_withdrawpnl(program_id, accounts),
-            AmmInstruction::WithdrawSrm(_withdrawsrm) => {
-                unimplemented!("This instruction is not supported")
-            }
-            AmmInstruction::SwapBaseIn(swap) => {
-                Self::process_swap_base_in(program_id, accounts, swap)
-            }
-            AmmInstruction::SwapBaseOut(swap) => {
-                Self::process_swap_base_out(program_id, accounts, swap)
-            }
-            AmmInstruction::SimulateInfo(_simulate) => {
-                unimplemented!("This instruction is not supported")
-            }
-            AmmInstruction::AdminCancelOrders(_cancel) => {
-                unimplemented!("This instruction is not supported")
-            }
-            AmmInstruction::CreateConfigAccount => {
-                Self::process_create_config(program_id, accounts)
-            }
-            AmmInstruction::UpdateConfigAccount(config_args) => {
-                Self::process_update_config(program_id, accounts, config_args)
-            }
-            AmmInstruction::SwapBaseInV2(swap) => {
-                Self::process_swap_base_in_v2(program_id, accounts, swap)
-            }
-            AmmInstruction::SwapBaseOutV2(swap) => {
-                Self::process_swap_base_out_v2(program_id, accounts, swap)
+    proptest! {
+        #[test]
+        fn test_ln_exp_pow_wad(x in 1u64..1_000_000_000_000, y in 0u64..40_000, e in 1u64..100) {
+            let wad = U256::from(WAD);
+            let ln = wad_to_f64(Calculator::ln_wad(U256::from(x) * wad).unwrap());
+            prop_assert!((ln - (x as f64).ln()).abs() <= 1e-12 * (x as f64).ln().max(1.0));
+
+            let exponent = y as f64 / 1000.0;
+            let exp = wad_to_f64(Calculator::exp_wad(U256::from(y) * wad / 1000).unwrap());
+            prop_assert!((exp - exponent.exp()).abs() <= 1e-12 * exponent.exp());
+
+            // x / 1e6 to the power e / 50
+            let base = x as f64 / 1e6;
+            let pow =
+                wad_to_f64(Calculator::pow_wad(U256::from(x) * wad / 1_000_000, e, 50).unwrap());
+            let reference = base.powf(e as f64 / 50.0);
+            prop_assert!((pow - reference).abs() <= 1e-12 * reference.max(1.0));
+        }
+
+        #[test]
+        fn test_weighted_curve(
+            coin_weight in MIN_WEIGHT..WEIGHT_DENOMINATOR,
+            total_coin in 1_000_000u64..1_000_000_000_000_000,
+            total_pc in 1_000_000u64..1_000_000_000_000_000,
+            in_ratio in 1u64..1_000_000,
+        ) {
+            let curve = WeightedCurve {
+                coin_weight,
+                pc_weight: WEIGHT_DENOMINATOR - coin_weight,
+            };
+            let amount_in = total_coin / 1_000_000 * in_ratio;
+            let amount_out = curve
+                .swap_token_amount_base_in(
+                    amount_in.into(),
+                    total_pc.into(),
+                    total_coin.into(),
+                    SwapDirection::Coin2PC,
+                )
+                .unwrap()
+                .as_u64();
+            // pc out = pc * (1 - (coin / (coin + in))^(coin_weight / pc_weight))
+            let reference = total_pc as f64
+                * (1.0
+                    - (total_coin as f64 / (total_coin + amount_in) as f64)
+                        .powf(coin_weight as f64 / curve.pc_weight as f64));
+            prop_assert!(amount_out as f64 <= reference + 1.0);
+            // 1 - pow loses the relative precision of pow_wad when in is tiny next to
+            // coin, leaving an error in the favor of the pool relative to pc
+            prop_assert!(
+                amount_out as f64 >= reference * (1.0 - 1e-9) - total_pc as f64 * 1e-13 - 2.0
+            );
+
+            // coin in = coin * ((pc / (pc - out))^(pc_weight / coin_weight) - 1)
+            if amount_out > 0 {
+                let amount_in_needed = curve
+                    .swap_token_amount_base_out(
+                        amount_out.into(),
+                        total_pc.into(),
+                        total_coin.into(),
+                        SwapDirection::Coin2PC,
+                    )
+                    .unwrap()
+                    .as_u64();
+                let reference = total_coin as f64
+                    * ((total_pc as f64 / (total_pc - amount_out) as f64)
+                        .powf(curve.pc_weight as f64 / coin_weight as f64)
+                        - 1.0);
+                prop_assert!(amount_in_needed as f64 >= reference - 1.0);
+                prop_assert!(amount_in_needed as f64 <= reference * (1.0 + 1e-9) + 2.0);
             }
         }
     }
-}
-#[cfg(test)]
-mod test {
-    use super::*;
+
+    #[test]
+    fn test_weighted_liquidity() {
+        // equal weights are the square root of x * y
+        let liquidity = Calculator::weighted_liquidity(4_000_000_000, 9_000_000_000, 50, 50);
+        assert!(liquidity.unwrap().abs_diff(6_000_000_000) <= 1);
+        // 80 / 20
+        let liquidity = Calculator::weighted_liquidity(1 << 40, 1 << 20, 80, 20).unwrap();
+        assert!(liquidity.abs_diff(1 << 36) <= 1);
+    }
+
+    #[test]
+    fn test_fill_range_orders_along_curve() {
+        let amm = AmmInfo {
+            coin_decimals: 9,
+            pc_decimals: 6,
+            sys_decimal_value: 1_000_000,
+            ..Default::default()
+        };
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        let mut target = TargetOrders::default();
+        target
+            .init_range_orders(&Pubkey::new_unique(), &Pubkey::new_unique())
+            .unwrap();
+        // pool price 3.0, buy 100 pc at 2.9
+        let (total_coin, total_pc) = (1_000_000_000_000u64, 3_000_000_000u64);
+        target
+            .take_range_order_slot(OrderSide::Buy, 0, 2_900_000, 100_000_000)
+            .unwrap();
+        let fill = |target: &mut TargetOrders, amount_in: u64| {
+            Processor::fill_range_orders_along_curve(
+                target,
+                &amm,
+                &fees,
+                &ConstantProductCurve,
+                total_pc,
+                total_coin,
+                SwapDirection::Coin2PC,
+                amount_in,
+            )
+            .unwrap()
+        };
+        // 1 coin keeps the pool price above the order
+        assert_eq!(fill(&mut target, 1_000_000_000), (0, 0, 0));
+        // 100 coin move the pool price through 2.9, the order is filled at its price
+        // and the curve takes the rest
+        let (range_in, range_out, fill_count) = fill(&mut target, 100_000_000_000);
+        assert_eq!(
+            (range_in, range_out, fill_count),
+            (34_482_758_621, 100_000_000, 1)
+        );
+        let vol = target.buy_orders[0].vol;
+        assert_eq!(vol, 0);
+
+        // orders already crossed by the pool price are filled up to MAX_RANGE_ORDER_FILLS
+        for slot in 1..=MAX_RANGE_ORDER_FILLS as usize + 1 {
+            target
+                .take_range_order_slot(OrderSide::Buy, slot, 3_100_000, 1_000_000)
+                .unwrap();
+        }
+        let (_, range_out, fill_count) = fill(&mut target, 100_000_000_000);
+        assert_eq!(fill_count, MAX_RANGE_ORDER_FILLS);
+        assert_eq!(range_out, MAX_RANGE_ORDER_FILLS * 1_000_000);
+    }
+
+    #[test]
+    fn test_weighted_curve_overflow() {
+        assert!(Calculator::ln_wad(U256::from(WAD - 1)).is_none());
+        // swapping most of the light side of a 98 / 2 pool overflows the power
+        let curve = WeightedCurve {
+            coin_weight: 98,
+            pc_weight: 2,
+        };
+        let (total_coin, total_pc) = (1_000_000_000u64, 1_000_000_000u64);
+        let result = curve.swap_token_amount_base_out(
+            900_000_000u64.into(),
+            total_pc.into(),
+            total_coin.into(),
+            SwapDirection::PC2Coin,
+        );
+        assert_eq!(result, Err(AmmError::CalculationExRateFailure));
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        let result = Processor::calc_swap_vault_base_out(
+            &fees,
+            &curve,
+            total_pc,
+            total_coin,
+            SwapDirection::PC2Coin,
+            999_999_999,
+        );
+        assert_eq!(result, Err(AmmError::CalculationExRateFailure.into()));
+    }
 
     #[test]
     fn test_calc_take_pnl() {
@@ -2908,6 +10164,57 @@ mod test {
         println!("delta_x:{}, delta_y:{}", delta_x, delta_y);
     }
 
+    #[test]
+    fn test_settle_pnl() {
+        let mut amm = AmmInfo::default();
+        amm.initialize(0, 0, 6, 6, 1, 1).unwrap();
+        // a legacy pool booking its pnl through calc_pnl
+        amm.protocol_fee_enabled = 0;
+        let mut target = TargetOrders::default();
+        let total = 1_000_000_000u64;
+        target.calc_pnl_x =
+            Calculator::normalize_decimal_v2(total, 6, amm.sys_decimal_value).as_u128();
+        target.calc_pnl_y = target.calc_pnl_x;
+
+        // the pool earned 10% of pc, the pnl share of the growth is booked
+        Processor::settle_pnl(&mut target, &mut amm, total + total / 10, total).unwrap();
+        let (need_take_pnl_coin, need_take_pnl_pc) = (
+            amm.state_data.need_take_pnl_coin,
+            amm.state_data.need_take_pnl_pc,
+        );
+        assert!(need_take_pnl_coin > 0 && need_take_pnl_pc > 0);
+        assert_eq!({ amm.protocol_fee_enabled }, 0);
+        let (calc_pnl_x, calc_pnl_y) = (target.calc_pnl_x, target.calc_pnl_y);
+        assert!(calc_pnl_x < (total + total / 10) as u128 && calc_pnl_y < total as u128);
+
+        // settling the reserves left books nothing more
+        Processor::settle_pnl(
+            &mut target,
+            &mut amm,
+            total + total / 10 - need_take_pnl_pc,
+            total - need_take_pnl_coin,
+        )
+        .unwrap();
+        assert_eq!({ amm.state_data.need_take_pnl_coin }, need_take_pnl_coin);
+        assert_eq!({ amm.state_data.need_take_pnl_pc }, need_take_pnl_pc);
+        assert_eq!(
+            (target.calc_pnl_x, target.calc_pnl_y),
+            (
+                (total + total / 10 - need_take_pnl_pc) as u128,
+                (total - need_take_pnl_coin) as u128
+            )
+        );
+
+        // a pool with protocol fee only moves calc_pnl
+        amm.protocol_fee_enabled = 1;
+        Processor::settle_pnl(&mut target, &mut amm, 2 * total, 2 * total).unwrap();
+        assert_eq!({ amm.state_data.need_take_pnl_pc }, need_take_pnl_pc);
+        assert_eq!(
+            (target.calc_pnl_x, target.calc_pnl_y),
+            ((2 * total) as u128, (2 * total) as u128)
+        );
+    }
+
     #[test]
     fn test_calc_pnl_precision() {
         // init
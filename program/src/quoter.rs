@@ -0,0 +1,370 @@
+//! Off-chain pool quotes
+
+use crate::{
+    error::AmmError,
+    math::{Calculator, SwapDirection},
+    processor::{post_transfer_fee_amount, pre_transfer_fee_amount, Processor, SwapAmounts},
+    state::{AmmInfo, AmmStatus, DynamicFeeState, Fees, TargetOrders},
+};
+use solana_program::program_error::ProgramError;
+use spl_token_2022::extension::transfer_fee::TransferFeeConfig;
+
+/// Amounts of a deposit, sent by the user before the transfer fees.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DepositQuote {
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    /// lp minted to the user
+    pub lp_amount: u64,
+}
+
+/// Amounts of a withdraw, sent by the vaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct WithdrawQuote {
+    pub coin_amount: u64,
+    pub pc_amount: u64,
+    /// amounts received by the user, after the transfer fees
+    pub coin_amount_after_transfer_fee: u64,
+    pub pc_amount_after_transfer_fee: u64,
+}
+
+/// Quotes of a pool computed with the processor math, from a decoded `AmmInfo` and
+/// the amounts of its two vaults. The pnl not withdrawn yet is left out of the pool
+/// like the instructions do. Swaps are quoted as `swap_base_in_v2` and
+/// `swap_base_out_v2`, without range or limit orders. The mints of the pool charge no
+/// transfer fee until their `TransferFeeConfig` is given. Statuses and open time are
+/// not checked.
+#[derive(Clone, Debug)]
+pub struct PoolQuoter {
+    amm: AmmInfo,
+    coin_vault_amount: u64,
+    pc_vault_amount: u64,
+    total_pc_without_take_pnl: u64,
+    total_coin_without_take_pnl: u64,
+    // None for a pool in dynamic fee mode until its DynamicFeeState is given
+    fees: Option<Fees>,
+    // totals a deposit or withdraw works on, None for a pool still taking its pnl by
+    // calc_pnl_x & calc_pnl_y until its TargetOrders are given
+    liquidity_totals: Option<(u64, u64)>,
+    timestamp: u64,
+    coin_transfer_fee: Option<TransferFeeConfig>,
+    pc_transfer_fee: Option<TransferFeeConfig>,
+    epoch: u64,
+}
+
+impl PoolQuoter {
+    /// Quoter of a pool at the unix timestamp, the stable curve amplification and the
+    /// dynamic fee depend on it.
+    pub fn new(
+        amm: &AmmInfo,
+        coin_vault_amount: u64,
+        pc_vault_amount: u64,
+        timestamp: u64,
+    ) -> Result<Self, AmmError> {
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            Calculator::calc_total_without_take_pnl_no_orderbook(
+                pc_vault_amount,
+                coin_vault_amount,
+                amm,
+            )?;
+        Ok(Self {
+            amm: *amm,
+            coin_vault_amount,
+            pc_vault_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            fees: if amm.dynamic_fee_enabled == 0 {
+                Some(amm.fees)
+            } else {
+                None
+            },
+            liquidity_totals: if amm.protocol_fee_enabled != 0 {
+                Some((total_pc_without_take_pnl, total_coin_without_take_pnl))
+            } else {
+                None
+            },
+            timestamp,
+            coin_transfer_fee: None,
+            pc_transfer_fee: None,
+            epoch: 0,
+        })
+    }
+
+    /// Sets the `TransferFeeConfig` of the Token-2022 mints of the pool charging a
+    /// transfer fee, and the epoch whose transfer fee the quotes are charged.
+    pub fn with_transfer_fees(
+        mut self,
+        coin_transfer_fee: Option<&TransferFeeConfig>,
+        pc_transfer_fee: Option<&TransferFeeConfig>,
+        epoch: u64,
+    ) -> Self {
+        self.coin_transfer_fee = coin_transfer_fee.copied();
+        self.pc_transfer_fee = pc_transfer_fee.copied();
+        self.epoch = epoch;
+        self
+    }
+
+    /// Sets the dynamic fee state of a pool in dynamic fee mode, the swap fee is the one
+    /// a swap at the quoter timestamp is charged.
+    pub fn with_dynamic_fee(mut self, dynamic_fee_state: &DynamicFeeState) -> Self {
+        if self.amm.dynamic_fee_enabled != 0 {
+            self.fees = Some(Processor::dynamic_swap_fees(
+                &self.amm,
+                &mut { *dynamic_fee_state },
+                self.total_pc_without_take_pnl,
+                self.total_coin_without_take_pnl,
+                self.timestamp,
+            ));
+        }
+        self
+    }
+
+    /// Sets the target orders of a pool still taking its pnl by calc_pnl_x & calc_pnl_y,
+    /// whose pnl the next deposit or withdraw books before moving the tokens.
+    pub fn with_target_orders(
+        mut self,
+        target_orders: &TargetOrders,
+    ) -> Result<Self, ProgramError> {
+        if self.amm.protocol_fee_enabled == 0 {
+            let mut amm = self.amm;
+            let (mut total_pc_without_take_pnl, mut total_coin_without_take_pnl) = (
+                self.total_pc_without_take_pnl,
+                self.total_coin_without_take_pnl,
+            );
+            let x1 = Calculator::normalize_decimal_v2(
+                total_pc_without_take_pnl,
+                amm.pc_decimals,
+                amm.sys_decimal_value,
+            );
+            let y1 = Calculator::normalize_decimal_v2(
+                total_coin_without_take_pnl,
+                amm.coin_decimals,
+                amm.sys_decimal_value,
+            );
+            Processor::migrate_protocol_fee(
+                target_orders,
+                &mut amm,
+                &mut total_pc_without_take_pnl,
+                &mut total_coin_without_take_pnl,
+                x1.as_u128().into(),
+                y1.as_u128().into(),
+            )?;
+            self.liquidity_totals = Some((total_pc_without_take_pnl, total_coin_without_take_pnl));
+        }
+        Ok(self)
+    }
+
+    /// Pc and coin of the pool a swap works on.
+    pub fn total_without_take_pnl(&self) -> (u64, u64) {
+        (
+            self.total_pc_without_take_pnl,
+            self.total_coin_without_take_pnl,
+        )
+    }
+
+    fn swap_fees(&self) -> Result<Fees, AmmError> {
+        self.fees.ok_or(AmmError::InvalidDynamicFeeAccount)
+    }
+
+    /// Transfer fees of the source and destination mints of a swap.
+    fn swap_transfer_fees(
+        &self,
+        swap_direction: SwapDirection,
+    ) -> (Option<&TransferFeeConfig>, Option<&TransferFeeConfig>) {
+        match swap_direction {
+            SwapDirection::Coin2PC => (
+                self.coin_transfer_fee.as_ref(),
+                self.pc_transfer_fee.as_ref(),
+            ),
+            SwapDirection::PC2Coin => (
+                self.pc_transfer_fee.as_ref(),
+                self.coin_transfer_fee.as_ref(),
+            ),
+        }
+    }
+
+    /// Amounts of a swap with a fixed amount_in sent by the user.
+    pub fn swap_base_in(
+        &self,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+    ) -> Result<SwapAmounts, ProgramError> {
+        let fees = self.swap_fees()?;
+        let curve = self.amm.curve(self.timestamp);
+        let (source_transfer_fee, destination_transfer_fee) =
+            self.swap_transfer_fees(swap_direction);
+        let amount_in_after_transfer_fee =
+            post_transfer_fee_amount(source_transfer_fee, self.epoch, amount_in)?;
+        let (swap_fee, amount_out) = Processor::calc_swap_vault_base_in(
+            &fees,
+            &*curve,
+            self.total_pc_without_take_pnl,
+            self.total_coin_without_take_pnl,
+            swap_direction,
+            amount_in_after_transfer_fee,
+        )?;
+        let amount_out_after_transfer_fee =
+            post_transfer_fee_amount(destination_transfer_fee, self.epoch, amount_out)?;
+        if amount_out_after_transfer_fee == 0 || amount_in == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let total_out_without_take_pnl = match swap_direction {
+            SwapDirection::Coin2PC => self.total_pc_without_take_pnl,
+            SwapDirection::PC2Coin => self.total_coin_without_take_pnl,
+        };
+        if amount_out >= total_out_without_take_pnl {
+            return Err(AmmError::InsufficientFunds.into());
+        }
+        Ok(SwapAmounts {
+            amount_in,
+            amount_in_after_transfer_fee,
+            swap_fee,
+            amount_out,
+            amount_out_after_transfer_fee,
+        })
+    }
+
+    /// Amounts of a swap with a fixed amount_out received by the user.
+    pub fn swap_base_out(
+        &self,
+        swap_direction: SwapDirection,
+        amount_out: u64,
+    ) -> Result<SwapAmounts, ProgramError> {
+        let fees = self.swap_fees()?;
+        let curve = self.amm.curve(self.timestamp);
+        let (source_transfer_fee, destination_transfer_fee) =
+            self.swap_transfer_fees(swap_direction);
+        let amount_out_before_transfer_fee =
+            pre_transfer_fee_amount(destination_transfer_fee, self.epoch, amount_out)?;
+        let (swap_fee, amount_in_after_transfer_fee) = Processor::calc_swap_vault_base_out(
+            &fees,
+            &*curve,
+            self.total_pc_without_take_pnl,
+            self.total_coin_without_take_pnl,
+            swap_direction,
+            amount_out_before_transfer_fee,
+        )?;
+        let amount_in = pre_transfer_fee_amount(
+            source_transfer_fee,
+            self.epoch,
+            amount_in_after_transfer_fee,
+        )?;
+        if amount_in == 0 || amount_out == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        Ok(SwapAmounts {
+            amount_in,
+            amount_in_after_transfer_fee,
+            swap_fee,
+            amount_out: amount_out_before_transfer_fee,
+            amount_out_after_transfer_fee: amount_out,
+        })
+    }
+
+    fn liquidity_totals(&self, withdraw: bool) -> Result<(u64, u64), AmmError> {
+        // a withdraw from a WithdrawOnly pool leaves its pnl untouched
+        if withdraw && self.amm.status == AmmStatus::WithdrawOnly.into_u64() {
+            return Ok((
+                self.total_pc_without_take_pnl,
+                self.total_coin_without_take_pnl,
+            ));
+        }
+        self.liquidity_totals.ok_or(AmmError::InvalidTargetOrders)
+    }
+
+    /// Amounts of a deposit of max_coin_amount, or max_pc_amount when base_side is 1,
+    /// failing like the instruction when the other side exceeds its maximum.
+    pub fn deposit(
+        &self,
+        max_coin_amount: u64,
+        max_pc_amount: u64,
+        base_side: u64,
+    ) -> Result<DepositQuote, ProgramError> {
+        if self.amm.lp_amount == 0 {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            self.liquidity_totals(false)?;
+        let (base_amount, max_other_amount, base_transfer_fee, other_transfer_fee) =
+            if base_side == 0 {
+                (
+                    max_coin_amount,
+                    max_pc_amount,
+                    self.coin_transfer_fee.as_ref(),
+                    self.pc_transfer_fee.as_ref(),
+                )
+            } else {
+                (
+                    max_pc_amount,
+                    max_coin_amount,
+                    self.pc_transfer_fee.as_ref(),
+                    self.coin_transfer_fee.as_ref(),
+                )
+            };
+        // the vaults receive the amounts sent by the user less the transfer fees
+        let deduct_base_amount =
+            post_transfer_fee_amount(base_transfer_fee, self.epoch, base_amount)?;
+        let (deduct_other_amount, lp_amount) = Processor::calc_deposit_vault_amounts(
+            &*self.amm.curve(self.timestamp),
+            self.amm.lp_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            base_side,
+            deduct_base_amount,
+        )?;
+        let other_amount =
+            pre_transfer_fee_amount(other_transfer_fee, self.epoch, deduct_other_amount)?;
+        if other_amount > max_other_amount {
+            return Err(AmmError::ExceededSlippage.into());
+        }
+        if lp_amount == 0 || deduct_base_amount == 0 || deduct_other_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let (coin_amount, pc_amount) = if base_side == 0 {
+            (base_amount, other_amount)
+        } else {
+            (other_amount, base_amount)
+        };
+        Ok(DepositQuote {
+            coin_amount,
+            pc_amount,
+            lp_amount,
+        })
+    }
+
+    /// Amounts of a withdraw burning lp_amount.
+    pub fn withdraw(&self, lp_amount: u64) -> Result<WithdrawQuote, ProgramError> {
+        if lp_amount >= self.amm.lp_amount {
+            return Err(AmmError::NotAllowZeroLP.into());
+        }
+        let (total_pc_without_take_pnl, total_coin_without_take_pnl) =
+            self.liquidity_totals(true)?;
+        let (coin_amount, pc_amount) = Processor::calc_withdraw_vault_amounts(
+            &*self.amm.curve(self.timestamp),
+            self.amm.lp_amount,
+            total_pc_without_take_pnl,
+            total_coin_without_take_pnl,
+            lp_amount,
+        )?;
+        if lp_amount == 0 || coin_amount == 0 || pc_amount == 0 {
+            return Err(AmmError::InvalidInput.into());
+        }
+        if coin_amount >= self.coin_vault_amount || pc_amount >= self.pc_vault_amount {
+            return Err(AmmError::TakePnlError.into());
+        }
+        Ok(WithdrawQuote {
+            coin_amount,
+            pc_amount,
+            coin_amount_after_transfer_fee: post_transfer_fee_amount(
+                self.coin_transfer_fee.as_ref(),
+                self.epoch,
+                coin_amount,
+            )?,
+            pc_amount_after_transfer_fee: post_transfer_fee_amount(
+                self.pc_transfer_fee.as_ref(),
+                self.epoch,
+                pc_amount,
+            )?,
+        })
+    }
+}
@@ -1,6 +1,13 @@
 //! State transition types
 
-use crate::error::AmmError;
+use crate::{
+    error::AmmError,
+    math::{
+        Calculator, ConstantProductCurve, CurveCalculator, RoundDirection, StableCurve,
+        SwapDirection, WeightedCurve,
+    },
+    processor::AMM_CONFIG_SEED,
+};
 use solana_program::{
     account_info::AccountInfo,
     program_error::ProgramError,
@@ -18,7 +25,31 @@ use std::{
 };
 
 pub const TEN_THOUSAND: u64 = 10000;
+/// Maximum share of the swap fee paid to referrers, in TEN_THOUSAND
+pub const MAX_REFERRER_FEE_RATE: u64 = 5000;
+/// Maximum fee of a flash loan, in TEN_THOUSAND
+pub const MAX_FLASH_FEE_RATE: u64 = 1000;
+/// Maximum delay before queued fees or status of a pool can be applied, in seconds
+pub const MAX_PARAMS_DELAY: u64 = 7 * 86400;
+/// Bounds of the stable curve amplification coefficient
+pub const MIN_AMP: u64 = 1;
+pub const MAX_AMP: u64 = 1_000_000;
+/// Maximum factor of an amplification coefficient ramp
+pub const MAX_AMP_CHANGE: u64 = 10;
+/// Minimum duration of an amplification coefficient ramp, in seconds
+pub const MIN_RAMP_DURATION: u64 = 86400;
+/// Denominator of the weighted curve weights
+pub const WEIGHT_DENOMINATOR: u64 = 100;
+/// Minimum weight of a token of a weighted curve, in WEIGHT_DENOMINATOR
+pub const MIN_WEIGHT: u64 = 1;
 pub const MAX_ORDER_LIMIT: usize = 10;
+/// Number of range order slots on each side of the TargetOrders
+pub const RANGE_ORDER_SLOTS: usize = 50;
+/// A range order deposits at least the pool vault of its token over this divisor
+pub const RANGE_ORDER_MIN_SIZE_DIVISOR: u64 = 10_000;
+/// Maximum number of range order fills of a swap, reaching each order along the curve
+/// costs a bisection of the curve math
+pub const MAX_RANGE_ORDER_FILLS: u64 = 8;
 
 #[cfg(not(test))]
 pub fn get_recent_epoch() -> Result<u64, ProgramError> {
@@ -82,7 +113,10 @@ unsafe impl TriviallyTransmutable for TargetOrder {}
 pub struct TargetOrders {
     pub owner: Pubkey,
     pub buy_orders: [TargetOrder; 50],
-    pub padding1: [u64; 8],
+    /// vault of the coin deposited by sell range orders, set by InitRangeOrders
+    pub range_coin_vault: Pubkey,
+    /// vault of the pc deposited by buy range orders, set by InitRangeOrders
+    pub range_pc_vault: Pubkey,
     pub target_x: u128,
     pub target_y: u128,
     pub plan_x_buy: u128,
@@ -94,7 +128,11 @@ pub struct TargetOrders {
     pub calc_pnl_x: u128,
     pub calc_pnl_y: u128,
     pub sell_orders: [TargetOrder; 50],
-    pub padding2: [u64; 6],
+    /// status queued by SetParams
+    pub pending_status: u64,
+    /// unix timestamp from which pending_status can be applied, 0 if nothing is queued
+    pub status_effective_time: u64,
+    pub padding2: [u64; 4],
     pub replace_buy_client_id: [u64; MAX_ORDER_LIMIT],
     pub replace_sell_client_id: [u64; MAX_ORDER_LIMIT],
     pub last_order_numerator: u64,
@@ -106,7 +144,11 @@ pub struct TargetOrders {
     pub valid_buy_order_num: u64,
     pub valid_sell_order_num: u64,
 
-    pub padding3: [u64; 10],
+    /// fees queued by SetParams
+    pub pending_fees: Fees,
+    /// unix timestamp from which pending_fees can be applied, 0 if nothing is queued
+    pub fees_effective_time: u64,
+    pub padding3: [u64; 1],
 
     pub free_slot_bits: u128,
 }
@@ -119,7 +161,8 @@ impl Default for TargetOrders {
         TargetOrders {
             owner: Pubkey::default(),
             buy_orders: [TargetOrder::default(); 50],
-            padding1: [0; 8],
+            range_coin_vault: Pubkey::default(),
+            range_pc_vault: Pubkey::default(),
             target_x: 0,
             target_y: 0,
             plan_x_buy: 0,
@@ -131,7 +174,9 @@ impl Default for TargetOrders {
             calc_pnl_x: 0,
             calc_pnl_y: 0,
             sell_orders: [TargetOrder::default(); 50],
-            padding2: [0; 6],
+            pending_status: 0,
+            status_effective_time: 0,
+            padding2: [0; 4],
             replace_buy_client_id: [0; MAX_ORDER_LIMIT],
             replace_sell_client_id: [0; MAX_ORDER_LIMIT],
             last_order_denominator: 0,
@@ -140,7 +185,9 @@ impl Default for TargetOrders {
             place_orders_cur: 0,
             valid_buy_order_num: 0,
             valid_sell_order_num: 0,
-            padding3: [0; 10],
+            pending_fees: Fees::default(),
+            fees_effective_time: 0,
+            padding3: [0; 1],
             free_slot_bits: std::u128::MAX,
         }
     }
@@ -177,6 +224,221 @@ impl TargetOrders {
         Ok(())
     }
 
+    /// Queues a status of the pool, to be applied from effective_time.
+    pub fn queue_status(&mut self, status: u64, effective_time: u64) {
+        self.pending_status = status;
+        self.status_effective_time = effective_time;
+    }
+
+    /// Drops the queued status, if any.
+    pub fn clear_pending_status(&mut self) {
+        self.pending_status = 0;
+        self.status_effective_time = 0;
+    }
+
+    /// Takes the queued status once it is effective at now.
+    pub fn take_effective_status(&mut self, now: u64) -> Option<u64> {
+        if self.status_effective_time == 0 || now < self.status_effective_time {
+            return None;
+        }
+        let status = self.pending_status;
+        self.clear_pending_status();
+        Some(status)
+    }
+
+    /// Queues fees of the pool, to be applied from effective_time.
+    pub fn queue_fees(&mut self, fees: Fees, effective_time: u64) {
+        self.pending_fees = fees;
+        self.fees_effective_time = effective_time;
+    }
+
+    /// Takes the queued fees once they are effective at now.
+    pub fn take_effective_fees(&mut self, now: u64) -> Option<Fees> {
+        if self.fees_effective_time == 0 || now < self.fees_effective_time {
+            return None;
+        }
+        let fees = self.pending_fees;
+        self.pending_fees = Fees::default();
+        self.fees_effective_time = 0;
+        Some(fees)
+    }
+
+    /// Whether InitRangeOrders has turned the order slots into range orders.
+    pub fn range_orders_enabled(&self) -> bool {
+        self.range_coin_vault != Pubkey::default()
+    }
+
+    /// Clears the slots left by the order book and frees them for range orders.
+    pub fn init_range_orders(
+        &mut self,
+        range_coin_vault: &Pubkey,
+        range_pc_vault: &Pubkey,
+    ) -> Result<(), ProgramError> {
+        if self.range_orders_enabled() {
+            return Err(AmmError::AlreadyInUse.into());
+        }
+        self.range_coin_vault = *range_coin_vault;
+        self.range_pc_vault = *range_pc_vault;
+        self.buy_orders = [TargetOrder::default(); 50];
+        self.sell_orders = [TargetOrder::default(); 50];
+        self.free_slot_bits = u128::MAX;
+        Ok(())
+    }
+
+    /// Vault of the token a range order side deposits.
+    pub fn range_order_vault(&self, side: OrderSide) -> Pubkey {
+        match side {
+            OrderSide::Buy => self.range_pc_vault,
+            OrderSide::Sell => self.range_coin_vault,
+        }
+    }
+
+    /// Range order slot, buy orders are kept in buy_orders and sell orders in sell_orders.
+    pub fn range_order_mut(&mut self, side: OrderSide, slot: usize) -> &mut TargetOrder {
+        match side {
+            OrderSide::Buy => &mut self.buy_orders[slot],
+            OrderSide::Sell => &mut self.sell_orders[slot],
+        }
+    }
+
+    /// free_slot_bits bit of a range order slot, the buy slots come first.
+    fn range_order_slot_bit(side: OrderSide, slot: usize) -> u128 {
+        1u128 << (side.into_u64() as usize * RANGE_ORDER_SLOTS + slot)
+    }
+
+    /// Places a range order in a free slot.
+    pub fn take_range_order_slot(
+        &mut self,
+        side: OrderSide,
+        slot: usize,
+        price: u64,
+        vol: u64,
+    ) -> Result<(), ProgramError> {
+        if slot >= RANGE_ORDER_SLOTS {
+            return Err(AmmError::InvalidInput.into());
+        }
+        let bit = Self::range_order_slot_bit(side, slot);
+        if self.free_slot_bits & bit == 0 {
+            return Err(AmmError::RangeOrderSlotInUse.into());
+        }
+        self.free_slot_bits &= !bit;
+        *self.range_order_mut(side, slot) = TargetOrder { price, vol };
+        Ok(())
+    }
+
+    /// Clears a range order slot and frees it.
+    pub fn free_range_order_slot(&mut self, side: OrderSide, slot: usize) {
+        self.free_slot_bits |= Self::range_order_slot_bit(side, slot);
+        *self.range_order_mut(side, slot) = TargetOrder::default();
+    }
+
+    /// Unfilled range order a swap in swap_direction takes first among the orders priced
+    /// at or better than pool_pc_price.
+    fn best_range_order(
+        &self,
+        swap_direction: SwapDirection,
+        pool_pc_price: u128,
+    ) -> Option<usize> {
+        match swap_direction {
+            // coin sellers take the highest buy orders
+            SwapDirection::Coin2PC => self
+                .buy_orders
+                .iter()
+                .enumerate()
+                .filter(|(_, order)| order.vol > 0 && order.price as u128 >= pool_pc_price)
+                .max_by_key(|(_, order)| order.price)
+                .map(|(i, _)| i),
+            // pc sellers take the lowest sell orders
+            SwapDirection::PC2Coin => self
+                .sell_orders
+                .iter()
+                .enumerate()
+                .filter(|(_, order)| order.vol > 0 && order.price as u128 <= pool_pc_price)
+                .min_by_key(|(_, order)| order.price)
+                .map(|(i, _)| i),
+        }
+    }
+
+    /// Price of the unfilled range order a swap in swap_direction reaches first, whatever
+    /// the pool price.
+    pub fn next_range_order_price(&self, swap_direction: SwapDirection) -> Option<u64> {
+        match swap_direction {
+            SwapDirection::Coin2PC => self
+                .best_range_order(swap_direction, 0)
+                .map(|i| self.buy_orders[i].price),
+            SwapDirection::PC2Coin => self
+                .best_range_order(swap_direction, u128::MAX)
+                .map(|i| self.sell_orders[i].price),
+        }
+    }
+
+    /// Fills up to max_fills range orders priced at or better than pool_pc_price with up to
+    /// amount_in, best price first and without fee. Returns the part of amount_in paid to
+    /// the orders, the amount they paid out and the number of fills. The taker side is
+    /// rounded up, so the range vaults always cover the proceeds claimed by the orders.
+    pub fn fill_range_orders(
+        &mut self,
+        amm: &AmmInfo,
+        swap_direction: SwapDirection,
+        amount_in: u64,
+        pool_pc_price: u128,
+        max_fills: u64,
+    ) -> (u64, u64, u64) {
+        let (mut range_in, mut range_out, mut fill_count) = (0u64, 0u64, 0u64);
+        while fill_count < max_fills {
+            let amount_in_left = amount_in - range_in;
+            let order = match (
+                swap_direction,
+                self.best_range_order(swap_direction, pool_pc_price),
+            ) {
+                (SwapDirection::Coin2PC, Some(i)) => &mut self.buy_orders[i],
+                (SwapDirection::PC2Coin, Some(i)) => &mut self.sell_orders[i],
+                (_, None) => break,
+            };
+            let (price, vol) = (order.price, order.vol);
+            let (fill, pay) = match swap_direction {
+                SwapDirection::Coin2PC => {
+                    let fill = vol.min(Calculator::range_order_coin_to_pc(
+                        amount_in_left,
+                        price,
+                        amm,
+                        RoundDirection::Floor,
+                    ));
+                    let pay = Calculator::range_order_pc_to_coin(
+                        fill,
+                        price,
+                        amm,
+                        RoundDirection::Ceiling,
+                    );
+                    (fill, pay)
+                }
+                SwapDirection::PC2Coin => {
+                    let fill = vol.min(Calculator::range_order_pc_to_coin(
+                        amount_in_left,
+                        price,
+                        amm,
+                        RoundDirection::Floor,
+                    ));
+                    let pay = Calculator::range_order_coin_to_pc(
+                        fill,
+                        price,
+                        amm,
+                        RoundDirection::Ceiling,
+                    );
+                    (fill, pay)
+                }
+            };
+            if fill == 0 {
+                break;
+            }
+            order.vol = vol - fill;
+            range_in += pay;
+            range_out += fill;
+            fill_count += 1;
+        }
+        (range_in, range_out, fill_count)
+    }
+
     /// load_mut_checked
     #[inline]
     pub fn load_mut_checked<'a>(
@@ -218,6 +480,39 @@ impl TargetOrders {
     }
 }
 
+#[repr(u64)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveType {
+    // x * y = k
+    ConstantProduct = 0u64,
+    // stable invariant with an amplification coefficient, for pegged tokens
+    Stable = 1u64,
+    // x^coin_weight * y^pc_weight = k, set at Initialize2
+    Weighted = 2u64,
+}
+impl CurveType {
+    pub fn from_u64(curve_type: u64) -> Self {
+        match curve_type {
+            0u64 => CurveType::ConstantProduct,
+            1u64 => CurveType::Stable,
+            2u64 => CurveType::Weighted,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn into_u64(&self) -> u64 {
+        match self {
+            CurveType::ConstantProduct => 0u64,
+            CurveType::Stable => 1u64,
+            CurveType::Weighted => 2u64,
+        }
+    }
+
+    pub fn valid_curve_type(curve_type: u64) -> bool {
+        matches!(curve_type, 0u64..=2u64)
+    }
+}
+
 #[repr(u64)]
 pub enum AmmStatus {
     Uninitialized = 0u64,
@@ -267,6 +562,15 @@ impl AmmStatus {
         }
     }
 
+    /// How far the status stops a pool, WithdrawOnly and Disabled are the emergency stops.
+    pub fn pause_level(&self) -> u64 {
+        match self {
+            AmmStatus::Disabled => 2,
+            AmmStatus::WithdrawOnly => 1,
+            _ => 0,
+        }
+    }
+
     pub fn deposit_permission(&self) -> bool {
         match self {
             AmmStatus::Uninitialized => false,
@@ -393,6 +697,59 @@ impl AmmParams {
     }
 }
 
+#[cfg_attr(feature = "client", derive(Debug))]
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+pub enum SimulateParams {
+    PoolInfo = 0u64,
+    SwapBaseInInfo = 1u64,
+    SwapBaseOutInfo = 2u64,
+}
+impl SimulateParams {
+    pub fn from_u64(param: u64) -> Result<Self, ProgramError> {
+        match param {
+            0u64 => Ok(SimulateParams::PoolInfo),
+            1u64 => Ok(SimulateParams::SwapBaseInInfo),
+            2u64 => Ok(SimulateParams::SwapBaseOutInfo),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    pub fn into_u64(&self) -> u64 {
+        match self {
+            SimulateParams::PoolInfo => 0u64,
+            SimulateParams::SwapBaseInInfo => 1u64,
+            SimulateParams::SwapBaseOutInfo => 2u64,
+        }
+    }
+}
+
+/// Side of a range or limit order. A buy order deposits pc and receives coin,
+/// a sell order deposits coin and receives pc.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[repr(u64)]
+pub enum OrderSide {
+    Buy = 0u64,
+    Sell = 1u64,
+}
+impl OrderSide {
+    pub fn from_u64(side: u64) -> Result<Self, ProgramError> {
+        match side {
+            0u64 => Ok(OrderSide::Buy),
+            1u64 => Ok(OrderSide::Sell),
+            _ => Err(ProgramError::InvalidInstructionData),
+        }
+    }
+
+    pub fn into_u64(&self) -> u64 {
+        match self {
+            OrderSide::Buy => 0u64,
+            OrderSide::Sell => 1u64,
+        }
+    }
+}
+
 #[cfg_attr(feature = "client", derive(Debug))]
 #[derive(Copy, Clone, Eq, PartialEq)]
 #[repr(u64)]
@@ -452,11 +809,23 @@ pub struct Fees {
 
 impl Fees {
     /// Validate that the fees are reasonable
-    pub fn validate(&self) -> Result<(), AmmError> {
+    /// Validate the fees, and the dynamic swap fee bounds around swap_fee_numerator
+    /// of a pool in dynamic fee mode
+    pub fn validate(&self, dynamic_fee: Option<&DynamicFeeParams>) -> Result<(), AmmError> {
         validate_fraction(self.min_separate_numerator, self.min_separate_denominator)?;
         validate_fraction(self.trade_fee_numerator, self.trade_fee_denominator)?;
         validate_fraction(self.pnl_numerator, self.pnl_denominator)?;
         validate_fraction(self.swap_fee_numerator, self.swap_fee_denominator)?;
+        if let Some(params) = dynamic_fee {
+            validate_fraction(params.max_fee_numerator, self.swap_fee_denominator)?;
+            if params.min_fee_numerator > self.swap_fee_numerator
+                || self.swap_fee_numerator > params.max_fee_numerator
+                || params.reduction_factor > TEN_THOUSAND
+                || params.filter_period > params.decay_period
+            {
+                return Err(AmmError::InvalidFee);
+            }
+        }
         Ok(())
     }
 
@@ -475,6 +844,14 @@ impl Fees {
         self.swap_fee_denominator = TEN_THOUSAND;
         Ok(())
     }
+
+    /// Protocol share of a swap fee, pnl_numerator / pnl_denominator of it.
+    pub fn protocol_fee(&self, swap_fee: u64) -> u64 {
+        if self.pnl_denominator == 0 {
+            return 0;
+        }
+        (swap_fee as u128 * self.pnl_numerator as u128 / self.pnl_denominator as u128) as u64
+    }
 }
 
 /// IsInitialized is required to use `Pack::pack` and `Pack::unpack`
@@ -550,8 +927,10 @@ pub struct StateData {
     pub total_pnl_coin: u64,
     /// pool open time
     pub pool_open_time: u64,
-    /// padding for future updates
-    pub padding: [u64; 2],
+    /// protocol fee of the coin accrued by swaps, paid out by withdrawpnl
+    pub protocol_fee_coin: u64,
+    /// protocol fee of the pc accrued by swaps, paid out by withdrawpnl
+    pub protocol_fee_pc: u64,
     /// Deprecated field.
     /// No longer in use or updated.
     pub orderbook_to_init_time: u64,
@@ -584,7 +963,8 @@ impl StateData {
         self.total_pnl_pc = 0u64;
         self.total_pnl_coin = 0u64;
         self.pool_open_time = open_time;
-        self.padding = Zeroable::zeroed();
+        self.protocol_fee_coin = 0u64;
+        self.protocol_fee_pc = 0u64;
         self.orderbook_to_init_time = 0u64;
         self.swap_coin_in_amount = 0u128;
         self.swap_pc_out_amount = 0u128;
@@ -659,8 +1039,24 @@ pub struct AmmInfo {
     pub market_program: Pubkey,
     /// target_orders key
     pub target_orders: Pubkey,
-    /// padding
-    pub padding1: [u64; 8],
+    /// 1 once swaps accrue the protocol fee, 0 while the pnl is taken by calc_pnl_x & calc_pnl_y
+    pub protocol_fee_enabled: u64,
+    /// 1 once swaps charge the dynamic swap fee of the pool DynamicFeeState
+    pub dynamic_fee_enabled: u64,
+    /// 1 while a flash loan of the vaults is open, deposit, withdraw and swap are refused
+    pub flash_loan_locked: u32,
+    /// 1 once the pool has an ObservationState, every price changing instruction must pass it
+    pub observation_enabled: u32,
+    /// CurveType of the swaps
+    pub curve_type: u64,
+    /// amplification coefficient of a stable curve at ramp_start_timestamp
+    pub initial_amp: u64,
+    /// amplification coefficient of a stable curve from ramp_stop_timestamp
+    pub target_amp: u64,
+    /// start of the linear amplification coefficient ramp
+    pub ramp_start_timestamp: u64,
+    /// end of the linear amplification coefficient ramp
+    pub ramp_stop_timestamp: u64,
     /// amm owner key
     pub amm_owner: Pubkey,
     /// pool lp amount
@@ -669,8 +1065,8 @@ pub struct AmmInfo {
     pub client_order_id: u64,
     /// recent epoch
     pub recent_epoch: u64,
-    /// padding
-    pub padding2: u64,
+    /// coin weight of a weighted curve in WEIGHT_DENOMINATOR, the pc weight is the rest
+    pub coin_weight: u64,
 }
 impl_loadable!(AmmInfo);
 
@@ -758,13 +1154,80 @@ impl AmmInfo {
         self.min_price_multiplier = 1;
         self.max_price_multiplier = 1000000000;
         self.client_order_id = 0;
-        self.padding1 = Zeroable::zeroed();
+        self.protocol_fee_enabled = 1;
+        self.dynamic_fee_enabled = 0;
+        self.flash_loan_locked = 0;
+        self.observation_enabled = 0;
+        self.curve_type = CurveType::ConstantProduct.into_u64();
+        self.initial_amp = 0;
+        self.target_amp = 0;
+        self.ramp_start_timestamp = 0;
+        self.ramp_stop_timestamp = 0;
         self.recent_epoch = get_recent_epoch().unwrap();
-        self.padding2 = Zeroable::zeroed();
+        self.coin_weight = 0;
 
         Ok(())
     }
 
+    /// Refuses to move the vault tokens while a flash loan of the pool is open.
+    pub fn check_flash_loan_unlocked(&self) -> Result<(), AmmError> {
+        if self.flash_loan_locked != 0 {
+            return Err(AmmError::FlashLoanLocked);
+        }
+        Ok(())
+    }
+
+    /// Pc weight of a weighted curve in WEIGHT_DENOMINATOR.
+    pub fn pc_weight(&self) -> u64 {
+        WEIGHT_DENOMINATOR.saturating_sub(self.coin_weight)
+    }
+
+    /// Amplification coefficient of the stable curve at timestamp.
+    pub fn amp(&self, timestamp: u64) -> u64 {
+        if timestamp >= self.ramp_stop_timestamp {
+            return self.target_amp;
+        }
+        let elapsed = timestamp.saturating_sub(self.ramp_start_timestamp) as u128;
+        let duration = (self.ramp_stop_timestamp - self.ramp_start_timestamp) as u128;
+        if self.target_amp > self.initial_amp {
+            let change = (self.target_amp - self.initial_amp) as u128 * elapsed / duration;
+            self.initial_amp + change as u64
+        } else {
+            let change = (self.initial_amp - self.target_amp) as u128 * elapsed / duration;
+            self.initial_amp - change as u64
+        }
+    }
+
+    /// Swap math of the pool curve at timestamp.
+    pub fn curve(&self, timestamp: u64) -> Box<dyn CurveCalculator> {
+        match CurveType::from_u64(self.curve_type) {
+            CurveType::ConstantProduct => Box::new(ConstantProductCurve),
+            CurveType::Stable => Box::new(StableCurve::new(
+                self.amp(timestamp),
+                self.coin_decimals,
+                self.pc_decimals,
+            )),
+            CurveType::Weighted => Box::new(WeightedCurve {
+                coin_weight: self.coin_weight,
+                pc_weight: self.pc_weight(),
+            }),
+        }
+    }
+
+    /// Coin taken out of the pool for the protocol, not yet withdrawn.
+    pub fn pending_take_pnl_coin(&self) -> u64 {
+        self.state_data
+            .need_take_pnl_coin
+            .saturating_add(self.state_data.protocol_fee_coin)
+    }
+
+    /// Pc taken out of the pool for the protocol, not yet withdrawn.
+    pub fn pending_take_pnl_pc(&self) -> u64 {
+        self.state_data
+            .need_take_pnl_pc
+            .saturating_add(self.state_data.protocol_fee_pc)
+    }
+
     pub fn incr_client_order_id(&mut self) -> u64 {
         self.client_order_id = self.client_order_id.wrapping_add(1);
         if self.client_order_id == 0 {
@@ -774,6 +1237,507 @@ impl AmmInfo {
     }
 }
 
+/// Number of observations kept by an observation account.
+pub const OBSERVATION_NUM: usize = 100;
+
+/// Cumulative prices of a pool at one point in time.
+/// Prices are normalized amounts ratios scaled by `sys_decimal_value`,
+/// the same scale as `Calculator::normalize_decimal_v2`.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default, PartialEq)]
+pub struct Observation {
+    /// unix timestamp of the observation
+    pub block_timestamp: u64,
+    /// sum of pc per coin price * elapsed seconds, wrapping
+    pub cumulative_pc_price: u128,
+    /// sum of coin per pc price * elapsed seconds, wrapping
+    pub cumulative_coin_price: u128,
+}
+
+impl Observation {
+    /// Time weighted average prices (pc per coin, coin per pc) between two observations.
+    pub fn twap(older: &Observation, newer: &Observation) -> Option<(u128, u128)> {
+        let elapsed = newer.block_timestamp.checked_sub(older.block_timestamp)?;
+        if elapsed == 0 {
+            return None;
+        }
+        Some((
+            newer
+                .cumulative_pc_price
+                .wrapping_sub(older.cumulative_pc_price)
+                / elapsed as u128,
+            newer
+                .cumulative_coin_price
+                .wrapping_sub(older.cumulative_coin_price)
+                / elapsed as u128,
+        ))
+    }
+}
+
+/// Ring buffer of pool price observations, optional per pool and written by swaps.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct ObservationState {
+    /// amm account the observations belong to
+    pub amm_id: Pubkey,
+    /// index of the latest observation
+    pub observation_index: u64,
+    pub observations: [Observation; OBSERVATION_NUM],
+    /// bump seed of the account address
+    pub bump: u64,
+    /// padding for future updates
+    pub padding: [u64; 15],
+}
+impl_loadable!(ObservationState);
+
+impl ObservationState {
+    /// init
+    pub fn initialize(
+        &mut self,
+        amm_id: &Pubkey,
+        bump: u8,
+        block_timestamp: u64,
+    ) -> Result<(), ProgramError> {
+        if self.amm_id != Pubkey::default() {
+            return Err(AmmError::AlreadyInUse.into());
+        }
+        self.amm_id = *amm_id;
+        self.observation_index = 0;
+        self.observations = [Observation::default(); OBSERVATION_NUM];
+        self.observations[0].block_timestamp = block_timestamp;
+        self.bump = bump as u64;
+        self.padding = Zeroable::zeroed();
+        Ok(())
+    }
+
+    /// latest observation
+    pub fn latest(&self) -> Observation {
+        self.observations[self.observation_index as usize]
+    }
+
+    /// Accumulates the prices which held since the latest observation into a new one.
+    /// At most one observation is written per timestamp.
+    pub fn update(&mut self, block_timestamp: u64, pc_price: u128, coin_price: u128) {
+        let latest = self.latest();
+        if block_timestamp <= latest.block_timestamp {
+            return;
+        }
+        let elapsed = (block_timestamp - latest.block_timestamp) as u128;
+        let next_index = (self.observation_index as usize + 1) % OBSERVATION_NUM;
+        self.observations[next_index] = Observation {
+            block_timestamp,
+            cumulative_pc_price: latest
+                .cumulative_pc_price
+                .wrapping_add(pc_price.wrapping_mul(elapsed)),
+            cumulative_coin_price: latest
+                .cumulative_coin_price
+                .wrapping_add(coin_price.wrapping_mul(elapsed)),
+        };
+        self.observation_index = next_index as u64;
+    }
+
+    /// The latest observation taken at or before block_timestamp, if still in the buffer.
+    pub fn observation_before(&self, block_timestamp: u64) -> Option<Observation> {
+        (0..OBSERVATION_NUM)
+            .map(|i| {
+                self.observations
+                    [(self.observation_index as usize + OBSERVATION_NUM - i) % OBSERVATION_NUM]
+            })
+            .take_while(|observation| observation.block_timestamp != 0)
+            .find(|observation| observation.block_timestamp <= block_timestamp)
+    }
+
+    /// load_mut_checked
+    #[inline]
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let data = Self::load_mut(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        Ok(data)
+    }
+
+    /// load_checked
+    #[inline]
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<Ref<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        let data = Self::load(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidObservationAccount.into());
+        }
+        Ok(data)
+    }
+}
+
+/// Volatility accumulator bound, in basis points of price moves
+pub const MAX_VOLATILITY_ACCUMULATOR: u64 = 1_000_000;
+
+/// Admin parameters of the dynamic swap fee of a pool.
+/// Fee numerators use `Fees::swap_fee_denominator` as denominator.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DynamicFeeParams {
+    /// lower bound of the swap fee numerator
+    pub min_fee_numerator: u64,
+    /// upper bound of the swap fee numerator
+    pub max_fee_numerator: u64,
+    /// swap fee numerator added per TEN_THOUSAND basis points of volatility
+    pub variable_fee_control: u64,
+    /// seconds since the last swap under which the volatility reference is kept
+    pub filter_period: u64,
+    /// seconds since the last swap after which the volatility is reset
+    pub decay_period: u64,
+    /// volatility kept after a filter_period, in TEN_THOUSAND
+    pub reduction_factor: u64,
+}
+
+/// A range order placed in a slot of the TargetOrders. The slot holds the price and the
+/// unfilled volume, the account holds the owner and the fills already claimed.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct RangeOrderState {
+    /// amm account the order belongs to
+    pub amm_id: Pubkey,
+    /// owner of the order, receives the proceeds and the refund
+    pub owner: Pubkey,
+    /// OrderSide
+    pub side: u64,
+    /// slot of the order in the buy or sell orders of the TargetOrders
+    pub slot: u64,
+    /// pc per coin, scaled like the pool price
+    pub price: u64,
+    /// deposited amount, pc for a buy order and coin for a sell order
+    pub amount: u64,
+    /// filled part of amount whose proceeds were claimed
+    pub claimed_fill: u64,
+    /// padding for future updates
+    pub padding: [u64; 4],
+}
+impl_loadable!(RangeOrderState);
+
+impl RangeOrderState {
+    /// init
+    pub fn initialize(
+        &mut self,
+        amm_id: &Pubkey,
+        owner: &Pubkey,
+        side: OrderSide,
+        slot: u64,
+        price: u64,
+        amount: u64,
+    ) {
+        self.amm_id = *amm_id;
+        self.owner = *owner;
+        self.side = side.into_u64();
+        self.slot = slot;
+        self.price = price;
+        self.amount = amount;
+        self.claimed_fill = 0;
+        self.padding = Zeroable::zeroed();
+    }
+
+    /// Proceeds of the fills since the last claim, coin for a buy order and pc for a
+    /// sell order. The total proceeds are rounded down, takers paid at least as much.
+    pub fn claimable(&self, amm: &AmmInfo, filled: u64) -> u64 {
+        let proceeds = |fill: u64| match OrderSide::from_u64(self.side) {
+            Ok(OrderSide::Buy) => {
+                Calculator::range_order_pc_to_coin(fill, self.price, amm, RoundDirection::Floor)
+            }
+            _ => Calculator::range_order_coin_to_pc(fill, self.price, amm, RoundDirection::Floor),
+        };
+        proceeds(filled) - proceeds(self.claimed_fill)
+    }
+
+    /// load_mut_checked
+    #[inline]
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidRangeOrderAccount.into());
+        }
+        let data = Self::load_mut(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidRangeOrderAccount.into());
+        }
+        Ok(data)
+    }
+}
+
+/// A resting limit order of a user. The escrowed amount is swapped through the pool
+/// as a whole once the pool price reaches the limit price.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct LimitOrderState {
+    /// amm account the order belongs to
+    pub amm_id: Pubkey,
+    /// owner of the order, receives the proceeds and the refund
+    pub owner: Pubkey,
+    /// id of the order among the orders of the owner
+    pub order_id: u64,
+    /// OrderSide
+    pub side: u64,
+    /// limit price, pc per coin scaled like the pool price
+    pub price: u64,
+    /// escrowed amount not executed yet, pc for a buy order and coin for a sell order
+    pub amount: u64,
+    /// executed amount not claimed yet, coin for a buy order and pc for a sell order
+    pub proceeds: u64,
+    /// padding for future updates
+    pub padding: [u64; 6],
+}
+impl_loadable!(LimitOrderState);
+
+impl LimitOrderState {
+    /// init
+    pub fn initialize(
+        &mut self,
+        amm_id: &Pubkey,
+        owner: &Pubkey,
+        order_id: u64,
+        side: OrderSide,
+        price: u64,
+        amount: u64,
+    ) {
+        self.amm_id = *amm_id;
+        self.owner = *owner;
+        self.order_id = order_id;
+        self.side = side.into_u64();
+        self.price = price;
+        self.amount = amount;
+        self.proceeds = 0;
+        self.padding = Zeroable::zeroed();
+    }
+
+    /// Whether the pool price reached the limit price.
+    pub fn limit_reached(&self, pool_pc_price: u128) -> bool {
+        match OrderSide::from_u64(self.side) {
+            Ok(OrderSide::Buy) => pool_pc_price <= self.price as u128,
+            _ => pool_pc_price >= self.price as u128,
+        }
+    }
+
+    /// Least amount the escrowed amount must be executed for at the limit price.
+    pub fn minimum_out(&self, amm: &AmmInfo) -> u64 {
+        match OrderSide::from_u64(self.side) {
+            Ok(OrderSide::Buy) => Calculator::range_order_pc_to_coin(
+                self.amount,
+                self.price,
+                amm,
+                RoundDirection::Ceiling,
+            ),
+            _ => Calculator::range_order_coin_to_pc(
+                self.amount,
+                self.price,
+                amm,
+                RoundDirection::Ceiling,
+            ),
+        }
+    }
+
+    /// load_mut_checked
+    #[inline]
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidLimitOrderAccount.into());
+        }
+        let data = Self::load_mut(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidLimitOrderAccount.into());
+        }
+        Ok(data)
+    }
+}
+
+/// Volatility state of a pool in dynamic fee mode, written by swaps.
+#[cfg_attr(feature = "client", derive(Debug))]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct DynamicFeeState {
+    /// amm account the state belongs to
+    pub amm_id: Pubkey,
+    pub params: DynamicFeeParams,
+    /// volatility carried over from the swaps before the reference price was taken
+    pub volatility_reference: u64,
+    /// volatility_reference plus the price move from the reference price, in basis points
+    pub volatility_accumulator: u64,
+    /// pc per coin price the price moves are measured from, scaled by sys_decimal_value
+    pub reference_price: u128,
+    /// unix timestamp of the last swap
+    pub last_update_timestamp: u64,
+    /// padding for future updates
+    pub padding: [u64; 8],
+}
+impl_loadable!(DynamicFeeState);
+
+impl DynamicFeeState {
+    /// init
+    pub fn initialize(&mut self, amm_id: &Pubkey) -> Result<(), ProgramError> {
+        if self.amm_id != Pubkey::default() {
+            return Err(AmmError::AlreadyInUse.into());
+        }
+        self.amm_id = *amm_id;
+        self.params = DynamicFeeParams::default();
+        self.volatility_reference = 0;
+        self.volatility_accumulator = 0;
+        self.reference_price = 0;
+        self.last_update_timestamp = 0;
+        self.padding = Zeroable::zeroed();
+        Ok(())
+    }
+
+    /// Accumulates the move of the pool price before a swap into the volatility.
+    /// The volatility decays with the time elapsed since the last swap.
+    pub fn update(&mut self, block_timestamp: u64, price: u128) {
+        let elapsed = block_timestamp.saturating_sub(self.last_update_timestamp);
+        if elapsed >= self.params.decay_period {
+            self.volatility_reference = 0;
+            self.reference_price = price;
+        } else if elapsed >= self.params.filter_period {
+            self.volatility_reference = (self.volatility_accumulator as u128
+                * self.params.reduction_factor as u128
+                / TEN_THOUSAND as u128) as u64;
+            self.reference_price = price;
+        }
+        let price_move = price
+            .abs_diff(self.reference_price)
+            .saturating_mul(TEN_THOUSAND as u128)
+            .checked_div(self.reference_price)
+            .unwrap_or(0);
+        self.volatility_accumulator = (self.volatility_reference as u128)
+            .saturating_add(price_move)
+            .min(MAX_VOLATILITY_ACCUMULATOR as u128) as u64;
+        self.last_update_timestamp = block_timestamp;
+    }
+
+    /// Swap fee numerator of the base fee plus the volatility fee, within the admin bounds.
+    pub fn swap_fee_numerator(&self, fees: &Fees) -> u64 {
+        let variable_fee_numerator = self.volatility_accumulator as u128
+            * self.params.variable_fee_control as u128
+            / TEN_THOUSAND as u128;
+        (fees.swap_fee_numerator as u128)
+            .saturating_add(variable_fee_numerator)
+            .max(self.params.min_fee_numerator as u128)
+            .min(self.params.max_fee_numerator as u128) as u64
+    }
+
+    /// load_mut_checked
+    #[inline]
+    pub fn load_mut_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<RefMut<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidDynamicFeeAccount.into());
+        }
+        let data = Self::load_mut(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidDynamicFeeAccount.into());
+        }
+        Ok(data)
+    }
+
+    /// load_checked
+    #[inline]
+    pub fn load_checked<'a>(
+        account: &'a AccountInfo,
+        program_id: &Pubkey,
+        amm_id: &Pubkey,
+    ) -> Result<Ref<'a, Self>, ProgramError> {
+        if account.owner != program_id || account.data_len() != size_of::<Self>() {
+            return Err(AmmError::InvalidDynamicFeeAccount.into());
+        }
+        let data = Self::load(account)?;
+        if data.amm_id != *amm_id {
+            return Err(AmmError::InvalidDynamicFeeAccount.into());
+        }
+        Ok(data)
+    }
+}
+
+/// Return data of `SimulateInfo` with `SimulateParams::PoolInfo`.
+/// 88 bytes, every field is a little endian u64 in declaration order.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SimulatePoolInfo {
+    /// amm status
+    pub status: u64,
+    /// coin decimals
+    pub coin_decimals: u64,
+    /// pc decimals
+    pub pc_decimals: u64,
+    /// lp token supply
+    pub lp_amount: u64,
+    /// coin vault amount without need_take_pnl_coin
+    pub pool_coin_amount: u64,
+    /// pc vault amount without need_take_pnl_pc
+    pub pool_pc_amount: u64,
+    /// delay to take pnl coin
+    pub need_take_pnl_coin: u64,
+    /// delay to take pnl pc
+    pub need_take_pnl_pc: u64,
+    /// swap fee numerator
+    pub swap_fee_numerator: u64,
+    /// swap fee denominator
+    pub swap_fee_denominator: u64,
+    /// pool open time
+    pub pool_open_time: u64,
+}
+impl_loadable!(SimulatePoolInfo);
+
+/// Return data of `SimulateInfo` with `SimulateParams::SwapBaseInInfo` or
+/// `SimulateParams::SwapBaseOutInfo`.
+/// 80 bytes, every field is a little endian u64 in declaration order.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SimulateSwapInfo {
+    /// amount sent by the user, before the source transfer fee
+    pub amount_in: u64,
+    /// amount received by the source vault
+    pub amount_in_after_transfer_fee: u64,
+    /// swap fee charged on the source token
+    pub swap_fee: u64,
+    /// amount sent by the destination vault
+    pub amount_out: u64,
+    /// amount received by the user, after the destination transfer fee
+    pub amount_out_after_transfer_fee: u64,
+    /// price impact of the trade against the pool price, in basis points
+    pub price_impact: u64,
+    /// coin amount without need_take_pnl_coin after the swap
+    pub pool_coin_amount: u64,
+    /// pc amount without need_take_pnl_pc after the swap
+    pub pool_pc_amount: u64,
+    /// delay to take pnl coin
+    pub need_take_pnl_coin: u64,
+    /// delay to take pnl pc
+    pub need_take_pnl_pc: u64,
+}
+impl_loadable!(SimulateSwapInfo);
+
 /// State of amm config account
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default, PartialEq)]
@@ -782,8 +1746,23 @@ pub struct AmmConfig {
     pub pnl_owner: Pubkey,
     /// admin amm order owner
     pub cancel_owner: Pubkey,
+    /// share of the swap fee paid to the referrer of a swap, in TEN_THOUSAND
+    pub referrer_fee_rate: u64,
+    /// fee of a flash loan, in TEN_THOUSAND of the amount lent
+    pub flash_fee_rate: u64,
+    /// admin of the program
+    pub admin: Pubkey,
+    /// admin proposed by the admin, until it accepts
+    pub pending_admin: Pubkey,
+    /// delay before the fees or status queued by SetParams can be applied, in seconds
+    pub params_delay: u64,
+    /// status of all the pools set by the guardian, Uninitialized when it does not apply
+    pub global_status: u64,
+    /// guardian set by the admin, can only move pools or the program to WithdrawOnly or
+    /// Disabled. No guardian while it is the default key.
+    pub guardian: Pubkey,
     /// pending
-    pub pending_1: [u64; 28],
+    pub pending_1: [u64; 12],
     /// pending
     pub pending_2: [u64; 31],
     /// init amm pool fee amount
@@ -792,6 +1771,28 @@ pub struct AmmConfig {
 impl_loadable!(AmmConfig);
 
 impl AmmConfig {
+    /// Share of swap_fee paid to the referrer of a swap, capped by MAX_REFERRER_FEE_RATE.
+    pub fn referrer_fee(&self, swap_fee: u64) -> u64 {
+        let referrer_fee_rate = self.referrer_fee_rate.min(MAX_REFERRER_FEE_RATE);
+        (swap_fee as u128 * referrer_fee_rate as u128 / TEN_THOUSAND as u128) as u64
+    }
+
+    /// Checks the global status lets an instruction moving funds of the vaults run,
+    /// withdrawals still run under WithdrawOnly.
+    pub fn check_global_status(&self, withdraw: bool) -> Result<(), AmmError> {
+        match AmmStatus::from_u64(self.global_status).pause_level() {
+            0 => Ok(()),
+            1 if withdraw => Ok(()),
+            _ => Err(AmmError::GlobalPaused),
+        }
+    }
+
+    /// Fee of lending amount by a flash loan, rounded up and capped by MAX_FLASH_FEE_RATE.
+    pub fn flash_fee(&self, amount: u64) -> u64 {
+        let flash_fee_rate = self.flash_fee_rate.min(MAX_FLASH_FEE_RATE);
+        (amount as u128 * flash_fee_rate as u128).div_ceil(TEN_THOUSAND as u128) as u64
+    }
+
     /// Helper function to get the more efficient packed size of the struct
     /// load_mut_checked
     #[inline]
@@ -805,6 +1806,7 @@ impl AmmConfig {
         if account.data_len() != size_of::<Self>() {
             return Err(AmmError::ExpectedAccount.into());
         }
+        Self::check_address(account, program_id)?;
         let data = Self::load_mut(account)?;
         Ok(data)
     }
@@ -821,9 +1823,19 @@ impl AmmConfig {
         if account.data_len() != size_of::<Self>() {
             return Err(AmmError::ExpectedAccount.into());
         }
+        Self::check_address(account, program_id)?;
         let data = Self::load(account)?;
         Ok(data)
     }
+
+    /// Checks the account is the amm config PDA of the program.
+    fn check_address(account: &AccountInfo, program_id: &Pubkey) -> Result<(), AmmError> {
+        let (pda, _) = Pubkey::find_program_address(&[AMM_CONFIG_SEED], program_id);
+        if *account.key != pda {
+            return Err(AmmError::InvalidConfigAccount);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -863,7 +1875,8 @@ mod test {
         let total_pnl_pc: u64 = 0x12f3456789abcde0;
         let total_pnl_coin: u64 = 0x1f23456789abcde0;
         let pool_open_time: u64 = 0x123456789abcedf0;
-        let padding: [u64; 2] = [0x123456789abecdf0, 0x123456789aebcdf0];
+        let protocol_fee_coin: u64 = 0x123456789abecdf0;
+        let protocol_fee_pc: u64 = 0x123456789aebcdf0;
         let orderbook_to_init_time: u64 = 0x123456789eabcdf0;
         let swap_coin_in_amount: u128 = 0x11002233445566778899aabbccddeeff;
         let swap_pc_out_amount: u128 = 0x11220033445566778899aabbccddeeff;
@@ -882,19 +1895,20 @@ mod test {
         let market_program = Pubkey::new_unique();
         let target_orders = Pubkey::new_unique();
 
-        let mut padding1: [u64; 8] = [0u64; 8];
-        let mut padding1_data = [0u8; 8 * 8];
-        let mut offset = 0;
-        for i in 0..8 {
-            padding1[i] = u64::MAX - i as u64;
-            padding1_data[offset..offset + 8].copy_from_slice(&padding1[i].to_le_bytes());
-            offset += 8;
-        }
+        let protocol_fee_enabled: u64 = 0x1234d56789abcdf0;
+        let dynamic_fee_enabled: u64 = 0x12d3456789abcdf0;
+        let flash_loan_locked: u32 = 0x1d234567;
+        let observation_enabled: u32 = 0x89abcdf0;
+        let curve_type: u64 = 0x123d456789abcdf0;
+        let initial_amp: u64 = 0x1234567d89abcdf0;
+        let target_amp: u64 = 0x12345678d9abcdf0;
+        let ramp_start_timestamp: u64 = 0x123456789dabcdf0;
+        let ramp_stop_timestamp: u64 = 0x123456789adbcdf0;
         let amm_owner = Pubkey::new_unique();
         let lp_amount: u64 = 0x123456e789abcdf0;
         let client_order_id: u64 = 0x12345e6789abcdf0;
         let recent_epoch: u64 = 0x1234e56789abcdf0;
-        let padding2: u64 = 0x123e456789abcdf0;
+        let coin_weight: u64 = 0x123e456789abcdf0;
 
         // serialize original data
         let mut pool_data = [0u8; 752];
@@ -959,9 +1973,9 @@ mod test {
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&pool_open_time.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding[0].to_le_bytes());
+        pool_data[offset..offset + 8].copy_from_slice(&protocol_fee_coin.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding[1].to_le_bytes());
+        pool_data[offset..offset + 8].copy_from_slice(&protocol_fee_pc.to_le_bytes());
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&orderbook_to_init_time.to_le_bytes());
         offset += 8;
@@ -996,8 +2010,24 @@ mod test {
         offset += 32;
         pool_data[offset..offset + 32].copy_from_slice(&target_orders.to_bytes());
         offset += 32;
-        pool_data[offset..offset + 8 * 8].copy_from_slice(&padding1_data);
-        offset += 8 * 8;
+        pool_data[offset..offset + 8].copy_from_slice(&protocol_fee_enabled.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&dynamic_fee_enabled.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 4].copy_from_slice(&flash_loan_locked.to_le_bytes());
+        offset += 4;
+        pool_data[offset..offset + 4].copy_from_slice(&observation_enabled.to_le_bytes());
+        offset += 4;
+        pool_data[offset..offset + 8].copy_from_slice(&curve_type.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&initial_amp.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&target_amp.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&ramp_start_timestamp.to_le_bytes());
+        offset += 8;
+        pool_data[offset..offset + 8].copy_from_slice(&ramp_stop_timestamp.to_le_bytes());
+        offset += 8;
         pool_data[offset..offset + 32].copy_from_slice(&amm_owner.to_bytes());
         offset += 32;
         pool_data[offset..offset + 8].copy_from_slice(&lp_amount.to_le_bytes());
@@ -1006,7 +2036,7 @@ mod test {
         offset += 8;
         pool_data[offset..offset + 8].copy_from_slice(&recent_epoch.to_le_bytes());
         offset += 8;
-        pool_data[offset..offset + 8].copy_from_slice(&padding2.to_le_bytes());
+        pool_data[offset..offset + 8].copy_from_slice(&coin_weight.to_le_bytes());
         offset += 8;
 
         // len check
@@ -1075,10 +2105,10 @@ mod test {
         assert_eq!(total_pnl_coin, unpack_total_pnl_coin);
         let unpack_pool_open_time = unpack_data.state_data.pool_open_time;
         assert_eq!(pool_open_time, unpack_pool_open_time);
-        for i in 0..2 {
-            let unpack_padding = unpack_data.state_data.padding[i];
-            assert_eq!(padding[i], unpack_padding);
-        }
+        let unpack_protocol_fee_coin = unpack_data.state_data.protocol_fee_coin;
+        assert_eq!(protocol_fee_coin, unpack_protocol_fee_coin);
+        let unpack_protocol_fee_pc = unpack_data.state_data.protocol_fee_pc;
+        assert_eq!(protocol_fee_pc, unpack_protocol_fee_pc);
         let unpack_orderbook_to_init_time = unpack_data.state_data.orderbook_to_init_time;
         assert_eq!(orderbook_to_init_time, unpack_orderbook_to_init_time);
         let unpack_swap_coin_in_amount = unpack_data.state_data.swap_coin_in_amount;
@@ -1111,10 +2141,24 @@ mod test {
         assert_eq!(market_program, unpack_market_program);
         let unpack_target_orders = unpack_data.target_orders;
         assert_eq!(target_orders, unpack_target_orders);
-        for i in 0..8 {
-            let unpack_padding1 = unpack_data.padding1[i];
-            assert_eq!(padding1[i], unpack_padding1);
-        }
+        let unpack_protocol_fee_enabled = unpack_data.protocol_fee_enabled;
+        assert_eq!(protocol_fee_enabled, unpack_protocol_fee_enabled);
+        let unpack_dynamic_fee_enabled = unpack_data.dynamic_fee_enabled;
+        assert_eq!(dynamic_fee_enabled, unpack_dynamic_fee_enabled);
+        let unpack_flash_loan_locked = unpack_data.flash_loan_locked;
+        assert_eq!(flash_loan_locked, unpack_flash_loan_locked);
+        let unpack_observation_enabled = unpack_data.observation_enabled;
+        assert_eq!(observation_enabled, unpack_observation_enabled);
+        let unpack_curve_type = unpack_data.curve_type;
+        assert_eq!(curve_type, unpack_curve_type);
+        let unpack_initial_amp = unpack_data.initial_amp;
+        assert_eq!(initial_amp, unpack_initial_amp);
+        let unpack_target_amp = unpack_data.target_amp;
+        assert_eq!(target_amp, unpack_target_amp);
+        let unpack_ramp_start_timestamp = unpack_data.ramp_start_timestamp;
+        assert_eq!(ramp_start_timestamp, unpack_ramp_start_timestamp);
+        let unpack_ramp_stop_timestamp = unpack_data.ramp_stop_timestamp;
+        assert_eq!(ramp_stop_timestamp, unpack_ramp_stop_timestamp);
         let unpack_amm_owner = unpack_data.amm_owner;
         assert_eq!(amm_owner, unpack_amm_owner);
         let unpack_lp_amount = unpack_data.lp_amount;
@@ -1123,8 +2167,8 @@ mod test {
         assert_eq!(client_order_id, unpack_client_order_id);
         let unpack_recent_epoch = unpack_data.recent_epoch;
         assert_eq!(recent_epoch, unpack_recent_epoch);
-        let unpack_padding2 = unpack_data.padding2;
-        assert_eq!(padding2, unpack_padding2);
+        let unpack_coin_weight = unpack_data.coin_weight;
+        assert_eq!(coin_weight, unpack_coin_weight);
     }
 
     #[test]
@@ -1141,10 +2185,8 @@ mod test {
             buy_orders_data[offset..offset + 8].copy_from_slice(&buy_orders[i].vol.to_le_bytes());
             offset += 8;
         }
-        let mut padding1 = [0u64; 8];
-        for i in 0..8 {
-            padding1[i] = 1 << i;
-        }
+        let range_coin_vault = Pubkey::new_unique();
+        let range_pc_vault = Pubkey::new_unique();
         let target_x: u128 = 0x11002233445566778899aabbccddeeff;
         let target_y: u128 = 0x11220033445566778899aabbccddeeff;
         let plan_x_buy: u128 = 0x11223300445566778899aabbccddeeff;
@@ -1167,9 +2209,11 @@ mod test {
             sell_orders_data[offset..offset + 8].copy_from_slice(&sell_orders[i].vol.to_le_bytes());
             offset += 8;
         }
-        let mut padding2 = [0u64; 6];
-        for i in 0..6 {
-            padding2[i] = 1 << (i + 8);
+        let pending_status: u64 = 1 << 8;
+        let status_effective_time: u64 = 1 << 9;
+        let mut padding2 = [0u64; 4];
+        for i in 0..4 {
+            padding2[i] = 1 << (i + 10);
         }
         let mut replace_buy_client_id = [0u64; MAX_ORDER_LIMIT];
         let mut replace_sell_client_id = [0u64; MAX_ORDER_LIMIT];
@@ -1183,10 +2227,18 @@ mod test {
         let place_orders_cur: u64 = 0x1234567809abcedf;
         let valid_buy_order_num: u64 = 0x1234567089abcedf;
         let valid_sell_order_num: u64 = 0x1234560789abcedf;
-        let mut padding3 = [0u64; 10];
-        for i in 0..10 {
-            padding3[i] = 1 << (i + 8 + 6 + MAX_ORDER_LIMIT + MAX_ORDER_LIMIT);
-        }
+        let pending_fees = Fees {
+            min_separate_numerator: 0x1234506789abcedf,
+            min_separate_denominator: 0x1234056789abcedf,
+            trade_fee_numerator: 0x1230456789abcedf,
+            trade_fee_denominator: 0x1203456789abcedf,
+            pnl_numerator: 0x1023456789abcedf,
+            pnl_denominator: 0x0123456789abcedf,
+            swap_fee_numerator: 0x123456789abcedf0,
+            swap_fee_denominator: 0x123456789abced0f,
+        };
+        let fees_effective_time: u64 = 1 << (8 + 6 + MAX_ORDER_LIMIT + MAX_ORDER_LIMIT);
+        let padding3 = [1u64 << (9 + 6 + MAX_ORDER_LIMIT + MAX_ORDER_LIMIT)];
         let free_slot_bits: u128 = 0x112233445566778899aabb00ccddeeff;
 
         // serialize original data
@@ -1198,10 +2250,10 @@ mod test {
 
         target_orders_data[offset..offset + 8 * 2 * 50].copy_from_slice(&buy_orders_data);
         offset += 8 * 2 * 50;
-        for i in 0..8 {
-            target_orders_data[offset..offset + 8].copy_from_slice(&padding1[i].to_le_bytes());
-            offset += 8;
-        }
+        target_orders_data[offset..offset + 32].copy_from_slice(&range_coin_vault.to_bytes());
+        offset += 32;
+        target_orders_data[offset..offset + 32].copy_from_slice(&range_pc_vault.to_bytes());
+        offset += 32;
         target_orders_data[offset..offset + 16].copy_from_slice(&target_x.to_le_bytes());
         offset += 16;
         target_orders_data[offset..offset + 16].copy_from_slice(&target_y.to_le_bytes());
@@ -1224,7 +2276,12 @@ mod test {
         offset += 16;
         target_orders_data[offset..offset + 8 * 2 * 50].copy_from_slice(&sell_orders_data);
         offset += 8 * 2 * 50;
-        for i in 0..6 {
+        target_orders_data[offset..offset + 8].copy_from_slice(&pending_status.to_le_bytes());
+        offset += 8;
+        target_orders_data[offset..offset + 8]
+            .copy_from_slice(&status_effective_time.to_le_bytes());
+        offset += 8;
+        for i in 0..4 {
             target_orders_data[offset..offset + 8].copy_from_slice(&padding2[i].to_le_bytes());
             offset += 8;
         }
@@ -1251,10 +2308,23 @@ mod test {
         offset += 8;
         target_orders_data[offset..offset + 8].copy_from_slice(&valid_sell_order_num.to_le_bytes());
         offset += 8;
-        for i in 0..10 {
-            target_orders_data[offset..offset + 8].copy_from_slice(&padding3[i].to_le_bytes());
+        for fee in [
+            pending_fees.min_separate_numerator,
+            pending_fees.min_separate_denominator,
+            pending_fees.trade_fee_numerator,
+            pending_fees.trade_fee_denominator,
+            pending_fees.pnl_numerator,
+            pending_fees.pnl_denominator,
+            pending_fees.swap_fee_numerator,
+            pending_fees.swap_fee_denominator,
+        ] {
+            target_orders_data[offset..offset + 8].copy_from_slice(&fee.to_le_bytes());
             offset += 8;
         }
+        target_orders_data[offset..offset + 8].copy_from_slice(&fees_effective_time.to_le_bytes());
+        offset += 8;
+        target_orders_data[offset..offset + 8].copy_from_slice(&padding3[0].to_le_bytes());
+        offset += 8;
         target_orders_data[offset..offset + 16].copy_from_slice(&free_slot_bits.to_le_bytes());
         offset += 16;
 
@@ -1281,10 +2351,10 @@ mod test {
             let unpack_vol = unpack_buy_orders[i].vol;
             assert_eq!(vol, unpack_vol);
         }
-        let unpack_padding1 = unpack_data.padding1;
-        for i in 0..8 {
-            assert_eq!(padding1[i], unpack_padding1[i]);
-        }
+        let unpack_range_coin_vault = unpack_data.range_coin_vault;
+        assert_eq!(range_coin_vault, unpack_range_coin_vault);
+        let unpack_range_pc_vault = unpack_data.range_pc_vault;
+        assert_eq!(range_pc_vault, unpack_range_pc_vault);
         let unpack_target_x = unpack_data.target_x;
         assert_eq!(target_x, unpack_target_x);
         let unpack_target_y = unpack_data.target_y;
@@ -1314,8 +2384,12 @@ mod test {
             let unpack_vol = unpack_sell_orders[i].vol;
             assert_eq!(vol, unpack_vol);
         }
+        let unpack_pending_status = unpack_data.pending_status;
+        assert_eq!(pending_status, unpack_pending_status);
+        let unpack_status_effective_time = unpack_data.status_effective_time;
+        assert_eq!(status_effective_time, unpack_status_effective_time);
         let unpack_padding2 = unpack_data.padding2;
-        for i in 0..6 {
+        for i in 0..4 {
             assert_eq!(padding2[i], unpack_padding2[i]);
         }
         let unpack_replace_buy_client_id = unpack_data.replace_buy_client_id;
@@ -1338,11 +2412,383 @@ mod test {
         assert_eq!(valid_buy_order_num, unpack_valid_buy_order_num);
         let unpack_valid_sell_order_num = unpack_data.valid_sell_order_num;
         assert_eq!(valid_sell_order_num, unpack_valid_sell_order_num);
+        let unpack_pending_fees = unpack_data.pending_fees;
+        assert_eq!(pending_fees, unpack_pending_fees);
+        let unpack_fees_effective_time = unpack_data.fees_effective_time;
+        assert_eq!(fees_effective_time, unpack_fees_effective_time);
         let unpack_padding3 = unpack_data.padding3;
-        for i in 0..10 {
-            assert_eq!(padding3[i], unpack_padding3[i]);
-        }
+        assert_eq!(padding3, unpack_padding3);
         let unpack_free_slot_bits = unpack_data.free_slot_bits;
         assert_eq!(free_slot_bits, unpack_free_slot_bits);
     }
+
+    #[test]
+    fn test_simulate_swap_info_layout() {
+        let swap_info = SimulateSwapInfo {
+            amount_in: 1,
+            amount_in_after_transfer_fee: 2,
+            swap_fee: 3,
+            amount_out: 4,
+            amount_out_after_transfer_fee: 5,
+            price_impact: 6,
+            pool_coin_amount: 7,
+            pool_pc_amount: 8,
+            need_take_pnl_coin: 9,
+            need_take_pnl_pc: 10,
+        };
+        let data = bytemuck::bytes_of(&swap_info);
+        assert_eq!(data.len(), 80);
+        for i in 0..10 {
+            let value = u64::from_le_bytes(data[i * 8..(i + 1) * 8].try_into().unwrap());
+            assert_eq!(value, i as u64 + 1);
+        }
+        assert_eq!(SimulateSwapInfo::load_from_bytes(data).unwrap(), &swap_info);
+        assert_eq!(size_of::<SimulatePoolInfo>(), 88);
+    }
+
+    #[test]
+    fn test_observation_twap() {
+        let mut observation_state: ObservationState = Zeroable::zeroed();
+        let amm_id = Pubkey::new_unique();
+        observation_state.initialize(&amm_id, 255, 1000).unwrap();
+        assert!(observation_state.initialize(&amm_id, 255, 1000).is_err());
+
+        // price 2 for 10s, then price 4 for 30s
+        observation_state.update(1010, 2, 50);
+        observation_state.update(1010, 3, 30);
+        observation_state.update(1040, 4, 25);
+        let latest = observation_state.latest();
+        let observation_index = observation_state.observation_index;
+        assert_eq!(observation_index, 2);
+        let (pc_twap, coin_twap) =
+            Observation::twap(&observation_state.observations[0], &latest).unwrap();
+        assert_eq!(pc_twap, (2 * 10 + 4 * 30) / 40);
+        assert_eq!(coin_twap, (50 * 10 + 25 * 30) / 40);
+        assert!(Observation::twap(&latest, &latest).is_none());
+
+        let observation = observation_state.observation_before(1039).unwrap();
+        let block_timestamp = observation.block_timestamp;
+        assert_eq!(block_timestamp, 1010);
+        assert!(observation_state.observation_before(999).is_none());
+
+        // the ring buffer wraps around and drops the oldest observations
+        for i in 1..OBSERVATION_NUM as u64 {
+            observation_state.update(1040 + i, 1, 1);
+        }
+        let observation_index = observation_state.observation_index;
+        assert_eq!(
+            observation_index as usize,
+            (2 + OBSERVATION_NUM - 1) % OBSERVATION_NUM
+        );
+        let oldest = observation_state.observation_before(1040).unwrap();
+        let block_timestamp = oldest.block_timestamp;
+        assert_eq!(block_timestamp, 1040);
+        assert!(observation_state.observation_before(1010).is_none());
+    }
+
+    #[test]
+    fn test_referrer_fee() {
+        let mut amm_config = AmmConfig::default();
+        assert_eq!(amm_config.referrer_fee(2500), 0);
+        amm_config.referrer_fee_rate = 2000;
+        assert_eq!(amm_config.referrer_fee(2500), 500);
+        assert_eq!(amm_config.referrer_fee(4), 0);
+        // the share never exceeds MAX_REFERRER_FEE_RATE of the swap fee
+        amm_config.referrer_fee_rate = TEN_THOUSAND;
+        assert_eq!(amm_config.referrer_fee(2500), 1250);
+        assert_eq!(amm_config.referrer_fee(u64::MAX), u64::MAX / 2);
+    }
+
+    #[test]
+    fn test_global_status() {
+        let mut amm_config = AmmConfig::default();
+        assert!(amm_config.check_global_status(false).is_ok());
+        amm_config.global_status = AmmStatus::WithdrawOnly.into_u64();
+        assert_eq!(
+            amm_config.check_global_status(false),
+            Err(AmmError::GlobalPaused)
+        );
+        assert!(amm_config.check_global_status(true).is_ok());
+        amm_config.global_status = AmmStatus::Disabled.into_u64();
+        assert_eq!(
+            amm_config.check_global_status(true),
+            Err(AmmError::GlobalPaused)
+        );
+        assert!(
+            AmmStatus::Disabled.pause_level() > AmmStatus::WithdrawOnly.pause_level()
+                && AmmStatus::WithdrawOnly.pause_level() > AmmStatus::SwapOnly.pause_level()
+        );
+    }
+
+    #[test]
+    fn test_flash_loan() {
+        let mut amm_config = AmmConfig::default();
+        assert_eq!(amm_config.flash_fee(1_000_000), 0);
+        amm_config.flash_fee_rate = 9;
+        assert_eq!(amm_config.flash_fee(1_000_000), 900);
+        // the fee is rounded up and never exceeds MAX_FLASH_FEE_RATE
+        assert_eq!(amm_config.flash_fee(1), 1);
+        assert_eq!(amm_config.flash_fee(0), 0);
+        amm_config.flash_fee_rate = TEN_THOUSAND;
+        assert_eq!(amm_config.flash_fee(1_000_000), 100_000);
+
+        let mut amm = AmmInfo::default();
+        assert!(amm.check_flash_loan_unlocked().is_ok());
+        amm.flash_loan_locked = 1;
+        assert_eq!(
+            amm.check_flash_loan_unlocked(),
+            Err(AmmError::FlashLoanLocked)
+        );
+    }
+
+    #[test]
+    fn test_amp_ramp() {
+        let mut amm = AmmInfo {
+            initial_amp: 100,
+            target_amp: 1000,
+            ramp_start_timestamp: 1_000,
+            ramp_stop_timestamp: 1_000 + MIN_RAMP_DURATION,
+            ..Default::default()
+        };
+        assert_eq!(amm.amp(0), 100);
+        assert_eq!(amm.amp(1_000), 100);
+        assert_eq!(amm.amp(1_000 + MIN_RAMP_DURATION / 2), 550);
+        assert_eq!(amm.amp(1_000 + MIN_RAMP_DURATION), 1000);
+        assert_eq!(amm.amp(u64::MAX), 1000);
+        // ramp down
+        amm.initial_amp = 1000;
+        amm.target_amp = 100;
+        assert_eq!(amm.amp(1_000 + MIN_RAMP_DURATION / 4), 775);
+        assert_eq!(amm.amp(1_000 + MIN_RAMP_DURATION), 100);
+    }
+
+    #[test]
+    fn test_range_orders() {
+        let amm = AmmInfo {
+            coin_decimals: 9,
+            pc_decimals: 6,
+            sys_decimal_value: 1_000_000,
+            ..Default::default()
+        };
+        let mut target = TargetOrders::default();
+        target
+            .init_range_orders(&Pubkey::new_unique(), &Pubkey::new_unique())
+            .unwrap();
+        assert!(target.range_orders_enabled());
+        // buy 100 pc at 2.5 and 50 pc at 2.0, sell 1 coin at 3.0
+        target
+            .take_range_order_slot(OrderSide::Buy, 0, 2_500_000, 100_000_000)
+            .unwrap();
+        target
+            .take_range_order_slot(OrderSide::Buy, 1, 2_000_000, 50_000_000)
+            .unwrap();
+        target
+            .take_range_order_slot(OrderSide::Sell, 0, 3_000_000, 1_000_000_000)
+            .unwrap();
+        assert_eq!(
+            target.take_range_order_slot(OrderSide::Buy, 0, 1, 1),
+            Err(AmmError::RangeOrderSlotInUse.into())
+        );
+        assert_eq!(
+            target.take_range_order_slot(OrderSide::Buy, RANGE_ORDER_SLOTS, 1, 1),
+            Err(AmmError::InvalidInput.into())
+        );
+
+        // pool price 2.2, only the order at 2.5 crosses
+        let fill = target.fill_range_orders(
+            &amm,
+            SwapDirection::Coin2PC,
+            10_000_000_000,
+            2_200_000,
+            MAX_RANGE_ORDER_FILLS,
+        );
+        assert_eq!(fill, (10_000_000_000, 25_000_000, 1));
+        // no fill left to the swap
+        let fill = target.fill_range_orders(&amm, SwapDirection::Coin2PC, 1_000_000, 2_200_000, 0);
+        assert_eq!(fill, (0, 0, 0));
+        let fill = target.fill_range_orders(
+            &amm,
+            SwapDirection::Coin2PC,
+            50_000_000_000,
+            2_200_000,
+            MAX_RANGE_ORDER_FILLS,
+        );
+        assert_eq!(fill, (30_000_000_000, 75_000_000, 1));
+        let vol = target.buy_orders[0].vol;
+        assert_eq!(vol, 0);
+        let vol = target.buy_orders[1].vol;
+        assert_eq!(vol, 50_000_000);
+
+        // the sell order is below the pool price 3.5, the taker pays the rounding
+        let fill = target.fill_range_orders(
+            &amm,
+            SwapDirection::PC2Coin,
+            1_000_000,
+            3_500_000,
+            MAX_RANGE_ORDER_FILLS,
+        );
+        assert_eq!(fill, (1_000_000, 333_333_333, 1));
+        let fill = target.fill_range_orders(
+            &amm,
+            SwapDirection::PC2Coin,
+            1_000_000,
+            2_900_000,
+            MAX_RANGE_ORDER_FILLS,
+        );
+        assert_eq!(fill, (0, 0, 0));
+
+        let mut order = RangeOrderState {
+            amm_id: Pubkey::default(),
+            owner: Pubkey::default(),
+            side: 0,
+            slot: 0,
+            price: 0,
+            amount: 0,
+            claimed_fill: 0,
+            padding: [0; 4],
+        };
+        order.initialize(
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            OrderSide::Buy,
+            0,
+            2_500_000,
+            100_000_000,
+        );
+        assert_eq!(order.claimable(&amm, 25_000_000), 10_000_000_000);
+        order.claimed_fill = 25_000_000;
+        assert_eq!(order.claimable(&amm, 100_000_000), 30_000_000_000);
+
+        target.free_range_order_slot(OrderSide::Buy, 0);
+        target
+            .take_range_order_slot(OrderSide::Buy, 0, 2_400_000, 1_000_000)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_pending_params() {
+        let mut target = TargetOrders::default();
+        assert_eq!(target.take_effective_status(u64::MAX), None);
+        assert_eq!(target.take_effective_fees(u64::MAX), None);
+
+        target.queue_status(AmmStatus::SwapOnly.into_u64(), 1000);
+        assert_eq!(target.take_effective_status(999), None);
+        assert_eq!(
+            target.take_effective_status(1000),
+            Some(AmmStatus::SwapOnly.into_u64())
+        );
+        // applied once
+        assert_eq!(target.take_effective_status(1001), None);
+        target.queue_status(AmmStatus::Initialized.into_u64(), 1000);
+        target.clear_pending_status();
+        assert_eq!(target.take_effective_status(1000), None);
+
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        target.queue_fees(fees, 2000);
+        assert_eq!(target.take_effective_fees(1999), None);
+        assert_eq!(target.take_effective_fees(2000), Some(fees));
+        assert_eq!(target.take_effective_fees(2001), None);
+    }
+
+    #[test]
+    fn test_limit_order() {
+        let amm = AmmInfo {
+            coin_decimals: 9,
+            pc_decimals: 6,
+            sys_decimal_value: 1_000_000,
+            ..Default::default()
+        };
+        let mut order = LimitOrderState {
+            amm_id: Pubkey::default(),
+            owner: Pubkey::default(),
+            order_id: 0,
+            side: 0,
+            price: 0,
+            amount: 0,
+            proceeds: 0,
+            padding: [0; 6],
+        };
+        // sell 2 coin at 3.0
+        order.initialize(
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            7,
+            OrderSide::Sell,
+            3_000_000,
+            2_000_000_000,
+        );
+        assert!(!order.limit_reached(2_999_999));
+        assert!(order.limit_reached(3_000_000));
+        assert_eq!(order.minimum_out(&amm), 6_000_000);
+        // buy coin with 10 pc at 3.0
+        order.side = OrderSide::Buy.into_u64();
+        order.amount = 10_000_000;
+        assert!(order.limit_reached(2_999_999));
+        assert!(!order.limit_reached(3_000_001));
+        assert_eq!(order.minimum_out(&amm), 3_333_333_334);
+    }
+
+    #[test]
+    fn test_dynamic_fee() {
+        // swap_fee = 25 / 10000
+        let mut fees = Fees::default();
+        fees.initialize().unwrap();
+        let params = DynamicFeeParams {
+            min_fee_numerator: 10,
+            max_fee_numerator: 100,
+            variable_fee_control: 1000,
+            filter_period: 30,
+            decay_period: 600,
+            reduction_factor: 5000,
+        };
+        assert!(fees.validate(Some(&params)).is_ok());
+        assert!(fees.validate(None).is_ok());
+        assert!(fees
+            .validate(Some(&DynamicFeeParams {
+                min_fee_numerator: 30,
+                ..params
+            }))
+            .is_err());
+        assert!(fees
+            .validate(Some(&DynamicFeeParams {
+                max_fee_numerator: 10000,
+                ..params
+            }))
+            .is_err());
+
+        let mut state: DynamicFeeState = Zeroable::zeroed();
+        state.params = params;
+        // the first swap only takes the reference price
+        state.update(1000, 1_000_000);
+        assert_eq!({ state.volatility_accumulator }, 0);
+        assert_eq!(state.swap_fee_numerator(&fees), 25);
+        // a 2% move within the filter period accumulates from the same reference price
+        state.update(1010, 1_020_000);
+        assert_eq!({ state.volatility_accumulator }, 200);
+        assert_eq!(state.swap_fee_numerator(&fees), 45);
+        state.update(1020, 1_050_000);
+        assert_eq!({ state.volatility_accumulator }, 500);
+        assert_eq!(state.swap_fee_numerator(&fees), 75);
+        // after the filter period the volatility is reduced and the reference price moves
+        state.update(1060, 1_050_000);
+        assert_eq!({ state.volatility_reference }, 250);
+        assert_eq!({ state.volatility_accumulator }, 250);
+        assert_eq!({ state.reference_price }, 1_050_000);
+        // the fee stays within max_fee_numerator
+        state.update(1070, 2_100_000);
+        assert_eq!({ state.volatility_accumulator }, 10250);
+        assert_eq!(state.swap_fee_numerator(&fees), 100);
+        // after the decay period the volatility is reset
+        state.update(1700, 2_000_000);
+        assert_eq!({ state.volatility_accumulator }, 0);
+        assert_eq!(state.swap_fee_numerator(&fees), 25);
+        assert_eq!(
+            state.swap_fee_numerator(&Fees {
+                swap_fee_numerator: 5,
+                ..fees
+            }),
+            10
+        );
+    }
 }